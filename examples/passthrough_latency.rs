@@ -0,0 +1,72 @@
+//! Measures how long the mapping engine takes to process key events for
+//! a key that isn't remapped, with and without low_latency_passthrough.
+//!
+//! ```console
+//! $ cargo run --release --example passthrough_latency
+//! ```
+use evdev_rs::TimeVal;
+use evremap::mapping::{KeyCode, Mapping};
+use evremap::{MappingConfig, MappingEngine};
+use std::time::Instant;
+
+const EVENTS: i64 = 200_000;
+
+/// Keys that the configuration remaps, to give the engine a realistic
+/// number of mappings to look through
+const REMAPPED: &[(KeyCode, KeyCode)] = &[
+    (KeyCode::KEY_CAPSLOCK, KeyCode::KEY_LEFTCTRL),
+    (KeyCode::KEY_F1, KeyCode::KEY_BACK),
+    (KeyCode::KEY_F2, KeyCode::KEY_FORWARD),
+    (KeyCode::KEY_F3, KeyCode::KEY_REFRESH),
+    (KeyCode::KEY_F4, KeyCode::KEY_SCALE),
+    (KeyCode::KEY_F5, KeyCode::KEY_BRIGHTNESSDOWN),
+    (KeyCode::KEY_F6, KeyCode::KEY_BRIGHTNESSUP),
+    (KeyCode::KEY_F7, KeyCode::KEY_MUTE),
+    (KeyCode::KEY_F8, KeyCode::KEY_VOLUMEDOWN),
+    (KeyCode::KEY_F9, KeyCode::KEY_VOLUMEUP),
+    (KeyCode::KEY_KP1, KeyCode::KEY_END),
+    (KeyCode::KEY_KP2, KeyCode::KEY_DOWN),
+    (KeyCode::KEY_KP3, KeyCode::KEY_PAGEDOWN),
+    (KeyCode::KEY_KP4, KeyCode::KEY_LEFT),
+    (KeyCode::KEY_KP6, KeyCode::KEY_RIGHT),
+    (KeyCode::KEY_KP7, KeyCode::KEY_HOME),
+    (KeyCode::KEY_KP8, KeyCode::KEY_UP),
+    (KeyCode::KEY_KP9, KeyCode::KEY_PAGEUP),
+];
+
+fn config(low_latency_passthrough: bool) -> MappingConfig {
+    let mut builder = MappingConfig::builder();
+    for (input, output) in REMAPPED {
+        builder = builder.mapping(Mapping::Remap {
+            input: [*input].into(),
+            output: [*output].into(),
+            order: vec![],
+            exact: false,
+        });
+    }
+    let mut config = builder.build();
+    config.low_latency_passthrough = low_latency_passthrough;
+    config
+}
+
+/// Returns the mean time, in nanoseconds, to process a press or
+/// release of a key that isn't remapped
+fn measure(low_latency_passthrough: bool) -> f64 {
+    let mut engine = MappingEngine::new(config(low_latency_passthrough), &TimeVal::new(0, 0));
+    let start = Instant::now();
+    for n in 0..EVENTS {
+        let time = TimeVal::new(n / 1000, (n % 1000) * 1000);
+        let value = (n % 2 == 0) as i32;
+        std::hint::black_box(engine.process_key(KeyCode::KEY_A, value, &time).unwrap());
+    }
+    start.elapsed().as_nanos() as f64 / EVENTS as f64
+}
+
+fn main() {
+    // Warm up, so that the first measurement isn't penalized
+    measure(false);
+    let tracked = measure(false);
+    let passthrough = measure(true);
+    println!("tracked:                 {tracked:8.0} ns/event");
+    println!("low_latency_passthrough: {passthrough:8.0} ns/event");
+}
@@ -0,0 +1,144 @@
+use anyhow::{bail, Context, Result};
+use evremap::mapping::{KeyCode, Mapping, MappingConfig};
+use evremap::remapper::is_modifier;
+use std::path::Path;
+
+/// Loads the config file at `config_file` without touching any devices,
+/// and reports the mappings that can't work as intended: duplicates,
+/// entries that are shadowed by earlier ones, and dual role keys whose
+/// input is also used by a remap.  Returns an error if there are any
+/// such problems, or if the config file can't be loaded.  Keys that
+/// are given by their numeric codes are listed with their names.
+pub fn check_config(config_file: &Path) -> Result<()> {
+    let config = MappingConfig::from_file(config_file).context(format!(
+        "loading MappingConfig from {}",
+        config_file.display()
+    ))?;
+
+    let mut problems = check_mappings("", &config.mappings);
+    for layer in &config.layers {
+        problems.extend(check_mappings(
+            &format!("layer {:?}: ", layer.name),
+            &layer.mappings,
+        ));
+    }
+    for profile in &config.profiles {
+        problems.extend(check_mappings(
+            &format!("profile {:?}: ", profile.name),
+            &profile.mappings,
+        ));
+    }
+    for (idx, device) in config.devices.iter().enumerate() {
+        problems.extend(check_mappings(
+            &format!("device {}: ", idx + 1),
+            &device.mappings,
+        ));
+    }
+
+    // Keys given by their codes, with their names, to show which keys they are
+    let numeric_keys = MappingConfig::numeric_keys(config_file)?;
+    for (code, key) in &numeric_keys {
+        println!("{}: key code {code} is {key:?}", config_file.display());
+    }
+
+    for problem in &problems {
+        println!("{}: {problem}", config_file.display());
+    }
+    if !problems.is_empty() {
+        bail!(
+            "found {} problem(s) in {}",
+            problems.len(),
+            config_file.display()
+        );
+    }
+    println!("{}: OK", config_file.display());
+    Ok(())
+}
+
+/// Checks a set of mappings that are in effect at the same time,
+/// returning a description of each problem, prefixed by `context`
+fn check_mappings(context: &str, mappings: &[Mapping]) -> Vec<String> {
+    let mut problems = vec![];
+    for (idx, map) in mappings.iter().enumerate() {
+        let earlier = &mappings[..idx];
+        if earlier.contains(map) {
+            problems.push(format!("{context}{map} is a duplicate"));
+            continue;
+        }
+
+        if let Some(input) = single_input(map) {
+            if let Some(other) = earlier.iter().find(|m| single_input(m) == Some(input)) {
+                problems.push(format!(
+                    "{context}{map} is shadowed by {other}, which has the same input"
+                ));
+            }
+        }
+
+        if let Mapping::Remap { input, .. } = map {
+            // An earlier remap uses up the non-modifier keys of its
+            // input, so that they can't also complete this one
+            let shadowed_by = earlier.iter().find(|m| match m {
+                Mapping::Remap { input: other, .. } => {
+                    other.is_subset(input) && other.iter().any(|k| !is_modifier(k))
+                }
+                _ => false,
+            });
+            if let Some(other) = shadowed_by {
+                problems.push(format!(
+                    "{context}{map} is shadowed by {other}, whose input it contains"
+                ));
+            }
+        }
+
+        if let Mapping::Sequence { keys, .. } = map {
+            let shadowed_by = earlier
+                .iter()
+                .find(|m| matches!(m, Mapping::Sequence { keys: other, .. } if other == keys));
+            if let Some(other) = shadowed_by {
+                problems.push(format!(
+                    "{context}{map} is shadowed by {other}, which has the same keys"
+                ));
+            }
+        }
+
+        if let Mapping::DualRole { input, .. } | Mapping::MultiFunction { input, .. } = map {
+            // The dual role key is consumed before remaps are applied,
+            // so a remap never sees it
+            let remap = mappings
+                .iter()
+                .find(|m| matches!(m, Mapping::Remap { input: keys, .. } if keys.contains(input)));
+            if let Some(remap) = remap {
+                problems.push(format!(
+                    "{context}{map} is also in the input of {remap}, which will never see it"
+                ));
+            }
+        }
+    }
+    problems
+}
+
+/// Returns the input key of the kinds of entry that are triggered
+/// by a single key, and which take it over completely
+fn single_input(map: &Mapping) -> Option<KeyCode> {
+    match map {
+        Mapping::DualRole { input, .. }
+        | Mapping::MultiFunction { input, .. }
+        | Mapping::RawKey { input, .. }
+        | Mapping::ModLock { input, .. }
+        | Mapping::Macro { input, .. }
+        | Mapping::MouseKey { input, .. }
+        | Mapping::OneShot { input, .. }
+        | Mapping::CapsWord { input }
+        | Mapping::TapDance { input, .. }
+        | Mapping::Turbo { input, .. }
+        | Mapping::Command { input, .. }
+        | Mapping::Layer { input, .. }
+        | Mapping::Profile { input, .. } => Some(*input),
+        Mapping::Remap { .. }
+        | Mapping::Swap { .. }
+        | Mapping::Encoder { .. }
+        | Mapping::Sequence { .. }
+        | Mapping::SubstituteModifier { .. }
+        | Mapping::ScanRemap { .. } => None,
+    }
+}
@@ -0,0 +1,228 @@
+use anyhow::{bail, Context, Result};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+use std::time::Duration;
+
+/// How long to wait for a client to send its command
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The commands that can be sent to the control socket
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ControlCommand {
+    /// Release everything and stop remapping, ungrabbing the devices
+    /// so that their events are seen as they are
+    Pause,
+    /// Grab the devices and start remapping again
+    Resume,
+    /// Load the config file again
+    Reload,
+    /// Describe each device and the keys that are held
+    Status,
+    /// Switch to the named profile
+    Profile(String),
+}
+
+impl ControlCommand {
+    pub fn parse(command: &str) -> Option<Self> {
+        match command.split_once(' ') {
+            Some(("profile", name)) => Some(Self::Profile(name.trim().to_string())),
+            Some(_) => None,
+            None => match command {
+                "pause" => Some(Self::Pause),
+                "resume" => Some(Self::Resume),
+                "reload" => Some(Self::Reload),
+                "status" => Some(Self::Status),
+                _ => None,
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for ControlCommand {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Pause => write!(f, "pause"),
+            Self::Resume => write!(f, "resume"),
+            Self::Reload => write!(f, "reload"),
+            Self::Status => write!(f, "status"),
+            Self::Profile(name) => write!(f, "profile {name}"),
+        }
+    }
+}
+
+/// Somewhere that control commands come from, whose file descriptor
+/// becomes readable when there is a command to accept
+pub trait ControlSource: AsRawFd {
+    /// Returns the next command that is waiting to be carried out, if any
+    fn accept(&self) -> Option<ControlRequest>;
+}
+
+/// Returns where the control socket is put by default: in /run/evremap
+/// when running as root, and in the runtime directory of the user
+/// otherwise
+pub fn default_socket_path() -> PathBuf {
+    let uid = unsafe { libc::geteuid() };
+    if uid == 0 {
+        return PathBuf::from("/run/evremap/evremap.sock");
+    }
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir).join("evremap.sock"),
+        None => PathBuf::from(format!("/tmp/evremap-{uid}.sock")),
+    }
+}
+
+/// The socket on which the remapper accepts `ControlCommand`s.
+/// Each connection sends one command as a line of text, and gets
+/// back `ok` or `error: <reason>` on the first line of the reply,
+/// followed by any output of the command.
+pub struct ControlSocket {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl ControlSocket {
+    pub fn bind(path: &Path) -> Result<Self> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).context(format!("creating {}", dir.display()))?;
+        }
+        if path.exists() {
+            if UnixStream::connect(path).is_ok() {
+                bail!("{} is in use by another evremap", path.display());
+            }
+            // Left behind by an evremap that was killed
+            std::fs::remove_file(path).context(format!("removing {}", path.display()))?;
+        }
+        let listener = UnixListener::bind(path).context(format!("binding {}", path.display()))?;
+        listener.set_nonblocking(true)?;
+        // The socket can grab and release the devices, so only
+        // the user running evremap may use it
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))
+            .context(format!("setting the permissions of {}", path.display()))?;
+        log::info!("Listening for control commands on {}", path.display());
+        Ok(Self {
+            listener,
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl ControlSource for ControlSocket {
+    /// Returns the next client that has sent a command, if there is
+    /// one waiting.  Clients that send something other than a valid
+    /// command are answered with an error here.
+    fn accept(&self) -> Option<ControlRequest> {
+        loop {
+            let (stream, _) = self.listener.accept().ok()?;
+            match read_request(stream) {
+                Ok(request) => return Some(request),
+                Err(err) => log::warn!("Ignoring a control request: {err:#}"),
+            }
+        }
+    }
+}
+
+impl AsRawFd for ControlSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Reads a command from a client of the control socket
+fn read_request(mut stream: UnixStream) -> Result<ControlRequest> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut line = String::new();
+    BufReader::new(&stream)
+        .read_line(&mut line)
+        .context("reading the command")?;
+    let line = line.trim();
+    match ControlCommand::parse(line) {
+        Some(command) => Ok(ControlRequest {
+            command,
+            replier: Replier::Socket(stream),
+        }),
+        None => {
+            let _ = writeln!(stream, "error: unknown command `{line}`");
+            bail!("unknown command `{line}`");
+        }
+    }
+}
+
+/// A command received from a `ControlSource`, which is
+/// waiting to be answered
+pub struct ControlRequest {
+    pub command: ControlCommand,
+    replier: Replier,
+}
+
+/// Where to send the outcome of a `ControlRequest`
+enum Replier {
+    /// The client of the control socket that sent it
+    Socket(UnixStream),
+    /// The thread that received it, which passes on
+    /// the outcome as a message of its own
+    #[cfg_attr(not(feature = "dbus"), allow(dead_code))]
+    Channel(Sender<Result<String>>),
+}
+
+impl ControlRequest {
+    #[cfg(feature = "dbus")]
+    pub(crate) fn with_channel(command: ControlCommand, sender: Sender<Result<String>>) -> Self {
+        Self {
+            command,
+            replier: Replier::Channel(sender),
+        }
+    }
+
+    /// Sends the outcome of the command back to where it came from
+    pub fn reply(self, result: Result<String>) {
+        match self.replier {
+            Replier::Socket(mut stream) => {
+                let reply = match result {
+                    Ok(output) => format!("ok\n{output}"),
+                    Err(err) => format!("error: {err:#}\n"),
+                };
+                if let Err(err) = stream.write_all(reply.as_bytes()) {
+                    log::warn!("Unable to reply to a control request: {err:#}");
+                }
+            }
+            Replier::Channel(sender) => {
+                // The receiver is gone if it gave up waiting
+                let _ = sender.send(result);
+            }
+        }
+    }
+}
+
+/// Sends `command` to the remapper listening on `path`, and returns
+/// its output, or an error if it failed
+pub fn send_command(path: &Path, command: ControlCommand) -> Result<String> {
+    let mut stream = UnixStream::connect(path).context(format!(
+        "connecting to {}; is evremap running?",
+        path.display()
+    ))?;
+    writeln!(stream, "{command}")?;
+    stream.shutdown(std::net::Shutdown::Write)?;
+    let mut reply = String::new();
+    stream
+        .read_to_string(&mut reply)
+        .context(format!("reading the reply from {}", path.display()))?;
+    match reply.split_once('\n') {
+        Some(("ok", output)) => Ok(output.to_string()),
+        Some((status, _)) => match status.strip_prefix("error: ") {
+            Some(reason) => bail!("{reason}"),
+            None => bail!("unexpected reply: {status}"),
+        },
+        None => bail!("evremap closed the connection without replying"),
+    }
+}
@@ -0,0 +1,119 @@
+use crate::control::{ControlCommand, ControlRequest, ControlSource};
+use anyhow::{Context, Result};
+use std::io::{Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::time::Duration;
+
+pub const BUS_NAME: &str = "org.evremap.Remapper";
+pub const OBJECT_PATH: &str = "/org/evremap/Remapper";
+
+/// How long a D-Bus method waits for the remap loop to carry it out
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Offers the control commands on D-Bus, as the methods of the
+/// org.evremap.Remapper interface.  It is on the system bus when
+/// running as root, and the session bus otherwise.
+///
+/// The methods are called on a thread of zbus, and are passed on to
+/// the remap loop, which accepts them in the same way as the commands
+/// sent to the control socket.
+pub struct DbusService {
+    receiver: Receiver<ControlRequest>,
+    /// Becomes readable when there is something to receive
+    wakeup: UnixStream,
+    _connection: zbus::blocking::Connection,
+}
+
+impl DbusService {
+    pub fn start() -> Result<Self> {
+        let (wakeup, notify) = UnixStream::pair().context("creating a socket pair")?;
+        wakeup.set_nonblocking(true)?;
+        let (sender, receiver) = channel();
+        let remapper = Remapper { sender, notify };
+        let builder = if unsafe { libc::geteuid() } == 0 {
+            zbus::blocking::connection::Builder::system()
+        } else {
+            zbus::blocking::connection::Builder::session()
+        }
+        .context("connecting to D-Bus")?;
+        let connection = builder
+            .name(BUS_NAME)?
+            .serve_at(OBJECT_PATH, remapper)?
+            .build()
+            .context(format!("registering {BUS_NAME} on D-Bus"))?;
+        log::info!("Listening for control commands on D-Bus as {BUS_NAME}");
+        Ok(Self {
+            receiver,
+            wakeup,
+            _connection: connection,
+        })
+    }
+}
+
+impl ControlSource for DbusService {
+    fn accept(&self) -> Option<ControlRequest> {
+        let mut buf = [0u8; 64];
+        while matches!((&self.wakeup).read(&mut buf), Ok(n) if n > 0) {}
+        self.receiver.try_recv().ok()
+    }
+}
+
+impl AsRawFd for DbusService {
+    fn as_raw_fd(&self) -> RawFd {
+        self.wakeup.as_raw_fd()
+    }
+}
+
+struct Remapper {
+    sender: Sender<ControlRequest>,
+    notify: UnixStream,
+}
+
+impl Remapper {
+    /// Passes `command` to the remap loop, and waits for its outcome
+    fn run(&self, command: ControlCommand) -> zbus::fdo::Result<String> {
+        let (sender, receiver) = channel();
+        self.sender
+            .send(ControlRequest::with_channel(command, sender))
+            .map_err(|_| zbus::fdo::Error::Failed("evremap is shutting down".to_string()))?;
+        let _ = (&self.notify).write_all(&[0]);
+        match receiver.recv_timeout(REPLY_TIMEOUT) {
+            Ok(Ok(output)) => Ok(output),
+            Ok(Err(err)) => Err(zbus::fdo::Error::Failed(format!("{err:#}"))),
+            Err(_) => Err(zbus::fdo::Error::TimedOut(
+                "evremap didn't carry out the command in time".to_string(),
+            )),
+        }
+    }
+}
+
+#[zbus::interface(name = "org.evremap.Remapper")]
+impl Remapper {
+    /// Release everything and stop remapping, ungrabbing the devices
+    fn pause(&self) -> zbus::fdo::Result<()> {
+        self.run(ControlCommand::Pause).map(drop)
+    }
+
+    /// Grab the devices and start remapping again
+    fn resume(&self) -> zbus::fdo::Result<()> {
+        self.run(ControlCommand::Resume).map(drop)
+    }
+
+    /// Load the config file again
+    fn reload(&self) -> zbus::fdo::Result<()> {
+        self.run(ControlCommand::Reload).map(drop)
+    }
+
+    /// Switch to the named profile
+    fn switch_profile(&self, name: String) -> zbus::fdo::Result<()> {
+        self.run(ControlCommand::Profile(name)).map(drop)
+    }
+
+    /// Describe each device, its active profile and layers,
+    /// and the keys that are held, as `evremap ctl status` does
+    fn status(&self) -> zbus::fdo::Result<String> {
+        self.run(ControlCommand::Status)
+    }
+}
@@ -1,7 +1,12 @@
+use crate::inotify::Inotify;
+use crate::mapping::{EventCode, KeyCode};
+use crate::remapper::VIRTUAL_DEVICE_PHYS_PREFIX;
 use anyhow::{bail, Context, Result};
-use evdev_rs::{Device, DeviceWrapper};
+use evdev_rs::{Device, DeviceWrapper, GrabMode, ReadFlag};
 use std::cmp::Ordering;
-use std::path::PathBuf;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
 #[derive(Debug, Clone)]
 pub struct DeviceInfo {
@@ -10,6 +15,84 @@ pub struct DeviceInfo {
     pub phys: String,
 }
 
+/// The settings that choose the device to be remapped
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct DeviceSelector {
+    pub path: Option<String>,
+    pub sysfs_path: Option<String>,
+    pub name: Option<String>,
+    pub phys: Option<String>,
+}
+
+impl DeviceSelector {
+    pub fn find(&self) -> Result<DeviceInfo> {
+        find_device(
+            self.path.as_deref(),
+            self.sysfs_path.as_deref(),
+            self.name.as_deref(),
+            self.phys.as_deref(),
+        )
+    }
+}
+
+/// Locate the requested device.  The selectors are considered in order
+/// of precedence: path, then sysfs_path, then name and phys.
+pub fn find_device(
+    path: Option<&str>,
+    sysfs_path: Option<&str>,
+    name: Option<&str>,
+    phys: Option<&str>,
+) -> Result<DeviceInfo> {
+    if let Some(path) = path {
+        DeviceInfo::with_path(path.into())
+    } else if let Some(sysfs_path) = sysfs_path {
+        DeviceInfo::with_sysfs_path(sysfs_path, name)
+    } else if let Some(name) = name {
+        DeviceInfo::with_name(name, phys)
+    } else {
+        bail!("device or path is required")
+    }
+}
+
+/// Notices when input devices are attached, by watching for their
+/// nodes in /dev/input being created, or having their permissions
+/// changed by udev so that we are able to open them
+pub struct DeviceWatcher {
+    inotify: Inotify,
+}
+
+impl DeviceWatcher {
+    pub fn new() -> Result<Self> {
+        let inotify = Inotify::new()?;
+        inotify.add_watch(Path::new("/dev/input"), libc::IN_CREATE | libc::IN_ATTRIB)?;
+        Ok(Self { inotify })
+    }
+
+    /// Consumes the pending notifications, and returns true if
+    /// there were any, without waiting
+    pub fn changed(&self) -> bool {
+        !self.inotify.read_names().is_empty()
+    }
+
+    /// Waits until a device may have been attached, or for the
+    /// timeout to elapse
+    pub fn wait(&self, timeout: Duration) -> Result<()> {
+        if self.inotify.wait(timeout)? {
+            // Let udev finish setting up the device, and gather up
+            // the other notifications that that produces
+            std::thread::sleep(Duration::from_millis(10));
+            self.inotify.read_names();
+        }
+        Ok(())
+    }
+}
+
+impl AsRawFd for DeviceWatcher {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inotify.as_raw_fd()
+    }
+}
+
 impl DeviceInfo {
     pub fn with_path(path: PathBuf) -> Result<Self> {
         let f = std::fs::File::open(&path).context(format!("opening {}", path.display()))?;
@@ -23,6 +106,168 @@ impl DeviceInfo {
         })
     }
 
+    /// Waits until none of the keys of the device are held, or for the
+    /// timeout to elapse, so that a key that is released after we grab
+    /// the device, such as the Enter that launched us, isn't left stuck
+    /// down.  If `quiet`, the keys being waited for are only logged
+    /// at debug level.
+    pub fn wait_for_keys_released(&self, timeout: Duration, quiet: bool) -> Result<()> {
+        let f =
+            std::fs::File::open(&self.path).context(format!("opening {}", self.path.display()))?;
+        let input = Device::new_from_file(f).with_context(|| {
+            format!(
+                "failed to create new Device from file {}",
+                self.path.display()
+            )
+        })?;
+        let deadline = Instant::now() + timeout;
+        let mut logged = false;
+        loop {
+            // Reading the events keeps the state of the keys up to date
+            while input.has_event_pending() {
+                input.next_event(ReadFlag::NORMAL)?;
+            }
+            let held: Vec<EventCode> = EventCode::EV_KEY(KeyCode::KEY_RESERVED)
+                .iter()
+                .filter(|code| matches!(code, EventCode::EV_KEY(_)))
+                .filter(|code| input.has(*code) && input.event_value(code).unwrap_or(0) != 0)
+                .collect();
+            if held.is_empty() {
+                return Ok(());
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                log::warn!(
+                    "Grabbing {} while {held:?} are held; they may be stuck \
+                     until pressed again",
+                    self.path.display()
+                );
+                return Ok(());
+            }
+            if !logged {
+                if quiet {
+                    log::debug!("Waiting for {held:?} to be released");
+                } else {
+                    log::warn!("Waiting for {held:?} to be released");
+                }
+                logged = true;
+            }
+            let mut pfd = libc::pollfd {
+                fd: input.file().as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            let res = unsafe { libc::poll(&mut pfd, 1, remaining.as_millis() as libc::c_int) };
+            if res < 0 {
+                let err = std::io::Error::last_os_error();
+                if err.kind() != std::io::ErrorKind::Interrupted {
+                    return Err(err).context(format!("waiting for {}", self.path.display()));
+                }
+            }
+        }
+    }
+
+    /// Describes the device as a JSON object, for `list-devices --json`
+    fn to_json(&self, grabbable: bool) -> String {
+        let sysfs = match sysfs_device_path(&self.path) {
+            Some(sysfs) => json_string(&sysfs.display().to_string()),
+            None => "null".to_string(),
+        };
+        let mut json = format!(
+            "{{\"name\":{},\"path\":{},\"phys\":{},\"sysfs\":{sysfs}",
+            json_string(&self.name),
+            json_string(&self.path.display().to_string()),
+            json_string(&self.phys),
+        );
+        if grabbable {
+            match self.probe_grab() {
+                Ok(()) => json.push_str(",\"grabbable\":true"),
+                Err(err) => json.push_str(&format!(
+                    ",\"grabbable\":false,\"grab_error\":{}",
+                    json_string(&format!("{err:#}"))
+                )),
+            }
+        }
+        json.push('}');
+        json
+    }
+
+    /// Check whether we are able to obtain exclusive access to the
+    /// device, as is required to remap it.  The grab is released again
+    /// immediately.
+    pub fn probe_grab(&self) -> Result<()> {
+        let f =
+            std::fs::File::open(&self.path).context(format!("opening {}", self.path.display()))?;
+        let mut input = Device::new_from_file(f).with_context(|| {
+            format!(
+                "failed to create new Device from file {}",
+                self.path.display()
+            )
+        })?;
+        input
+            .grab(GrabMode::Grab)
+            .context(format!("grabbing {}", self.path.display()))?;
+        input
+            .grab(GrabMode::Ungrab)
+            .context(format!("ungrabbing {}", self.path.display()))?;
+        Ok(())
+    }
+
+    /// Find the event device that sits beneath `sysfs_path` in the
+    /// sysfs device hierarchy.  This allows matching a device by the
+    /// physical port that it is plugged into, for example
+    /// `/sys/devices/pci0000:00/0000:00:14.0/usb1/1-2`.
+    /// If `name` is specified, only devices with that name are considered.
+    pub fn with_sysfs_path(sysfs_path: &str, name: Option<&str>) -> Result<Self> {
+        let sysfs_path = Path::new(sysfs_path)
+            .canonicalize()
+            .context(format!("resolving sysfs path {}", sysfs_path))?;
+
+        let mut devices = vec![];
+        for entry in std::fs::read_dir("/sys/class/input")? {
+            let entry = entry?;
+            let file_name = entry.file_name();
+            let file_name = file_name.to_str().unwrap_or("");
+            if !file_name.starts_with("event") {
+                continue;
+            }
+            match entry.path().canonicalize() {
+                Ok(dev_path) if dev_path.starts_with(&sysfs_path) => {}
+                _ => continue,
+            }
+            match DeviceInfo::with_path(Path::new("/dev/input").join(file_name)) {
+                Ok(item) => {
+                    if name.map(|name| name == item.name).unwrap_or(true) {
+                        devices.push(item);
+                    }
+                }
+                Err(err) => log::error!("{:#}", err),
+            }
+        }
+        devices.sort_by_key(|item| event_number_from_path(&item.path));
+
+        if devices.is_empty() {
+            bail!("No device found beneath `{}`", sysfs_path.display());
+        }
+
+        if devices.len() > 1 {
+            log::warn!(
+                "The following devices are beneath `{}`:",
+                sysfs_path.display()
+            );
+            for dev in &devices {
+                log::warn!("{:?}", dev);
+            }
+            log::warn!(
+                "evremap will use the first entry. If you want to \
+                       use one of the others, add the corresponding device_name \
+                       to your configuration, or use a more specific sysfs_path."
+            );
+        }
+
+        Ok(devices.remove(0))
+    }
+
     pub fn with_name(name: &str, phys: Option<&str>) -> Result<Self> {
         let mut devices = Self::obtain_device_list()?;
 
@@ -39,9 +284,10 @@ impl DeviceInfo {
             }
         }
 
+        // A virtual device of ours may have been given the same name
         let mut devices_with_name: Vec<_> = devices
             .into_iter()
-            .filter(|item| item.name == name)
+            .filter(|item| item.name == name && !item.phys.starts_with(VIRTUAL_DEVICE_PHYS_PREFIX))
             .collect();
 
         if devices_with_name.is_empty() {
@@ -65,7 +311,7 @@ impl DeviceInfo {
         Ok(devices_with_name.remove(0))
     }
 
-    fn obtain_device_list() -> Result<Vec<DeviceInfo>> {
+    pub fn obtain_device_list() -> Result<Vec<DeviceInfo>> {
         let mut devices = vec![];
         for entry in std::fs::read_dir("/dev/input")? {
             let entry = entry?;
@@ -101,7 +347,7 @@ impl DeviceInfo {
     }
 }
 
-fn event_number_from_path(path: &PathBuf) -> u32 {
+fn event_number_from_path(path: &Path) -> u32 {
     match path.to_str() {
         Some(s) => match s.rfind("event") {
             Some(idx) => s[idx + 5..].parse().unwrap_or(0),
@@ -111,12 +357,59 @@ fn event_number_from_path(path: &PathBuf) -> u32 {
     }
 }
 
-pub fn list_devices() -> Result<()> {
+/// Returns the location of the device that backs the event device
+/// `path` in the sysfs device hierarchy
+fn sysfs_device_path(path: &Path) -> Option<PathBuf> {
+    let event = path.file_name()?;
+    Path::new("/sys/class/input")
+        .join(event)
+        .join("device")
+        .canonicalize()
+        .ok()
+}
+
+/// Formats `s` as a JSON string, with quotes
+pub fn json_string(s: &str) -> String {
+    let mut result = String::with_capacity(s.len() + 2);
+    result.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            c if c.is_control() => result.push_str(&format!("\\u{:04x}", c as u32)),
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+    result
+}
+
+/// Print the available devices.  Devices that cannot be opened by
+/// the current user are logged as errors rather than listed.
+/// If `grabbable` is true, also check whether each device can be grabbed.
+/// If `json` is true, each device is printed as a line of JSON.
+pub fn list_devices(grabbable: bool, json: bool) -> Result<()> {
     let devices = DeviceInfo::obtain_device_list()?;
     for item in &devices {
+        if json {
+            println!("{}", item.to_json(grabbable));
+            continue;
+        }
         println!("Name: {}", item.name);
         println!("Path: {}", item.path.display());
         println!("Phys: {}", item.phys);
+        if let Some(sysfs) = sysfs_device_path(&item.path) {
+            println!("Sysfs: {}", sysfs.display());
+        }
+        if grabbable {
+            match item.probe_grab() {
+                Ok(()) => println!("Grabbable: yes"),
+                Err(err) => println!("Grabbable: no: {err:#}"),
+            }
+        }
         println!();
     }
     Ok(())
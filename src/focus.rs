@@ -0,0 +1,317 @@
+use crate::mapping::FocusProviderKind;
+use anyhow::{anyhow, bail, Context, Result};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::process::{Child, ChildStdout, Command, Stdio};
+use std::sync::mpsc::{channel, Receiver};
+
+/// Reports which application has the keyboard focus, identified by
+/// its Wayland app_id or X11 window class
+pub trait FocusProvider: Send {
+    /// Waits until the focus changes, and returns the newly focused
+    /// application, or None if no application is focused.  The first
+    /// call returns the application that is focused already.
+    fn next_focus(&mut self) -> Result<Option<String>>;
+}
+
+/// Returns the provider of the given kind, or if None, the one
+/// for the desktop that is running according to the environment
+pub fn focus_provider(kind: Option<FocusProviderKind>) -> Result<Box<dyn FocusProvider>> {
+    let kind = match kind {
+        Some(kind) => kind,
+        None if std::env::var_os("HYPRLAND_INSTANCE_SIGNATURE").is_some() => {
+            FocusProviderKind::Hyprland
+        }
+        None if std::env::var_os("SWAYSOCK").is_some() => FocusProviderKind::Sway,
+        None if std::env::var_os("DISPLAY").is_some() => FocusProviderKind::X11,
+        None => bail!(
+            "unable to tell which desktop is running; set focus_provider, \
+             and the environment variables that it needs"
+        ),
+    };
+    Ok(match kind {
+        FocusProviderKind::Hyprland => Box::new(HyprlandFocus::connect()?),
+        FocusProviderKind::Sway => Box::new(SwayFocus::connect()?),
+        FocusProviderKind::X11 => Box::new(X11Focus::spawn()?),
+    })
+}
+
+/// Runs a `FocusProvider` on its own thread, so that the remapper
+/// can wait for focus changes alongside its input devices
+pub struct FocusWatcher {
+    receiver: Receiver<Option<String>>,
+    /// Becomes readable when there is something to receive
+    wakeup: UnixStream,
+}
+
+impl FocusWatcher {
+    pub fn spawn(mut provider: Box<dyn FocusProvider>) -> Result<Self> {
+        let (wakeup, mut notify) = UnixStream::pair().context("creating a socket pair")?;
+        wakeup.set_nonblocking(true)?;
+        let (sender, receiver) = channel();
+        std::thread::Builder::new()
+            .name("focus".to_string())
+            .spawn(move || loop {
+                match provider.next_focus() {
+                    Ok(app) => {
+                        log::debug!("focused application: {app:?}");
+                        if sender.send(app).is_err() || notify.write_all(&[0]).is_err() {
+                            return;
+                        }
+                    }
+                    Err(err) => {
+                        log::error!("No longer watching the focused application: {err:#}");
+                        return;
+                    }
+                }
+            })
+            .context("starting the focus watcher thread")?;
+        Ok(Self { receiver, wakeup })
+    }
+
+    /// Returns the most recently focused application, if the focus
+    /// has changed since this was last called
+    pub fn changed(&self) -> Option<Option<String>> {
+        let mut buf = [0u8; 64];
+        while matches!((&self.wakeup).read(&mut buf), Ok(n) if n > 0) {}
+        self.receiver.try_iter().last()
+    }
+}
+
+impl AsRawFd for FocusWatcher {
+    fn as_raw_fd(&self) -> RawFd {
+        self.wakeup.as_raw_fd()
+    }
+}
+
+/// Returns the directory in which Hyprland puts its sockets
+fn hyprland_socket_dir() -> Result<PathBuf> {
+    let signature = std::env::var("HYPRLAND_INSTANCE_SIGNATURE")
+        .context("HYPRLAND_INSTANCE_SIGNATURE is not set")?;
+    // Older versions of Hyprland put their sockets in /tmp
+    let runtime_dir = std::env::var("XDG_RUNTIME_DIR").unwrap_or_default();
+    let dir = PathBuf::from(runtime_dir).join("hypr").join(&signature);
+    if dir.exists() {
+        return Ok(dir);
+    }
+    Ok(PathBuf::from("/tmp/hypr").join(&signature))
+}
+
+/// Follows the `activewindow` events of Hyprland's event socket
+struct HyprlandFocus {
+    events: BufReader<UnixStream>,
+    /// The application that was focused on connecting
+    initial: Option<Option<String>>,
+}
+
+impl HyprlandFocus {
+    fn connect() -> Result<Self> {
+        let dir = hyprland_socket_dir()?;
+        let path = dir.join(".socket2.sock");
+        let events =
+            UnixStream::connect(&path).context(format!("connecting to {}", path.display()))?;
+
+        // The request socket describes the window that is focused already
+        let path = dir.join(".socket.sock");
+        let mut request =
+            UnixStream::connect(&path).context(format!("connecting to {}", path.display()))?;
+        request.write_all(b"activewindow")?;
+        let mut reply = String::new();
+        request.read_to_string(&mut reply)?;
+        let initial = reply
+            .lines()
+            .find_map(|line| line.trim().strip_prefix("class: "))
+            .filter(|class| !class.is_empty())
+            .map(str::to_string);
+
+        Ok(Self {
+            events: BufReader::new(events),
+            initial: Some(initial),
+        })
+    }
+}
+
+impl FocusProvider for HyprlandFocus {
+    fn next_focus(&mut self) -> Result<Option<String>> {
+        if let Some(initial) = self.initial.take() {
+            return Ok(initial);
+        }
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if self.events.read_line(&mut line)? == 0 {
+                bail!("Hyprland closed its event socket");
+            }
+            // activewindow>>CLASS,TITLE
+            if let Some(window) = line.trim_end().strip_prefix("activewindow>>") {
+                let class = window.split(',').next().unwrap_or_default();
+                return Ok((!class.is_empty()).then(|| class.to_string()));
+            }
+        }
+    }
+}
+
+/// Subscribes to the window events of sway's IPC socket
+struct SwayFocus {
+    stream: UnixStream,
+    /// The application that was focused on connecting
+    initial: Option<Option<String>>,
+}
+
+const I3_IPC_MAGIC: &[u8] = b"i3-ipc";
+const I3_IPC_SUBSCRIBE: u32 = 2;
+const I3_IPC_GET_TREE: u32 = 4;
+const I3_IPC_EVENT_WINDOW: u32 = 0x80000003;
+
+impl SwayFocus {
+    fn connect() -> Result<Self> {
+        let path = std::env::var("SWAYSOCK").context("SWAYSOCK is not set")?;
+        let mut stream = UnixStream::connect(&path).context(format!("connecting to {path}"))?;
+
+        send_i3_message(&mut stream, I3_IPC_GET_TREE, "")?;
+        let (_, tree) = read_i3_message(&mut stream)?;
+        let initial = tree
+            .find("\"focused\":true")
+            .and_then(|pos| app_name(&tree[pos..]));
+
+        send_i3_message(&mut stream, I3_IPC_SUBSCRIBE, "[\"window\"]")?;
+        let (_, reply) = read_i3_message(&mut stream)?;
+        if !reply.contains("\"success\":true") {
+            bail!("sway refused the subscription: {reply}");
+        }
+        Ok(Self {
+            stream,
+            initial: Some(initial),
+        })
+    }
+}
+
+impl FocusProvider for SwayFocus {
+    fn next_focus(&mut self) -> Result<Option<String>> {
+        if let Some(initial) = self.initial.take() {
+            return Ok(initial);
+        }
+        loop {
+            let (message_type, payload) = read_i3_message(&mut self.stream)?;
+            if message_type == I3_IPC_EVENT_WINDOW && payload.contains("\"change\":\"focus\"") {
+                return Ok(payload
+                    .find("\"container\"")
+                    .and_then(|pos| app_name(&payload[pos..])));
+            }
+        }
+    }
+}
+
+fn send_i3_message(stream: &mut UnixStream, message_type: u32, payload: &str) -> Result<()> {
+    let mut message = I3_IPC_MAGIC.to_vec();
+    message.extend((payload.len() as u32).to_ne_bytes());
+    message.extend(message_type.to_ne_bytes());
+    message.extend(payload.as_bytes());
+    stream.write_all(&message).context("writing to sway")
+}
+
+fn read_i3_message(stream: &mut UnixStream) -> Result<(u32, String)> {
+    let mut header = [0u8; 14];
+    stream
+        .read_exact(&mut header)
+        .context("reading from sway")?;
+    if &header[..6] != I3_IPC_MAGIC {
+        bail!("sway sent a message without the i3-ipc header");
+    }
+    let len = u32::from_ne_bytes(header[6..10].try_into().unwrap());
+    let message_type = u32::from_ne_bytes(header[10..14].try_into().unwrap());
+    let mut payload = vec![0u8; len as usize];
+    stream
+        .read_exact(&mut payload)
+        .context("reading from sway")?;
+    Ok((message_type, String::from_utf8_lossy(&payload).into_owned()))
+}
+
+/// Returns the app_id of the first window described by `json`, or for
+/// an X11 window, its class.  This deliberately avoids parsing the whole
+/// of sway's JSON, since we are only interested in these fields.
+fn app_name(json: &str) -> Option<String> {
+    match json_string_field(json, "app_id") {
+        Some(Some(app_id)) => Some(app_id),
+        _ => json_string_field(json, "class").flatten(),
+    }
+}
+
+/// Finds the first `"field": value` in `json`, and returns its value
+/// if it is a string, or Some(None) if it is something else
+fn json_string_field(json: &str, field: &str) -> Option<Option<String>> {
+    let key = format!("\"{field}\":");
+    let start = json.find(&key)? + key.len();
+    let value = json[start..].trim_start();
+    let Some(value) = value.strip_prefix('"') else {
+        return Some(None);
+    };
+    let mut result = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => return Some(Some(result)),
+            '\\' => result.extend(chars.next()),
+            c => result.push(c),
+        }
+    }
+    None
+}
+
+/// Follows the _NET_ACTIVE_WINDOW property of the X11 root window,
+/// using `xprop`
+struct X11Focus {
+    spy: BufReader<ChildStdout>,
+    child: Child,
+}
+
+impl X11Focus {
+    fn spawn() -> Result<Self> {
+        let mut child = Command::new("xprop")
+            .args(["-root", "-spy", "_NET_ACTIVE_WINDOW"])
+            .stdout(Stdio::piped())
+            .spawn()
+            .context("running xprop")?;
+        let spy = child
+            .stdout
+            .take()
+            .ok_or_else(|| anyhow!("xprop has no stdout"))?;
+        Ok(Self {
+            spy: BufReader::new(spy),
+            child,
+        })
+    }
+}
+
+impl FocusProvider for X11Focus {
+    fn next_focus(&mut self) -> Result<Option<String>> {
+        // _NET_ACTIVE_WINDOW(WINDOW): window id # 0x1e00007
+        let mut line = String::new();
+        if self.spy.read_line(&mut line)? == 0 {
+            bail!("xprop exited");
+        }
+        let Some(window) = line.trim_end().rsplit(' ').next().filter(|w| *w != "0x0") else {
+            return Ok(None);
+        };
+        // WM_CLASS(STRING) = "instance", "Class"
+        let output = Command::new("xprop")
+            .args(["-id", window, "WM_CLASS"])
+            .output()
+            .context("running xprop")?;
+        let output = String::from_utf8_lossy(&output.stdout);
+        Ok(output
+            .split('"')
+            .nth(3)
+            .filter(|class| !class.is_empty())
+            .map(str::to_string))
+    }
+}
+
+impl Drop for X11Focus {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
@@ -0,0 +1,142 @@
+use anyhow::{bail, Context, Result};
+use evremap::mapping::MappingConfig;
+use std::path::Path;
+
+/// Prints a systemd service unit that runs the remapper with the
+/// config file at `config_file`, using the evremap binary at `binary`
+/// or the one that is running if that is None
+pub fn systemd_unit(config_file: &Path, binary: Option<&Path>) -> Result<()> {
+    let devices = load_devices(config_file)?;
+    let config_file = config_file
+        .canonicalize()
+        .context(format!("resolving {}", config_file.display()))?;
+    let binary = match binary {
+        Some(binary) => binary.to_path_buf(),
+        None => std::env::current_exe().context("finding the evremap binary")?,
+    };
+    let devices: Vec<String> = devices.iter().map(describe).collect();
+
+    println!("# Generated by `evremap generate systemd-unit`");
+    println!("[Unit]");
+    println!("Description=evremap for {}", devices.join(", "));
+    println!();
+    println!("[Service]");
+    // evremap tells systemd when it has grabbed the device
+    println!("Type=notify");
+    println!("WatchdogSec=30");
+    println!("WorkingDirectory=/");
+    println!(
+        "ExecStart={} remap {}",
+        quote_systemd(&binary.display().to_string()),
+        quote_systemd(&config_file.display().to_string())
+    );
+    println!("ExecReload=/bin/kill -HUP $MAINPID");
+    println!("Restart=always");
+    println!();
+    println!("[Install]");
+    println!("WantedBy=multi-user.target");
+    Ok(())
+}
+
+/// Prints a udev rule that starts `unit` when any of the devices
+/// described by the config file at `config_file` is attached
+pub fn udev_rule(config_file: &Path, unit: &str) -> Result<()> {
+    let devices = load_devices(config_file)?;
+    println!("# Generated by `evremap generate udev-rule`");
+    for device in devices {
+        let mut matches = vec![
+            "ACTION==\"add\"".to_string(),
+            "SUBSYSTEM==\"input\"".to_string(),
+            "KERNEL==\"event*\"".to_string(),
+        ];
+        if let Some(path) = &device.path {
+            // udev matches the symlinks in /dev without the prefix
+            let Some(link) = path.strip_prefix("/dev/") else {
+                bail!("the path {path} is not in /dev");
+            };
+            if link.starts_with("input/event") {
+                matches.push(format!(
+                    "KERNEL==\"{}\"",
+                    udev_value(&link["input/".len()..])?
+                ));
+            } else {
+                matches.push(format!("SYMLINK==\"{}\"", udev_value(link)?));
+            }
+        } else {
+            if let Some(sysfs_path) = &device.sysfs_path {
+                let devpath = sysfs_path.strip_prefix("/sys").unwrap_or(sysfs_path);
+                matches.push(format!("DEVPATH==\"{}/*\"", udev_value(devpath)?));
+            }
+            if let Some(name) = &device.device_name {
+                matches.push(format!("ATTRS{{name}}==\"{}\"", udev_value(name)?));
+            }
+            if let Some(phys) = &device.phys {
+                matches.push(format!("ATTRS{{phys}}==\"{}\"", udev_value(phys)?));
+            }
+        }
+        println!("# {}", describe(&device));
+        println!(
+            "{}, TAG+=\"systemd\", ENV{{SYSTEMD_WANTS}}+=\"{}\"",
+            matches.join(", "),
+            udev_value(unit)?
+        );
+    }
+    Ok(())
+}
+
+/// Returns a config for each of the devices in the config file
+fn load_devices(config_file: &Path) -> Result<Vec<MappingConfig>> {
+    let devices = MappingConfig::from_file(config_file)
+        .context(format!(
+            "loading MappingConfig from {}",
+            config_file.display()
+        ))?
+        .split_devices();
+    if devices.iter().any(|device| {
+        device.path.is_none() && device.sysfs_path.is_none() && device.device_name.is_none()
+    }) {
+        bail!(
+            "{} doesn't say which device to remap",
+            config_file.display()
+        );
+    }
+    Ok(devices)
+}
+
+/// Returns the most specific of the settings that choose the device
+fn describe(device: &MappingConfig) -> String {
+    match (&device.path, &device.sysfs_path, &device.device_name) {
+        (Some(path), _, _) => path.clone(),
+        (None, Some(sysfs_path), _) => sysfs_path.clone(),
+        (None, None, Some(name)) => match &device.phys {
+            Some(phys) => format!("{name} ({phys})"),
+            None => name.clone(),
+        },
+        (None, None, None) => "an unspecified device".to_string(),
+    }
+}
+
+/// udev has no way of escaping a quote within a value
+fn udev_value(value: &str) -> Result<&str> {
+    if value.contains('"') || value.contains('\n') {
+        bail!("{value:?} can't be matched by a udev rule");
+    }
+    Ok(value)
+}
+
+/// Quotes a word of a systemd command line, if it needs it
+fn quote_systemd(word: &str) -> String {
+    if word
+        .chars()
+        .any(|c| c.is_whitespace() || matches!(c, '"' | '\'' | '\\' | '$' | '%' | ';'))
+    {
+        let escaped = word
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('$', "$$")
+            .replace('%', "%%");
+        format!("\"{escaped}\"")
+    } else {
+        word.to_string()
+    }
+}
@@ -0,0 +1,89 @@
+use anyhow::{Context, Result};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::path::Path;
+use std::time::Duration;
+
+/// A non-blocking inotify instance, for noticing changes to files
+/// without polling for them
+pub struct Inotify {
+    fd: OwnedFd,
+}
+
+impl Inotify {
+    pub fn new() -> Result<Self> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error()).context("inotify_init1");
+        }
+        Ok(Self {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+        })
+    }
+
+    /// Watch `path` for the events in `mask`, such as `libc::IN_CREATE`
+    pub fn add_watch(&self, path: &Path, mask: u32) -> Result<()> {
+        let path_name = std::ffi::CString::new(path.as_os_str().as_encoded_bytes())?;
+        let wd = unsafe { libc::inotify_add_watch(self.fd.as_raw_fd(), path_name.as_ptr(), mask) };
+        if wd < 0 {
+            return Err(std::io::Error::last_os_error())
+                .context(format!("watching {}", path.display()));
+        }
+        Ok(())
+    }
+
+    /// Consumes the pending notifications, without waiting for any,
+    /// and returns the file names that they are about.  For a watch
+    /// on a file rather than a directory, the name is empty.
+    pub fn read_names(&self) -> Vec<Vec<u8>> {
+        const HEADER_SIZE: usize = std::mem::size_of::<libc::inotify_event>();
+        let mut names = vec![];
+        let mut buf = [0u8; 4096];
+        loop {
+            let len = unsafe {
+                libc::read(
+                    self.fd.as_raw_fd(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                )
+            };
+            if len <= 0 {
+                return names;
+            }
+            let mut events = &buf[..len as usize];
+            while events.len() >= HEADER_SIZE {
+                // The name follows the header, padded with NULs
+                let name_len = u32::from_ne_bytes(events[12..16].try_into().unwrap()) as usize;
+                let name = &events[HEADER_SIZE..HEADER_SIZE + name_len];
+                let name = name.split(|b| *b == 0).next().unwrap_or_default();
+                names.push(name.to_vec());
+                events = &events[HEADER_SIZE + name_len..];
+            }
+        }
+    }
+
+    /// Waits until there is a notification to be read, or for the
+    /// timeout to elapse.  Returns true if there is a notification.
+    pub fn wait(&self, timeout: Duration) -> Result<bool> {
+        let mut pfd = libc::pollfd {
+            fd: self.fd.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout_ms = timeout.as_millis().min(i32::MAX as u128) as i32;
+        let res = unsafe { libc::poll(&mut pfd, 1, timeout_ms) };
+        if res < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() == std::io::ErrorKind::Interrupted {
+                return Ok(false);
+            }
+            return Err(err).context("waiting for inotify");
+        }
+        Ok(res > 0)
+    }
+}
+
+impl AsRawFd for Inotify {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
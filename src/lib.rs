@@ -0,0 +1,44 @@
+//! The remapping engine behind the `evremap` command, for programs
+//! that want to remap input devices without running it.
+//!
+//! A [`MappingConfig`] describes a device and what to do with its keys.
+//! It is usually loaded from a config file with
+//! [`MappingConfig::from_file`], but can also be put together in code
+//! with [`MappingConfig::builder`].  [`DeviceInfo`] finds the device,
+//! and an [`InputMapper`] grabs it and remaps its events onto a virtual
+//! device:
+//!
+//! ```no_run
+//! use evremap::mapping::KeyCode;
+//! use evremap::{DeviceInfo, InputMapper, MappingConfig};
+//!
+//! fn main() -> anyhow::Result<()> {
+//!     let config = MappingConfig::builder()
+//!         .device_name("AT Translated Set 2 keyboard")
+//!         .dual_role(KeyCode::KEY_CAPSLOCK, [KeyCode::KEY_LEFTCTRL], [KeyCode::KEY_ESC])
+//!         .remap([KeyCode::KEY_LEFTALT, KeyCode::KEY_H], [KeyCode::KEY_LEFT])
+//!         .build();
+//!     let device = DeviceInfo::with_name("AT Translated Set 2 keyboard", None)?;
+//!     let mapper = InputMapper::create_mapper(device.path, config, false)?;
+//!     InputMapper::run_mappers(&mut [mapper], &[], None)
+//! }
+//! ```
+
+pub mod control;
+#[cfg(feature = "dbus")]
+pub mod dbus;
+pub mod deviceinfo;
+pub mod focus;
+mod inotify;
+pub mod mapping;
+pub mod privileges;
+pub mod remapper;
+pub mod sandbox;
+mod session;
+mod spawn;
+pub mod systemd;
+mod xkb;
+
+pub use deviceinfo::DeviceInfo;
+pub use mapping::{Mapping, MappingConfig, MappingConfigBuilder};
+pub use remapper::{InputMapper, MappingEngine};
@@ -1,15 +1,19 @@
-use crate::deviceinfo::DeviceInfo;
-use crate::mapping::*;
-use crate::remapper::*;
 use anyhow::Error;
 use anyhow::{Context, Result};
-use clap::Parser;
+use clap::{CommandFactory, Parser, Subcommand};
+use evremap::control::{self, ControlCommand, ControlSocket, ControlSource};
+use evremap::deviceinfo::{self, DeviceInfo, DeviceWatcher};
+use evremap::mapping::*;
+use evremap::remapper::*;
+use evremap::systemd::Notifier;
+use std::io::IsTerminal;
 use std::path::PathBuf;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-mod deviceinfo;
-mod mapping;
-mod remapper;
+mod check;
+mod generate;
+mod simulate;
+mod wizard;
 
 /// Remap libinput evdev keyboard inputs
 #[derive(Debug, Parser)]
@@ -18,7 +22,16 @@ enum Opt {
     /// Rather than running the remapper, list currently available devices.
     /// This is helpful to check their names when setting up the initial
     /// configuration
-    ListDevices,
+    ListDevices {
+        /// Also check whether the current user is able to grab
+        /// exclusive access to each device, as is needed to remap it
+        #[arg(long)]
+        grabbable: bool,
+
+        /// Print each device as a line of JSON
+        #[arg(long)]
+        json: bool,
+    },
 
     /// Show a list of possible KEY_XXX values
     ListKeys,
@@ -38,6 +51,87 @@ enum Opt {
         /// Specify the path
         #[arg(long)]
         path: Option<String>,
+
+        /// Specify a location in the sysfs device hierarchy,
+        /// such as a USB port, beneath which the device is found
+        #[arg(long)]
+        sysfs_path: Option<String>,
+
+        /// Print each event as a line of JSON on stdout,
+        /// rather than logging it
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Check a config file for mistakes, such as unknown key names and
+    /// mappings that are shadowed by others, without remapping anything.
+    /// Exits with a non-zero status if any are found.
+    Check {
+        /// Specify the configuration file to be checked
+        #[arg(name = "CONFIG-FILE")]
+        config_file: PathBuf,
+    },
+
+    /// Feed a script of timed key events through the mappings in a
+    /// config file, without any devices, and print the events that
+    /// would be emitted.  Each line of the script is a time in
+    /// milliseconds followed by a key name and `press`, `release`
+    /// or `repeat`.
+    Simulate {
+        /// Specify the configuration file to be loaded
+        #[arg(long)]
+        config: PathBuf,
+
+        /// Specify the script of key events
+        #[arg(long)]
+        script: PathBuf,
+
+        /// Compare the output with the contents of this file, in the
+        /// same format as it is printed, and fail if they differ
+        #[arg(long)]
+        expect: Option<PathBuf>,
+    },
+
+    /// Run the scenarios in a file through the mappings in a config
+    /// file, without any devices, and check that each produces the
+    /// events that it expects.  Exits with a non-zero status if any
+    /// of them doesn't.
+    Test {
+        /// Specify the file of scenarios to be run
+        #[arg(long)]
+        scenario: PathBuf,
+
+        /// Specify the configuration file to be tested
+        #[arg(name = "CONFIG-FILE")]
+        config_file: PathBuf,
+    },
+
+    /// Print a config file rewritten for the current version of the
+    /// config file format, replacing any deprecated settings.
+    MigrateConfig {
+        /// Specify the configuration file to be migrated
+        #[arg(name = "CONFIG-FILE")]
+        config_file: PathBuf,
+
+        /// Write the migrated config to this file rather than
+        /// printing it
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+
+    /// Print a file that helps to run the remapper as a service,
+    /// filled in from a config file
+    Generate {
+        #[command(subcommand)]
+        what: GenerateCommand,
+    },
+
+    /// Interactively create a config file: choose a device, then
+    /// press each key that you want to remap followed by its replacement
+    Wizard {
+        /// Specify the configuration file to be written
+        #[arg(name = "CONFIG-FILE")]
+        config_file: PathBuf,
     },
 
     /// Load a remapper config and run the remapper.
@@ -48,14 +142,19 @@ enum Opt {
         #[arg(name = "CONFIG-FILE")]
         config_file: PathBuf,
 
-        /// Number of seconds for user to release keys on startup
-        #[arg(short, long, default_value = "2")]
+        /// The longest time, in seconds, to wait on startup for keys
+        /// that are held to be released before grabbing the device
+        #[arg(short, long, default_value = "10")]
         delay: f64,
 
         /// Override the device path specified by the config file
         #[arg(long)]
         path: Option<String>,
 
+        /// Override the sysfs path specified by the config file
+        #[arg(long)]
+        sysfs_path: Option<String>,
+
         /// Override the device name specified by the config file
         #[arg(long)]
         device_name: Option<String>,
@@ -65,15 +164,140 @@ enum Opt {
         phys: Option<String>,
 
         /// If the device isn't found on startup, wait forever
-        /// until the device is plugged in. This works by polling
-        /// the set of devices every few seconds. It is not as
-        /// efficient as setting up a udev rule to spawn evremap,
-        /// but is simpler to setup ad-hoc.
+        /// until the device is plugged in. This works by watching
+        /// /dev/input for new devices, and is simpler to setup
+        /// ad-hoc than a udev rule to spawn evremap.
         #[arg(long)]
         wait_for_device: bool,
+
+        /// Don't prompt to release keys that are held on startup.
+        /// This is implied when stdin is not a terminal, such as
+        /// when running as a service.
+        #[arg(long)]
+        quiet_startup: bool,
+
+        /// Don't grab the device or create the virtual device; instead,
+        /// print each key event along with the entry that it matches,
+        /// and the events that would be emitted as a result
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Don't grab the device, so that its events are seen as they
+        /// are, and only add the events that the mappings produce.
+        /// This is the same as `grab = false` in the config file.
+        #[arg(long)]
+        no_grab: bool,
+
+        /// Listen for `evremap ctl` commands on this socket, rather than
+        /// the default of /run/evremap/evremap.sock when running as root,
+        /// or $XDG_RUNTIME_DIR/evremap.sock otherwise
+        #[arg(long)]
+        control_socket: Option<PathBuf>,
+
+        /// Don't listen for `evremap ctl` commands
+        #[arg(long, conflicts_with = "control_socket")]
+        no_control_socket: bool,
+
+        /// Don't offer the control commands on D-Bus
+        #[cfg(feature = "dbus")]
+        #[arg(long)]
+        no_dbus: bool,
+
+        /// Once the devices are open, switch to this user, given as
+        /// a name or uid, rather than remapping them as root
+        #[arg(long, value_name = "USER")]
+        setuid: Option<String>,
+
+        /// Once the devices are open, switch to this group, given as
+        /// a name or gid.  The default with --setuid is the primary
+        /// group of that user.
+        #[arg(long, value_name = "GROUP")]
+        setgid: Option<String>,
+
+        /// Once the devices are open, restrict the files that can be
+        /// opened with Landlock, and the system calls that can be made
+        /// with seccomp
+        #[arg(long)]
+        sandbox: bool,
+    },
+
+    /// Print a script that makes your shell complete the subcommands
+    /// and options of evremap
+    Completions {
+        /// The shell to complete for
+        #[arg(value_enum)]
+        shell: clap_complete::Shell,
+    },
+
+    /// Control a running remapper through its control socket
+    Ctl {
+        /// Specify the control socket of the remapper, if it
+        /// was given one with --control-socket
+        #[arg(long)]
+        socket: Option<PathBuf>,
+
+        #[command(subcommand)]
+        command: CtlCommand,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum GenerateCommand {
+    /// A systemd service unit that remaps the devices of the config file
+    SystemdUnit {
+        /// Specify the configuration file to be loaded by the service
+        #[arg(name = "CONFIG-FILE")]
+        config_file: PathBuf,
+
+        /// The path of the evremap binary that the service runs,
+        /// rather than that of the one that is running now
+        #[arg(long)]
+        binary: Option<PathBuf>,
+    },
+    /// A udev rule that starts the service when any of the devices
+    /// of the config file is attached
+    UdevRule {
+        /// Specify the configuration file whose devices are matched
+        #[arg(name = "CONFIG-FILE")]
+        config_file: PathBuf,
+
+        /// The name of the systemd unit to start
+        #[arg(long, default_value = "evremap.service")]
+        unit: String,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum CtlCommand {
+    /// Stop remapping and release the devices, so that their
+    /// events are seen as they are
+    Pause,
+    /// Grab the devices and start remapping again
+    Resume,
+    /// Load the config file again
+    Reload,
+    /// Show whether each device is being remapped, and the
+    /// keys that are held
+    Status,
+    /// Switch to a profile
+    Profile {
+        /// The name of the profile
+        name: String,
     },
 }
 
+impl From<CtlCommand> for ControlCommand {
+    fn from(command: CtlCommand) -> Self {
+        match command {
+            CtlCommand::Pause => Self::Pause,
+            CtlCommand::Resume => Self::Resume,
+            CtlCommand::Reload => Self::Reload,
+            CtlCommand::Status => Self::Status,
+            CtlCommand::Profile { name } => Self::Profile(name),
+        }
+    }
+}
+
 pub fn list_keys() -> Result<()> {
     let mut keys: Vec<String> = EventCode::EV_KEY(KeyCode::KEY_RESERVED)
         .iter()
@@ -101,56 +325,55 @@ fn setup_logger() {
 
 fn get_device(
     path: Option<&str>,
+    sysfs_path: Option<&str>,
     name: Option<&str>,
     phys: Option<&str>,
     wait_for_device: bool,
 ) -> anyhow::Result<DeviceInfo> {
-    if let Some(path) = path {
-        match deviceinfo::DeviceInfo::with_path(path.into()) {
-            Ok(dev) => return Ok(dev),
-            Err(err) if !wait_for_device => return Err(err),
-            Err(err) => {
-                log::warn!("{err:#}. Will wait until it is attached.");
-            }
-        }
-    } else if let Some(name) = name {
-        match deviceinfo::DeviceInfo::with_name(name, phys) {
-            Ok(dev) => return Ok(dev),
-            Err(err) if !wait_for_device => return Err(err),
-            Err(err) => {
-                log::warn!("{err:#}. Will wait until it is attached.");
-            }
-        }
-    } else {
+    if path.is_none() && sysfs_path.is_none() && name.is_none() {
         return Err(Error::msg("device or path is required"));
     }
 
+    // Start watching before looking, so that a device that is
+    // attached in the meantime isn't missed
+    let watcher = if wait_for_device {
+        DeviceWatcher::new()
+            .map_err(|err| log::warn!("{err:#}. Will poll for the device instead."))
+            .ok()
+    } else {
+        None
+    };
+
+    match deviceinfo::find_device(path, sysfs_path, name, phys) {
+        Ok(dev) => return Ok(dev),
+        Err(err) if !wait_for_device => return Err(err),
+        Err(err) => {
+            log::warn!("{err:#}. Will wait until it is attached.");
+        }
+    }
+
     const MAX_SLEEP: Duration = Duration::from_secs(10);
     const ONE_SECOND: Duration = Duration::from_secs(1);
     let mut sleep = ONE_SECOND;
 
     loop {
-        std::thread::sleep(sleep);
-        sleep = (sleep + ONE_SECOND).min(MAX_SLEEP);
-        if let Some(path) = path {
-            match deviceinfo::DeviceInfo::with_path(path.into()) {
-                Ok(dev) => return Ok(dev),
-                Err(err) => {
-                    log::debug!("{err:#}");
-                }
+        match &watcher {
+            Some(watcher) => watcher.wait(MAX_SLEEP)?,
+            None => {
+                std::thread::sleep(sleep);
+                sleep = (sleep + ONE_SECOND).min(MAX_SLEEP);
             }
-        } else if let Some(name) = name {
-            match deviceinfo::DeviceInfo::with_name(name, phys) {
-                Ok(dev) => return Ok(dev),
-                Err(err) => {
-                    log::debug!("{err:#}");
-                }
+        }
+        match deviceinfo::find_device(path, sysfs_path, name, phys) {
+            Ok(dev) => return Ok(dev),
+            Err(err) => {
+                log::debug!("{err:#}");
             }
         }
     }
 }
 
-fn debug_events(device: DeviceInfo) -> Result<()> {
+fn debug_events(device: DeviceInfo, json: bool) -> Result<()> {
     let f =
         std::fs::File::open(&device.path).context(format!("opening {}", device.path.display()))?;
     let input = evdev_rs::Device::new_from_file(f).with_context(|| {
@@ -160,16 +383,43 @@ fn debug_events(device: DeviceInfo) -> Result<()> {
         )
     })?;
 
+    // Whether we are reading the changes that libevdev worked out
+    // after the kernel dropped some events
+    let mut syncing = false;
     loop {
-        let (status, event) =
-            input.next_event(evdev_rs::ReadFlag::NORMAL | evdev_rs::ReadFlag::BLOCKING)?;
+        let flags = if syncing {
+            evdev_rs::ReadFlag::SYNC
+        } else {
+            evdev_rs::ReadFlag::NORMAL | evdev_rs::ReadFlag::BLOCKING
+        };
+        let (status, event) = match input.next_event(flags) {
+            Ok(result) => result,
+            Err(err) if syncing && err.raw_os_error() == Some(libc::EAGAIN) => {
+                syncing = false;
+                continue;
+            }
+            Err(err) => return Err(err.into()),
+        };
         match status {
-            evdev_rs::ReadStatus::Success => {
-                if let EventCode::EV_KEY(key) = event.event_code {
-                    log::info!("{key:?} {}", event.value);
+            evdev_rs::ReadStatus::Sync if !syncing => {
+                log::warn!("The device dropped events; showing what changed meanwhile");
+                syncing = true;
+            }
+            evdev_rs::ReadStatus::Success | evdev_rs::ReadStatus::Sync => {
+                let event_type = match event.event_code {
+                    EventCode::EV_KEY(_) => "EV_KEY",
+                    EventCode::EV_REL(_) => "EV_REL",
+                    _ => continue,
+                };
+                if json {
+                    println!(
+                        "{{\"time\":{}.{:06},\"type\":\"{event_type}\",\"code\":\"{}\",\"value\":{}}}",
+                        event.time.tv_sec, event.time.tv_usec, event.event_code, event.value
+                    );
+                } else {
+                    log::info!("{} {}", event.event_code, event.value);
                 }
             }
-            evdev_rs::ReadStatus::Sync => anyhow::bail!("ReadStatus::Sync!"),
         }
     }
 }
@@ -179,34 +429,98 @@ fn main() -> Result<()> {
     let opt = Opt::parse();
 
     match opt {
-        Opt::ListDevices => deviceinfo::list_devices(),
+        Opt::ListDevices { grabbable, json } => deviceinfo::list_devices(grabbable, json),
         Opt::ListKeys => list_keys(),
         Opt::DebugEvents {
             path,
+            sysfs_path,
             device_name,
             phys,
+            json,
         } => {
             let device_info = get_device(
                 path.as_deref(),
+                sysfs_path.as_deref(),
                 device_name.as_deref(),
                 phys.as_deref(),
                 false,
             )?;
-            debug_events(device_info)
+            debug_events(device_info, json)
+        }
+        Opt::Check { config_file } => check::check_config(&config_file),
+        Opt::Simulate {
+            config,
+            script,
+            expect,
+        } => simulate::simulate(&config, &script, expect.as_deref()),
+        Opt::Test {
+            scenario,
+            config_file,
+        } => simulate::test_scenarios(&config_file, &scenario),
+        Opt::Wizard { config_file } => wizard::run_wizard(&config_file),
+        Opt::Generate { what } => match what {
+            GenerateCommand::SystemdUnit {
+                config_file,
+                binary,
+            } => generate::systemd_unit(&config_file, binary.as_deref()),
+            GenerateCommand::UdevRule { config_file, unit } => {
+                generate::udev_rule(&config_file, &unit)
+            }
+        },
+        Opt::MigrateConfig {
+            config_file,
+            output,
+        } => {
+            let migrated = migrate_config_file(&config_file)?;
+            match output {
+                Some(output) => {
+                    std::fs::write(&output, migrated)
+                        .context(format!("writing {}", output.display()))?;
+                    MappingConfig::from_file(&output).context(format!(
+                        "verifying the migrated config {}",
+                        output.display()
+                    ))?;
+                    Ok(())
+                }
+                None => {
+                    print!("{migrated}");
+                    Ok(())
+                }
+            }
         }
         Opt::Remap {
             path,
+            sysfs_path,
             config_file,
             delay,
             device_name,
             phys,
             wait_for_device,
+            quiet_startup,
+            dry_run,
+            no_grab,
+            control_socket,
+            no_control_socket,
+            #[cfg(feature = "dbus")]
+            no_dbus,
+            setuid,
+            setgid,
+            sandbox,
         } => {
             let mut mapping_config = MappingConfig::from_file(&config_file).context(format!(
                 "loading MappingConfig from {}",
                 config_file.display()
             ))?;
 
+            let overrides_device =
+                device_name.is_some() || phys.is_some() || path.is_some() || sysfs_path.is_some();
+            if overrides_device && !mapping_config.devices.is_empty() {
+                anyhow::bail!(
+                    "{} has [[device]] sections, so the device cannot be \
+                     specified on the command line",
+                    config_file.display()
+                );
+            }
             if let Some(device) = device_name {
                 mapping_config.device_name = Some(device);
             }
@@ -216,19 +530,79 @@ fn main() -> Result<()> {
             if let Some(path) = path {
                 mapping_config.path = Some(path);
             }
+            if let Some(sysfs_path) = sysfs_path {
+                mapping_config.sysfs_path = Some(sysfs_path);
+            }
+            if no_grab {
+                mapping_config.overlay = true;
+            }
 
-            log::warn!("Short delay: release any keys now!");
-            std::thread::sleep(Duration::from_secs_f64(delay));
+            let quiet_startup = quiet_startup || !std::io::stdin().is_terminal();
+            let release_deadline = Instant::now() + Duration::from_secs_f64(delay);
 
-            let device_info = get_device(
-                mapping_config.path.as_deref(),
-                mapping_config.device_name.as_deref(),
-                mapping_config.phys.as_deref(),
-                wait_for_device,
-            )?;
+            let mut mappers = vec![];
+            for (idx, config) in mapping_config.split_devices().into_iter().enumerate() {
+                let device_info = get_device(
+                    config.path.as_deref(),
+                    config.sysfs_path.as_deref(),
+                    config.device_name.as_deref(),
+                    config.phys.as_deref(),
+                    wait_for_device,
+                )?;
+
+                // Nothing is grabbed for a dry run, so keys that are
+                // held at startup don't matter
+                if !dry_run {
+                    let timeout = release_deadline.saturating_duration_since(Instant::now());
+                    device_info.wait_for_keys_released(timeout, quiet_startup)?;
+                }
+
+                let watch_config = config.watch_config;
+                let mut mapper = InputMapper::create_mapper(device_info.path, config, dry_run)?;
+                mapper.watch_config_file(&config_file, idx, watch_config);
+                mappers.push(mapper);
+            }
 
-            let mut mapper = InputMapper::create_mapper(device_info.path, mapping_config.mappings)?;
-            mapper.run_mapper()
+            let mut control: Vec<Box<dyn ControlSource>> = vec![];
+            if !no_control_socket {
+                let path = control_socket.unwrap_or_else(control::default_socket_path);
+                match ControlSocket::bind(&path) {
+                    Ok(socket) => control.push(Box::new(socket)),
+                    Err(err) => log::warn!("`evremap ctl` will not work: {err:#}"),
+                }
+            }
+            #[cfg(feature = "dbus")]
+            if !no_dbus {
+                match evremap::dbus::DbusService::start() {
+                    Ok(service) => control.push(Box::new(service)),
+                    Err(err) => log::warn!("The D-Bus interface will not be available: {err:#}"),
+                }
+            }
+            // The socket for systemd notifications can't be created
+            // once in the sandbox
+            let notifier = Notifier::from_env();
+            evremap::privileges::drop_privileges(setuid.as_deref(), setgid.as_deref())
+                .context("dropping privileges")?;
+            if sandbox {
+                evremap::sandbox::enter(&config_file).context("entering the sandbox")?;
+            }
+            let control: Vec<&dyn ControlSource> = control.iter().map(|c| c.as_ref()).collect();
+            InputMapper::run_mappers(&mut mappers, &control, notifier)
+        }
+        Opt::Completions { shell } => {
+            clap_complete::generate(
+                shell,
+                &mut Opt::command(),
+                "evremap",
+                &mut std::io::stdout(),
+            );
+            Ok(())
+        }
+        Opt::Ctl { socket, command } => {
+            let socket = socket.unwrap_or_else(control::default_socket_path);
+            let output = control::send_command(&socket, command.into())?;
+            print!("{output}");
+            Ok(())
         }
     }
 }
@@ -1,133 +1,2700 @@
+use crate::xkb;
 use anyhow::Context;
-pub use evdev_rs::enums::{EventCode, EventType, EV_KEY as KeyCode};
+pub use evdev_rs::enums::{
+    BusType, EventCode, EventType, EV_KEY as KeyCode, EV_LED as LedCode, EV_REL as RelCode,
+    EV_SW as SwitchCode,
+};
 use serde::Deserialize;
-use std::collections::HashSet;
+use std::cell::OnceCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::path::Path;
+use std::time::Duration;
 use thiserror::Error;
 
-#[derive(Debug, Clone)]
+/// The settings and mappings for remapping a device
+#[derive(Debug, Clone, Default)]
 pub struct MappingConfig {
     pub device_name: Option<String>,
     pub phys: Option<String>,
     pub mappings: Vec<Mapping>,
+    pub layers: Vec<Layer>,
+    /// Named sets of mappings, of which one at a time applies in
+    /// addition to `mappings`, and the index of the one that
+    /// applies at startup
+    pub profiles: Vec<Profile>,
+    pub default_profile: usize,
+    /// The devices from `[[device]]` sections, which are remapped
+    /// instead of the one described by the other device settings
+    pub devices: Vec<DeviceMappings>,
     pub path: Option<String>,
+    pub sysfs_path: Option<String>,
+    pub release_triggers: Vec<ReleaseTrigger>,
+    pub keys_only: bool,
+    pub sanitize: bool,
+    /// Leave the input device ungrabbed, so that its events are seen
+    /// as they are, and only add the events of the mappings to them.
+    /// This is only read at startup.
+    pub overlay: bool,
+    pub keepalive_interval: Option<Duration>,
+    /// Reload the config file when it changes on disk.  This
+    /// is only read at startup.
+    pub watch_config: bool,
+    pub reload_scope: ReloadScope,
+    pub post_grab_settle: Duration,
+    pub post_grab_settle_mode: SettleMode,
+    /// How the virtual output device identifies itself
+    pub output_device: OutputDevice,
+    /// Keys that are replaced by another key in every event
+    /// that is written to the output device
+    pub modifier_rewrite: HashMap<KeyCode, KeyCode>,
+    pub max_drag: Option<Duration>,
+    /// Any key held on the output device for longer than this is released
+    pub max_hold: Option<Duration>,
+    /// Keys that the output device supports even if no mapping produces them
+    pub extra_output_keys: Vec<KeyCode>,
+    pub periodic_syn: Option<Duration>,
+    pub low_latency_passthrough: bool,
+    pub suppress_modifier_pulses: bool,
+    pub modifier_settle: Option<Duration>,
+    /// How to find out which application is focused, for layers
+    /// with an `only_when` condition.  None means to guess from the
+    /// environment.
+    pub focus_provider: Option<FocusProviderKind>,
+    pub autoshift: Option<AutoShift>,
+    /// Which hand types each key, for the `chordal_hold` of DualRole keys
+    pub hands: HashMap<KeyCode, Hand>,
+    /// Changes of a key within this long of its last change are taken
+    /// to be switch chatter, and ignored
+    pub debounce: Option<Duration>,
+    /// Keys that are debounced for a different length of time
+    pub debounce_keys: HashMap<KeyCode, Duration>,
 }
 
 impl MappingConfig {
     pub fn from_file<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
         let path = path.as_ref();
-        let toml_data = std::fs::read_to_string(path)
+        let mut toml_data = std::fs::read_to_string(path)
             .context(format!("reading toml from {}", path.display()))?;
-        let config_file: ConfigFile =
+        let mut document: toml_edit::DocumentMut = toml_data
+            .parse()
+            .context(format!("parsing toml from {}", path.display()))?;
+        if migrate_config(&mut document).context(format!("migrating {}", path.display()))? {
+            log::warn!(
+                "{} uses deprecated settings. Run `evremap migrate-config {}` \
+                 to see it in the current format.",
+                path.display(),
+                path.display()
+            );
+            toml_data = document.to_string();
+        }
+        let mut value: toml::Value =
             toml::from_str(&toml_data).context(format!("parsing toml from {}", path.display()))?;
-        let mut mappings = vec![];
-        for dual in config_file.dual_role {
-            mappings.push(dual.into());
+        let config_file: ConfigFile = if value.get("gamepad_layout").is_some() {
+            resolve_gamepad_aliases(&mut value)
+                .context(format!("resolving gamepad aliases in {}", path.display()))?;
+            value.try_into()
+        } else {
+            // Deserialize from the text, which produces errors with line numbers
+            toml::from_str(&toml_data)
+        }
+        .context(format!("parsing toml from {}", path.display()))?;
+        let layer_names: Vec<String> = config_file
+            .layer
+            .iter()
+            .map(|layer| layer.name.clone())
+            .collect();
+        for (idx, name) in layer_names.iter().enumerate() {
+            if layer_names[..idx].contains(name) {
+                return Err(ConfigError::DuplicateLayer(name.clone()))
+                    .context(format!("parsing layers in {}", path.display()));
+            }
+        }
+        let profile_names: Vec<String> = config_file
+            .profile
+            .iter()
+            .map(|profile| profile.name.clone())
+            .collect();
+        for (idx, name) in profile_names.iter().enumerate() {
+            if profile_names[..idx].contains(name) {
+                return Err(ConfigError::DuplicateProfile(name.clone()))
+                    .context(format!("parsing profiles in {}", path.display()));
+            }
+        }
+        let context = SectionContext {
+            layers: &layer_names,
+            profiles: &profile_names,
+            xkb_layout: config_file.xkb_layout.as_deref(),
+            xkb_variant: config_file.xkb_variant.as_deref(),
+            keymap: OnceCell::new(),
+        };
+        let default_profile = match &config_file.default_profile {
+            Some(name) => profile_names
+                .iter()
+                .position(|profile| profile == name)
+                .ok_or_else(|| ConfigError::UnknownProfile(name.clone()))
+                .context(format!("parsing default_profile in {}", path.display()))?,
+            None => 0,
+        };
+        let mappings = MappingSections {
+            dual_role: config_file.dual_role,
+            remap: config_file.remap,
+            swap: config_file.swap,
+            keymap: config_file.keymap,
+            multi_function: config_file.multi_function,
+            encoder: config_file.encoder,
+            sequence: config_file.sequence,
+            raw_key: config_file.raw_key,
+            substitute_modifier: config_file.substitute_modifier,
+            mod_lock: config_file.mod_lock,
+            toggle: config_file.toggle,
+            macros: config_file.macros,
+            mouse_key: config_file.mouse_key,
+            one_shot: config_file.one_shot,
+            caps_word: config_file.caps_word,
+            tap_dance: config_file.tap_dance,
+            turbo: config_file.turbo,
+            command: config_file.command,
+            layer_key: config_file.layer_key,
+            profile_key: config_file.profile_key,
+        }
+        .into_mappings(path, &context)?;
+        let mut layers = vec![];
+        for layer in config_file.layer {
+            let mappings = layer
+                .mappings
+                .into_mappings(path, &context)
+                .context(format!("in layer `{}`", layer.name))?;
+            let only_when = layer
+                .only_when
+                .map(LayerCondition::try_from)
+                .transpose()
+                .context(format!("in layer `{}`", layer.name))?;
+            layers.push(Layer {
+                name: layer.name,
+                mappings,
+                only_when,
+                led: layer.led.map(Into::into),
+                default: layer.default,
+            });
         }
-        for remap in config_file.remap {
-            mappings.push(remap.into());
+        let mut profiles = vec![];
+        for profile in config_file.profile {
+            let mappings = profile
+                .mappings
+                .into_mappings(path, &context)
+                .context(format!("in profile `{}`", profile.name))?;
+            profiles.push(Profile {
+                name: profile.name,
+                mappings,
+                led: profile.led.map(Into::into),
+            });
+        }
+        let mut devices = vec![];
+        for (idx, device) in config_file.device.into_iter().enumerate() {
+            let mappings = device
+                .mappings
+                .into_mappings(path, &context)
+                .context(format!("in device section {}", idx + 1))?;
+            devices.push(DeviceMappings {
+                device_name: device.device_name,
+                phys: device.phys,
+                path: device.path,
+                sysfs_path: device.sysfs_path,
+                mappings,
+                debounce: device.debounce_ms.map(Duration::from_millis),
+                output_device: device.output_device.map(Into::into),
+            });
+        }
+        let mut modifier_rewrite = HashMap::new();
+        for (from, to) in config_file.modifier_rewrite {
+            let from = KeyCodeWrapper::try_from(from)
+                .context(format!("parsing modifier_rewrite in {}", path.display()))?;
+            modifier_rewrite.insert(from.into(), to.into());
+        }
+        let mut debounce_keys = HashMap::new();
+        for (key, ms) in config_file.debounce_keys {
+            let key = KeyCodeWrapper::try_from(key)
+                .context(format!("parsing debounce_keys in {}", path.display()))?;
+            debounce_keys.insert(key.into(), Duration::from_millis(ms));
         }
         Ok(Self {
             device_name: config_file.device_name,
             phys: config_file.phys,
             mappings,
+            layers,
+            profiles,
+            default_profile,
+            devices,
             path: config_file.path,
+            sysfs_path: config_file.sysfs_path,
+            release_triggers: config_file
+                .release_all
+                .into_iter()
+                .map(Into::into)
+                .collect(),
+            keys_only: config_file.keys_only,
+            sanitize: config_file.sanitize,
+            overlay: !config_file.grab,
+            keepalive_interval: config_file.keepalive_interval_ms.map(Duration::from_millis),
+            watch_config: config_file.watch_config,
+            reload_scope: config_file.reload_scope,
+            post_grab_settle: Duration::from_millis(config_file.post_grab_settle_ms),
+            post_grab_settle_mode: config_file.post_grab_settle_mode,
+            output_device: config_file
+                .output_device
+                .map(Into::into)
+                .unwrap_or_default(),
+            modifier_rewrite,
+            max_drag: config_file.max_drag_ms.map(Duration::from_millis),
+            max_hold: config_file.max_hold_ms.map(Duration::from_millis),
+            extra_output_keys: config_file
+                .extra_output_keys
+                .into_iter()
+                .map(Into::into)
+                .collect(),
+            periodic_syn: config_file.periodic_syn_ms.map(Duration::from_millis),
+            low_latency_passthrough: config_file.low_latency_passthrough,
+            suppress_modifier_pulses: config_file.suppress_modifier_pulses,
+            modifier_settle: config_file.modifier_settle_ms.map(Duration::from_millis),
+            focus_provider: config_file.focus_provider,
+            autoshift: config_file.autoshift.map(Into::into),
+            hands: match config_file.hands {
+                Some(hands) => hands.into(),
+                None => default_hands(),
+            },
+            debounce: config_file.debounce_ms.map(Duration::from_millis),
+            debounce_keys,
         })
     }
+
+    /// Returns the mappings of the base layer followed
+    /// by those of every other layer and profile
+    pub fn all_mappings(&self) -> Vec<Mapping> {
+        self.mappings
+            .iter()
+            .chain(self.layers.iter().flat_map(|layer| &layer.mappings))
+            .chain(self.profiles.iter().flat_map(|profile| &profile.mappings))
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the keys that the config file at `path` gives by their
+    /// numeric codes rather than by name, with the names that they have
+    pub fn numeric_keys<P: AsRef<Path>>(path: P) -> anyhow::Result<BTreeMap<i64, KeyCode>> {
+        let path = path.as_ref();
+        let toml_data = std::fs::read_to_string(path)
+            .context(format!("reading toml from {}", path.display()))?;
+        let value: toml::Value =
+            toml::from_str(&toml_data).context(format!("parsing toml from {}", path.display()))?;
+        let mut keys = BTreeMap::new();
+        collect_numeric_keys(&value, false, &mut keys);
+        Ok(keys)
+    }
+
+    /// Returns a builder for a config that is put together in code,
+    /// rather than loaded from a file
+    pub fn builder() -> MappingConfigBuilder {
+        MappingConfigBuilder::default()
+    }
+
+    /// Returns a config for each device that is to be remapped: one
+    /// for each `[[device]]` section, or just this one if there are none.
+    /// The mappings of a `[[device]]` section take precedence over the
+    /// mappings that are shared by all of the devices.
+    pub fn split_devices(self) -> Vec<MappingConfig> {
+        if self.devices.is_empty() {
+            return vec![self];
+        }
+        self.devices
+            .iter()
+            .map(|device| MappingConfig {
+                device_name: device.device_name.clone(),
+                phys: device.phys.clone(),
+                path: device.path.clone(),
+                sysfs_path: device.sysfs_path.clone(),
+                mappings: device
+                    .mappings
+                    .iter()
+                    .chain(&self.mappings)
+                    .cloned()
+                    .collect(),
+                debounce: device.debounce.or(self.debounce),
+                output_device: device
+                    .output_device
+                    .clone()
+                    .unwrap_or_else(|| self.output_device.clone()),
+                devices: vec![],
+                ..self.clone()
+            })
+            .collect()
+    }
 }
 
-#[derive(Debug, Clone, Eq, PartialEq)]
-pub enum Mapping {
-    DualRole {
+/// Builds a `MappingConfig` one setting or mapping at a time.
+/// Settings that aren't specified take the same defaults as they
+/// do in a config file, and mappings are considered in the order
+/// that they are added.
+#[derive(Debug, Default)]
+pub struct MappingConfigBuilder {
+    config: MappingConfig,
+}
+
+impl MappingConfigBuilder {
+    /// Remap the device with this name
+    pub fn device_name(mut self, name: impl Into<String>) -> Self {
+        self.config.device_name = Some(name.into());
+        self
+    }
+
+    /// Choose between devices that have the same name by their `phys`
+    pub fn phys(mut self, phys: impl Into<String>) -> Self {
+        self.config.phys = Some(phys.into());
+        self
+    }
+
+    /// Remap the device at this path, such as `/dev/input/event3`
+    pub fn path(mut self, path: impl Into<String>) -> Self {
+        self.config.path = Some(path.into());
+        self
+    }
+
+    /// Remap the device found beneath this location in the sysfs
+    /// device hierarchy
+    pub fn sysfs_path(mut self, sysfs_path: impl Into<String>) -> Self {
+        self.config.sysfs_path = Some(sysfs_path.into());
+        self
+    }
+
+    /// Add a `[[remap]]`: while all of `input` are held, `output`
+    /// is held in their place
+    pub fn remap(
+        self,
+        input: impl IntoIterator<Item = KeyCode>,
+        output: impl IntoIterator<Item = KeyCode>,
+    ) -> Self {
+        self.mapping(Mapping::Remap {
+            input: input.into_iter().collect(),
+            output: output.into_iter().collect(),
+            order: vec![],
+            exact: false,
+        })
+    }
+
+    /// Add a `[[dual_role]]`: holding `input` holds `hold`,
+    /// and tapping it taps `tap`
+    pub fn dual_role(
+        self,
         input: KeyCode,
-        hold: Vec<KeyCode>,
-        tap: Vec<KeyCode>,
-    },
-    Remap {
-        input: HashSet<KeyCode>,
-        output: HashSet<KeyCode>,
-    },
+        hold: impl IntoIterator<Item = KeyCode>,
+        tap: impl IntoIterator<Item = KeyCode>,
+    ) -> Self {
+        self.mapping(Mapping::DualRole {
+            input,
+            hold: hold.into_iter().collect(),
+            tap: tap.into_iter().collect(),
+            abort_hold_emits_tap: false,
+            hold_timeout: None,
+            hold_on_other_key_press: true,
+            permissive_hold: false,
+            quick_tap: None,
+            chordal_hold: false,
+        })
+    }
+
+    /// Add any kind of mapping
+    pub fn mapping(mut self, mapping: Mapping) -> Self {
+        self.config.mappings.push(mapping);
+        self
+    }
+
+    /// Add a layer, which can be activated by a `Mapping::Layer`
+    /// that refers to it by its index in the order that layers
+    /// were added
+    pub fn layer(mut self, name: impl Into<String>, mappings: Vec<Mapping>) -> Self {
+        self.config.layers.push(Layer {
+            name: name.into(),
+            mappings,
+            only_when: None,
+            led: None,
+            default: LayerDefault::Transparent,
+        });
+        self
+    }
+
+    /// Add a profile, which can be switched to by a `Mapping::Profile`
+    /// that refers to it by its index in the order that profiles were
+    /// added.  The first profile is the one that applies at startup.
+    pub fn profile(mut self, name: impl Into<String>, mappings: Vec<Mapping>) -> Self {
+        self.config.profiles.push(Profile {
+            name: name.into(),
+            mappings,
+            led: None,
+        });
+        self
+    }
+
+    /// Returns the finished config
+    pub fn build(self) -> MappingConfig {
+        self.config
+    }
+}
+
+/// A device from a `[[device]]` section, and the mappings that
+/// apply only to it
+#[derive(Debug, Clone)]
+pub struct DeviceMappings {
+    pub device_name: Option<String>,
+    pub phys: Option<String>,
+    pub path: Option<String>,
+    pub sysfs_path: Option<String>,
+    pub mappings: Vec<Mapping>,
+    /// Overrides the `debounce` of the config for this device
+    pub debounce: Option<Duration>,
+    /// Overrides the `output_device` of the config for this device
+    pub output_device: Option<OutputDevice>,
+}
+
+/// The identity of the virtual output device.  Those that are None
+/// are the same as the input device's, except for the name, which
+/// defaults to one that says which device it is remapping.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OutputDevice {
+    pub name: Option<String>,
+    pub vendor: Option<u16>,
+    pub product: Option<u16>,
+    pub bustype: Option<BusType>,
 }
 
 #[derive(Debug, Deserialize)]
-#[serde(try_from = "String")]
-struct KeyCodeWrapper {
-    pub code: KeyCode,
+struct DeviceConfig {
+    #[serde(default)]
+    device_name: Option<String>,
+    #[serde(default)]
+    phys: Option<String>,
+    #[serde(default)]
+    path: Option<String>,
+    #[serde(default)]
+    sysfs_path: Option<String>,
+    #[serde(default)]
+    debounce_ms: Option<u64>,
+    #[serde(default)]
+    output_device: Option<OutputDeviceConfig>,
+    #[serde(flatten)]
+    mappings: MappingSections,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct OutputDeviceConfig {
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    vendor: Option<u16>,
+    #[serde(default)]
+    product: Option<u16>,
+    #[serde(default)]
+    bustype: Option<BusTypeWrapper>,
 }
 
-impl Into<KeyCode> for KeyCodeWrapper {
-    fn into(self) -> KeyCode {
-        self.code
+impl From<OutputDeviceConfig> for OutputDevice {
+    fn from(config: OutputDeviceConfig) -> Self {
+        Self {
+            name: config.name,
+            vendor: config.vendor,
+            product: config.product,
+            bustype: config.bustype.map(|wrapper| wrapper.bustype),
+        }
     }
 }
 
-#[derive(Error, Debug)]
-pub enum ConfigError {
-    #[error("Invalid key `{0}`.  Use `evremap list-keys` to see possible keys.")]
-    InvalidKey(String),
-    #[error("Impossible: parsed KEY_XXX but not into an EV_KEY")]
-    ImpossibleParseKey,
+/// A named set of mappings that take precedence over the
+/// others while the layer is active
+#[derive(Debug, Clone)]
+pub struct Layer {
+    pub name: String,
+    pub mappings: Vec<Mapping>,
+    /// If set, the layer is also active whenever the condition holds
+    pub only_when: Option<LayerCondition>,
+    /// An LED of the input device that is lit while the layer is active
+    pub led: Option<LedCode>,
+    /// What happens to the keys that have no mapping in the layer
+    pub default: LayerDefault,
 }
 
-impl std::convert::TryFrom<String> for KeyCodeWrapper {
-    type Error = ConfigError;
-    fn try_from(s: String) -> Result<KeyCodeWrapper, Self::Error> {
-        match EventCode::from_str(&EventType::EV_KEY, &s) {
-            Some(code) => match code {
-                EventCode::EV_KEY(code) => Ok(KeyCodeWrapper { code }),
-                _ => Err(ConfigError::ImpossibleParseKey),
-            },
-            None => Err(ConfigError::InvalidKey(s)),
+impl Layer {
+    /// Returns true if the layer has a condition, and it holds
+    /// while `app` has the focus, the locks are in `locks` and
+    /// `switches` are the switches that are on
+    pub fn condition_holds(
+        &self,
+        app: Option<&str>,
+        locks: &LockState,
+        switches: &HashSet<SwitchCode>,
+    ) -> bool {
+        let Some(condition) = &self.only_when else {
+            return false;
+        };
+        if condition.is_empty() {
+            return false;
         }
+        let app_matches = condition.apps.is_empty()
+            || app.is_some_and(|app| {
+                condition
+                    .apps
+                    .iter()
+                    .any(|name| name.eq_ignore_ascii_case(app))
+            });
+        app_matches
+            && condition.numlock.is_none_or(|on| on == locks.numlock)
+            && condition.capslock.is_none_or(|on| on == locks.capslock)
+            && condition.scrolllock.is_none_or(|on| on == locks.scrolllock)
+            && condition
+                .switches
+                .iter()
+                .all(|(switch, on)| switches.contains(switch) == *on)
     }
 }
 
-#[derive(Debug, Deserialize)]
-struct DualRoleConfig {
-    input: KeyCodeWrapper,
-    hold: Vec<KeyCodeWrapper>,
-    tap: Vec<KeyCodeWrapper>,
+/// When a layer applies without being activated by a layer key.
+/// Everything that is set must hold.
+#[derive(Debug, Clone, Default)]
+pub struct LayerCondition {
+    /// The Wayland app_ids or X11 window classes of the applications
+    /// that the layer applies to while they have the focus
+    pub apps: Vec<String>,
+    /// Whether each lock must be on or off
+    pub numlock: Option<bool>,
+    pub capslock: Option<bool>,
+    pub scrolllock: Option<bool>,
+    /// Whether each switch, such as SW_TABLET_MODE, must be on or off
+    pub switches: Vec<(SwitchCode, bool)>,
 }
 
-impl Into<Mapping> for DualRoleConfig {
-    fn into(self) -> Mapping {
-        Mapping::DualRole {
-            input: self.input.into(),
-            hold: self.hold.into_iter().map(Into::into).collect(),
-            tap: self.tap.into_iter().map(Into::into).collect(),
+impl LayerCondition {
+    fn is_empty(&self) -> bool {
+        self.apps.is_empty()
+            && self.numlock.is_none()
+            && self.capslock.is_none()
+            && self.scrolllock.is_none()
+            && self.switches.is_empty()
+    }
+}
+
+/// Which of the locks are on
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct LockState {
+    pub numlock: bool,
+    pub capslock: bool,
+    pub scrolllock: bool,
+}
+
+/// The ways of finding out which application has the focus
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FocusProviderKind {
+    /// The event socket of Hyprland
+    Hyprland,
+    /// The IPC socket of sway
+    Sway,
+    /// The `_NET_ACTIVE_WINDOW` property of the X11 root window
+    X11,
+}
+
+/// A named set of mappings that apply in addition to the others
+/// while it is the active profile
+#[derive(Debug, Clone)]
+pub struct Profile {
+    pub name: String,
+    pub mappings: Vec<Mapping>,
+    /// An LED of the input device that is lit while this is the
+    /// active profile
+    pub led: Option<LedCode>,
+}
+
+/// A step of a `Mapping::Macro`
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum MacroStep {
+    /// Press and release the key
+    Tap(KeyCode),
+    /// Press the key, and keep it held until it is released
+    /// by a later step
+    Press(KeyCode),
+    Release(KeyCode),
+    /// Wait this long before the next step
+    Delay(Duration),
+}
+
+impl MacroStep {
+    /// Returns the key that the step presses or releases, if any
+    pub fn key(&self) -> Option<KeyCode> {
+        match self {
+            Self::Tap(key) | Self::Press(key) | Self::Release(key) => Some(*key),
+            Self::Delay(_) => None,
         }
     }
 }
 
-#[derive(Debug, Deserialize)]
-struct RemapConfig {
-    input: Vec<KeyCodeWrapper>,
-    output: Vec<KeyCodeWrapper>,
+impl From<KeyCode> for MacroStep {
+    fn from(key: KeyCode) -> Self {
+        Self::Tap(key)
+    }
+}
+
+/// A program that is run by a `Mapping::Command`
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CommandSpec {
+    /// The program and its arguments
+    pub argv: Vec<String>,
+    /// Environment variables to set, in addition to those of evremap
+    pub env: BTreeMap<String, String>,
+    /// Who to run it as, rather than the user running evremap
+    pub user: Option<CommandUser>,
+}
+
+/// A user to run a `CommandSpec` as
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum CommandUser {
+    Name(String),
+    Uid(u32),
 }
 
-impl Into<Mapping> for RemapConfig {
-    fn into(self) -> Mapping {
-        Mapping::Remap {
-            input: self.input.into_iter().map(Into::into).collect(),
-            output: self.output.into_iter().map(Into::into).collect(),
+/// The pointer motion or scrolling produced by a `Mapping::MouseKey`
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MouseKeyDirection {
+    Up,
+    Down,
+    Left,
+    Right,
+    WheelUp,
+    WheelDown,
+    WheelLeft,
+    WheelRight,
+}
+
+impl MouseKeyDirection {
+    /// Returns the axis that moves in this direction, and
+    /// the sign of the movement
+    pub fn axis(&self) -> (RelCode, i32) {
+        match self {
+            Self::Up => (RelCode::REL_Y, -1),
+            Self::Down => (RelCode::REL_Y, 1),
+            Self::Left => (RelCode::REL_X, -1),
+            Self::Right => (RelCode::REL_X, 1),
+            Self::WheelUp => (RelCode::REL_WHEEL, 1),
+            Self::WheelDown => (RelCode::REL_WHEEL, -1),
+            Self::WheelLeft => (RelCode::REL_HWHEEL, -1),
+            Self::WheelRight => (RelCode::REL_HWHEEL, 1),
         }
     }
+
+    fn is_wheel(&self) -> bool {
+        matches!(
+            self,
+            Self::WheelUp | Self::WheelDown | Self::WheelLeft | Self::WheelRight
+        )
+    }
 }
 
-#[derive(Debug, Deserialize)]
-struct ConfigFile {
+/// What is taken from the config file when it is reloaded
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReloadScope {
+    /// The mappings and settings, but not the device settings,
+    /// so the device is never let go of
+    #[default]
+    Mappings,
+    /// The device settings as well, switching to a different
+    /// device if they now choose one
+    Full,
+}
+
+/// What a layer does with the keys that it has no mapping for
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LayerDefault {
+    /// They are looked up in the layers below, and
+    /// the mappings outside of any layer
+    #[default]
+    Transparent,
+    /// They are swallowed.  Only the layer and profile keys
+    /// of the layers below still work.
+    Block,
+}
+
+/// How a `Mapping::Layer` key activates its layer
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LayerMode {
+    /// The layer is active while the key is held
+    #[default]
+    Momentary,
+    /// Each press of the key activates or deactivates the layer
+    Toggle,
+}
+
+/// The sections of a config file that define mappings.  These can
+/// appear both at the top level and within a layer.
+#[derive(Debug, Default, Deserialize)]
+struct MappingSections {
     #[serde(default)]
-    device_name: Option<String>,
+    dual_role: Vec<DualRoleConfig>,
+    #[serde(default)]
+    remap: Vec<RemapConfig>,
+    #[serde(default)]
+    swap: Vec<SwapConfig>,
+    #[serde(default)]
+    keymap: BTreeMap<String, KeyCodeWrapper>,
+    #[serde(default)]
+    multi_function: Vec<MultiFunctionConfig>,
+    #[serde(default)]
+    encoder: Vec<EncoderConfig>,
+    #[serde(default)]
+    sequence: Vec<SequenceConfig>,
+    #[serde(default)]
+    raw_key: Vec<RawKeyConfig>,
+    #[serde(default)]
+    substitute_modifier: Vec<SubstituteModifierConfig>,
+    #[serde(default)]
+    mod_lock: Vec<ModLockConfig>,
+    /// Another name for `mod_lock`, for keys that aren't modifiers
+    #[serde(default)]
+    toggle: Vec<ModLockConfig>,
+    #[serde(default, rename = "macro")]
+    macros: Vec<MacroConfig>,
+    #[serde(default)]
+    mouse_key: Vec<MouseKeyConfig>,
+    #[serde(default)]
+    one_shot: Vec<OneShotConfig>,
+    #[serde(default)]
+    caps_word: Vec<CapsWordConfig>,
+    #[serde(default)]
+    tap_dance: Vec<TapDanceConfig>,
+    #[serde(default)]
+    turbo: Vec<TurboConfig>,
+    #[serde(default)]
+    command: Vec<CommandConfig>,
+    #[serde(default)]
+    layer_key: Vec<LayerKeyConfig>,
+    #[serde(default)]
+    profile_key: Vec<ProfileKeyConfig>,
+}
+
+/// What the sections of mappings refer to: the names of the layers
+/// and profiles, whose positions are used to refer to them, and the
+/// keyboard layout that text is typed with
+struct SectionContext<'a> {
+    layers: &'a [String],
+    profiles: &'a [String],
+    xkb_layout: Option<&'a str>,
+    xkb_variant: Option<&'a str>,
+    keymap: OnceCell<Option<xkb::Keymap>>,
+}
+
+impl SectionContext<'_> {
+    /// Returns the XKB keymap, which is loaded the first time that
+    /// it's needed.  Without it, text is typed with Ctrl+Shift+U.
+    fn keymap(&self) -> Option<&xkb::Keymap> {
+        self.keymap
+            .get_or_init(
+                || match xkb::Keymap::new(self.xkb_layout, self.xkb_variant) {
+                    Ok(keymap) => Some(keymap),
+                    Err(err) => {
+                        log::warn!("Typing all text with Ctrl+Shift+U: {err:#}");
+                        None
+                    }
+                },
+            )
+            .as_ref()
+    }
+}
+
+impl MappingSections {
+    fn into_mappings(self, path: &Path, context: &SectionContext) -> anyhow::Result<Vec<Mapping>> {
+        let mut mappings = vec![];
+        for dual in self.dual_role {
+            mappings.push((dual.priority, dual.into()));
+        }
+        for multi in self.multi_function {
+            mappings.push((multi.priority, multi.into()));
+        }
+        for mut remap in self.remap {
+            let priority = remap.priority;
+            let remap = remap
+                .resolve_chars(context)
+                .and_then(|()| Mapping::try_from(remap))
+                .context(format!("parsing remap in {}", path.display()))?;
+            mappings.push((priority, remap));
+        }
+        for swap in self.swap {
+            mappings.push((swap.priority, swap.into()));
+        }
+        for (input, output) in self.keymap {
+            let input = KeyCodeWrapper::try_from(input)
+                .context(format!("parsing keymap in {}", path.display()))?;
+            mappings.push((
+                0,
+                Mapping::Remap {
+                    input: [input.into()].into(),
+                    output: [output.into()].into(),
+                    order: vec![],
+                    exact: false,
+                },
+            ));
+        }
+        for encoder in self.encoder {
+            mappings.push((0, encoder.into()));
+        }
+        for sequence in self.sequence {
+            mappings.push((0, sequence.into()));
+        }
+        for raw in self.raw_key {
+            mappings.push((0, raw.into()));
+        }
+        for substitute in self.substitute_modifier {
+            mappings.push((0, substitute.into()));
+        }
+        for lock in self.mod_lock.into_iter().chain(self.toggle) {
+            mappings.push((0, lock.into()));
+        }
+        for macro_config in self.macros {
+            mappings.push((0, macro_config.into_mapping(context)));
+        }
+        for mouse_key in self.mouse_key {
+            mappings.push((0, mouse_key.into()));
+        }
+        for one_shot in self.one_shot {
+            mappings.push((0, one_shot.into()));
+        }
+        for caps_word in self.caps_word {
+            mappings.push((0, caps_word.into()));
+        }
+        for tap_dance in self.tap_dance {
+            mappings.push((0, tap_dance.into()));
+        }
+        for turbo in self.turbo {
+            if turbo.rate_hz == 0 {
+                return Err(ConfigError::InvalidTurboRate)
+                    .context(format!("parsing turbo in {}", path.display()));
+            }
+            mappings.push((0, turbo.into()));
+        }
+        for command in self.command {
+            let command = Mapping::try_from(command)
+                .context(format!("parsing command in {}", path.display()))?;
+            mappings.push((0, command));
+        }
+        for layer_key in self.layer_key {
+            let layer = context
+                .layers
+                .iter()
+                .position(|name| *name == layer_key.layer)
+                .ok_or_else(|| ConfigError::UnknownLayer(layer_key.layer.clone()))
+                .context(format!("parsing layer_key in {}", path.display()))?;
+            mappings.push((
+                0,
+                Mapping::Layer {
+                    input: layer_key.input.into(),
+                    layer,
+                    mode: layer_key.mode,
+                    lock_on_double_tap: layer_key.lock_on_double_tap,
+                    output: layer_key.output.into_iter().map(Into::into).collect(),
+                },
+            ));
+        }
+        for profile_key in self.profile_key {
+            let profile = context
+                .profiles
+                .iter()
+                .position(|name| *name == profile_key.profile)
+                .ok_or_else(|| ConfigError::UnknownProfile(profile_key.profile.clone()))
+                .context(format!("parsing profile_key in {}", path.display()))?;
+            mappings.push((
+                0,
+                Mapping::Profile {
+                    input: profile_key.input.into(),
+                    profile,
+                },
+            ));
+        }
+        // Entries with a higher priority are considered first.  The sort
+        // is stable, so entries with the same priority keep their order.
+        mappings.sort_by_key(|(priority, _)| std::cmp::Reverse(*priority));
+        Ok(mappings.into_iter().map(|(_, map)| map).collect())
+    }
+}
 
+#[derive(Debug, Deserialize)]
+struct LayerConfig {
+    name: String,
     #[serde(default)]
-    path: Option<String>,
+    only_when: Option<LayerConditionConfig>,
+    #[serde(default)]
+    led: Option<LedCodeWrapper>,
+    #[serde(default)]
+    default: LayerDefault,
+    #[serde(flatten)]
+    mappings: MappingSections,
+}
 
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LayerConditionConfig {
     #[serde(default)]
-    phys: Option<String>,
+    app: Vec<String>,
+    #[serde(default)]
+    numlock: Option<bool>,
+    #[serde(default)]
+    capslock: Option<bool>,
+    #[serde(default)]
+    scrolllock: Option<bool>,
+    #[serde(default)]
+    switches: BTreeMap<String, bool>,
+}
+
+impl TryFrom<LayerConditionConfig> for LayerCondition {
+    type Error = ConfigError;
+    fn try_from(config: LayerConditionConfig) -> Result<Self, Self::Error> {
+        let mut switches = vec![];
+        for (name, on) in config.switches {
+            match EventCode::from_str(&EventType::EV_SW, &name) {
+                Some(EventCode::EV_SW(switch)) => switches.push((switch, on)),
+                _ => return Err(ConfigError::InvalidSwitch(name)),
+            }
+        }
+        Ok(Self {
+            apps: config.app,
+            numlock: config.numlock,
+            capslock: config.capslock,
+            scrolllock: config.scrolllock,
+            switches,
+        })
+    }
+}
 
+#[derive(Debug, Deserialize)]
+struct ProfileConfig {
+    name: String,
     #[serde(default)]
-    dual_role: Vec<DualRoleConfig>,
+    led: Option<LedCodeWrapper>,
+    #[serde(flatten)]
+    mappings: MappingSections,
+}
+
+#[derive(Debug, Deserialize)]
+struct ProfileKeyConfig {
+    input: KeyCodeWrapper,
+    profile: String,
+}
 
+#[derive(Debug, Deserialize)]
+struct LayerKeyConfig {
+    input: KeyCodeWrapper,
+    layer: String,
     #[serde(default)]
-    remap: Vec<RemapConfig>,
+    mode: LayerMode,
+    #[serde(default)]
+    lock_on_double_tap: bool,
+    #[serde(default)]
+    output: Vec<KeyCodeWrapper>,
+}
+
+/// The version of the config file format that this build understands.
+/// Config files that don't specify a `version` predate versioning,
+/// and are treated as version 1.
+pub const CONFIG_VERSION: i64 = 1;
+
+/// Top level settings that were renamed: the version of the config file
+/// format that introduced the new name, the old name and the new name
+const RENAMED_SETTINGS: &[(i64, &str, &str)] = &[];
+
+/// Brings a config file that was written for an older version of evremap
+/// up to date, warning about each deprecated setting that it uses.
+/// Returns true if anything was changed.
+/// The document is edited in place, so that its comments and layout
+/// are kept.
+fn migrate_config(document: &mut toml_edit::DocumentMut) -> anyhow::Result<bool> {
+    let version = match document.get("version") {
+        None => 1,
+        Some(item) => match item.as_integer() {
+            Some(version) => version,
+            None => anyhow::bail!(
+                "version must be an integer, not {}",
+                item.to_string().trim()
+            ),
+        },
+    };
+    if version > CONFIG_VERSION {
+        anyhow::bail!(
+            "config file version {version} requires a newer evremap; \
+             this one understands version {CONFIG_VERSION}"
+        );
+    }
+
+    let mut changed = false;
+    for (since, old, new) in RENAMED_SETTINGS {
+        if version >= *since {
+            continue;
+        }
+        if let Some((key, setting)) = document.remove_entry(old) {
+            log::warn!("`{old}` is deprecated; use `{new}` instead");
+            // Keep the comments that came before the old name
+            let key = toml_edit::Key::new(*new).with_leaf_decor(key.leaf_decor().clone());
+            document.insert_formatted(&key, setting);
+            changed = true;
+        }
+    }
+    Ok(changed)
+}
+
+/// Returns the contents of the config file at `path`, rewritten for
+/// the current version of the config file format.  Only the settings
+/// that changed are touched; everything else, including comments, is
+/// left as it was.
+pub fn migrate_config_file(path: &Path) -> anyhow::Result<String> {
+    let toml_data =
+        std::fs::read_to_string(path).context(format!("reading toml from {}", path.display()))?;
+    let mut document: toml_edit::DocumentMut = toml_data
+        .parse()
+        .context(format!("parsing toml from {}", path.display()))?;
+    migrate_config(&mut document).context(format!("migrating {}", path.display()))?;
+    match document.get_mut("version") {
+        Some(version) => *version = toml_edit::value(CONFIG_VERSION),
+        None => {
+            document.insert("version", toml_edit::value(CONFIG_VERSION));
+        }
+    }
+    Ok(document.to_string())
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Mapping {
+    DualRole {
+        input: KeyCode,
+        hold: Vec<KeyCode>,
+        tap: Vec<KeyCode>,
+        /// If true, a quick press and release still produces `tap` when
+        /// other keys were pressed in the meantime, provided that they
+        /// were all modifiers that didn't make use of `hold`
+        abort_hold_emits_tap: bool,
+        /// If set, `hold` is not pressed until either this much time
+        /// has passed or another key has been pressed, and a release
+        /// within this time produces `tap`
+        hold_timeout: Option<Duration>,
+        /// If false, pressing another key doesn't decide that the key is
+        /// held; that is only decided by the timeout, or by permissive_hold.
+        /// The other keys are held back until it is decided.
+        hold_on_other_key_press: bool,
+        /// If true, pressing and releasing another key while this one is
+        /// held decides that it is held, without waiting for the timeout
+        permissive_hold: bool,
+        /// Pressing the key again within this time of tapping it taps
+        /// it straight away, holding `tap` for as long as it is held
+        quick_tap: Option<Duration>,
+        /// If true, pressing another key on the same hand, according to
+        /// `MappingConfig::hands`, while this one is undecided makes it a tap
+        chordal_hold: bool,
+    },
+    Remap {
+        input: HashSet<KeyCode>,
+        output: HashSet<KeyCode>,
+        /// Output keys that must be pressed first, in this sequence,
+        /// and released last, in the reverse sequence
+        order: Vec<KeyCode>,
+        /// If true, it only applies while no keys other than `input`
+        /// are held, rather than whenever `input` are among them
+        exact: bool,
+    },
+    /// `a` produces `b` and `b` produces `a`, including while both
+    /// are held, which a pair of remaps gets wrong
+    Swap { a: KeyCode, b: KeyCode },
+    /// Combines the tap, hold, double-tap and hold-after-tap functions
+    /// of a single key.  Each of these is decided based on timing:
+    /// a press or release within `timeout` of the previous transition
+    /// counts as a tap, otherwise as a hold.
+    MultiFunction {
+        input: KeyCode,
+        tap: Vec<KeyCode>,
+        hold: Vec<KeyCode>,
+        double_tap: Vec<KeyCode>,
+        hold_after_tap: Vec<KeyCode>,
+        timeout: Duration,
+    },
+    /// Maps the movement of a relative axis, such as a rotary encoder
+    /// or a scroll wheel, to key taps.  Positive movement produces `cw`
+    /// and negative movement produces `ccw`.
+    Encoder {
+        input: RelCode,
+        cw: Vec<KeyCode>,
+        ccw: Vec<KeyCode>,
+        acceleration: Option<EncoderAcceleration>,
+    },
+    /// Pressing `keys` one after the other, each within `window` of
+    /// the previous one, taps `output` instead.  The keys are held back
+    /// until the sequence is either complete or broken.
+    Sequence {
+        keys: Vec<KeyCode>,
+        output: Vec<KeyCode>,
+        window: Duration,
+    },
+    /// Taps `press`, `release` or `repeat` for each event of the input
+    /// key with the corresponding value, bypassing all other processing
+    /// of that key
+    RawKey {
+        input: KeyCode,
+        press: Vec<KeyCode>,
+        release: Vec<KeyCode>,
+        repeat: Vec<KeyCode>,
+    },
+    /// When `modifier` is held together with other keys, none of which
+    /// are in `except`, `replacement` is held in its place
+    SubstituteModifier {
+        modifier: KeyCode,
+        replacement: KeyCode,
+        except: HashSet<KeyCode>,
+    },
+    /// Pressing `input` activates the layer with this index in
+    /// `MappingConfig::layers`, as described by `mode`
+    Layer {
+        input: KeyCode,
+        layer: usize,
+        mode: LayerMode,
+        /// If true, tapping the key and then pressing it again
+        /// quickly locks the layer on, or unlocks it
+        lock_on_double_tap: bool,
+        /// Keys that are tapped when the key is pressed, before
+        /// the layer is activated
+        output: Vec<KeyCode>,
+    },
+    /// Pressing `input` switches to the profile with this
+    /// index in `MappingConfig::profiles`
+    Profile { input: KeyCode, profile: usize },
+    /// Each press of `input` carries out the `output` steps one at a
+    /// time, waiting for `delay` after each of them that is a key
+    Macro {
+        input: KeyCode,
+        output: Vec<MacroStep>,
+        delay: Duration,
+    },
+    /// While `input` is held, moves the pointer or turns the scroll
+    /// wheel in `direction`, speeding up from `initial_speed` to
+    /// `max_speed` units per second over the `ramp` duration
+    MouseKey {
+        input: KeyCode,
+        direction: MouseKeyDirection,
+        initial_speed: u32,
+        max_speed: u32,
+        ramp: Duration,
+    },
+    /// Holding `input` holds `output`, like any other key, but
+    /// tapping it holds `output` until the next key is released.
+    /// Tapping it again before then cancels that.
+    OneShot {
+        input: KeyCode,
+        output: Vec<KeyCode>,
+    },
+    /// Pressing `input` turns on Caps Word, which shifts letters until
+    /// a key that isn't part of a word is pressed.  Pressing it again
+    /// turns it off.
+    CapsWord { input: KeyCode },
+    /// Tapping `input` N times in a row, each tap starting within
+    /// `timeout` of the previous one ending, taps the Nth entry of `taps`
+    TapDance {
+        input: KeyCode,
+        taps: Vec<Vec<KeyCode>>,
+        timeout: Duration,
+    },
+    /// While `input` is held, `output` is pressed and released over
+    /// and over, once every `interval`
+    Turbo {
+        input: KeyCode,
+        output: Vec<KeyCode>,
+        interval: Duration,
+    },
+    /// Each press of `input` toggles whether `output` is held.
+    /// If `timeout` is set, `output` is released after being
+    /// held for that long.
+    ModLock {
+        input: KeyCode,
+        output: Vec<KeyCode>,
+        timeout: Option<Duration>,
+    },
+    /// Pressing `input` runs `command`, without waiting for it
+    Command {
+        input: KeyCode,
+        command: CommandSpec,
+        /// If set, `command` is only run once `input` has
+        /// been held for this long
+        min_hold: Option<Duration>,
+        /// Run when `input` is released, if `command` was run
+        release: Option<CommandSpec>,
+    },
+    /// While the key whose press is reported with the scancode `scan`
+    /// is held, `output` is held in its place, whichever key code the
+    /// kernel gives it
+    ScanRemap { scan: u32, output: Vec<KeyCode> },
+}
+
+/// Describes an entry in the terms of the config file,
+/// such as `[[dual_role]] KEY_CAPSLOCK`
+impl std::fmt::Display for Mapping {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fn key_list<'a>(keys: impl IntoIterator<Item = &'a KeyCode>) -> String {
+            let mut names: Vec<String> = keys.into_iter().map(|k| format!("{k:?}")).collect();
+            names.sort();
+            names.join("+")
+        }
+        match self {
+            Mapping::DualRole { input, .. } => write!(f, "[[dual_role]] {input:?}"),
+            Mapping::MultiFunction { input, .. } => write!(f, "[[multi_function]] {input:?}"),
+            Mapping::RawKey { input, .. } => write!(f, "[[raw_key]] {input:?}"),
+            Mapping::ModLock { input, .. } => write!(f, "[[mod_lock]] {input:?}"),
+            Mapping::Macro { input, .. } => write!(f, "[[macro]] {input:?}"),
+            Mapping::MouseKey { input, .. } => write!(f, "[[mouse_key]] {input:?}"),
+            Mapping::OneShot { input, .. } => write!(f, "[[one_shot]] {input:?}"),
+            Mapping::CapsWord { input } => write!(f, "[[caps_word]] {input:?}"),
+            Mapping::TapDance { input, .. } => write!(f, "[[tap_dance]] {input:?}"),
+            Mapping::Turbo { input, .. } => write!(f, "[[turbo]] {input:?}"),
+            Mapping::Command { input, .. } => write!(f, "[[command]] {input:?}"),
+            Mapping::Layer { input, .. } => write!(f, "[[layer_key]] {input:?}"),
+            Mapping::Profile { input, .. } => write!(f, "[[profile_key]] {input:?}"),
+            Mapping::Remap { input, .. } => write!(f, "[[remap]] {}", key_list(input)),
+            Mapping::ScanRemap { scan, .. } => write!(f, "[[remap]] scancode {scan:#x}"),
+            Mapping::Swap { a, b } => write!(f, "[[swap]] {a:?}, {b:?}"),
+            Mapping::Encoder { input, .. } => write!(f, "[[encoder]] {input:?}"),
+            Mapping::Sequence { keys, .. } => {
+                let names: Vec<String> = keys.iter().map(|k| format!("{k:?}")).collect();
+                write!(f, "[[sequence]] {}", names.join(", "))
+            }
+            Mapping::SubstituteModifier { modifier, .. } => {
+                write!(f, "[[substitute_modifier]] {modifier:?}")
+            }
+        }
+    }
+}
+
+/// When ticks in the same direction arrive less than `interval` apart,
+/// each one produces an additional tap, up to `max` taps per tick.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct EncoderAcceleration {
+    pub interval: Duration,
+    pub max: u32,
+}
+
+/// Holding one of `keys` for at least `timeout` types it with Shift,
+/// instead of letting it autorepeat
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct AutoShift {
+    pub timeout: Duration,
+    pub keys: HashSet<KeyCode>,
+}
+
+impl AutoShift {
+    /// The letters, digits and punctuation of a US layout
+    pub fn default_keys() -> HashSet<KeyCode> {
+        [
+            KeyCode::KEY_A,
+            KeyCode::KEY_B,
+            KeyCode::KEY_C,
+            KeyCode::KEY_D,
+            KeyCode::KEY_E,
+            KeyCode::KEY_F,
+            KeyCode::KEY_G,
+            KeyCode::KEY_H,
+            KeyCode::KEY_I,
+            KeyCode::KEY_J,
+            KeyCode::KEY_K,
+            KeyCode::KEY_L,
+            KeyCode::KEY_M,
+            KeyCode::KEY_N,
+            KeyCode::KEY_O,
+            KeyCode::KEY_P,
+            KeyCode::KEY_Q,
+            KeyCode::KEY_R,
+            KeyCode::KEY_S,
+            KeyCode::KEY_T,
+            KeyCode::KEY_U,
+            KeyCode::KEY_V,
+            KeyCode::KEY_W,
+            KeyCode::KEY_X,
+            KeyCode::KEY_Y,
+            KeyCode::KEY_Z,
+            KeyCode::KEY_1,
+            KeyCode::KEY_2,
+            KeyCode::KEY_3,
+            KeyCode::KEY_4,
+            KeyCode::KEY_5,
+            KeyCode::KEY_6,
+            KeyCode::KEY_7,
+            KeyCode::KEY_8,
+            KeyCode::KEY_9,
+            KeyCode::KEY_0,
+            KeyCode::KEY_MINUS,
+            KeyCode::KEY_EQUAL,
+            KeyCode::KEY_LEFTBRACE,
+            KeyCode::KEY_RIGHTBRACE,
+            KeyCode::KEY_SEMICOLON,
+            KeyCode::KEY_APOSTROPHE,
+            KeyCode::KEY_GRAVE,
+            KeyCode::KEY_BACKSLASH,
+            KeyCode::KEY_COMMA,
+            KeyCode::KEY_DOT,
+            KeyCode::KEY_SLASH,
+        ]
+        .into()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Hand {
+    Left,
+    Right,
+}
+
+/// Splits the letters, digits and punctuation of a US QWERTY
+/// layout between the hands, as touch typists use them
+pub fn default_hands() -> HashMap<KeyCode, Hand> {
+    let left = [
+        KeyCode::KEY_GRAVE,
+        KeyCode::KEY_1,
+        KeyCode::KEY_2,
+        KeyCode::KEY_3,
+        KeyCode::KEY_4,
+        KeyCode::KEY_5,
+        KeyCode::KEY_Q,
+        KeyCode::KEY_W,
+        KeyCode::KEY_E,
+        KeyCode::KEY_R,
+        KeyCode::KEY_T,
+        KeyCode::KEY_A,
+        KeyCode::KEY_S,
+        KeyCode::KEY_D,
+        KeyCode::KEY_F,
+        KeyCode::KEY_G,
+        KeyCode::KEY_Z,
+        KeyCode::KEY_X,
+        KeyCode::KEY_C,
+        KeyCode::KEY_V,
+        KeyCode::KEY_B,
+    ];
+    let right = [
+        KeyCode::KEY_6,
+        KeyCode::KEY_7,
+        KeyCode::KEY_8,
+        KeyCode::KEY_9,
+        KeyCode::KEY_0,
+        KeyCode::KEY_MINUS,
+        KeyCode::KEY_EQUAL,
+        KeyCode::KEY_Y,
+        KeyCode::KEY_U,
+        KeyCode::KEY_I,
+        KeyCode::KEY_O,
+        KeyCode::KEY_P,
+        KeyCode::KEY_LEFTBRACE,
+        KeyCode::KEY_RIGHTBRACE,
+        KeyCode::KEY_BACKSLASH,
+        KeyCode::KEY_H,
+        KeyCode::KEY_J,
+        KeyCode::KEY_K,
+        KeyCode::KEY_L,
+        KeyCode::KEY_SEMICOLON,
+        KeyCode::KEY_APOSTROPHE,
+        KeyCode::KEY_N,
+        KeyCode::KEY_M,
+        KeyCode::KEY_COMMA,
+        KeyCode::KEY_DOT,
+        KeyCode::KEY_SLASH,
+    ];
+    left.into_iter()
+        .map(|key| (key, Hand::Left))
+        .chain(right.into_iter().map(|key| (key, Hand::Right)))
+        .collect()
+}
+
+/// A condition that causes every key held on the output device
+/// to be released, so that nothing is left logically stuck down.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ReleaseTrigger {
+    /// No input has been received from the device for the
+    /// specified duration
+    Idle(Duration),
+    /// A login session was locked, as reported by logind on D-Bus
+    SessionLock,
+}
+
+/// What to do with input that arrives while waiting for the
+/// virtual output device to settle after grabbing the input device
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SettleMode {
+    /// Process it once the settle time has elapsed
+    #[default]
+    Buffer,
+    /// Discard it
+    Drop,
+}
+
+/// A key, given by its KEY_XXX or BTN_XXX name, or by its numeric code
+#[derive(Debug, Deserialize)]
+#[serde(try_from = "toml::Value")]
+struct KeyCodeWrapper {
+    pub code: KeyCode,
+}
+
+impl From<KeyCodeWrapper> for KeyCode {
+    fn from(wrapper: KeyCodeWrapper) -> Self {
+        wrapper.code
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Invalid key `{0}`.  Use `evremap list-keys` to see possible keys.")]
+    InvalidKey(String),
+    #[error("Key code `{0}` has no name in the table of keys that evremap was built with, so it can't be used.  Use `evremap list-keys` to see possible keys.")]
+    UnknownKeyCode(i64),
+    #[error("Impossible: parsed KEY_XXX but not into an EV_KEY")]
+    ImpossibleParseKey,
+    #[error("Invalid relative axis `{0}`.  Use `evremap debug-events` to see the axes that your device reports.")]
+    InvalidRelAxis(String),
+    #[error("A remap needs an `input` or `input_char`.")]
+    MissingRemapInput,
+    #[error("A remap needs an `output`, or `disable = true` to swallow its input.")]
+    MissingRemapOutput,
+    #[error("A remap with `disable = true` can't have an `output`.")]
+    DisabledRemapWithOutput,
+    #[error("A remap with `trigger = \"release\"` must have a single `input` key.")]
+    ReleaseTriggerChord,
+    #[error("Invalid macro step `{0}`.  Steps look like `{{press KEY_A}}`, `{{tap KEY_A}}`, `{{release KEY_A}}` and `{{delay 50}}`.")]
+    InvalidMacroStep(String),
+    #[error("The output of a macro must be a list of keys and steps, or `{{ text = \"...\" }}`.")]
+    InvalidMacroOutput,
+    #[error("The rate_hz of a turbo entry must be at least 1.")]
+    InvalidTurboRate,
+    #[error("A command entry needs at least the name of the program in `command`.")]
+    EmptyCommand,
+    #[error("A command entry can have a `user` or a `uid`, but not both.")]
+    CommandUserAndUid,
+    #[error("Invalid LED `{0}`.  Use names like LED_SCROLLL and LED_CAPSL.")]
+    InvalidLed(String),
+    #[error("Invalid bustype `{0}`.  Use names like `usb`, `bluetooth` and `i8042`.")]
+    InvalidBusType(String),
+    #[error("Invalid switch `{0}`.  Use names like SW_TABLET_MODE and SW_LID.")]
+    InvalidSwitch(String),
+    #[error("`{0}` is not a single character.")]
+    NotAChar(String),
+    #[error("The keyboard layout has no key that types `{0}`.  Set xkb_layout and xkb_variant to the layout that you use.")]
+    CharNotOnLayout(String),
+    #[error("The XKB keymap couldn't be loaded, so characters can't be used in place of keys.")]
+    NoKeymap,
+    #[error("A remap can't have both `{0}` and `{0}_char`.")]
+    KeysAndChar(&'static str),
+    #[error("A remap can't have both `input` and `input_scan`.")]
+    KeysAndScan,
+    #[error("A remap with `input_scan` can't have `order`, `exact` or `trigger = \"release\"`.")]
+    ScanRemapOptions,
+    #[error("Invalid gamepad_layout `{0}`.  Possible layouts are `xbox` and `playstation`.")]
+    InvalidGamepadLayout(String),
+    #[error("`{0}` is not a button of the `{1}` gamepad layout, nor a KEY_XXX or BTN_XXX name.")]
+    InvalidGamepadAlias(String, String),
+    #[error("No layer is named `{0}`.")]
+    UnknownLayer(String),
+    #[error("There is more than one layer named `{0}`.")]
+    DuplicateLayer(String),
+    #[error("No profile is named `{0}`.")]
+    UnknownProfile(String),
+    #[error("There is more than one profile named `{0}`.")]
+    DuplicateProfile(String),
+}
+
+impl std::convert::TryFrom<String> for KeyCodeWrapper {
+    type Error = ConfigError;
+    fn try_from(s: String) -> Result<KeyCodeWrapper, Self::Error> {
+        match EventCode::from_str(&EventType::EV_KEY, &s) {
+            Some(code) => match code {
+                EventCode::EV_KEY(code) => Ok(KeyCodeWrapper { code }),
+                _ => Err(ConfigError::ImpossibleParseKey),
+            },
+            None => match parse_key_code(&s) {
+                Some(code) => KeyCodeWrapper::from_code(code),
+                None => Err(ConfigError::InvalidKey(s)),
+            },
+        }
+    }
+}
+
+impl std::convert::TryFrom<toml::Value> for KeyCodeWrapper {
+    type Error = ConfigError;
+    fn try_from(value: toml::Value) -> Result<KeyCodeWrapper, Self::Error> {
+        match value {
+            toml::Value::String(s) => KeyCodeWrapper::try_from(s),
+            toml::Value::Integer(code) => KeyCodeWrapper::from_code(code),
+            other => Err(ConfigError::InvalidKey(other.to_string())),
+        }
+    }
+}
+
+impl KeyCodeWrapper {
+    fn from_code(code: i64) -> Result<KeyCodeWrapper, ConfigError> {
+        u32::try_from(code)
+            .ok()
+            .and_then(evdev_rs::enums::int_to_ev_key)
+            .map(|code| KeyCodeWrapper { code })
+            .ok_or(ConfigError::UnknownKeyCode(code))
+    }
+}
+
+/// Parses a key that is given by its code, in decimal or as `0x` hex,
+/// in a string, such as the name of a table entry or a macro step
+fn parse_key_code(s: &str) -> Option<i64> {
+    match s.strip_prefix("0x") {
+        Some(hex) => i64::from_str_radix(hex, 16).ok(),
+        None if s.starts_with(|c: char| c.is_ascii_digit()) => s.parse().ok(),
+        None => None,
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(try_from = "String")]
+struct RelCodeWrapper {
+    pub code: RelCode,
+}
+
+impl From<RelCodeWrapper> for RelCode {
+    fn from(wrapper: RelCodeWrapper) -> Self {
+        wrapper.code
+    }
+}
+
+impl std::convert::TryFrom<String> for RelCodeWrapper {
+    type Error = ConfigError;
+    fn try_from(s: String) -> Result<RelCodeWrapper, Self::Error> {
+        match EventCode::from_str(&EventType::EV_REL, &s) {
+            Some(EventCode::EV_REL(code)) => Ok(RelCodeWrapper { code }),
+            _ => Err(ConfigError::InvalidRelAxis(s)),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(try_from = "String")]
+struct LedCodeWrapper {
+    pub code: LedCode,
+}
+
+impl From<LedCodeWrapper> for LedCode {
+    fn from(wrapper: LedCodeWrapper) -> Self {
+        wrapper.code
+    }
+}
+
+impl std::convert::TryFrom<String> for LedCodeWrapper {
+    type Error = ConfigError;
+    fn try_from(s: String) -> Result<LedCodeWrapper, Self::Error> {
+        match EventCode::from_str(&EventType::EV_LED, &s) {
+            Some(EventCode::EV_LED(code)) => Ok(LedCodeWrapper { code }),
+            _ => Err(ConfigError::InvalidLed(s)),
+        }
+    }
+}
+
+/// A bus type such as `usb` or `BUS_USB`
+#[derive(Debug, Deserialize)]
+#[serde(try_from = "String")]
+struct BusTypeWrapper {
+    pub bustype: BusType,
+}
+
+impl std::convert::TryFrom<String> for BusTypeWrapper {
+    type Error = ConfigError;
+    fn try_from(s: String) -> Result<BusTypeWrapper, Self::Error> {
+        let upper = s.to_ascii_uppercase();
+        let name = if upper.starts_with("BUS_") {
+            upper
+        } else {
+            format!("BUS_{upper}")
+        };
+        match name.parse() {
+            Ok(bustype) => Ok(BusTypeWrapper { bustype }),
+            Err(()) => Err(ConfigError::InvalidBusType(s)),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct DualRoleConfig {
+    input: KeyCodeWrapper,
+    hold: Vec<KeyCodeWrapper>,
+    tap: Vec<KeyCodeWrapper>,
+    #[serde(default)]
+    abort_hold_emits_tap: bool,
+    #[serde(default)]
+    hold_timeout_ms: Option<u64>,
+    #[serde(default = "default_hold_on_other_key_press")]
+    hold_on_other_key_press: bool,
+    #[serde(default)]
+    permissive_hold: bool,
+    #[serde(default)]
+    quick_tap_ms: Option<u64>,
+    #[serde(default)]
+    chordal_hold: bool,
+    #[serde(default)]
+    priority: i32,
+}
+
+fn default_hold_on_other_key_press() -> bool {
+    true
+}
+
+impl From<DualRoleConfig> for Mapping {
+    fn from(config: DualRoleConfig) -> Self {
+        Mapping::DualRole {
+            input: config.input.into(),
+            hold: config.hold.into_iter().map(Into::into).collect(),
+            tap: config.tap.into_iter().map(Into::into).collect(),
+            abort_hold_emits_tap: config.abort_hold_emits_tap,
+            hold_timeout: config.hold_timeout_ms.map(Duration::from_millis),
+            hold_on_other_key_press: config.hold_on_other_key_press,
+            permissive_hold: config.permissive_hold,
+            quick_tap: config.quick_tap_ms.map(Duration::from_millis),
+            chordal_hold: config.chordal_hold,
+        }
+    }
+}
+
+fn default_multi_function_timeout_ms() -> u64 {
+    200
+}
+
+#[derive(Debug, Deserialize)]
+struct MultiFunctionConfig {
+    input: KeyCodeWrapper,
+    #[serde(default)]
+    tap: Vec<KeyCodeWrapper>,
+    #[serde(default)]
+    hold: Vec<KeyCodeWrapper>,
+    #[serde(default)]
+    double_tap: Vec<KeyCodeWrapper>,
+    #[serde(default)]
+    hold_after_tap: Vec<KeyCodeWrapper>,
+    #[serde(default = "default_multi_function_timeout_ms")]
+    timeout_ms: u64,
+    #[serde(default)]
+    priority: i32,
+}
+
+impl From<MultiFunctionConfig> for Mapping {
+    fn from(config: MultiFunctionConfig) -> Self {
+        Mapping::MultiFunction {
+            input: config.input.into(),
+            tap: config.tap.into_iter().map(Into::into).collect(),
+            hold: config.hold.into_iter().map(Into::into).collect(),
+            double_tap: config.double_tap.into_iter().map(Into::into).collect(),
+            hold_after_tap: config.hold_after_tap.into_iter().map(Into::into).collect(),
+            timeout: Duration::from_millis(config.timeout_ms),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct RemapConfig {
+    #[serde(default)]
+    input: Vec<KeyCodeWrapper>,
+    #[serde(default)]
+    output: Option<Vec<KeyCodeWrapper>>,
+    /// A character of the keyboard layout, whose key and modifiers
+    /// are used as the `input`
+    #[serde(default)]
+    input_char: Option<String>,
+    /// The same for `output`
+    #[serde(default)]
+    output_char: Option<String>,
+    /// The scancode that the kernel reports in an MSC_SCAN event
+    /// along with the key, in place of `input`
+    #[serde(default)]
+    input_scan: Option<u32>,
+    /// Swallows the input keys, the same as an empty `output`
+    #[serde(default)]
+    disable: bool,
+    #[serde(default)]
+    order: Vec<KeyCodeWrapper>,
+    #[serde(default)]
+    exact: bool,
+    #[serde(default)]
+    trigger: RemapTrigger,
+    #[serde(default)]
+    priority: i32,
+}
+
+impl RemapConfig {
+    /// Replaces `input_char` and `output_char` with the keys
+    /// that type them on the keyboard layout
+    fn resolve_chars(&mut self, context: &SectionContext) -> Result<(), ConfigError> {
+        let keys = |c: &str| -> Result<Vec<KeyCodeWrapper>, ConfigError> {
+            let mut chars = c.chars();
+            let (Some(first), None) = (chars.next(), chars.next()) else {
+                return Err(ConfigError::NotAChar(c.to_string()));
+            };
+            let keymap = context.keymap().ok_or(ConfigError::NoKeymap)?;
+            let stroke = keymap
+                .find(first)
+                .ok_or_else(|| ConfigError::CharNotOnLayout(c.to_string()))?;
+            Ok(stroke
+                .modifiers
+                .into_iter()
+                .chain([stroke.key])
+                .map(|code| KeyCodeWrapper { code })
+                .collect())
+        };
+        if let Some(c) = self.input_char.take() {
+            if !self.input.is_empty() {
+                return Err(ConfigError::KeysAndChar("input"));
+            }
+            self.input = keys(&c)?;
+        }
+        if let Some(c) = self.output_char.take() {
+            if self.output.is_some() {
+                return Err(ConfigError::KeysAndChar("output"));
+            }
+            self.output = Some(keys(&c)?);
+        }
+        match (self.input.is_empty(), self.input_scan) {
+            (true, None) => return Err(ConfigError::MissingRemapInput),
+            (false, Some(_)) => return Err(ConfigError::KeysAndScan),
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// Whether a remap holds its output while its input is held,
+/// or taps it when its input is released
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum RemapTrigger {
+    #[default]
+    Press,
+    Release,
+}
+
+impl std::convert::TryFrom<RemapConfig> for Mapping {
+    type Error = ConfigError;
+    fn try_from(config: RemapConfig) -> Result<Self, Self::Error> {
+        let output = match (config.output, config.disable) {
+            (Some(output), false) => output,
+            (None, true) => vec![],
+            (Some(output), true) if output.is_empty() => output,
+            (Some(_), true) => return Err(ConfigError::DisabledRemapWithOutput),
+            (None, false) => return Err(ConfigError::MissingRemapOutput),
+        };
+        if let Some(scan) = config.input_scan {
+            if config.trigger == RemapTrigger::Release || config.exact || !config.order.is_empty() {
+                return Err(ConfigError::ScanRemapOptions);
+            }
+            return Ok(Mapping::ScanRemap {
+                scan,
+                output: output.into_iter().map(Into::into).collect(),
+            });
+        }
+        if config.trigger == RemapTrigger::Release {
+            // Nothing happens until the key is released, which is
+            // what a raw key does with only `release` keys
+            let Ok([input]) = <[KeyCodeWrapper; 1]>::try_from(config.input) else {
+                return Err(ConfigError::ReleaseTriggerChord);
+            };
+            return Ok(Mapping::RawKey {
+                input: input.into(),
+                press: vec![],
+                release: output.into_iter().map(Into::into).collect(),
+                repeat: vec![],
+            });
+        }
+        Ok(Mapping::Remap {
+            input: config.input.into_iter().map(Into::into).collect(),
+            output: output.into_iter().map(Into::into).collect(),
+            order: config.order.into_iter().map(Into::into).collect(),
+            exact: config.exact,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct SwapConfig {
+    keys: [KeyCodeWrapper; 2],
+    #[serde(default)]
+    priority: i32,
+}
+
+impl From<SwapConfig> for Mapping {
+    fn from(config: SwapConfig) -> Self {
+        let [a, b] = config.keys;
+        Mapping::Swap {
+            a: a.into(),
+            b: b.into(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "trigger", rename_all = "snake_case")]
+enum ReleaseTriggerConfig {
+    Idle { idle_ms: u64 },
+    SessionLock,
+}
+
+impl From<ReleaseTriggerConfig> for ReleaseTrigger {
+    fn from(config: ReleaseTriggerConfig) -> Self {
+        match config {
+            ReleaseTriggerConfig::Idle { idle_ms } => {
+                ReleaseTrigger::Idle(Duration::from_millis(idle_ms))
+            }
+            ReleaseTriggerConfig::SessionLock => ReleaseTrigger::SessionLock,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct AutoShiftConfig {
+    #[serde(default = "default_autoshift_timeout_ms")]
+    timeout_ms: u64,
+    #[serde(default)]
+    keys: Option<Vec<KeyCodeWrapper>>,
+}
+
+fn default_autoshift_timeout_ms() -> u64 {
+    175
+}
+
+impl From<AutoShiftConfig> for AutoShift {
+    fn from(config: AutoShiftConfig) -> Self {
+        AutoShift {
+            timeout: Duration::from_millis(config.timeout_ms),
+            keys: match config.keys {
+                Some(keys) => keys.into_iter().map(Into::into).collect(),
+                None => AutoShift::default_keys(),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct HandsConfig {
+    #[serde(default)]
+    left: Vec<KeyCodeWrapper>,
+    #[serde(default)]
+    right: Vec<KeyCodeWrapper>,
+}
+
+impl From<HandsConfig> for HashMap<KeyCode, Hand> {
+    fn from(config: HandsConfig) -> Self {
+        config
+            .left
+            .into_iter()
+            .map(|key| (key.into(), Hand::Left))
+            .chain(
+                config
+                    .right
+                    .into_iter()
+                    .map(|key| (key.into(), Hand::Right)),
+            )
+            .collect()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct EncoderAccelerationConfig {
+    interval_ms: u64,
+    max: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct EncoderConfig {
+    input: RelCodeWrapper,
+    #[serde(default)]
+    cw: Vec<KeyCodeWrapper>,
+    #[serde(default)]
+    ccw: Vec<KeyCodeWrapper>,
+    #[serde(default)]
+    acceleration: Option<EncoderAccelerationConfig>,
+}
+
+impl From<EncoderConfig> for Mapping {
+    fn from(config: EncoderConfig) -> Self {
+        Mapping::Encoder {
+            input: config.input.into(),
+            cw: config.cw.into_iter().map(Into::into).collect(),
+            ccw: config.ccw.into_iter().map(Into::into).collect(),
+            acceleration: config.acceleration.map(|accel| EncoderAcceleration {
+                interval: Duration::from_millis(accel.interval_ms),
+                max: accel.max.max(1),
+            }),
+        }
+    }
+}
+
+const DEFAULT_SEQUENCE_WINDOW_MS: u64 = 200;
+/// Leader sequences are typed deliberately rather than rolled, so
+/// they allow more time between keys
+const DEFAULT_LEADER_WINDOW_MS: u64 = 1000;
+
+#[derive(Debug, Deserialize)]
+struct SequenceConfig {
+    /// A key that must be pressed before `keys`
+    leader: Option<KeyCodeWrapper>,
+    keys: Vec<KeyCodeWrapper>,
+    output: Vec<KeyCodeWrapper>,
+    window_ms: Option<u64>,
+}
+
+impl From<SequenceConfig> for Mapping {
+    fn from(config: SequenceConfig) -> Self {
+        let default_window_ms = if config.leader.is_some() {
+            DEFAULT_LEADER_WINDOW_MS
+        } else {
+            DEFAULT_SEQUENCE_WINDOW_MS
+        };
+        Mapping::Sequence {
+            keys: config
+                .leader
+                .into_iter()
+                .chain(config.keys)
+                .map(Into::into)
+                .collect(),
+            output: config.output.into_iter().map(Into::into).collect(),
+            window: Duration::from_millis(config.window_ms.unwrap_or(default_window_ms)),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct RawKeyConfig {
+    input: KeyCodeWrapper,
+    #[serde(default)]
+    press: Vec<KeyCodeWrapper>,
+    #[serde(default)]
+    release: Vec<KeyCodeWrapper>,
+    #[serde(default)]
+    repeat: Vec<KeyCodeWrapper>,
+}
+
+impl From<RawKeyConfig> for Mapping {
+    fn from(config: RawKeyConfig) -> Self {
+        Mapping::RawKey {
+            input: config.input.into(),
+            press: config.press.into_iter().map(Into::into).collect(),
+            release: config.release.into_iter().map(Into::into).collect(),
+            repeat: config.repeat.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct SubstituteModifierConfig {
+    modifier: KeyCodeWrapper,
+    replacement: KeyCodeWrapper,
+    #[serde(default)]
+    except: Vec<KeyCodeWrapper>,
+}
+
+impl From<SubstituteModifierConfig> for Mapping {
+    fn from(config: SubstituteModifierConfig) -> Self {
+        Mapping::SubstituteModifier {
+            modifier: config.modifier.into(),
+            replacement: config.replacement.into(),
+            except: config.except.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ModLockConfig {
+    input: KeyCodeWrapper,
+    output: Vec<KeyCodeWrapper>,
+    #[serde(default)]
+    timeout_ms: Option<u64>,
+}
+
+impl From<ModLockConfig> for Mapping {
+    fn from(config: ModLockConfig) -> Self {
+        Mapping::ModLock {
+            input: config.input.into(),
+            output: config.output.into_iter().map(Into::into).collect(),
+            timeout: config.timeout_ms.map(Duration::from_millis),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct MacroConfig {
+    input: KeyCodeWrapper,
+    output: MacroOutputConfig,
+    #[serde(default)]
+    delay_ms: u64,
+}
+
+/// The `output` of a macro: a list of keys and steps,
+/// or `{ text = "..." }`
+#[derive(Debug, Deserialize)]
+#[serde(try_from = "toml::Value")]
+enum MacroOutputConfig {
+    Steps(Vec<MacroStepsWrapper>),
+    /// Text that is typed with the keys of the keyboard layout
+    Text(String),
+}
+
+impl std::convert::TryFrom<toml::Value> for MacroOutputConfig {
+    type Error = ConfigError;
+    fn try_from(value: toml::Value) -> Result<MacroOutputConfig, Self::Error> {
+        match value {
+            toml::Value::Array(entries) => entries
+                .into_iter()
+                .map(|entry| match entry {
+                    toml::Value::String(s) => MacroStepsWrapper::try_from(s),
+                    toml::Value::Integer(code) => Ok(MacroStepsWrapper {
+                        steps: vec![MacroStep::Tap(KeyCodeWrapper::from_code(code)?.into())],
+                    }),
+                    _ => Err(ConfigError::InvalidMacroOutput),
+                })
+                .collect::<Result<_, _>>()
+                .map(MacroOutputConfig::Steps),
+            toml::Value::Table(mut table) => match (table.remove("text"), table.is_empty()) {
+                (Some(toml::Value::String(text)), true) => Ok(MacroOutputConfig::Text(text)),
+                _ => Err(ConfigError::InvalidMacroOutput),
+            },
+            _ => Err(ConfigError::InvalidMacroOutput),
+        }
+    }
+}
+
+impl MacroConfig {
+    fn into_mapping(self, context: &SectionContext) -> Mapping {
+        let output = match self.output {
+            MacroOutputConfig::Steps(steps) => {
+                steps.into_iter().flat_map(|steps| steps.steps).collect()
+            }
+            MacroOutputConfig::Text(text) => xkb::type_text(&text, context.keymap()),
+        };
+        Mapping::Macro {
+            input: self.input.into(),
+            output,
+            delay: Duration::from_millis(self.delay_ms),
+        }
+    }
+}
+
+/// An entry of the `output` of a macro: either the name of a key to
+/// tap, or any number of steps like `{press KEY_LEFTCTRL}`, `{tap KEY_C}`,
+/// `{delay 50}` and `{release KEY_LEFTCTRL}`
+#[derive(Debug)]
+struct MacroStepsWrapper {
+    steps: Vec<MacroStep>,
+}
+
+impl std::convert::TryFrom<String> for MacroStepsWrapper {
+    type Error = ConfigError;
+    fn try_from(s: String) -> Result<MacroStepsWrapper, Self::Error> {
+        if !s.trim_start().starts_with('{') {
+            let key = KeyCodeWrapper::try_from(s)?;
+            return Ok(MacroStepsWrapper {
+                steps: vec![MacroStep::Tap(key.into())],
+            });
+        }
+        let mut steps = vec![];
+        let mut rest = s.trim();
+        while !rest.is_empty() {
+            let Some((step, after)) = rest.strip_prefix('{').and_then(|rest| rest.split_once('}'))
+            else {
+                return Err(ConfigError::InvalidMacroStep(rest.to_string()));
+            };
+            let invalid = || ConfigError::InvalidMacroStep(format!("{{{step}}}"));
+            let (verb, arg) = step.trim().split_once(' ').ok_or_else(invalid)?;
+            let arg = arg.trim();
+            steps.push(match verb {
+                "tap" => MacroStep::Tap(KeyCodeWrapper::try_from(arg.to_string())?.into()),
+                "press" => MacroStep::Press(KeyCodeWrapper::try_from(arg.to_string())?.into()),
+                "release" => MacroStep::Release(KeyCodeWrapper::try_from(arg.to_string())?.into()),
+                "delay" => {
+                    MacroStep::Delay(Duration::from_millis(arg.parse().map_err(|_| invalid())?))
+                }
+                _ => return Err(invalid()),
+            });
+            rest = after.trim_start();
+        }
+        Ok(MacroStepsWrapper { steps })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct MouseKeyConfig {
+    input: KeyCodeWrapper,
+    direction: MouseKeyDirection,
+    #[serde(default)]
+    initial_speed: Option<u32>,
+    #[serde(default)]
+    max_speed: Option<u32>,
+    #[serde(default = "default_mouse_key_ramp_ms")]
+    ramp_ms: u64,
+}
+
+fn default_mouse_key_ramp_ms() -> u64 {
+    1000
+}
+
+impl From<MouseKeyConfig> for Mapping {
+    fn from(config: MouseKeyConfig) -> Self {
+        // Pointer speeds are in pixels per second, but wheel
+        // speeds are in clicks per second
+        let (initial_speed, max_speed) = if config.direction.is_wheel() {
+            (5, 20)
+        } else {
+            (100, 1200)
+        };
+        let initial_speed = config.initial_speed.unwrap_or(initial_speed);
+        Mapping::MouseKey {
+            input: config.input.into(),
+            direction: config.direction,
+            initial_speed,
+            max_speed: config.max_speed.unwrap_or(max_speed).max(initial_speed),
+            ramp: Duration::from_millis(config.ramp_ms),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct OneShotConfig {
+    input: KeyCodeWrapper,
+    output: Vec<KeyCodeWrapper>,
+}
+
+impl From<OneShotConfig> for Mapping {
+    fn from(config: OneShotConfig) -> Self {
+        Mapping::OneShot {
+            input: config.input.into(),
+            output: config.output.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct CapsWordConfig {
+    input: KeyCodeWrapper,
+}
+
+impl From<CapsWordConfig> for Mapping {
+    fn from(config: CapsWordConfig) -> Self {
+        Mapping::CapsWord {
+            input: config.input.into(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct TapDanceConfig {
+    input: KeyCodeWrapper,
+    taps: Vec<Vec<KeyCodeWrapper>>,
+    #[serde(default = "default_tap_dance_timeout_ms")]
+    timeout_ms: u64,
+}
+
+fn default_tap_dance_timeout_ms() -> u64 {
+    200
+}
+
+#[derive(Debug, Deserialize)]
+struct TurboConfig {
+    input: KeyCodeWrapper,
+    output: Vec<KeyCodeWrapper>,
+    #[serde(default = "default_turbo_rate_hz")]
+    rate_hz: u32,
+}
+
+fn default_grab() -> bool {
+    true
+}
+
+fn default_watch_config() -> bool {
+    true
+}
+
+fn default_turbo_rate_hz() -> u32 {
+    20
+}
+
+impl From<TurboConfig> for Mapping {
+    fn from(config: TurboConfig) -> Self {
+        Mapping::Turbo {
+            input: config.input.into(),
+            output: config.output.into_iter().map(Into::into).collect(),
+            interval: Duration::from_secs(1) / config.rate_hz,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct CommandConfig {
+    input: KeyCodeWrapper,
+    command: Vec<String>,
+    #[serde(default)]
+    release_command: Vec<String>,
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+    #[serde(default)]
+    user: Option<String>,
+    #[serde(default)]
+    uid: Option<u32>,
+    #[serde(default)]
+    min_hold_ms: Option<u64>,
+}
+
+impl std::convert::TryFrom<CommandConfig> for Mapping {
+    type Error = ConfigError;
+    fn try_from(config: CommandConfig) -> Result<Self, Self::Error> {
+        if config.command.is_empty() {
+            return Err(ConfigError::EmptyCommand);
+        }
+        let user = match (config.user, config.uid) {
+            (Some(name), None) => Some(CommandUser::Name(name)),
+            (None, Some(uid)) => Some(CommandUser::Uid(uid)),
+            (None, None) => None,
+            (Some(_), Some(_)) => return Err(ConfigError::CommandUserAndUid),
+        };
+        let release = (!config.release_command.is_empty()).then(|| CommandSpec {
+            argv: config.release_command,
+            env: config.env.clone(),
+            user: user.clone(),
+        });
+        Ok(Mapping::Command {
+            input: config.input.into(),
+            command: CommandSpec {
+                argv: config.command,
+                env: config.env,
+                user,
+            },
+            min_hold: config.min_hold_ms.map(Duration::from_millis),
+            release,
+        })
+    }
+}
+
+impl From<TapDanceConfig> for Mapping {
+    fn from(config: TapDanceConfig) -> Self {
+        Mapping::TapDance {
+            input: config.input.into(),
+            taps: config
+                .taps
+                .into_iter()
+                .map(|keys| keys.into_iter().map(Into::into).collect())
+                .collect(),
+            timeout: Duration::from_millis(config.timeout_ms),
+        }
+    }
+}
+
+/// Friendly names for gamepad buttons that are shared by all layouts
+const GAMEPAD_COMMON_ALIASES: &[(&str, &str)] = &[
+    ("DPAD_UP", "BTN_DPAD_UP"),
+    ("DPAD_DOWN", "BTN_DPAD_DOWN"),
+    ("DPAD_LEFT", "BTN_DPAD_LEFT"),
+    ("DPAD_RIGHT", "BTN_DPAD_RIGHT"),
+];
+
+const GAMEPAD_XBOX_ALIASES: &[(&str, &str)] = &[
+    ("A", "BTN_SOUTH"),
+    ("B", "BTN_EAST"),
+    ("X", "BTN_WEST"),
+    ("Y", "BTN_NORTH"),
+    ("LB", "BTN_TL"),
+    ("RB", "BTN_TR"),
+    ("LT", "BTN_TL2"),
+    ("RT", "BTN_TR2"),
+    ("LS", "BTN_THUMBL"),
+    ("RS", "BTN_THUMBR"),
+    ("BACK", "BTN_SELECT"),
+    ("VIEW", "BTN_SELECT"),
+    ("START", "BTN_START"),
+    ("MENU", "BTN_START"),
+    ("GUIDE", "BTN_MODE"),
+];
+
+const GAMEPAD_PLAYSTATION_ALIASES: &[(&str, &str)] = &[
+    ("CROSS", "BTN_SOUTH"),
+    ("CIRCLE", "BTN_EAST"),
+    ("SQUARE", "BTN_WEST"),
+    ("TRIANGLE", "BTN_NORTH"),
+    ("L1", "BTN_TL"),
+    ("R1", "BTN_TR"),
+    ("L2", "BTN_TL2"),
+    ("R2", "BTN_TR2"),
+    ("L3", "BTN_THUMBL"),
+    ("R3", "BTN_THUMBR"),
+    ("SELECT", "BTN_SELECT"),
+    ("SHARE", "BTN_SELECT"),
+    ("CREATE", "BTN_SELECT"),
+    ("START", "BTN_START"),
+    ("OPTIONS", "BTN_START"),
+    ("PS", "BTN_MODE"),
+];
+
+/// The fields of the mapping entries whose values are key names
+const KEY_FIELDS: &[&str] = &[
+    "input",
+    "output",
+    "order",
+    "hold",
+    "tap",
+    "double_tap",
+    "hold_after_tap",
+    "cw",
+    "ccw",
+    "keys",
+    "press",
+    "release",
+    "repeat",
+    "modifier",
+    "replacement",
+    "except",
+    "taps",
+    "leader",
+];
+
+/// Adds the keys in `value` that are given by their codes to `keys`.
+/// `is_key` is whether `value` is that of a field whose values are keys.
+fn collect_numeric_keys(value: &toml::Value, is_key: bool, keys: &mut BTreeMap<i64, KeyCode>) {
+    match value {
+        toml::Value::Integer(code) if is_key => {
+            if let Ok(key) = KeyCodeWrapper::from_code(*code) {
+                keys.insert(*code, key.into());
+            }
+        }
+        toml::Value::String(s) if is_key => collect_numeric_keys_in_str(s, keys),
+        toml::Value::Array(values) => {
+            for value in values {
+                collect_numeric_keys(value, is_key, keys);
+            }
+        }
+        toml::Value::Table(table) => {
+            for (name, value) in table {
+                match (name.as_str(), value) {
+                    // Tables whose entries are named by keys
+                    (
+                        "keymap" | "modifier_rewrite" | "debounce_keys",
+                        toml::Value::Table(entries),
+                    ) => {
+                        for (key, value) in entries {
+                            collect_numeric_keys_in_str(key, keys);
+                            collect_numeric_keys(value, name != "debounce_keys", keys);
+                        }
+                    }
+                    ("extra_output_keys" | "left" | "right", _) => {
+                        collect_numeric_keys(value, true, keys)
+                    }
+                    (name, _) => collect_numeric_keys(value, KEY_FIELDS.contains(&name), keys),
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Adds the key that `s` gives by its code to `keys`, or those
+/// of the steps of a macro, such as `{tap 276}`
+fn collect_numeric_keys_in_str(s: &str, keys: &mut BTreeMap<i64, KeyCode>) {
+    for step in s.split('}') {
+        let step = step.trim().trim_start_matches('{');
+        let arg = match step.split_once(' ') {
+            Some(("delay", _)) => continue,
+            Some((_, arg)) => arg.trim(),
+            None => step,
+        };
+        if let Some(code) = parse_key_code(arg) {
+            if let Ok(key) = KeyCodeWrapper::from_code(code) {
+                keys.insert(code, key.into());
+            }
+        }
+    }
+}
+
+/// Rewrites any gamepad button aliases, such as `A` or `Cross`, that are
+/// used in place of key names in `value` into their BTN_XXX names,
+/// according to the `gamepad_layout` that it specifies
+fn resolve_gamepad_aliases(value: &mut toml::Value) -> Result<(), ConfigError> {
+    let layout = match value.get("gamepad_layout") {
+        Some(toml::Value::String(layout)) => layout.clone(),
+        Some(other) => return Err(ConfigError::InvalidGamepadLayout(other.to_string())),
+        None => return Ok(()),
+    };
+    let aliases = match layout.as_str() {
+        "xbox" => GAMEPAD_XBOX_ALIASES,
+        "playstation" => GAMEPAD_PLAYSTATION_ALIASES,
+        _ => return Err(ConfigError::InvalidGamepadLayout(layout)),
+    };
+    let resolve = |name: &str| -> Result<String, ConfigError> {
+        // Macro steps such as `{tap KEY_A}` name their keys in full,
+        // and keys may be given by their codes
+        if name.starts_with("KEY_")
+            || name.starts_with("BTN_")
+            || name.starts_with('{')
+            || parse_key_code(name).is_some()
+        {
+            return Ok(name.to_string());
+        }
+        aliases
+            .iter()
+            .chain(GAMEPAD_COMMON_ALIASES)
+            .find(|(alias, _)| alias.eq_ignore_ascii_case(name))
+            .map(|(_, btn)| btn.to_string())
+            .ok_or_else(|| ConfigError::InvalidGamepadAlias(name.to_string(), layout.clone()))
+    };
+    let resolve_value = |value: &mut toml::Value| -> Result<(), ConfigError> {
+        match value {
+            toml::Value::String(name) => *name = resolve(name)?,
+            toml::Value::Array(names) => {
+                for name in names {
+                    match name {
+                        toml::Value::String(name) => *name = resolve(name)?,
+                        // The lists of keys in a list, such as tap_dance taps
+                        toml::Value::Array(names) => {
+                            for name in names {
+                                if let toml::Value::String(name) = name {
+                                    *name = resolve(name)?;
+                                }
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    };
+
+    let Some(table) = value.as_table_mut() else {
+        return Ok(());
+    };
+    resolve_gamepad_aliases_in_sections(table, &resolve, &resolve_value)?;
+    for nested in ["layer", "profile", "device"] {
+        if let Some(toml::Value::Array(entries)) = table.get_mut(nested) {
+            for entry in entries {
+                if let Some(entry) = entry.as_table_mut() {
+                    resolve_gamepad_aliases_in_sections(entry, &resolve, &resolve_value)?;
+                }
+            }
+        }
+    }
+    if let Some(value) = table.get_mut("extra_output_keys") {
+        resolve_value(value)?;
+    }
+    Ok(())
+}
+
+/// Rewrites the gamepad button aliases in the mapping sections
+/// of either the top level of the config file, or of a layer or device
+fn resolve_gamepad_aliases_in_sections(
+    table: &mut toml::Table,
+    resolve: &dyn Fn(&str) -> Result<String, ConfigError>,
+    resolve_value: &dyn Fn(&mut toml::Value) -> Result<(), ConfigError>,
+) -> Result<(), ConfigError> {
+    for section in [
+        "dual_role",
+        "multi_function",
+        "remap",
+        "swap",
+        "encoder",
+        "sequence",
+        "raw_key",
+        "substitute_modifier",
+        "mod_lock",
+        "toggle",
+        "macro",
+        "mouse_key",
+        "one_shot",
+        "caps_word",
+        "tap_dance",
+        "turbo",
+        "command",
+        "layer_key",
+        "profile_key",
+    ] {
+        let Some(toml::Value::Array(entries)) = table.get_mut(section) else {
+            continue;
+        };
+        for entry in entries {
+            let Some(entry) = entry.as_table_mut() else {
+                continue;
+            };
+            for (field, value) in entry.iter_mut() {
+                // The input of an encoder is a REL_XXX axis
+                if KEY_FIELDS.contains(&field.as_str())
+                    && !(section == "encoder" && field == "input")
+                {
+                    resolve_value(value)?;
+                }
+            }
+        }
+    }
+    for section in ["keymap", "modifier_rewrite", "debounce_keys"] {
+        let Some(toml::Value::Table(entries)) = table.get_mut(section) else {
+            continue;
+        };
+        let mut resolved = toml::Table::new();
+        for (name, mut value) in std::mem::take(entries) {
+            resolve_value(&mut value)?;
+            resolved.insert(resolve(&name)?, value);
+        }
+        *entries = resolved;
+    }
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+struct ConfigFile {
+    #[serde(default)]
+    device_name: Option<String>,
+
+    #[serde(default)]
+    path: Option<String>,
+
+    #[serde(default)]
+    sysfs_path: Option<String>,
+
+    #[serde(default)]
+    phys: Option<String>,
+
+    #[serde(default)]
+    dual_role: Vec<DualRoleConfig>,
+
+    #[serde(default)]
+    remap: Vec<RemapConfig>,
+    #[serde(default)]
+    swap: Vec<SwapConfig>,
+
+    #[serde(default)]
+    keymap: BTreeMap<String, KeyCodeWrapper>,
+
+    #[serde(default)]
+    multi_function: Vec<MultiFunctionConfig>,
+
+    #[serde(default)]
+    encoder: Vec<EncoderConfig>,
+
+    #[serde(default)]
+    sequence: Vec<SequenceConfig>,
+
+    #[serde(default)]
+    raw_key: Vec<RawKeyConfig>,
+
+    #[serde(default)]
+    substitute_modifier: Vec<SubstituteModifierConfig>,
+
+    #[serde(default)]
+    mod_lock: Vec<ModLockConfig>,
+    #[serde(default)]
+    toggle: Vec<ModLockConfig>,
+    #[serde(default, rename = "macro")]
+    macros: Vec<MacroConfig>,
+    #[serde(default)]
+    mouse_key: Vec<MouseKeyConfig>,
+    #[serde(default)]
+    one_shot: Vec<OneShotConfig>,
+    #[serde(default)]
+    caps_word: Vec<CapsWordConfig>,
+    #[serde(default)]
+    tap_dance: Vec<TapDanceConfig>,
+    #[serde(default)]
+    turbo: Vec<TurboConfig>,
+    #[serde(default)]
+    command: Vec<CommandConfig>,
+
+    #[serde(default)]
+    layer_key: Vec<LayerKeyConfig>,
+
+    #[serde(default)]
+    layer: Vec<LayerConfig>,
+
+    #[serde(default)]
+    profile_key: Vec<ProfileKeyConfig>,
+
+    #[serde(default)]
+    profile: Vec<ProfileConfig>,
+
+    #[serde(default)]
+    default_profile: Option<String>,
+
+    #[serde(default)]
+    device: Vec<DeviceConfig>,
+
+    #[serde(default)]
+    release_all: Vec<ReleaseTriggerConfig>,
+
+    #[serde(default)]
+    keys_only: bool,
+
+    #[serde(default)]
+    xkb_layout: Option<String>,
+    #[serde(default)]
+    xkb_variant: Option<String>,
+
+    #[serde(default)]
+    sanitize: bool,
+
+    #[serde(default = "default_grab")]
+    grab: bool,
+
+    #[serde(default)]
+    keepalive_interval_ms: Option<u64>,
+
+    #[serde(default = "default_watch_config")]
+    watch_config: bool,
+
+    #[serde(default)]
+    reload_scope: ReloadScope,
+
+    #[serde(default)]
+    post_grab_settle_ms: u64,
+
+    #[serde(default)]
+    post_grab_settle_mode: SettleMode,
+
+    #[serde(default)]
+    output_device: Option<OutputDeviceConfig>,
+
+    #[serde(default)]
+    modifier_rewrite: BTreeMap<String, KeyCodeWrapper>,
+
+    #[serde(default)]
+    max_drag_ms: Option<u64>,
+
+    #[serde(default)]
+    max_hold_ms: Option<u64>,
+
+    #[serde(default)]
+    extra_output_keys: Vec<KeyCodeWrapper>,
+
+    #[serde(default)]
+    periodic_syn_ms: Option<u64>,
+
+    #[serde(default)]
+    low_latency_passthrough: bool,
+
+    #[serde(default)]
+    suppress_modifier_pulses: bool,
+
+    #[serde(default)]
+    modifier_settle_ms: Option<u64>,
+
+    #[serde(default)]
+    focus_provider: Option<FocusProviderKind>,
+
+    #[serde(default)]
+    autoshift: Option<AutoShiftConfig>,
+
+    #[serde(default)]
+    hands: Option<HandsConfig>,
+
+    #[serde(default)]
+    debounce_ms: Option<u64>,
+
+    #[serde(default)]
+    debounce_keys: BTreeMap<String, u64>,
 }
@@ -0,0 +1,159 @@
+use crate::mapping::CommandUser;
+use anyhow::{bail, Context, Result};
+use std::ffi::{CStr, CString};
+
+/// A user account, as found in the password and group databases
+pub(crate) struct Account {
+    pub name: String,
+    pub uid: libc::uid_t,
+    pub gid: libc::gid_t,
+    pub home: String,
+    /// The supplementary groups of the user
+    pub groups: Vec<libc::gid_t>,
+}
+
+/// Switches the process to `user` and/or `group`, each of which is a
+/// name or a number, so that the devices can be opened as root without
+/// remaining root for as long as they are remapped.  With a user, the
+/// process gets the groups of that user, and their primary group
+/// unless `group` says otherwise.
+///
+/// Devices that are plugged in later are opened as that user, so they
+/// must be readable by it, such as by being in the `input` group.
+pub fn drop_privileges(user: Option<&str>, group: Option<&str>) -> Result<()> {
+    let group = group.map(lookup_group).transpose()?;
+    let (uid, gid, groups) = match user {
+        Some(user) => {
+            let user = match user.parse() {
+                Ok(uid) => CommandUser::Uid(uid),
+                Err(_) => CommandUser::Name(user.to_string()),
+            };
+            let account = lookup_account(&user)?;
+            let gid = group.unwrap_or(account.gid);
+            let mut groups = account.groups;
+            if !groups.contains(&gid) {
+                groups.push(gid);
+            }
+            (Some(account.uid), gid, groups)
+        }
+        None => match group {
+            Some(gid) => (None, gid, vec![gid]),
+            None => return Ok(()),
+        },
+    };
+    unsafe {
+        if libc::setgroups(groups.len(), groups.as_ptr()) != 0 {
+            return Err(std::io::Error::last_os_error()).context("setgroups");
+        }
+        if libc::setgid(gid) != 0 {
+            return Err(std::io::Error::last_os_error()).context(format!("setgid({gid})"));
+        }
+        if let Some(uid) = uid {
+            if libc::setuid(uid) != 0 {
+                return Err(std::io::Error::last_os_error()).context(format!("setuid({uid})"));
+            }
+            // Make sure that there is no way back
+            if uid != 0 && libc::setuid(0) == 0 {
+                bail!("still able to become root after setuid({uid})");
+            }
+        }
+    }
+    log::info!(
+        "Now running as uid {} gid {}",
+        unsafe { libc::getuid() },
+        unsafe { libc::getgid() }
+    );
+    Ok(())
+}
+
+/// Returns the gid of `group`, which is a name or a number
+fn lookup_group(group: &str) -> Result<libc::gid_t> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+    let name = CString::new(group)?;
+    let mut entry: libc::group = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 16384];
+    let mut result = std::ptr::null_mut();
+    let status = unsafe {
+        libc::getgrnam_r(
+            name.as_ptr(),
+            &mut entry,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    if status != 0 {
+        return Err(std::io::Error::from_raw_os_error(status))
+            .context(format!("looking up the group `{group}`"));
+    }
+    if result.is_null() {
+        bail!("there is no group `{group}`");
+    }
+    Ok(entry.gr_gid)
+}
+
+/// Looks up the user in the password and group databases
+pub(crate) fn lookup_account(user: &CommandUser) -> Result<Account> {
+    let who = match user {
+        CommandUser::Name(name) => format!("`{name}`"),
+        CommandUser::Uid(uid) => format!("with uid {uid}"),
+    };
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 16384];
+    let mut result = std::ptr::null_mut();
+    let status = match user {
+        CommandUser::Name(name) => {
+            let name = CString::new(name.as_str())?;
+            unsafe {
+                libc::getpwnam_r(
+                    name.as_ptr(),
+                    &mut passwd,
+                    buf.as_mut_ptr(),
+                    buf.len(),
+                    &mut result,
+                )
+            }
+        }
+        CommandUser::Uid(uid) => unsafe {
+            libc::getpwuid_r(*uid, &mut passwd, buf.as_mut_ptr(), buf.len(), &mut result)
+        },
+    };
+    if status != 0 {
+        return Err(std::io::Error::from_raw_os_error(status))
+            .context(format!("looking up the user {who}"));
+    }
+    if result.is_null() {
+        bail!("there is no user {who}");
+    }
+    let name = unsafe { CStr::from_ptr(passwd.pw_name) };
+    let home = unsafe { CStr::from_ptr(passwd.pw_dir) };
+
+    let mut groups = vec![0 as libc::gid_t; 64];
+    loop {
+        let mut count = groups.len() as libc::c_int;
+        let found = unsafe {
+            libc::getgrouplist(
+                name.as_ptr(),
+                passwd.pw_gid,
+                groups.as_mut_ptr(),
+                &mut count,
+            )
+        };
+        // When there are too many, count says how many there are
+        if found >= 0 {
+            groups.truncate(count as usize);
+            break;
+        }
+        groups.resize(count.max(groups.len() as libc::c_int * 2) as usize, 0);
+    }
+
+    Ok(Account {
+        name: name.to_string_lossy().into_owned(),
+        uid: passwd.pw_uid,
+        gid: passwd.pw_gid,
+        home: home.to_string_lossy().into_owned(),
+        groups,
+    })
+}
@@ -1,9 +1,22 @@
+use crate::control::{ControlCommand, ControlSource};
+use crate::deviceinfo::{DeviceSelector, DeviceWatcher};
+use crate::focus::{focus_provider, FocusWatcher};
+use crate::inotify::Inotify;
 use crate::mapping::*;
+use crate::session::SessionLockWatcher;
+use crate::spawn;
+use crate::systemd::Notifier;
 use anyhow::*;
-use evdev_rs::{Device, DeviceWrapper, GrabMode, InputEvent, ReadFlag, TimeVal, UInputDevice};
+use evdev_rs::{
+    Device, DeviceWrapper, GrabMode, InputEvent, LedState, ReadFlag, TimeVal, UInputDevice,
+};
 use std::cmp::Ordering;
-use std::collections::{HashMap, HashSet};
-use std::path::Path;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
 use std::time::Duration;
 
 #[derive(Clone, Copy, Debug)]
@@ -36,6 +49,9 @@ impl KeyEventType {
 
 fn timeval_diff(newer: &TimeVal, older: &TimeVal) -> Duration {
     const MICROS_PER_SECOND: libc::time_t = 1000000;
+    if newer <= older {
+        return Duration::ZERO;
+    }
     let secs = newer.tv_sec - older.tv_sec;
     let usecs = newer.tv_usec - older.tv_usec;
 
@@ -48,298 +64,4546 @@ fn timeval_diff(newer: &TimeVal, older: &TimeVal) -> Duration {
     Duration::from_micros(((secs * MICROS_PER_SECOND) + usecs) as u64)
 }
 
+fn timeval_add(time: &TimeVal, duration: Duration) -> TimeVal {
+    TimeVal::new(
+        time.tv_sec + duration.as_secs() as libc::time_t,
+        time.tv_usec + duration.subsec_micros() as libc::suseconds_t,
+    )
+}
+
+/// Returns the current time, using the same clock as the timestamps
+/// of the events read from the input device; see `create_mapper`.
+fn now() -> TimeVal {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe {
+        libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts);
+    }
+    TimeVal::new(ts.tv_sec, ts.tv_nsec / 1000)
+}
+
+/// Wait for any of `fds` to become readable, or for the timeout to
+/// elapse.  Returns whether each of them is readable; negative fds
+/// are ignored.
+fn wait_for_fds(fds: &[RawFd], timeout: Option<Duration>) -> Result<Vec<bool>> {
+    let mut pfds: Vec<libc::pollfd> = fds
+        .iter()
+        .map(|fd| libc::pollfd {
+            fd: *fd,
+            events: libc::POLLIN,
+            revents: 0,
+        })
+        .collect();
+    // Round up so that we don't wake up just before a deadline
+    let timeout_ms = match timeout {
+        Some(timeout) => timeout.as_micros().div_ceil(1000).min(i32::MAX as u128) as i32,
+        None => -1,
+    };
+    let res = unsafe { libc::poll(pfds.as_mut_ptr(), pfds.len() as libc::nfds_t, timeout_ms) };
+    if res < 0 {
+        let err = std::io::Error::last_os_error();
+        if err.kind() != std::io::ErrorKind::Interrupted {
+            return Err(err).context("waiting for input");
+        }
+    }
+    Ok(pfds.iter().map(|pfd| res > 0 && pfd.revents != 0).collect())
+}
+
+/// Set by the SIGUSR2 handler to request a dump of the state
+static DUMP_STATE: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_state_dump(_signal: libc::c_int) {
+    DUMP_STATE.store(true, AtomicOrdering::SeqCst);
+}
+
+/// Set by the SIGHUP handler to request that the config file be reloaded
+static RELOAD_CONFIG: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_reload(_signal: libc::c_int) {
+    RELOAD_CONFIG.store(true, AtomicOrdering::SeqCst);
+}
+
+/// Set by the SIGUSR1 handler to request a switch to the next profile
+static NEXT_PROFILE: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_next_profile(_signal: libc::c_int) {
+    NEXT_PROFILE.store(true, AtomicOrdering::SeqCst);
+}
+
+/// Set by the SIGTERM and SIGINT handlers to request a clean exit
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_shutdown(_signal: libc::c_int) {
+    SHUTDOWN.store(true, AtomicOrdering::SeqCst);
+}
+
+/// Arrange for `signal` to call `handler`.  The handler is installed
+/// without SA_RESTART so that it interrupts the poll in wait_for_input,
+/// and the handlers only set a flag that is acted upon in the main loop.
+fn install_signal_handler(
+    signal: libc::c_int,
+    handler: extern "C" fn(libc::c_int),
+    name: &str,
+) -> Result<()> {
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handler as *const () as libc::sighandler_t;
+        libc::sigemptyset(&mut action.sa_mask);
+        if libc::sigaction(signal, &action, std::ptr::null_mut()) != 0 {
+            return Err(std::io::Error::last_os_error())
+                .context(format!("installing {name} handler"));
+        }
+    }
+    Ok(())
+}
+
+/// Starts following which application is focused, if any of the
+/// layers in `config` depend on it
+fn start_focus_watcher(config: &MappingConfig) -> Option<FocusWatcher> {
+    if config.layers.iter().all(|layer| {
+        layer
+            .only_when
+            .as_ref()
+            .is_none_or(|condition| condition.apps.is_empty())
+    }) {
+        return None;
+    }
+    match focus_provider(config.focus_provider).and_then(FocusWatcher::spawn) {
+        std::result::Result::Ok(watcher) => Some(watcher),
+        Err(err) => {
+            log::warn!(
+                "Unable to find out which application is focused, so layers \
+                 with an only_when condition will have no effect: {err:#}"
+            );
+            None
+        }
+    }
+}
+
+/// Starts watching for a login session to be locked, if that is one
+/// of the `release_all` triggers in `config`
+fn start_lock_watcher(config: &MappingConfig) -> Option<SessionLockWatcher> {
+    if !config
+        .release_triggers
+        .contains(&ReleaseTrigger::SessionLock)
+    {
+        return None;
+    }
+    match SessionLockWatcher::spawn() {
+        std::result::Result::Ok(watcher) => Some(watcher),
+        Err(err) => {
+            log::warn!(
+                "Unable to watch for the session to be locked, so the \
+                 session_lock trigger will have no effect: {err:#}"
+            );
+            None
+        }
+    }
+}
+
+/// Watches for changes to the config file.  The directory containing
+/// it is watched, rather than the file itself, because many editors
+/// save a file by replacing it.
+struct ConfigWatcher {
+    inotify: Inotify,
+    file_name: Vec<u8>,
+}
+
+impl ConfigWatcher {
+    fn new(config_file: &Path) -> Result<Self> {
+        let file_name = config_file
+            .file_name()
+            .ok_or_else(|| anyhow!("{} is not a file", config_file.display()))?;
+        let dir = match config_file.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let inotify = Inotify::new()?;
+        inotify.add_watch(dir, libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO)?;
+        Ok(Self {
+            inotify,
+            file_name: file_name.as_encoded_bytes().to_vec(),
+        })
+    }
+
+    /// Consumes the pending notifications, and returns true if
+    /// any of them were for the config file
+    fn changed(&self) -> bool {
+        self.inotify.read_names().contains(&self.file_name)
+    }
+}
+
+/// Formats a set of keys as a JSON array, sorted so that
+/// successive dumps can be compared
+fn json_key_list<'a>(keys: impl Iterator<Item = &'a KeyCode>) -> String {
+    let mut names: Vec<String> = keys.map(|key| format!("\"{key:?}\"")).collect();
+    names.sort();
+    format!("[{}]", names.join(","))
+}
+
+/// Tracks the progress of a `Mapping::MultiFunction` key
+#[derive(Clone, Copy, Debug)]
+enum MultiFunctionState {
+    /// The key is down and we haven't decided what it does yet.
+    /// `second` is true if this is the second press of a double tap.
+    Pressed { since: TimeVal, second: bool },
+    /// The key was tapped once and we are waiting to see whether
+    /// it will be pressed again
+    Released { since: TimeVal },
+    /// The key has been resolved as being held
+    Holding { after_tap: bool },
+}
+
+/// Tracks the progress of a `Mapping::OneShot` key
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum OneShotState {
+    /// The key is held.  `used` is true if another key
+    /// was pressed in the meantime.
+    Held { used: bool },
+    /// The key was tapped, and applies to the next key that is pressed
+    Armed,
+    /// The key applies to this key until it is released
+    Applied(KeyCode),
+}
+
+/// Tracks a `Mapping::TapDance` key that has been pressed, but
+/// whose number of taps isn't known yet
+struct TapDanceState {
+    taps: Vec<Vec<KeyCode>>,
+    timeout: Duration,
+    /// The number of presses so far
+    count: usize,
+    /// When the key was last released, or None if it is held
+    released: Option<TimeVal>,
+}
+
+/// Tracks a `Mapping::Turbo` key that is held
+struct TurboState {
+    /// Whether its output is pressed at the moment
+    pressed: bool,
+    /// How long its output is pressed, and then released, for
+    half_interval: Duration,
+    /// When its output is next pressed or released
+    next: TimeVal,
+}
+
+/// Tracks a `Mapping::MouseKey` key that is held
+struct MouseKeyState {
+    axis: RelCode,
+    sign: i32,
+    initial_speed: f64,
+    max_speed: f64,
+    ramp: Duration,
+    since: TimeVal,
+    /// The fraction of a unit of movement that is still owed
+    remainder: f64,
+}
+
+/// Tracks a `Mapping::Sequence` that has been started, but which
+/// is neither complete nor broken yet
+struct PendingSequence {
+    /// The keys of the sequence that have been pressed so far
+    pressed: Vec<KeyCode>,
+    /// The events for those keys, which are held back until we know
+    /// whether the sequence will be completed
+    held_back: Vec<InputEvent>,
+    /// The sequence is broken if its next key isn't pressed by this time
+    deadline: TimeVal,
+}
+
+/// Tracks a `Mapping::DualRole` key that has been pressed, but for
+/// which it isn't yet decided whether it is tapped or held
+struct PendingDualRole {
+    input: KeyCode,
+    tap: Vec<KeyCode>,
+    hold_on_other_key_press: bool,
+    permissive_hold: bool,
+    chordal_hold: bool,
+    /// It is held if it is still down at this time
+    deadline: TimeVal,
+    /// The events of other keys since it was pressed, which are held
+    /// back until it is decided
+    held_back: Vec<InputEvent>,
+}
+
+/// Tracks modifiers that have been pressed ahead of the keys that
+/// they are combined with, which wait for them to settle
+struct PendingSettle {
+    /// The other keys are pressed at this time
+    deadline: TimeVal,
+    /// The events of keys since the modifiers were pressed, which
+    /// are held back until then
+    held_back: Vec<InputEvent>,
+}
+
+/// Tracks a key that changed state recently, while any further changes
+/// of it are taken to be chatter
+#[derive(Clone, Copy)]
+struct Debounce {
+    /// The value of the change that was processed
+    value: i32,
+    /// The last value that was read since, if any
+    latest: Option<i32>,
+    /// When the key can change state again
+    until: TimeVal,
+}
+
+/// Something that the `MappingEngine` wants done to the output device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Output {
+    /// Write this event
+    Event(InputEvent),
+    /// Start this program
+    Run(CommandSpec),
+}
+
+/// Reads and writes the devices, and runs their events
+/// through a `MappingEngine`
 pub struct InputMapper {
     input: Device,
-    output: UInputDevice,
+    /// The virtual device, or None for a dry run, in which the input
+    /// device is not grabbed and the output is printed instead
+    output: Option<UInputDevice>,
+    /// Whether the input device is grabbed while remapping, which
+    /// it isn't for a dry run or in overlay mode
+    grab: bool,
+    engine: MappingEngine,
+
+    /// The keys that the virtual output device is able to produce
+    supported_output_keys: HashSet<KeyCode>,
+
+    /// The state last given to the LEDs of the input device that
+    /// indicate the active layers and profile
+    indicator_leds: Vec<(LedCode, bool)>,
+
+    /// How to find the input device again after it has been
+    /// removed, and whether it has been
+    device_selector: DeviceSelector,
+    device_path: PathBuf,
+    detached: bool,
+
+    /// Set by the `pause` control command: the input device is not
+    /// grabbed, and its events are read but ignored
+    paused: bool,
+
+    /// The config file, if it is to be reloaded when it changes,
+    /// and which of its devices this is
+    config_file: Option<(PathBuf, usize)>,
+    config_watcher: Option<ConfigWatcher>,
+    /// When to reload the config file after it last changed, so that
+    /// an editor writing it several times in a row only reloads it once
+    config_reload_due: Option<TimeVal>,
+
+    /// Reports which application is focused, if any layers
+    /// depend on it
+    focus_watcher: Option<FocusWatcher>,
+
+    /// Reports when a login session is locked, if that is a
+    /// `release_all` trigger
+    lock_watcher: Option<SessionLockWatcher>,
+
+    /// Other devices, such as those of a laptop's tablet mode and lid
+    /// switches, that report the switches that layers depend on when
+    /// the input device doesn't.  They are read, but not grabbed.
+    switch_devices: Vec<Device>,
+
+    /// How often to poke the input device to keep it from being
+    /// suspended by power management, and when we last did so
+    keepalive_interval: Option<Duration>,
+    last_keepalive: TimeVal,
+}
+
+/// Decides what to write to the output device for each input event,
+/// and as time passes.  It doesn't do any I/O itself, and is only
+/// told the time, so that it can be driven by anything.
+pub struct MappingEngine {
+    /// The events that are waiting to be written to the output device
+    pending_output: Vec<Output>,
+
     /// If present in this map, the key is down since the instant
     /// of its associated value
     input_state: HashMap<KeyCode, TimeVal>,
 
+    /// Keys that are still physically held, but whose effects were
+    /// undone by release_all.  Their events are ignored until they
+    /// are released.
+    released_held: HashSet<KeyCode>,
+
+    /// The mappings that are currently in effect: those of the active
+    /// layers, topmost first, followed by `base_mappings`
     mappings: Vec<Mapping>,
+    /// If a blocking layer is active, the keys that have a mapping,
+    /// and so are not swallowed, and the keys that were swallowed
+    /// while they are held
+    block_unmapped: Option<HashSet<KeyCode>>,
+    blocked_keys: HashSet<KeyCode>,
+    /// Keys that were held when the mappings changed in a way that
+    /// affects them, and the mappings that were in effect when they
+    /// were pressed, which go on applying to them until they are released
+    pressed_under: HashMap<KeyCode, Rc<Vec<Mapping>>>,
+    base_mappings: Vec<Mapping>,
+    layers: Vec<Layer>,
+    /// The stack of active layers, as indices into `layers`
+    active_layers: Vec<(usize, LayerMode)>,
+    /// The Layer keys that are held, the layers that they activated,
+    /// and when they were pressed if that can be the first tap of a
+    /// double tap that locks the layer
+    layer_keys: HashMap<KeyCode, (usize, LayerMode, Option<TimeVal>)>,
+    /// The lock_on_double_tap Layer key that was most recently
+    /// tapped, and when it was released
+    last_layer_tap: Option<(KeyCode, TimeVal)>,
+    /// The profiles, the one that is active, and the Profile
+    /// keys that are held
+    profiles: Vec<Profile>,
+    active_profile: usize,
+    profile_keys: HashSet<KeyCode>,
+    /// The application that has the focus, which activates
+    /// the layers whose `only_when` condition it satisfies
+    focused_app: Option<String>,
+    /// Which locks are on, which activates the layers whose `only_when`
+    /// condition they satisfy.  This follows the lock keys that are
+    /// pressed on the output device.
+    locks: LockState,
+    locks_changed: bool,
+    /// The switches that are on, such as SW_TABLET_MODE, which activate
+    /// the layers whose `only_when` condition they satisfy
+    switches: HashSet<SwitchCode>,
 
     /// The most recent candidate for a tap function is held here
     tapping: Option<KeyCode>,
 
+    /// If true, the modifiers produced by a DualRole hold are not
+    /// pressed while its key is the only one held, until its tap
+    /// timeout has elapsed.  `resolved_holds` are the DualRole keys
+    /// whose timeout has elapsed.
+    suppress_modifier_pulses: bool,
+    resolved_holds: HashSet<KeyCode>,
+
+    /// How long to wait between pressing modifiers and pressing
+    /// the other keys that they are combined with
+    modifier_settle: Option<Duration>,
+    settling: Option<PendingSettle>,
+
+    /// Keys that were held while some other non-modifier key was
+    /// pressed, which makes use of their DualRole hold function
+    hold_consumed: HashSet<KeyCode>,
+
+    /// The DualRole key that is waiting to be decided, when it
+    /// doesn't hold on other key press
+    dual_role_pending: Option<PendingDualRole>,
+    /// The DualRole key that was most recently tapped, and when
+    last_dual_role_tap: Option<(KeyCode, TimeVal)>,
+    /// DualRole keys that were pressed again within their quick_tap
+    /// time, and are holding their `tap` keys
+    quick_tapping: HashSet<KeyCode>,
+    /// DualRole keys that were decided to be tapped by chordal_hold
+    /// while they were still down, whose release is ignored
+    dual_role_tapped: HashSet<KeyCode>,
+    hands: HashMap<KeyCode, Hand>,
+
     output_keys: HashSet<KeyCode>,
+    /// The `order` hints of the Remap items that applied when the
+    /// output_keys were last changed, so that their keys are released
+    /// in the reverse sequence even once the Remap no longer applies
+    output_order: Vec<KeyCode>,
+
+    /// The state of each MultiFunction key that is in progress
+    multi_function: HashMap<KeyCode, MultiFunctionState>,
+
+    /// The ModLock keys that are currently locked, and since when
+    locked: HashMap<KeyCode, TimeVal>,
+
+    /// The TapDance keys that are being tapped
+    tap_dance: HashMap<KeyCode, TapDanceState>,
+
+    /// The Turbo keys that are held
+    turbo: HashMap<KeyCode, TurboState>,
+
+    /// The OneShot keys whose output is held
+    one_shot: HashMap<KeyCode, OneShotState>,
+
+    /// Whether Caps Word is on, shifting letters until the end of a word
+    caps_word: bool,
+
+    autoshift: Option<AutoShift>,
+    /// The autoshift key that is held, and since when, while we wait
+    /// to find out whether it is held for long enough to be shifted
+    autoshift_pending: Option<(KeyCode, TimeVal)>,
+    /// Autoshift keys that have been typed while they are still held,
+    /// whose autorepeat and release are ignored
+    autoshift_typed: HashSet<KeyCode>,
+
+    /// The MouseKey keys that are held, and when they last
+    /// moved the pointer
+    mouse_keys: HashMap<KeyCode, MouseKeyState>,
+    last_mouse_key_tick: TimeVal,
+
+    /// The keys that Macro mappings have yet to type, each with the
+    /// time to wait after it, and when the next one is due
+    macro_queue: VecDeque<(MacroStep, Duration)>,
+    /// The keys that are held by the `{press}` steps of macros
+    macro_held: HashSet<KeyCode>,
+    /// The scancode of the current frame of input events, from its
+    /// MSC_SCAN event, if it had one
+    frame_scan: Option<u32>,
+    /// The keys whose press was matched to a ScanRemap by its
+    /// scancode, and the output that is held for each
+    scan_held: HashMap<KeyCode, Vec<KeyCode>>,
+    next_macro_key: TimeVal,
+
+    /// The Command keys with a min_hold that are held, and when
+    /// their command is run unless they are released first
+    held_commands: HashMap<KeyCode, (CommandSpec, TimeVal)>,
+
+    /// The Sequence that is in progress, if any
+    sequence: Option<PendingSequence>,
+    /// Keys that completed a Sequence while held, whose release
+    /// must not be seen by the other mappings
+    sequence_swallow: HashSet<KeyCode>,
+    /// True while the events of a broken Sequence are being processed
+    replaying_sequence: bool,
+
+    /// For each encoder axis: the time and direction of its most
+    /// recent movement, and the number of taps it produced
+    encoder_state: HashMap<RelCode, (TimeVal, i32, u32)>,
+
+    release_triggers: Vec<ReleaseTrigger>,
+
+    /// The time at which the most recent input event was received
+    last_input: TimeVal,
+
+    /// If true, only EV_KEY and EV_SYN events are passed through
+    keys_only: bool,
+
+    /// If true, key events that are inconsistent with the tracked
+    /// input state are dropped
+    sanitize: bool,
+
+    /// If true, the input device isn't grabbed, so its events are seen
+    /// anyway, and only the events that they don't account for are
+    /// output.  This tracks the keys that are physically held, those
+    /// that are held on the output device, and whether anything has
+    /// been output since the last SYN_REPORT.
+    overlay: bool,
+    physical_keys: HashSet<KeyCode>,
+    overlay_keys: HashSet<KeyCode>,
+    overlay_pending_syn: bool,
+
+    /// How often to send a SYN_REPORT to the output device whether
+    /// or not anything happened, and when we last did so
+    periodic_syn: Option<Duration>,
+    last_periodic_syn: TimeVal,
+
+    /// If set, events for the keys that are not in this set are
+    /// passed through exactly as they are whenever possible
+    low_latency_passthrough: Option<HashSet<KeyCode>>,
+    /// The keys that were passed through that way and are still held,
+    /// which an `exact` Remap has to know about
+    passthrough_held: HashSet<KeyCode>,
+
+    /// The keys that are the input of some mapping, so that the touch
+    /// and tool buttons of a touchpad can pass through when they aren't
+    mapped_input_keys: HashSet<KeyCode>,
+
+    /// Keys that are replaced by another key as the final step
+    /// before an event is written to the output device.  The
+    /// output_keys are tracked in terms of the original keys.
+    modifier_rewrite: HashMap<KeyCode, KeyCode>,
+    /// For each key that is the target of a rewrite and is held on
+    /// the output device, the keys that are holding it, so that it is
+    /// only released once all of them are
+    rewrite_held: HashMap<KeyCode, HashSet<KeyCode>>,
+
+    /// Mouse buttons held on the output device for longer than this
+    /// are released, in case we missed the release of their input.
+    max_drag: Option<Duration>,
+    /// Any key held on the output device for longer than this is
+    /// released, as a safety net for a missed release or a bug
+    max_hold: Option<Duration>,
+    /// The keys held on the output device, and since when
+    held_since: HashMap<KeyCode, TimeVal>,
+    /// Keys that were released by the max_drag or max_hold watchdog, and
+    /// which must not be pressed again until they are no longer wanted
+    expired_holds: HashSet<KeyCode>,
+    debounce: Option<Duration>,
+    debounce_keys: HashMap<KeyCode, Duration>,
+    debounce_state: HashMap<KeyCode, Debounce>,
+    /// How many events were discarded as chatter
+    debounced: u64,
 }
 
 fn enable_key_code(input: &mut Device, key: KeyCode) -> Result<()> {
     input
-        .enable(EventCode::EV_KEY(key.clone()))
+        .enable(EventCode::EV_KEY(key))
         .context(format!("enable key {:?}", key))?;
     Ok(())
 }
 
+/// A DualRole key that is released within this time of being
+/// pressed produces its tap function, unless it specifies its
+/// own hold_timeout
+const DUAL_ROLE_TAP_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// A Layer key with lock_on_double_tap that is tapped, and then
+/// pressed again within this time, locks or unlocks its layer
+const LAYER_DOUBLE_TAP_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// The config file is reloaded once it hasn't changed for this long
+const CONFIG_RELOAD_DELAY: Duration = Duration::from_millis(100);
+
+/// The name of the virtual output device is this prefix followed
+/// by the path of the input device
+const VIRTUAL_DEVICE_NAME_PREFIX: &str = "evremap Virtual input for ";
+
+/// When the virtual output device is given a name of the user's
+/// choosing, its phys is this prefix followed by the path of the input
+/// device instead, so that it can still be told apart from the others
+pub(crate) const VIRTUAL_DEVICE_PHYS_PREFIX: &str = "evremap/";
+
+/// How often the pointer is moved while a MouseKey key is held
+const MOUSE_KEY_TICK: Duration = Duration::from_millis(16);
+
+/// How often to look for an input device that was removed,
+/// in case we aren't notified when it comes back
+const RESCAN_INTERVAL: Duration = Duration::from_secs(10);
+
+/// EVIOCSREP from linux/input.h: _IOW('E', 0x03, unsigned int[2])
+const EVIOCSREP: libc::c_ulong = 0x40084503;
+
+/// The kernel autorepeats keys held on the virtual device using its
+/// own default timing, which can differ from the delay and rate that
+/// the user has configured for the physical device.  Copy the physical
+/// device's settings across so that both repeat in the same way.
+fn copy_repeat_settings(input: &Device, output: &UInputDevice) -> Result<()> {
+    let delay = input.event_value(&EventCode::EV_REP(evdev_rs::enums::EV_REP::REP_DELAY));
+    let period = input.event_value(&EventCode::EV_REP(evdev_rs::enums::EV_REP::REP_PERIOD));
+    let (Some(delay), Some(period)) = (delay, period) else {
+        // The physical device doesn't autorepeat
+        return Ok(());
+    };
+    let devnode = output
+        .devnode()
+        .ok_or_else(|| anyhow!("virtual device has no device node"))?;
+    let f = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(devnode)
+        .context(format!("opening {devnode}"))?;
+    let rep: [libc::c_uint; 2] = [delay as libc::c_uint, period as libc::c_uint];
+    if unsafe { libc::ioctl(f.as_raw_fd(), EVIOCSREP, rep.as_ptr()) } == -1 {
+        return Err(std::io::Error::last_os_error()).context(format!("EVIOCSREP on {devnode}"));
+    }
+    log::debug!("set key repeat delay={delay}ms period={period}ms on {devnode}");
+    Ok(())
+}
+
+/// Returns true if `device` is one of the virtual devices of evremap
+fn is_virtual_device(device: &Device) -> bool {
+    device
+        .name()
+        .is_some_and(|name| name.starts_with(VIRTUAL_DEVICE_NAME_PREFIX))
+        || device
+            .phys()
+            .is_some_and(|phys| phys.starts_with(VIRTUAL_DEVICE_PHYS_PREFIX))
+}
+
+/// Returns the switches that the `only_when` conditions of
+/// the layers in `config` depend on
+fn condition_switches(config: &MappingConfig) -> HashSet<SwitchCode> {
+    config
+        .layers
+        .iter()
+        .filter_map(|layer| layer.only_when.as_ref())
+        .flat_map(|condition| condition.switches.iter().map(|(switch, _)| *switch))
+        .collect()
+}
+
+/// Opens the devices other than the one at `input_path` that report
+/// any of `switches`, without grabbing them
+fn open_switch_devices(switches: &HashSet<SwitchCode>, input_path: &Path) -> Vec<Device> {
+    let std::result::Result::Ok(entries) = std::fs::read_dir("/dev/input") else {
+        return vec![];
+    };
+    let mut paths: Vec<PathBuf> = entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with("event"))
+                && path != input_path
+        })
+        .collect();
+    paths.sort();
+    let mut devices = vec![];
+    for path in paths {
+        let std::result::Result::Ok(f) = std::fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(&path)
+        else {
+            continue;
+        };
+        let std::result::Result::Ok(device) = Device::new_from_file(f) else {
+            continue;
+        };
+        if is_virtual_device(&device)
+            || !switches
+                .iter()
+                .any(|switch| device.has(EventCode::EV_SW(*switch)))
+        {
+            continue;
+        }
+        log::debug!(
+            "Following the switches of {} ({})",
+            path.display(),
+            device.name().unwrap_or("")
+        );
+        devices.push(device);
+    }
+    devices
+}
+
+/// Opens the input device at `path`, ready to be remapped
+fn open_input_device(path: &Path, writable: bool) -> Result<Device> {
+    let f = std::fs::OpenOptions::new()
+        .read(true)
+        .write(writable)
+        .open(path)
+        .context(format!("opening {}", path.display()))?;
+    let input = Device::new_from_file(f)
+        .with_context(|| format!("failed to create new Device from file {}", path.display()))?;
+
+    // Timestamp events using the monotonic clock so that they can
+    // be compared against our timers
+    input
+        .set_clock_id(libc::CLOCK_MONOTONIC)
+        .context(format!("setting clock on {}", path.display()))?;
+
+    // Grabbing one of our own virtual devices would feed our output
+    // back into our input; most likely a stale instance of evremap
+    // is still running, or the configured device_name is wrong.
+    if is_virtual_device(&input) {
+        bail!(
+            "{} ({}) is a virtual device created by evremap, \
+             and cannot itself be remapped. Check that the \
+             device_name in your config refers to the physical \
+             device, and that no other evremap is still running \
+             for it.",
+            path.display(),
+            input.name().unwrap_or("")
+        );
+    }
+
+    Ok(input)
+}
+
 impl InputMapper {
-    pub fn create_mapper<P: AsRef<Path>>(path: P, mappings: Vec<Mapping>) -> Result<Self> {
+    pub fn create_mapper<P: AsRef<Path>>(
+        path: P,
+        mut config: MappingConfig,
+        dry_run: bool,
+    ) -> Result<Self> {
         let path = path.as_ref();
-        let f = std::fs::File::open(path).context(format!("opening {}", path.display()))?;
-        let mut input = Device::new_from_file(f)
-            .with_context(|| format!("failed to create new Device from file {}", path.display()))?;
+        if dry_run {
+            // The keepalive works by writing to the device, which
+            // we won't be opening for writing
+            config.keepalive_interval = None;
+        }
+        // The keys used by every layer must be set up front
+        let all_mappings = config.all_mappings();
+        // The keepalive works by writing to the device
+        let mut input = open_input_device(path, config.keepalive_interval.is_some())?;
 
-        input.set_name(&format!("evremap Virtual input for {}", path.display()));
+        // The virtual device is created with every event type and code
+        // that the input device has, including the axes of a touchpad or
+        // the switches of a laptop, so that the events that aren't
+        // remapped can be passed through as they are.  The exception is
+        // force feedback, as nothing would answer the uploads of effects
+        // to the virtual device, which would leave the program waiting.
+        if input.has(EventType::EV_FF) {
+            log::debug!(
+                "{} supports force feedback, which the virtual device won't",
+                path.display()
+            );
+            input.disable(EventType::EV_FF).context("disable EV_FF")?;
+        }
+        log::debug!(
+            "{} has event types {:?}",
+            path.display(),
+            event_types(&input)
+        );
 
-        // Ensure that any remapped keys are supported by the generated output device
-        for map in &mappings {
+        let identity = &config.output_device;
+        match &identity.name {
+            Some(name) => {
+                input.set_name(name);
+                input.set_phys(&format!("{VIRTUAL_DEVICE_PHYS_PREFIX}{}", path.display()));
+            }
+            None => input.set_name(&format!("{VIRTUAL_DEVICE_NAME_PREFIX}{}", path.display())),
+        }
+        if let Some(vendor) = identity.vendor {
+            input.set_vendor_id(vendor);
+        }
+        if let Some(product) = identity.product {
+            input.set_product_id(product);
+        }
+        if let Some(bustype) = identity.bustype {
+            input.set_bustype(bustype as u16);
+        }
+
+        // Mappings for keys that the device never reports can't do anything.
+        // This must be checked before we enable the output keys below.
+        for key in mapping_input_keys(&all_mappings) {
+            if input.has(EventCode::EV_KEY(key)) {
+                continue;
+            }
+            if key == KeyCode::KEY_FN {
+                log::warn!(
+                    "{} doesn't report KEY_FN; its Fn key, if any, is handled \
+                     by the keyboard firmware and cannot be remapped",
+                    path.display()
+                );
+            } else {
+                log::warn!(
+                    "{} doesn't report {key:?}, so mappings for it will have no effect",
+                    path.display()
+                );
+            }
+        }
+
+        // Show the timing that is actually used for each key whose
+        // behavior depends on it
+        for map in &all_mappings {
             match map {
-                Mapping::DualRole { tap, hold, .. } => {
-                    for t in tap {
-                        enable_key_code(&mut input, t.clone())?;
-                    }
-                    for h in hold {
-                        enable_key_code(&mut input, h.clone())?;
-                    }
-                }
-                Mapping::Remap { output, .. } => {
-                    for o in output {
-                        enable_key_code(&mut input, o.clone())?;
+                Mapping::DualRole {
+                    input,
+                    abort_hold_emits_tap,
+                    hold_timeout,
+                    hold_on_other_key_press,
+                    permissive_hold,
+                    quick_tap,
+                    chordal_hold,
+                    ..
+                } => log::debug!(
+                    "{input:?}: dual role, tap if released within {:?}, \
+                     hold {}, abort_hold_emits_tap={abort_hold_emits_tap}, \
+                     quick_tap={quick_tap:?}, chordal_hold={chordal_hold}",
+                    hold_timeout.unwrap_or(DUAL_ROLE_TAP_TIMEOUT),
+                    match (hold_on_other_key_press, permissive_hold) {
+                        (true, false) if hold_timeout.is_none() => "immediately",
+                        (true, _) => "after the timeout or when another key is pressed",
+                        (false, true) => "after the timeout or when another key is tapped",
+                        (false, false) => "after the timeout",
                     }
+                ),
+                Mapping::MultiFunction { input, timeout, .. } => {
+                    log::debug!("{input:?}: multi function, timeout {timeout:?}")
                 }
+                _ => {}
+            }
+        }
+
+        // Ensure that any remapped keys are supported by the generated output device
+        for key in mapping_output_keys(&all_mappings) {
+            enable_key_code(&mut input, key)?;
+        }
+        for to in config.modifier_rewrite.values() {
+            enable_key_code(&mut input, *to)?;
+        }
+        for key in &config.extra_output_keys {
+            enable_key_code(&mut input, *key)?;
+        }
+        if config.autoshift.is_some() {
+            enable_key_code(&mut input, KeyCode::KEY_LEFTSHIFT)?;
+        }
+        let supported_output_keys: HashSet<KeyCode> = EventCode::EV_KEY(KeyCode::KEY_RESERVED)
+            .iter()
+            .filter_map(|code| match code {
+                EventCode::EV_KEY(key) if input.has(code) => Some(key),
+                _ => None,
+            })
+            .collect();
+
+        // libinput only treats a device as a pointer if it can move, and
+        // ignores the mouse buttons of anything else.  If we produce mouse
+        // buttons from a keyboard, make the virtual device look like a mouse.
+        if supported_output_keys.iter().any(is_mouse_button)
+            && !input.has(EventType::EV_REL)
+            && !input.has(EventType::EV_ABS)
+        {
+            for axis in [
+                RelCode::REL_X,
+                RelCode::REL_Y,
+                RelCode::REL_WHEEL,
+                RelCode::REL_HWHEEL,
+            ] {
+                input
+                    .enable(EventCode::EV_REL(axis))
+                    .context(format!("enable {:?}", axis))?;
+            }
+        }
+
+        // The axes that MouseKey mappings move
+        for map in &all_mappings {
+            if let Mapping::MouseKey { direction, .. } = map {
+                let (axis, _) = direction.axis();
+                input
+                    .enable(EventCode::EV_REL(axis))
+                    .context(format!("enable {:?}", axis))?;
             }
         }
 
-        let output = UInputDevice::create_from_device(&input)
+        if config.keepalive_interval.is_some() && device_leds(&input).is_empty() {
+            log::warn!(
+                "{} has no LEDs, so keepalive_interval_ms will have no effect",
+                path.display()
+            );
+        }
+
+        let focus_watcher = start_focus_watcher(&config);
+        let lock_watcher = start_lock_watcher(&config);
+        let locks = device_lock_state(&input);
+        let switches = condition_switches(&config);
+
+        let grab = !dry_run && !config.overlay;
+        let output = if dry_run {
+            log::info!(
+                "Dry run: {} is not grabbed, and the events that would be \
+                 emitted are printed instead",
+                path.display()
+            );
+            None
+        } else {
+            if !grab {
+                log::info!(
+                    "Overlay mode: {} is not grabbed, and only the events \
+                     that its mappings add are emitted",
+                    path.display()
+                );
+            }
+            Some(Self::create_output(
+                &mut input,
+                path,
+                grab,
+                config.post_grab_settle,
+                config.post_grab_settle_mode,
+            )?)
+        };
+
+        let mut mapper = Self {
+            input,
+            output,
+            grab,
+            supported_output_keys,
+            indicator_leds: vec![],
+            device_selector: DeviceSelector {
+                path: config.path.clone(),
+                sysfs_path: config.sysfs_path.clone(),
+                name: config.device_name.clone(),
+                phys: config.phys.clone(),
+            },
+            device_path: path.to_path_buf(),
+            detached: false,
+            paused: false,
+            config_file: None,
+            config_watcher: None,
+            config_reload_due: None,
+            focus_watcher,
+            lock_watcher,
+            switch_devices: vec![],
+            keepalive_interval: config.keepalive_interval,
+            last_keepalive: now(),
+            engine: {
+                let mut engine = MappingEngine::new(config, &now());
+                engine.set_lock_state(locks, &now())?;
+                engine
+            },
+        };
+        mapper.watch_switches(&switches)?;
+        mapper.update_indicator_leds();
+        Ok(mapper)
+    }
+
+    /// Creates the virtual output device, and grabs the input device,
+    /// if `grab` is true, so that only the output device is seen by
+    /// everything else
+    fn create_output(
+        input: &mut Device,
+        path: &Path,
+        grab: bool,
+        post_grab_settle: Duration,
+        post_grab_settle_mode: SettleMode,
+    ) -> Result<UInputDevice> {
+        // If anything below fails, or we are killed part way through,
+        // there is nothing to clean up by hand: the kernel destroys the
+        // virtual device and releases the grab when their respective
+        // file descriptors are closed.
+        let output = UInputDevice::create_from_device(input)
             .context(format!("creating UInputDevice from {}", path.display()))?;
 
+        if let Err(err) = copy_repeat_settings(input, &output) {
+            log::warn!("Unable to copy key repeat settings to the virtual device: {err:#}");
+        }
+
+        // The LEDs that the session sets on the virtual device are read
+        // back from it in forward_leds, which mustn't block
+        if let Some(fd) = output.as_fd() {
+            unsafe {
+                let flags = libc::fcntl(fd, libc::F_GETFL);
+                libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK);
+            }
+        }
+
+        if !grab {
+            return Ok(output);
+        }
         input
             .grab(GrabMode::Grab)
             .context(format!("grabbing exclusive access on {}", path.display()))?;
 
-        Ok(Self {
-            input,
-            output,
-            input_state: HashMap::new(),
-            output_keys: HashSet::new(),
-            tapping: None,
-            mappings,
-        })
+        // Give the desktop a chance to notice the new virtual device before
+        // we emit anything to it.  The kernel queues any input that arrives
+        // in the meantime, so it is either processed or discarded below.
+        if !post_grab_settle.is_zero() {
+            std::thread::sleep(post_grab_settle);
+            if post_grab_settle_mode == SettleMode::Drop {
+                while input.has_event_pending() {
+                    let (_, event) = input.next_event(ReadFlag::NORMAL)?;
+                    log::trace!("DROP {:?}", event);
+                }
+            }
+        }
+
+        Ok(output)
+    }
+
+    /// Reload the config when SIGHUP is received, and if `watch`
+    /// is true, when it is changed
+    pub fn watch_config_file(&mut self, config_file: &Path, device_index: usize, watch: bool) {
+        if let Err(err) = install_signal_handler(libc::SIGHUP, request_reload, "SIGHUP") {
+            log::warn!("{err:#}");
+        }
+        if watch {
+            match ConfigWatcher::new(config_file) {
+                std::result::Result::Ok(watcher) => self.config_watcher = Some(watcher),
+                Err(err) => log::warn!(
+                    "Unable to watch {} for changes: {err:#}",
+                    config_file.display()
+                ),
+            }
+        }
+        self.config_file = Some((config_file.to_path_buf(), device_index));
     }
 
-    pub fn run_mapper(&mut self) -> Result<()> {
+    /// Runs several mappers, each for its own device, in the same loop.
+    /// The commands that come from each of `control` are carried out
+    /// on all of the mappers.  `notifier` is told when remapping starts
+    /// and stops, and feeds the watchdog; it has to be created before
+    /// the sandbox is entered, which no longer allows opening a socket.
+    pub fn run_mappers(
+        mappers: &mut [InputMapper],
+        control: &[&dyn ControlSource],
+        notifier: Option<Notifier>,
+    ) -> Result<()> {
+        if let Err(err) = install_signal_handler(libc::SIGUSR2, request_state_dump, "SIGUSR2") {
+            log::warn!("{err:#}");
+        }
+        if let Err(err) = install_signal_handler(libc::SIGUSR1, request_next_profile, "SIGUSR1") {
+            log::warn!("{err:#}");
+        }
+        for (signal, name) in [(libc::SIGTERM, "SIGTERM"), (libc::SIGINT, "SIGINT")] {
+            if let Err(err) = install_signal_handler(signal, request_shutdown, name) {
+                log::warn!("{err:#}");
+            }
+        }
+        // Used to notice when a device that was removed comes back
+        let device_watcher = DeviceWatcher::new()
+            .map_err(|err| log::debug!("{err:#}"))
+            .ok();
+        let mut next_rescan = timeval_add(&now(), RESCAN_INTERVAL);
+
+        // Every device has been grabbed, and its virtual device created
+        let mut next_watchdog = None;
+        if let Some(notifier) = &notifier {
+            notifier.notify(&format!(
+                "READY=1\nSTATUS=Remapping {} device(s)",
+                mappers.len()
+            ));
+            next_watchdog = notifier
+                .watchdog_interval()
+                .map(|interval| timeval_add(&now(), interval));
+        }
+
         log::info!("Going into read loop");
         loop {
-            let (status, event) = self
-                .input
-                .next_event(ReadFlag::NORMAL | ReadFlag::BLOCKING)?;
-            match status {
-                evdev_rs::ReadStatus::Success => {
-                    if let EventCode::EV_KEY(ref key) = event.event_code {
-                        log::trace!("IN {:?}", event);
-                        self.update_with_event(&event, key.clone())?;
-                    } else {
-                        log::trace!("PASSTHRU {:?}", event);
-                        self.output.write_event(&event)?;
+            if SHUTDOWN.load(AtomicOrdering::SeqCst) {
+                log::info!("Shutting down");
+                if let Some(notifier) = &notifier {
+                    notifier.notify("STOPPING=1");
+                }
+                for mapper in mappers.iter_mut() {
+                    mapper.shutdown()?;
+                }
+                return Ok(());
+            }
+            let dump_state = DUMP_STATE.swap(false, AtomicOrdering::SeqCst);
+            let reload_config = RELOAD_CONFIG.swap(false, AtomicOrdering::SeqCst);
+            let next_profile = NEXT_PROFILE.swap(false, AtomicOrdering::SeqCst);
+            for mapper in mappers.iter_mut() {
+                if dump_state {
+                    log::info!("{}", mapper.engine.state_as_json());
+                }
+                if reload_config {
+                    mapper.reload_config()?;
+                }
+                if next_profile {
+                    let output = mapper.engine.next_profile(&now())?;
+                    mapper.write_outputs(output)?;
+                }
+            }
+
+            let time = now();
+            let timeout = if mappers.iter().any(|mapper| mapper.has_event_pending()) {
+                Some(Duration::ZERO)
+            } else {
+                mappers
+                    .iter()
+                    .filter(|mapper| !mapper.detached && !mapper.paused)
+                    .filter_map(|mapper| mapper.next_deadline())
+                    .chain(mappers.iter().filter_map(|mapper| mapper.config_reload_due))
+                    .chain(
+                        mappers
+                            .iter()
+                            .any(|mapper| mapper.detached)
+                            .then_some(next_rescan),
+                    )
+                    .chain(next_watchdog)
+                    .min()
+                    .map(|deadline| timeval_diff(&deadline, &time))
+            };
+            let mut fds = vec![];
+            for mapper in mappers.iter() {
+                fds.push(if mapper.detached {
+                    -1
+                } else {
+                    mapper.input.file().as_raw_fd()
+                });
+                fds.push(
+                    mapper
+                        .config_watcher
+                        .as_ref()
+                        .map_or(-1, |w| w.inotify.as_raw_fd()),
+                );
+                fds.push(mapper.focus_watcher.as_ref().map_or(-1, |w| w.as_raw_fd()));
+                fds.push(mapper.lock_watcher.as_ref().map_or(-1, |w| w.as_raw_fd()));
+                fds.push(
+                    mapper
+                        .output
+                        .as_ref()
+                        .and_then(|output| output.as_fd())
+                        .unwrap_or(-1),
+                );
+            }
+            let first_control = fds.len();
+            fds.extend(control.iter().map(|c| c.as_raw_fd()));
+            let first_switch = fds.len();
+            let mut switch_devices = vec![];
+            for (mapper_idx, mapper) in mappers.iter().enumerate() {
+                for (idx, device) in mapper.switch_devices.iter().enumerate() {
+                    fds.push(device.file().as_raw_fd());
+                    switch_devices.push((mapper_idx, idx));
+                }
+            }
+            fds.push(device_watcher.as_ref().map_or(-1, |w| w.as_raw_fd()));
+            let ready = wait_for_fds(&fds, timeout)?;
+
+            // In reverse, so that removing a device that has gone away
+            // doesn't change the index of those still to be read
+            for (&(mapper_idx, idx), ready) in
+                switch_devices.iter().zip(&ready[first_switch..]).rev()
+            {
+                if *ready {
+                    mappers[mapper_idx].read_switches(idx)?;
+                }
+            }
+
+            for (source, ready) in control.iter().zip(&ready[first_control..]) {
+                if !ready {
+                    continue;
+                }
+                while let Some(request) = source.accept() {
+                    log::info!("Control command: {}", request.command);
+                    let result = Self::run_control_command(mappers, &request.command);
+                    request.reply(result);
+                }
+            }
+
+            // Look for detached devices when something is attached,
+            // and every so often in case we missed it
+            let mut rescan =
+                ready[fds.len() - 1] && device_watcher.as_ref().is_some_and(|w| w.changed());
+            if next_rescan <= time {
+                rescan = true;
+                next_rescan = timeval_add(&time, RESCAN_INTERVAL);
+            }
+
+            // The watchdog is only fed while the loop keeps going round
+            if let (Some(notifier), Some(deadline)) = (&notifier, next_watchdog) {
+                if deadline <= time {
+                    notifier.notify("WATCHDOG=1");
+                    next_watchdog = notifier
+                        .watchdog_interval()
+                        .map(|interval| timeval_add(&time, interval));
+                }
+            }
+
+            for (mapper, ready) in mappers.iter_mut().zip(ready.chunks(5)) {
+                if ready[1] && mapper.config_watcher.as_ref().is_some_and(|w| w.changed()) {
+                    mapper.config_reload_due = Some(timeval_add(&time, CONFIG_RELOAD_DELAY));
+                }
+                if mapper.config_reload_due.is_some_and(|due| due <= time) {
+                    mapper.config_reload_due = None;
+                    mapper.reload_config()?;
+                }
+                if let Some(app) = ready[2]
+                    .then(|| mapper.focus_watcher.as_ref().and_then(|w| w.changed()))
+                    .flatten()
+                {
+                    let output = mapper.engine.set_focused_app(app, &now())?;
+                    mapper.write_outputs(output)?;
+                }
+                if ready[3] && mapper.lock_watcher.as_ref().is_some_and(|w| w.locked()) {
+                    let output = mapper.engine.session_locked(&now())?;
+                    mapper.write_outputs(output)?;
+                }
+                if ready[4] {
+                    mapper.forward_leds()?;
+                }
+                if mapper.detached {
+                    if rescan {
+                        mapper.reattach();
                     }
+                } else if ready[0] || mapper.has_event_pending() {
+                    mapper.process_input()?;
+                } else if !mapper.paused {
+                    mapper.handle_timeouts(&now())?;
                 }
-                evdev_rs::ReadStatus::Sync => bail!("ReadStatus::Sync!"),
             }
         }
     }
 
-    /// Compute the effective set of keys that are pressed
-    fn compute_keys(&self) -> HashSet<KeyCode> {
-        // Start with the input keys
-        let mut keys: HashSet<KeyCode> = self.input_state.keys().cloned().collect();
-
-        // First phase is to apply any DualRole mappings as they are likely to
-        // be used to produce modifiers when held.
-        for map in &self.mappings {
-            if let Mapping::DualRole { input, hold, .. } = map {
-                if keys.contains(input) {
-                    keys.remove(input);
-                    for h in hold {
-                        keys.insert(h.clone());
+    /// Carries out a control command on each of `mappers`,
+    /// and returns its output
+    fn run_control_command(
+        mappers: &mut [InputMapper],
+        command: &ControlCommand,
+    ) -> Result<String> {
+        let mut output = String::new();
+        for mapper in mappers.iter_mut() {
+            match command {
+                ControlCommand::Pause => mapper.pause()?,
+                ControlCommand::Resume => mapper.resume()?,
+                ControlCommand::Reload => {
+                    if let Some(config) = mapper.load_config()? {
+                        mapper.apply_config(config)?;
+                        log::info!("Reloaded the config of {}", mapper.device_path.display());
                     }
                 }
+                ControlCommand::Profile(name) => {
+                    let events = mapper.engine.set_profile(name, &now())?;
+                    mapper.write_outputs(events)?;
+                }
+                ControlCommand::Status => {
+                    let state = if mapper.detached {
+                        "detached"
+                    } else if mapper.paused {
+                        "paused"
+                    } else {
+                        "remapping"
+                    };
+                    let profile = mapper
+                        .engine
+                        .profile_name()
+                        .map(|name| format!(", profile `{name}`"))
+                        .unwrap_or_default();
+                    output.push_str(&format!(
+                        "{}: {state}{profile}\n  {}\n",
+                        mapper.device_path.display(),
+                        mapper.engine.state_as_json()
+                    ));
+                }
             }
         }
+        Ok(output)
+    }
 
-        let mut keys_minus_remapped = keys.clone();
-
-        // Second pass to apply Remap items
+    /// Releases everything and ungrabs the input device,
+    /// so that its events are seen as they are
+    fn pause(&mut self) -> Result<()> {
+        if self.paused {
+            return Ok(());
+        }
+        if !self.detached {
+            self.engine.release_all(&now())?;
+            let output = self.engine.take_output();
+            self.write_outputs(output)?;
+            if self.grab {
+                self.input.grab(GrabMode::Ungrab).context(format!(
+                    "releasing exclusive access on {}",
+                    self.device_path.display()
+                ))?;
+            }
+        }
+        self.paused = true;
+        log::info!("Paused remapping {}", self.device_path.display());
+        Ok(())
+    }
+
+    /// Releases every key that is held on the virtual device, so that
+    /// nothing is left stuck down once it is gone.  The input device is
+    /// ungrabbed and the virtual device destroyed when the mapper is
+    /// dropped.
+    fn shutdown(&mut self) -> Result<()> {
+        if !self.detached && !self.paused {
+            self.engine.release_all(&now())?;
+            let output = self.engine.take_output();
+            self.write_outputs(output)?;
+        }
+        Ok(())
+    }
+
+    /// Writes a release for each key that is held on the virtual device,
+    /// without going through the engine, whose state can't be trusted
+    /// after a panic or an error part of the way through an event.
+    /// Nothing is left to do here after a clean `shutdown`.
+    fn release_output_keys(&mut self) {
+        let Some(output) = &self.output else {
+            return;
+        };
+        let keys = self.engine.held_output_keys();
+        self.engine.output_keys.clear();
+        self.engine.rewrite_held.clear();
+        self.engine.passthrough_held.clear();
+        if keys.is_empty() {
+            return;
+        }
+        log::warn!("Releasing keys that were left held: {keys:?}");
+        let time = now();
+        for key in keys {
+            if let Err(err) =
+                output.write_event(&InputEvent::new(&time, &EventCode::EV_KEY(key), 0))
+            {
+                log::error!("failed to release {key:?}: {err:#}");
+            }
+        }
+        let sync = InputEvent::new(
+            &time,
+            &EventCode::EV_SYN(evdev_rs::enums::EV_SYN::SYN_REPORT),
+            0,
+        );
+        if let Err(err) = output.write_event(&sync) {
+            log::error!("failed to write SYN_REPORT: {err:#}");
+        }
+    }
+
+    /// Grabs the input device again after `pause`
+    fn resume(&mut self) -> Result<()> {
+        if !self.paused {
+            return Ok(());
+        }
+        if !self.detached && self.grab {
+            self.input.grab(GrabMode::Grab).context(format!(
+                "grabbing exclusive access on {}",
+                self.device_path.display()
+            ))?;
+        }
+        self.paused = false;
+        self.engine.last_input = now();
+        self.release_keys_not_held()?;
+        log::info!("Resumed remapping {}", self.device_path.display());
+        Ok(())
+    }
+
+    fn has_event_pending(&self) -> bool {
+        // The device of a detached mapper is always readable,
+        // because it reports an error
+        !self.detached && self.input.has_event_pending()
+    }
+
+    /// Called when the input device has gone away.  Everything is
+    /// released, but the virtual device is kept, so that the input
+    /// device can be reattached when it comes back without the
+    /// desktop seeing the virtual device go away.
+    fn detach(&mut self) -> Result<()> {
+        log::warn!("The input device was removed; waiting for it to return");
+        self.detached = true;
+        self.engine.release_all(&now())?;
+        let output = self.engine.take_output();
+        self.write_outputs(output)
+    }
+
+    /// Switch to the device chosen by `selector`, if that isn't
+    /// the input device.  Until it is found, the mapper is detached.
+    fn select_device(&mut self, selector: DeviceSelector) -> Result<()> {
+        if selector == self.device_selector {
+            return Ok(());
+        }
+        self.device_selector = selector;
+        let same_device = self
+            .device_selector
+            .find()
+            .is_ok_and(|info| info.path == self.device_path);
+        if same_device || self.detached {
+            return Ok(());
+        }
+        log::info!(
+            "The device settings changed; letting go of {}",
+            self.device_path.display()
+        );
+        self.detached = true;
+        self.engine.release_all(&now())?;
+        let output = self.engine.take_output();
+        self.write_outputs(output)?;
+        if self.grab && !self.paused {
+            if let Err(err) = self.input.grab(GrabMode::Ungrab) {
+                log::warn!("ungrabbing {}: {err:#}", self.device_path.display());
+            }
+        }
+        self.reattach();
+        Ok(())
+    }
+
+    /// Try to find, open and grab the input device again
+    fn reattach(&mut self) {
+        let result = self.device_selector.find().and_then(|info| {
+            let mut input = open_input_device(&info.path, self.keepalive_interval.is_some())?;
+            if self.grab && !self.paused {
+                input.grab(GrabMode::Grab).context(format!(
+                    "grabbing exclusive access on {}",
+                    info.path.display()
+                ))?;
+            }
+            Ok((info, input))
+        });
+        match result {
+            std::result::Result::Ok((info, input)) => {
+                log::info!("Reattached {}", info.path.display());
+                let locks = device_lock_state(&input);
+                self.input = input;
+                // Light the indicators of the device that was attached
+                self.indicator_leds.clear();
+                self.device_path = info.path;
+                self.detached = false;
+                self.engine.last_input = now();
+                self.last_keepalive = now();
+                // The locks may have been toggled by another keyboard
+                // while the device was away
+                match self.engine.set_lock_state(locks, &now()) {
+                    std::result::Result::Ok(output) => {
+                        if let Err(err) = self.write_outputs(output) {
+                            log::warn!("{err:#}");
+                        }
+                    }
+                    Err(err) => log::warn!("{err:#}"),
+                }
+                if !self.paused {
+                    if let Err(err) = self.release_keys_not_held() {
+                        log::warn!("{err:#}");
+                    }
+                }
+            }
+            Err(err) => log::debug!("{err:#}"),
+        }
+    }
+
+    /// Handles the events that are ready to be read from the input device
+    fn process_input(&mut self) -> Result<()> {
+        loop {
+            let (status, event) = match self.input.next_event(ReadFlag::NORMAL | ReadFlag::BLOCKING)
+            {
+                std::result::Result::Ok(result) => result,
+                Err(err) if err.raw_os_error() == Some(libc::ENODEV) => return self.detach(),
+                Err(err) => return Err(err.into()),
+            };
+            match status {
+                evdev_rs::ReadStatus::Success if self.paused => {
+                    log::trace!("PAUSED {:?}", event);
+                }
+                // Only reports the LEDs that were set by forward_leds
+                // or update_indicator_leds, which the session already
+                // knows about or has no business with
+                evdev_rs::ReadStatus::Success
+                    if matches!(event.event_code, EventCode::EV_LED(_))
+                        && self.output.is_some() =>
+                {
+                    log::trace!("LED {:?}", event);
+                }
+                evdev_rs::ReadStatus::Success => {
+                    if self.output.is_none() {
+                        self.print_input(&event);
+                    }
+                    let output = self.engine.process_event(&event)?;
+                    self.write_outputs(output)?;
+                }
+                evdev_rs::ReadStatus::Sync => self.resync()?,
+            }
+            if !self.input.has_event_pending() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Catches up after the kernel dropped events because we didn't
+    /// read them quickly enough.  libevdev describes how the state of
+    /// the device changed in the meantime as a series of events, which
+    /// are processed as usual, and then any key that is still thought
+    /// to be held, but which the device says is up, is released.
+    fn resync(&mut self) -> Result<()> {
+        log::warn!(
+            "{} dropped events; resynchronizing",
+            self.device_path.display()
+        );
+        loop {
+            let event = match self.input.next_event(ReadFlag::SYNC) {
+                std::result::Result::Ok((_, event)) => event,
+                // All of the changes have been seen
+                Err(err) if err.raw_os_error() == Some(libc::EAGAIN) => break,
+                Err(err) if err.raw_os_error() == Some(libc::ENODEV) => return self.detach(),
+                Err(err) => return Err(err.into()),
+            };
+            if self.paused {
+                log::trace!("PAUSED {:?}", event);
+                continue;
+            }
+            log::debug!("SYNC {:?}", event);
+            if self.output.is_none() {
+                self.print_input(&event);
+            }
+            let output = self.engine.process_event(&event)?;
+            self.write_outputs(output)?;
+        }
+        if !self.paused {
+            self.release_keys_not_held()?;
+        }
+        Ok(())
+    }
+
+    /// Releases the keys that the engine thinks are held, but which
+    /// the input device says are up
+    fn release_keys_not_held(&mut self) -> Result<()> {
+        let input = &self.input;
+        let output = self.engine.release_keys_not_held(
+            |key| input.event_value(&EventCode::EV_KEY(key)) != Some(0),
+            &now(),
+        )?;
+        self.write_outputs(output)
+    }
+
+    /// Parse the config file again and switch over to its mappings.
+    /// If it can't be loaded, the current mappings are kept.
+    fn reload_config(&mut self) -> Result<()> {
+        let Some((config_file, _)) = &self.config_file else {
+            return Ok(());
+        };
+        let config_file = config_file.clone();
+        match self.load_config() {
+            std::result::Result::Ok(Some(config)) => {
+                if config.reload_scope == ReloadScope::Full {
+                    self.select_device(DeviceSelector {
+                        path: config.path.clone(),
+                        sysfs_path: config.sysfs_path.clone(),
+                        name: config.device_name.clone(),
+                        phys: config.phys.clone(),
+                    })?;
+                }
+                self.apply_config(config)?;
+                log::info!("Reloaded {}", config_file.display());
+            }
+            std::result::Result::Ok(None) => {}
+            Err(err) => log::error!(
+                "Keeping the current config, because {} could not be loaded: {err:#}",
+                config_file.display()
+            ),
+        }
+        Ok(())
+    }
+
+    /// Parse the config file for this device, if it is being watched
+    fn load_config(&self) -> Result<Option<MappingConfig>> {
+        let Some((config_file, device_index)) = &self.config_file else {
+            return Ok(None);
+        };
+        let config = MappingConfig::from_file(config_file)?
+            .split_devices()
+            .into_iter()
+            .nth(*device_index)
+            .ok_or_else(|| anyhow!("device section {} was removed", device_index + 1))?;
+        Ok(Some(config))
+    }
+
+    /// Switch over to the mappings and settings in `config`.
+    /// The device settings are ignored, as the device remains grabbed.
+    fn apply_config(&mut self, config: MappingConfig) -> Result<()> {
+        // Keys can't be added to the output device once it exists
+        let mut missing: Vec<KeyCode> = mapping_output_keys(&config.all_mappings())
+            .into_iter()
+            .chain(config.modifier_rewrite.values().cloned())
+            .chain(config.extra_output_keys.iter().cloned())
+            .chain(config.autoshift.is_some().then_some(KeyCode::KEY_LEFTSHIFT))
+            .filter(|key| !self.supported_output_keys.contains(key))
+            .collect();
+        if !missing.is_empty() {
+            missing.sort_by_key(|key| format!("{key:?}"));
+            log::warn!(
+                "The virtual device doesn't support {missing:?}; \
+                 restart evremap to be able to produce them"
+            );
+        }
+        if config.keepalive_interval.is_some() && self.keepalive_interval.is_none() {
+            log::warn!("restart evremap to enable keepalive_interval_ms");
+        }
+        if self.keepalive_interval.is_some() {
+            self.keepalive_interval = config.keepalive_interval;
+        }
+        if self.focus_watcher.is_none() {
+            self.focus_watcher = start_focus_watcher(&config);
+        }
+        if self.lock_watcher.is_none() {
+            self.lock_watcher = start_lock_watcher(&config);
+        }
+        let switches = condition_switches(&config);
+
+        let output = self.engine.apply_config(config, &now())?;
+        self.write_outputs(output)?;
+        self.watch_switches(&switches)
+    }
+
+    /// Follows `switches`, on the input device if it has them, and
+    /// otherwise on the other devices that do, and tells the engine
+    /// which of them are on
+    fn watch_switches(&mut self, switches: &HashSet<SwitchCode>) -> Result<()> {
+        let elsewhere: HashSet<SwitchCode> = switches
+            .iter()
+            .filter(|switch| !self.input.has(EventCode::EV_SW(**switch)))
+            .copied()
+            .collect();
+        self.switch_devices = if elsewhere.is_empty() {
+            vec![]
+        } else {
+            open_switch_devices(&elsewhere, &self.device_path)
+        };
+        for switch in switches {
+            let code = EventCode::EV_SW(*switch);
+            let value = std::iter::once(&self.input)
+                .chain(&self.switch_devices)
+                .find(|device| device.has(code))
+                .and_then(|device| device.event_value(&code));
+            let Some(value) = value else {
+                log::warn!(
+                    "No device reports {switch:?}, so layers that depend on it \
+                     take it to be off"
+                );
+                continue;
+            };
+            let output = self.engine.set_switch(*switch, value != 0, &now())?;
+            self.write_outputs(output)?;
+        }
+        Ok(())
+    }
+
+    /// Passes the changes of the switches of one of the switch devices
+    /// on to the engine
+    fn read_switches(&mut self, idx: usize) -> Result<()> {
+        loop {
+            let event = match self.switch_devices[idx].next_event(ReadFlag::NORMAL) {
+                std::result::Result::Ok((evdev_rs::ReadStatus::Success, event)) => event,
+                // libevdev has caught up with the state of the device,
+                // but only describes the changes in sync mode
+                std::result::Result::Ok((evdev_rs::ReadStatus::Sync, _)) => {
+                    let device = &self.switch_devices[idx];
+                    let states: Vec<(SwitchCode, bool)> = EventCode::EV_SW(SwitchCode::SW_LID)
+                        .iter()
+                        .filter_map(|code| match code {
+                            EventCode::EV_SW(switch) if device.has(code) => {
+                                Some((switch, device.event_value(&code) == Some(1)))
+                            }
+                            _ => None,
+                        })
+                        .collect();
+                    for (switch, on) in states {
+                        let output = self.engine.set_switch(switch, on, &now())?;
+                        self.write_outputs(output)?;
+                    }
+                    continue;
+                }
+                Err(err) if err.raw_os_error() == Some(libc::EAGAIN) => return Ok(()),
+                Err(err) => {
+                    log::warn!("No longer following a switch device: {err}");
+                    self.switch_devices.remove(idx);
+                    return Ok(());
+                }
+            };
+            if let EventCode::EV_SW(switch) = event.event_code {
+                log::trace!("SWITCH {:?}", event);
+                let output = self.engine.set_switch(switch, event.value != 0, &now())?;
+                self.write_outputs(output)?;
+            }
+        }
+    }
+
+    /// Returns the earliest time at which `handle_timeouts` has
+    /// something to do, or None if there is no pending timeout.
+    fn next_deadline(&self) -> Option<TimeVal> {
+        [self.engine.next_deadline(), self.keepalive_deadline()]
+            .into_iter()
+            .flatten()
+            .min()
+    }
+
+    fn handle_timeouts(&mut self, time: &TimeVal) -> Result<()> {
+        if let Some(deadline) = self.keepalive_deadline() {
+            if deadline <= *time {
+                self.keepalive(time);
+            }
+        }
+        let output = self.engine.handle_timeouts(time)?;
+        self.write_outputs(output)
+    }
+
+    fn keepalive_deadline(&self) -> Option<TimeVal> {
+        self.keepalive_interval
+            .map(|interval| timeval_add(&self.last_keepalive, interval))
+    }
+
+    /// Some devices are suspended by power management when they have
+    /// been idle for a while, which delays the first keystroke after
+    /// they wake up.  Re-sending the current state of the LEDs to the
+    /// device is harmless, but is enough to keep it awake.
+    fn keepalive(&mut self, time: &TimeVal) {
+        self.last_keepalive = *time;
+        for led in device_leds(&self.input) {
+            let state = match self.input.event_value(&led) {
+                Some(0) => LedState::Off,
+                _ => LedState::On,
+            };
+            if let Err(err) = self.input.kernel_set_led_value(&led, state) {
+                log::debug!("keepalive: failed to set {led:?}: {err:#}");
+            }
+        }
+    }
+
+    /// Writes the output of the engine to the virtual device,
+    /// or for a dry run, prints the events of interest
+    fn write_outputs(&mut self, output: Vec<Output>) -> Result<()> {
+        for item in output {
+            match (&self.output, item) {
+                (Some(device), Output::Event(event)) => device.write_event(&event)?,
+                (None, Output::Event(event)) => {
+                    if matches!(
+                        event.event_code,
+                        EventCode::EV_KEY(_) | EventCode::EV_REL(_)
+                    ) {
+                        println!("    OUT {}", describe_event(&event));
+                    }
+                }
+                (Some(_), Output::Run(command)) => {
+                    if let Err(err) = spawn::spawn(&command) {
+                        log::error!("Unable to run {:?}: {err:#}", command.argv);
+                    }
+                }
+                (None, Output::Run(command)) => println!("    RUN {:?}", command.argv),
+            }
+        }
+        self.update_indicator_leds();
+        Ok(())
+    }
+
+    /// Lights the LEDs of the input device that indicate the layers
+    /// and profiles that are active, if that has changed
+    fn update_indicator_leds(&mut self) {
+        if self.output.is_none() || self.detached {
+            return;
+        }
+        let leds = self.engine.indicator_leds();
+        for (led, lit) in &leds {
+            if self.indicator_leds.contains(&(*led, *lit)) {
+                continue;
+            }
+            let state = if *lit { LedState::On } else { LedState::Off };
+            if let Err(err) = self
+                .input
+                .kernel_set_led_value(&EventCode::EV_LED(*led), state)
+            {
+                log::warn!("Unable to set {led:?}: {err:#}");
+            }
+        }
+        self.indicator_leds = leds;
+    }
+
+    /// The input device is grabbed, so the LEDs that the session sets,
+    /// such as CapsLock and NumLock, are set on the virtual device
+    /// instead.  Reads them back from it and sets them on the input
+    /// device, other than those that indicate a layer or profile.
+    fn forward_leds(&mut self) -> Result<()> {
+        let Some(fd) = self.output.as_ref().and_then(|output| output.as_fd()) else {
+            return Ok(());
+        };
+        loop {
+            let mut event = std::mem::MaybeUninit::<libc::input_event>::uninit();
+            let size = std::mem::size_of::<libc::input_event>();
+            let len = unsafe { libc::read(fd, event.as_mut_ptr().cast(), size) };
+            if len < 0 {
+                let err = std::io::Error::last_os_error();
+                if err.kind() == std::io::ErrorKind::WouldBlock {
+                    return Ok(());
+                }
+                return Err(err).context("reading the LEDs of the virtual device");
+            }
+            if len as usize != size {
+                return Ok(());
+            }
+            let event = unsafe { event.assume_init() };
+            if event.type_ != EventType::EV_LED as u16 {
+                continue;
+            }
+            let Some(led) = evdev_rs::enums::int_to_ev_led(event.code.into()) else {
+                continue;
+            };
+            if self.detached || self.indicator_leds.iter().any(|(l, _)| *l == led) {
+                continue;
+            }
+            log::trace!("LED {led:?} {}", event.value);
+            let state = if event.value != 0 {
+                LedState::On
+            } else {
+                LedState::Off
+            };
+            if let Err(err) = self
+                .input
+                .kernel_set_led_value(&EventCode::EV_LED(led), state)
+            {
+                log::warn!("Unable to set {led:?}: {err:#}");
+            }
+        }
+    }
+
+    /// Prints an input event for a dry run, along with the
+    /// entry that it matches
+    fn print_input(&self, event: &InputEvent) {
+        let rule = match event.event_code {
+            EventCode::EV_KEY(key) => match self.engine.matching_rule(key) {
+                Some(map) => map.to_string(),
+                None => "no mapping".to_string(),
+            },
+            EventCode::EV_REL(_) => match self.engine.lookup_encoder_mapping(event) {
+                Some(map) => map.to_string(),
+                None => return,
+            },
+            _ => return,
+        };
+        println!("IN {} ({rule})", describe_event(event));
+    }
+}
+
+impl MappingEngine {
+    pub fn new(config: MappingConfig, time: &TimeVal) -> Self {
+        let low_latency_passthrough = if config.low_latency_passthrough {
+            Some(low_latency_mapped_keys(
+                &config.all_mappings(),
+                &config.modifier_rewrite,
+            ))
+        } else {
+            None
+        };
+        let mapped_input_keys = mapping_input_keys(&config.all_mappings());
+        let mappings = config.mappings;
+
+        let mut engine = Self {
+            pending_output: vec![],
+            input_state: HashMap::new(),
+            released_held: HashSet::new(),
+            output_keys: HashSet::new(),
+            output_order: vec![],
+            tapping: None,
+            hold_consumed: HashSet::new(),
+            dual_role_pending: None,
+            last_dual_role_tap: None,
+            quick_tapping: HashSet::new(),
+            dual_role_tapped: HashSet::new(),
+            hands: config.hands,
+            suppress_modifier_pulses: config.suppress_modifier_pulses,
+            resolved_holds: HashSet::new(),
+            modifier_settle: config.modifier_settle,
+            settling: None,
+            multi_function: HashMap::new(),
+            locked: HashMap::new(),
+            macro_queue: VecDeque::new(),
+            macro_held: HashSet::new(),
+            frame_scan: None,
+            scan_held: HashMap::new(),
+            mouse_keys: HashMap::new(),
+            one_shot: HashMap::new(),
+            caps_word: false,
+            autoshift: config.autoshift,
+            autoshift_pending: None,
+            autoshift_typed: HashSet::new(),
+            tap_dance: HashMap::new(),
+            turbo: HashMap::new(),
+            last_mouse_key_tick: *time,
+            next_macro_key: *time,
+            held_commands: HashMap::new(),
+            sequence: None,
+            sequence_swallow: HashSet::new(),
+            replaying_sequence: false,
+            encoder_state: HashMap::new(),
+            mappings: mappings.clone(),
+            pressed_under: HashMap::new(),
+            block_unmapped: None,
+            blocked_keys: HashSet::new(),
+            base_mappings: mappings,
+            layers: config.layers,
+            active_layers: vec![],
+            layer_keys: HashMap::new(),
+            last_layer_tap: None,
+            active_profile: config.default_profile,
+            profiles: config.profiles,
+            profile_keys: HashSet::new(),
+            focused_app: None,
+            locks: LockState::default(),
+            switches: HashSet::new(),
+            locks_changed: false,
+            release_triggers: config.release_triggers,
+            last_input: *time,
+            keys_only: config.keys_only,
+            sanitize: config.sanitize,
+            overlay: config.overlay,
+            physical_keys: HashSet::new(),
+            overlay_keys: HashSet::new(),
+            overlay_pending_syn: false,
+            periodic_syn: config.periodic_syn,
+            low_latency_passthrough,
+            passthrough_held: HashSet::new(),
+            mapped_input_keys,
+            last_periodic_syn: *time,
+            modifier_rewrite: config.modifier_rewrite,
+            rewrite_held: HashMap::new(),
+            max_drag: config.max_drag,
+            max_hold: config.max_hold,
+            held_since: HashMap::new(),
+            expired_holds: HashSet::new(),
+            debounce: config.debounce,
+            debounce_keys: config.debounce_keys,
+            debounce_state: HashMap::new(),
+            debounced: 0,
+        };
+        engine.rebuild_mappings();
+        engine
+    }
+
+    /// Processes an event from the input device, and returns
+    /// what to write to the output device as a result
+    pub fn process_event(&mut self, event: &InputEvent) -> Result<Vec<Output>> {
+        self.expire_timeouts(&event.time)?;
+        self.last_input = event.time;
+        match event.event_code {
+            EventCode::EV_MSC(evdev_rs::enums::EV_MSC::MSC_SCAN) => {
+                self.frame_scan = Some(event.value as u32);
+            }
+            EventCode::EV_SYN(evdev_rs::enums::EV_SYN::SYN_REPORT) => self.frame_scan = None,
+            _ => {}
+        }
+        if let EventCode::EV_KEY(ref key) = event.event_code {
+            if self.overlay {
+                match KeyEventType::from_value(event.value) {
+                    KeyEventType::Release => self.physical_keys.remove(key),
+                    _ => self.physical_keys.insert(*key),
+                };
+            }
+            if self.update_scan_remap(event, *key)? {
+                log::trace!("SCAN {:?} {:?}", self.frame_scan, event);
+            } else if is_touch_key(key) && !self.mapped_input_keys.contains(key) {
+                // These say what the axes of the same frame mean, so they
+                // mustn't be held back, nor count as another key
+                log::trace!("PASSTHRU {:?}", event);
+                self.write_output(event);
+            } else if self.is_chatter(event, key) {
+                log::trace!("CHATTER {:?}", event);
+            } else if self.is_low_latency_passthrough(event, key) {
+                log::trace!("PASSTHRU {:?}", event);
+                match KeyEventType::from_value(event.value) {
+                    KeyEventType::Release => self.passthrough_held.remove(key),
+                    _ => self.passthrough_held.insert(*key),
+                };
+                self.write_output(event);
+            } else {
+                log::trace!("IN {:?}", event);
+                self.update_with_event(event, *key)?;
+            }
+        } else if let Some(mapping) = self.lookup_encoder_mapping(event) {
+            log::trace!("IN {:?}", event);
+            self.update_with_encoder_event(event, mapping)?;
+        } else if (self.keys_only || self.overlay)
+            && !matches!(event.event_code, EventCode::EV_SYN(_))
+        {
+            log::trace!("DROP {:?}", event);
+        } else {
+            log::trace!("PASSTHRU {:?}", event);
+            self.write_output(event);
+        }
+        if let EventCode::EV_SW(switch) = event.event_code {
+            self.update_switch(switch, event.value != 0, &event.time)?;
+        }
+        self.apply_lock_changes(&event.time)?;
+        Ok(self.take_output())
+    }
+
+    /// Processes a key event with the given value (1 for a press, 0 for
+    /// a release and 2 for an autorepeat) that happened at `time`
+    pub fn process_key(&mut self, key: KeyCode, value: i32, time: &TimeVal) -> Result<Vec<Output>> {
+        self.process_event(&InputEvent::new(time, &EventCode::EV_KEY(key), value))
+    }
+
+    /// Releases each key that is tracked as held but for which `is_held`
+    /// returns false, as though its release had been read at `time`.
+    /// This puts things right after events from the input device were
+    /// lost, or weren't looked at while remapping was paused.  Keys
+    /// that were held when everything was last released, and which
+    /// `is_held` says have since been released, are no longer ignored.
+    pub fn release_keys_not_held(
+        &mut self,
+        is_held: impl Fn(KeyCode) -> bool,
+        time: &TimeVal,
+    ) -> Result<Vec<Output>> {
+        self.released_held.retain(|key| is_held(*key));
+        let mut released: Vec<KeyCode> = self
+            .input_state
+            .keys()
+            .filter(|key| !is_held(**key))
+            .cloned()
+            .collect();
+        released.sort_by_key(|key| {
+            let time = self.input_state[key];
+            (time.tv_sec, time.tv_usec)
+        });
+        let mut output = vec![];
+        for key in released {
+            log::debug!("{key:?} was released while events were dropped");
+            output.extend(self.process_key(key, 0, time)?);
+        }
+        Ok(output)
+    }
+
+    /// Does whatever was waiting for `time` to be reached, such as
+    /// deciding that a key is being held rather than tapped.
+    /// `next_deadline` says when that will next be needed.
+    pub fn handle_timeouts(&mut self, time: &TimeVal) -> Result<Vec<Output>> {
+        self.expire_timeouts(time)?;
+        self.apply_lock_changes(time)?;
+        Ok(self.take_output())
+    }
+
+    fn take_output(&mut self) -> Vec<Output> {
+        std::mem::take(&mut self.pending_output)
+    }
+
+    /// Returns the LEDs of the input device that indicate a layer or
+    /// profile, and whether each should be lit.  An LED that indicates
+    /// several of them is lit while any of them is active.
+    pub fn indicator_leds(&self) -> Vec<(LedCode, bool)> {
+        let mut leds: Vec<(LedCode, bool)> = vec![];
+        let layers = self.layers.iter().enumerate().map(|(idx, layer)| {
+            let active = self.active_layers.iter().any(|(active, _)| *active == idx);
+            (layer.led, active)
+        });
+        let profiles = self
+            .profiles
+            .iter()
+            .enumerate()
+            .map(|(idx, profile)| (profile.led, idx == self.active_profile));
+        for (led, active) in layers.chain(profiles) {
+            let Some(led) = led else {
+                continue;
+            };
+            match leds.iter_mut().find(|(l, _)| *l == led) {
+                Some((_, lit)) => *lit |= active,
+                None => leds.push((led, active)),
+            }
+        }
+        leds
+    }
+
+    /// Returns the keys that are held on the output device: the targets
+    /// of modifier_rewrite in place of the keys that they replace, and
+    /// the keys that were passed straight through
+    pub fn held_output_keys(&self) -> Vec<KeyCode> {
+        let rewritten = |key: &&KeyCode| {
+            self.modifier_rewrite.contains_key(key)
+                || self.modifier_rewrite.values().any(|target| target == *key)
+        };
+        let mut keys: Vec<KeyCode> = self
+            .output_keys
+            .iter()
+            .filter(|key| !rewritten(key))
+            .chain(
+                self.rewrite_held
+                    .iter()
+                    .filter(|(_, holders)| !holders.is_empty())
+                    .map(|(target, _)| target),
+            )
+            .chain(self.passthrough_held.iter())
+            .cloned()
+            .collect();
+        keys.sort();
+        keys.dedup();
+        keys
+    }
+
+    /// Returns the name of the active profile, if there are any profiles
+    pub fn profile_name(&self) -> Option<&str> {
+        self.profiles
+            .get(self.active_profile)
+            .map(|profile| profile.name.as_str())
+    }
+
+    /// Switches to the profile named `name`.  Everything is released
+    /// first, since the keys that are held may not mean the same thing
+    /// in the new profile.
+    pub fn set_profile(&mut self, name: &str, time: &TimeVal) -> Result<Vec<Output>> {
+        let Some(profile) = self.profiles.iter().position(|p| p.name == name) else {
+            bail!("no profile is named `{name}`");
+        };
+        self.switch_profile(profile, time)?;
+        Ok(self.take_output())
+    }
+
+    /// Switches to the profile after the active one, wrapping
+    /// around after the last
+    pub fn next_profile(&mut self, time: &TimeVal) -> Result<Vec<Output>> {
+        if !self.profiles.is_empty() {
+            self.switch_profile((self.active_profile + 1) % self.profiles.len(), time)?;
+        }
+        Ok(self.take_output())
+    }
+
+    fn switch_profile(&mut self, profile: usize, time: &TimeVal) -> Result<()> {
+        if profile == self.active_profile {
+            return Ok(());
+        }
+        self.release_all(time)?;
+        self.active_profile = profile;
+        log::info!("Switched to profile `{}`", self.profiles[profile].name);
+        self.layers_changed(time)
+    }
+
+    /// Tells the engine that a login session was locked, which releases
+    /// everything if that is one of its `release_all` triggers
+    pub fn session_locked(&mut self, time: &TimeVal) -> Result<Vec<Output>> {
+        if self.release_triggers.contains(&ReleaseTrigger::SessionLock)
+            && !(self.output_keys.is_empty() && self.input_state.is_empty())
+        {
+            log::info!("The session was locked; releasing all held keys");
+            self.release_all(time)?;
+        }
+        Ok(self.take_output())
+    }
+
+    /// Tells the engine which application has the focus, so that the
+    /// layers whose `only_when` condition it satisfies are activated
+    pub fn set_focused_app(&mut self, app: Option<String>, time: &TimeVal) -> Result<Vec<Output>> {
+        if app != self.focused_app {
+            self.focused_app = app;
+            self.layers_changed(time)?;
+        }
+        Ok(self.take_output())
+    }
+
+    /// Tells the engine which locks are on, so that the layers whose
+    /// `only_when` condition they satisfy are activated
+    pub fn set_lock_state(&mut self, locks: LockState, time: &TimeVal) -> Result<Vec<Output>> {
+        if locks != self.locks {
+            self.locks = locks;
+            self.layers_changed(time)?;
+        }
+        Ok(self.take_output())
+    }
+
+    /// Tells the engine whether a switch is on, so that the layers whose
+    /// `only_when` condition it satisfies are activated
+    pub fn set_switch(
+        &mut self,
+        switch: SwitchCode,
+        on: bool,
+        time: &TimeVal,
+    ) -> Result<Vec<Output>> {
+        self.update_switch(switch, on, time)?;
+        Ok(self.take_output())
+    }
+
+    fn update_switch(&mut self, switch: SwitchCode, on: bool, time: &TimeVal) -> Result<()> {
+        let changed = if on {
+            self.switches.insert(switch)
+        } else {
+            self.switches.remove(&switch)
+        };
+        if changed {
+            log::debug!("{switch:?} is {}", if on { "on" } else { "off" });
+            self.layers_changed(time)?;
+        }
+        Ok(())
+    }
+
+    /// Applies the layers that depend on the locks, once a lock key
+    /// that was pressed on the output device has been processed
+    fn apply_lock_changes(&mut self, time: &TimeVal) -> Result<()> {
+        if std::mem::take(&mut self.locks_changed) {
+            self.layers_changed(time)?;
+        }
+        Ok(())
+    }
+
+    /// Replace the mappings and settings with those from `config`.
+    /// Everything is released first, since the keys that are held
+    /// may not mean the same thing afterwards.  The device settings
+    /// are ignored.
+    pub fn apply_config(&mut self, config: MappingConfig, time: &TimeVal) -> Result<Vec<Output>> {
+        self.release_all(time)?;
+        self.active_layers.clear();
+        self.low_latency_passthrough = if config.low_latency_passthrough {
+            Some(low_latency_mapped_keys(
+                &config.all_mappings(),
+                &config.modifier_rewrite,
+            ))
+        } else {
+            None
+        };
+        self.mapped_input_keys = mapping_input_keys(&config.all_mappings());
+        self.base_mappings = config.mappings;
+        self.layers = config.layers;
+        // Stay in the same profile, if it still exists
+        let profile = self.profile_name().map(str::to_string);
+        self.active_profile = profile
+            .and_then(|name| config.profiles.iter().position(|p| p.name == name))
+            .unwrap_or(config.default_profile);
+        self.profiles = config.profiles;
+        self.rebuild_mappings();
+        self.release_triggers = config.release_triggers;
+        self.keys_only = config.keys_only;
+        self.sanitize = config.sanitize;
+        self.periodic_syn = config.periodic_syn;
+        self.modifier_rewrite = config.modifier_rewrite;
+        self.max_drag = config.max_drag;
+        self.max_hold = config.max_hold;
+        self.autoshift = config.autoshift;
+        self.hands = config.hands;
+        self.suppress_modifier_pulses = config.suppress_modifier_pulses;
+        self.modifier_settle = config.modifier_settle;
+        self.debounce = config.debounce;
+        self.debounce_keys = config.debounce_keys;
+        Ok(self.take_output())
+    }
+
+    /// Describes the keys that are held, and anything that is in
+    /// progress, as a single line of JSON for diagnosing stuck keys
+    pub fn state_as_json(&self) -> String {
+        let mut multi_function: Vec<String> = self
+            .multi_function
+            .iter()
+            .map(|(key, state)| {
+                let state = match state {
+                    MultiFunctionState::Pressed { second: false, .. } => "pressed",
+                    MultiFunctionState::Pressed { second: true, .. } => "pressed_again",
+                    MultiFunctionState::Released { .. } => "released",
+                    MultiFunctionState::Holding { after_tap: false } => "holding",
+                    MultiFunctionState::Holding { after_tap: true } => "holding_after_tap",
+                };
+                format!("\"{key:?}\":\"{state}\"")
+            })
+            .collect();
+        multi_function.sort();
+        format!(
+            "{{\"input\":{},\"output\":{},\"tapping\":{},\"multi_function\":{{{}}},\"sequence\":{},\"locked\":{},\"expired_holds\":{},\"layers\":[{}],\"locked_layers\":[{}],\"profile\":{},\"mouse_keys\":{},\"one_shot\":{},\"caps_word\":{},\"autoshift\":{},\"dual_role\":{},\"tap_dance\":{},\"turbo\":{},\"debounced\":{}}}",
+            json_key_list(self.input_state.keys()),
+            json_key_list(self.output_keys.iter()),
+            json_key_list(self.tapping.iter()),
+            multi_function.join(","),
+            json_key_list(self.sequence.iter().flat_map(|pending| &pending.pressed)),
+            json_key_list(self.locked.keys()),
+            json_key_list(self.expired_holds.iter()),
+            self.active_layers
+                .iter()
+                .map(|(layer, _)| format!("\"{}\"", self.layers[*layer].name))
+                .collect::<Vec<_>>()
+                .join(","),
+            self.active_layers
+                .iter()
+                .filter(|(_, mode)| *mode == LayerMode::Toggle)
+                .map(|(layer, _)| format!("\"{}\"", self.layers[*layer].name))
+                .collect::<Vec<_>>()
+                .join(","),
+            self.profile_name()
+                .map_or("null".to_string(), crate::deviceinfo::json_string),
+            json_key_list(self.mouse_keys.keys()),
+            json_key_list(self.one_shot.keys()),
+            self.caps_word,
+            json_key_list(self.autoshift_pending.iter().map(|(key, _)| key)),
+            json_key_list(self.dual_role_pending.iter().map(|pending| &pending.input)),
+            json_key_list(self.tap_dance.keys()),
+            json_key_list(self.turbo.keys()),
+            self.debounced,
+        )
+    }
+
+    /// Returns true if the event is switch chatter: a change of a key
+    /// that is being debounced, within the time since its last change.
+    /// The last such change is processed once that time is up, if it
+    /// leaves the key in a different state.
+    fn is_chatter(&mut self, event: &InputEvent, key: &KeyCode) -> bool {
+        let Some(window) = self.debounce_keys.get(key).copied().or(self.debounce) else {
+            return false;
+        };
+        let state = self
+            .debounce_state
+            .get_mut(key)
+            .filter(|state| event.time < state.until);
+        match (KeyEventType::from_value(event.value), state) {
+            // Autorepeat only follows a change that was processed
+            (KeyEventType::Repeat, state) => state.is_some_and(|state| state.value == 0),
+            (_, Some(state)) => {
+                if state.latest.replace(event.value).is_some() {
+                    self.count_chatter(key);
+                }
+                true
+            }
+            (_, None) => {
+                self.debounce_state.insert(
+                    *key,
+                    Debounce {
+                        value: event.value,
+                        latest: None,
+                        until: timeval_add(&event.time, window),
+                    },
+                );
+                false
+            }
+        }
+    }
+
+    fn count_chatter(&mut self, key: &KeyCode) {
+        self.debounced += 1;
+        log::info!(
+            "Ignored chatter from {key:?}; {} events ignored so far",
+            self.debounced
+        );
+    }
+
+    fn debounce_deadline(&self) -> Option<TimeVal> {
+        self.debounce_state
+            .values()
+            .filter(|state| state.latest.is_some())
+            .map(|state| state.until)
+            .min()
+    }
+
+    /// Processes the changes that were held back by debouncing, once
+    /// they have outlasted the chatter
+    fn handle_debounce_timeouts(&mut self, time: &TimeVal) -> Result<()> {
+        let mut expired: Vec<(KeyCode, Debounce)> = vec![];
+        self.debounce_state.retain(|key, state| {
+            if state.until > *time {
+                return true;
+            }
+            if state.latest.is_some() {
+                expired.push((*key, *state));
+            }
+            false
+        });
+        expired.sort_by_key(|(_, state)| state.until);
+        for (key, state) in expired {
+            let Some(value) = state.latest else {
+                continue;
+            };
+            if value == state.value {
+                // It went back to how it was
+                self.count_chatter(&key);
+                continue;
+            }
+            let event = InputEvent::new(&state.until, &EventCode::EV_KEY(key), value);
+            log::trace!("DEBOUNCED {:?}", event);
+            if let Some(window) = self.debounce_keys.get(&key).copied().or(self.debounce) {
+                self.debounce_state.insert(
+                    key,
+                    Debounce {
+                        value,
+                        latest: None,
+                        until: timeval_add(&state.until, window),
+                    },
+                );
+            }
+            self.update_with_event(&event, key)?;
+        }
+        Ok(())
+    }
+
+    /// Returns true if the event can be passed through to the output
+    /// device exactly as it is, relying on the input device to send the
+    /// SYN_REPORT that follows it.  That is the case for a key that isn't
+    /// mapped, and which isn't tracked in input_state, when nothing else
+    /// is in progress that might need to know that it was pressed.
+    fn is_low_latency_passthrough(&self, event: &InputEvent, key: &KeyCode) -> bool {
+        let Some(mapped_keys) = &self.low_latency_passthrough else {
+            return false;
+        };
+        if mapped_keys.contains(key)
+            || self.input_state.contains_key(key)
+            || self.released_held.contains(key)
+            || self.sanitize
+        {
+            return false;
+        }
+        if self.autoshift_pending.is_some()
+            || self.dual_role_pending.is_some()
+            || !self.one_shot.is_empty()
+            || !self.tap_dance.is_empty()
+            || !self.macro_queue.is_empty()
+            || !self.turbo.is_empty()
+            || self.settling.is_some()
+            || self.block_unmapped.is_some()
+            || self.blocked_keys.contains(key)
+            || self.autoshift_typed.contains(key)
+            || self
+                .autoshift
+                .as_ref()
+                .is_some_and(|a| a.keys.contains(key))
+        {
+            return false;
+        }
+        match KeyEventType::from_value(event.value) {
+            KeyEventType::Press => {
+                self.input_state.is_empty()
+                    && self.multi_function.is_empty()
+                    && self.sequence.is_none()
+                    && !self.caps_word
+            }
+            _ => true,
+        }
+    }
+
+    /// Returns the earliest time at which `handle_timeouts` has
+    /// something to do, or None if there is no pending timeout.
+    pub fn next_deadline(&self) -> Option<TimeVal> {
+        [
+            self.release_deadline(),
+            self.multi_function_deadline(),
+            self.deferred_hold_deadline(),
+            self.mod_lock_deadline(),
+            self.macro_deadline(),
+            self.mouse_key_deadline(),
+            self.tap_dance_deadline(),
+            self.turbo.values().map(|state| state.next).min(),
+            self.held_commands
+                .values()
+                .map(|(_, deadline)| *deadline)
+                .min(),
+            self.autoshift_deadline(),
+            self.dual_role_pending
+                .as_ref()
+                .map(|pending| pending.deadline),
+            self.settling.as_ref().map(|pending| pending.deadline),
+            self.sequence.as_ref().map(|pending| pending.deadline),
+            self.hold_deadline(),
+            self.periodic_syn_deadline(),
+            self.debounce_deadline(),
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+    }
+
+    fn expire_timeouts(&mut self, time: &TimeVal) -> Result<()> {
+        self.handle_debounce_timeouts(time)?;
+        if let Some(pending) = self.settling.take_if(|pending| pending.deadline <= *time) {
+            self.compute_and_apply_keys(&pending.deadline)?;
+            for event in &pending.held_back {
+                if let EventCode::EV_KEY(key) = event.event_code {
+                    // Not before the keys that were waiting for the modifiers
+                    let event = InputEvent::new(&pending.deadline, &event.event_code, event.value);
+                    self.update_with_event(&event, key)?;
+                }
+            }
+        }
+        if let Some(pending) = self.sequence.take_if(|pending| pending.deadline <= *time) {
+            self.replay_sequence(pending)?;
+        }
+        self.handle_multi_function_timeouts(time)?;
+        if self
+            .dual_role_pending
+            .as_ref()
+            .is_some_and(|pending| pending.deadline <= *time)
+        {
+            self.decide_dual_role(true, time)?;
+        }
+        if let Some((code, deadline)) = self.deferred_hold() {
+            if deadline <= *time {
+                self.resolved_holds.insert(code);
+                self.compute_and_apply_keys(time)?;
+            }
+        }
+        if let Some(deadline) = self.mod_lock_deadline() {
+            if deadline <= *time {
+                self.handle_mod_lock_timeouts(time)?;
+            }
+        }
+        self.type_macro_keys(time)?;
+        self.run_held_commands(time);
+        self.handle_tap_dance_timeouts(time)?;
+        self.handle_turbo_timeouts(time)?;
+        if let Some(deadline) = self.autoshift_deadline() {
+            if deadline <= *time {
+                self.type_autoshift(true, time)?;
+            }
+        }
+        if let Some(deadline) = self.mouse_key_deadline() {
+            if deadline <= *time {
+                self.move_mouse(time)?;
+            }
+        }
+        if let Some(deadline) = self.release_deadline() {
+            if deadline <= *time {
+                log::info!("No input for a while; releasing all held keys");
+                self.release_all(time)?;
+            }
+        }
+        self.handle_hold_timeouts(time)?;
+        if let Some(deadline) = self.periodic_syn_deadline() {
+            if deadline <= *time {
+                self.last_periodic_syn = *time;
+                self.generate_sync_event(time)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn periodic_syn_deadline(&self) -> Option<TimeVal> {
+        self.periodic_syn
+            .map(|interval| timeval_add(&self.last_periodic_syn, interval))
+    }
+
+    /// Returns how long `key` may be held on the output device
+    /// before it is released by the watchdog, if there is a limit
+    fn max_hold_of(&self, key: &KeyCode) -> Option<Duration> {
+        let max_drag = self.max_drag.filter(|_| is_mouse_button(key));
+        match (self.max_hold, max_drag) {
+            (Some(max_hold), Some(max_drag)) => Some(max_hold.min(max_drag)),
+            (max_hold, max_drag) => max_hold.or(max_drag),
+        }
+    }
+
+    fn hold_deadline(&self) -> Option<TimeVal> {
+        self.held_since
+            .iter()
+            .filter_map(|(key, since)| Some(timeval_add(since, self.max_hold_of(key)?)))
+            .min()
+    }
+
+    /// Release any keys that have been held for longer than max_hold,
+    /// and mouse buttons held for longer than max_drag, so that a
+    /// missed release doesn't leave a key or a drag stuck
+    fn handle_hold_timeouts(&mut self, time: &TimeVal) -> Result<()> {
+        let mut expired: Vec<KeyCode> = self
+            .held_since
+            .iter()
+            .filter(|(key, since)| {
+                self.max_hold_of(key)
+                    .is_some_and(|limit| timeval_add(since, limit) <= *time)
+            })
+            .map(|(key, _)| *key)
+            .collect();
+        if expired.is_empty() {
+            return Ok(());
+        }
+        expired.sort();
+        log::warn!("{expired:?} held for longer than allowed; releasing");
+        self.expired_holds.extend(expired.iter().cloned());
+        self.emit_keys(&expired, time, KeyEventType::Release)
+    }
+
+    fn release_deadline(&self) -> Option<TimeVal> {
+        if self.output_keys.is_empty() && self.input_state.is_empty() {
+            return None;
+        }
+        self.release_triggers
+            .iter()
+            .filter_map(|trigger| match trigger {
+                ReleaseTrigger::Idle(idle) => Some(timeval_add(&self.last_input, *idle)),
+                ReleaseTrigger::SessionLock => None,
+            })
+            .min()
+    }
+
+    /// Returns the DualRole key whose hold keys are being held back, either
+    /// because it has a hold_timeout or, for its modifiers only, because of
+    /// suppress_modifier_pulses, and when they will be pressed anyway.
+    /// That is the case while it is the only key held, until its tap
+    /// timeout has elapsed.
+    fn deferred_hold(&self) -> Option<(KeyCode, TimeVal)> {
+        if self.input_state.len() != 1 {
+            return None;
+        }
+        let (code, pressed_at) = self.input_state.iter().next()?;
+        if self.resolved_holds.contains(code) {
+            return None;
+        }
+        let Some(Mapping::DualRole { hold_timeout, .. }) = self.lookup_dual_role_mapping(*code)
+        else {
+            return None;
+        };
+        if hold_timeout.is_none() && !self.suppress_modifier_pulses {
+            return None;
+        }
+        Some((
+            *code,
+            timeval_add(pressed_at, hold_timeout.unwrap_or(DUAL_ROLE_TAP_TIMEOUT)),
+        ))
+    }
+
+    /// Returns the time at which each locked ModLock key times out
+    fn mod_lock_timeouts(&self) -> impl Iterator<Item = (KeyCode, TimeVal)> + '_ {
+        self.mappings.iter().filter_map(|map| match map {
+            Mapping::ModLock {
+                input,
+                timeout: Some(timeout),
+                ..
+            } => self
+                .locked
+                .get(input)
+                .map(|since| (*input, timeval_add(since, *timeout))),
+            _ => None,
+        })
+    }
+
+    fn mod_lock_deadline(&self) -> Option<TimeVal> {
+        self.mod_lock_timeouts().map(|(_, deadline)| deadline).min()
+    }
+
+    fn handle_mod_lock_timeouts(&mut self, time: &TimeVal) -> Result<()> {
+        let expired: Vec<KeyCode> = self
+            .mod_lock_timeouts()
+            .filter(|(_, deadline)| deadline <= time)
+            .map(|(input, _)| input)
+            .collect();
+        for input in expired {
+            log::debug!("{input:?}: lock timed out");
+            self.locked.remove(&input);
+        }
+        self.compute_and_apply_keys(time)
+    }
+
+    fn deferred_hold_deadline(&self) -> Option<TimeVal> {
+        self.deferred_hold().map(|(_, deadline)| deadline)
+    }
+
+    fn multi_function_deadline(&self) -> Option<TimeVal> {
+        self.multi_function
+            .iter()
+            .filter_map(|(code, state)| {
+                let timeout = match self.lookup_multi_function_mapping(*code) {
+                    Some(Mapping::MultiFunction { timeout, .. }) => timeout,
+                    _ => return None,
+                };
+                match state {
+                    MultiFunctionState::Pressed { since, .. }
+                    | MultiFunctionState::Released { since } => Some(timeval_add(since, timeout)),
+                    MultiFunctionState::Holding { .. } => None,
+                }
+            })
+            .min()
+    }
+
+    /// Resolve any MultiFunction keys whose timeout has expired:
+    /// a key that is still down becomes a hold, and a key that was
+    /// tapped once without being pressed again becomes a tap.
+    fn handle_multi_function_timeouts(&mut self, time: &TimeVal) -> Result<()> {
+        let states: Vec<(KeyCode, MultiFunctionState)> = self
+            .multi_function
+            .iter()
+            .map(|(code, state)| (*code, *state))
+            .collect();
+        let mut changed = false;
+        for (code, state) in states {
+            let (tap, timeout) = match self.lookup_multi_function_mapping(code) {
+                Some(Mapping::MultiFunction { tap, timeout, .. }) => (tap, timeout),
+                _ => continue,
+            };
+            match state {
+                MultiFunctionState::Pressed { since, second } => {
+                    if timeval_add(&since, timeout) <= *time {
+                        self.multi_function
+                            .insert(code, MultiFunctionState::Holding { after_tap: second });
+                        changed = true;
+                    }
+                }
+                MultiFunctionState::Released { since } => {
+                    if timeval_add(&since, timeout) <= *time {
+                        self.multi_function.remove(&code);
+                        self.emit_tap(&tap, time)?;
+                    }
+                }
+                MultiFunctionState::Holding { .. } => {}
+            }
+        }
+        if changed {
+            self.compute_and_apply_keys(time)?;
+        }
+        Ok(())
+    }
+
+    /// Another key was pressed; any MultiFunction keys that are still
+    /// undecided are resolved immediately: a key that is down becomes
+    /// a hold and a key that is awaiting a second tap becomes a tap.
+    fn interrupt_multi_function(&mut self, code: KeyCode, time: &TimeVal) -> Result<()> {
+        let states: Vec<(KeyCode, MultiFunctionState)> = self
+            .multi_function
+            .iter()
+            .filter(|(key, _)| **key != code)
+            .map(|(key, state)| (*key, *state))
+            .collect();
+        for (key, state) in states {
+            match state {
+                MultiFunctionState::Pressed { second, .. } => {
+                    self.multi_function
+                        .insert(key, MultiFunctionState::Holding { after_tap: second });
+                }
+                MultiFunctionState::Released { .. } => {
+                    self.multi_function.remove(&key);
+                    if let Some(Mapping::MultiFunction { tap, .. }) =
+                        self.lookup_multi_function_mapping(key)
+                    {
+                        self.emit_tap(&tap, time)?;
+                    }
+                }
+                MultiFunctionState::Holding { .. } => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn press_multi_function(&mut self, code: KeyCode, time: &TimeVal) -> Result<()> {
+        let second = matches!(
+            self.multi_function.get(&code),
+            Some(MultiFunctionState::Released { .. })
+        );
+        self.multi_function.insert(
+            code,
+            MultiFunctionState::Pressed {
+                since: *time,
+                second,
+            },
+        );
+        Ok(())
+    }
+
+    fn release_multi_function(&mut self, code: KeyCode, time: &TimeVal) -> Result<()> {
+        let (tap, double_tap, hold_after_tap) = match self.lookup_multi_function_mapping(code) {
+            Some(Mapping::MultiFunction {
+                tap,
+                double_tap,
+                hold_after_tap,
+                ..
+            }) => (tap, double_tap, hold_after_tap),
+            _ => return Ok(()),
+        };
+        match self.multi_function.remove(&code) {
+            Some(MultiFunctionState::Pressed { second: false, .. }) => {
+                if double_tap.is_empty() && hold_after_tap.is_empty() {
+                    // Nothing to wait for
+                    self.emit_tap(&tap, time)?;
+                } else {
+                    self.multi_function
+                        .insert(code, MultiFunctionState::Released { since: *time });
+                }
+            }
+            Some(MultiFunctionState::Pressed { second: true, .. }) => {
+                if double_tap.is_empty() {
+                    self.emit_tap(&tap, time)?;
+                    self.emit_tap(&tap, time)?;
+                } else {
+                    self.emit_tap(&double_tap, time)?;
+                }
+            }
+            Some(MultiFunctionState::Holding { .. })
+            | Some(MultiFunctionState::Released { .. })
+            | None => {}
+        }
+        Ok(())
+    }
+
+    /// Returns the outputs that are held by a MultiFunction key that
+    /// has been resolved as a hold
+    fn multi_function_hold_keys<'a>(
+        &self,
+        input: &KeyCode,
+        hold: &'a [KeyCode],
+        hold_after_tap: &'a [KeyCode],
+    ) -> &'a [KeyCode] {
+        match self.multi_function.get(input) {
+            Some(MultiFunctionState::Holding { after_tap: true }) if !hold_after_tap.is_empty() => {
+                hold_after_tap
+            }
+            Some(MultiFunctionState::Holding { .. }) => hold,
+            _ => &[],
+        }
+    }
+
+    fn emit_tap(&mut self, keys: &[KeyCode], time: &TimeVal) -> Result<()> {
+        if !keys.is_empty() {
+            self.emit_keys(keys, time, KeyEventType::Press)?;
+            self.emit_keys(keys, time, KeyEventType::Release)?;
+        }
+        Ok(())
+    }
+
+    /// Release every key that is held on the output device, and undo
+    /// the effects of the keys that are physically held, so that nothing
+    /// is left logically stuck down.  Those keys are then ignored until
+    /// they are released.
+    fn release_all(&mut self, time: &TimeVal) -> Result<()> {
+        self.released_held
+            .extend(self.input_state.drain().map(|(key, _)| key));
+        self.released_held
+            .extend(self.passthrough_held.iter().copied());
+        self.pressed_under.clear();
+        self.blocked_keys.clear();
+        self.hold_consumed.clear();
+        self.resolved_holds.clear();
+        self.dual_role_pending = None;
+        self.settling = None;
+        self.last_dual_role_tap = None;
+        self.quick_tapping.clear();
+        self.dual_role_tapped.clear();
+        self.locked.clear();
+        self.macro_queue.clear();
+        self.macro_held.clear();
+        self.scan_held.clear();
+        self.mouse_keys.clear();
+        self.one_shot.clear();
+        self.caps_word = false;
+        self.autoshift_pending = None;
+        self.autoshift_typed.clear();
+        self.tap_dance.clear();
+        self.turbo.clear();
+        self.multi_function.clear();
+        self.held_commands.clear();
+        self.sequence.take();
+        self.sequence_swallow.clear();
+        self.cancel_pending_tap();
+        self.layer_keys.clear();
+        self.last_layer_tap = None;
+        self.profile_keys.clear();
+        self.active_layers
+            .retain(|(_, mode)| *mode == LayerMode::Toggle);
+        self.rebuild_mappings();
+        let mut keys: Vec<KeyCode> = self
+            .output_keys
+            .iter()
+            .cloned()
+            .chain(self.passthrough_held.drain())
+            .collect();
+        if !keys.is_empty() {
+            keys.sort_by(|a, b| press_order(&self.output_order, a, b).reverse());
+            self.emit_keys(&keys, time, KeyEventType::Release)?;
+        }
+        self.output_order.clear();
+        Ok(())
+    }
+
+    /// Compute the effective set of keys that are pressed
+    /// Also returns the `order` hints of the Remap items that applied.
+    fn compute_keys(&self) -> (HashSet<KeyCode>, Vec<KeyCode>) {
+        // Keys that were pressed before the mappings last changed keep
+        // the meaning that they had then, so they are mapped separately
+        let mut groups: Vec<(&[Mapping], HashSet<KeyCode>)> =
+            vec![(&self.mappings, HashSet::new())];
+        for key in self.input_state.keys() {
+            let mappings = self.mappings_for(*key);
+            match groups.iter_mut().find(|(m, _)| std::ptr::eq(*m, mappings)) {
+                Some((_, keys)) => {
+                    keys.insert(*key);
+                }
+                None => groups.push((mappings, HashSet::from([*key]))),
+            }
+        }
+
+        let mut keys = HashSet::new();
+        let mut order = vec![];
+        for (idx, (mappings, mut input)) in groups.into_iter().enumerate() {
+            if idx == 0 {
+                // Anything that is locked
+                for map in mappings {
+                    match map {
+                        Mapping::ModLock {
+                            input: key, output, ..
+                        } if self.locked.contains_key(key) => {
+                            input.extend(output.iter().cloned());
+                        }
+                        Mapping::OneShot { input: key, output }
+                            if self.one_shot.contains_key(key) =>
+                        {
+                            input.extend(output.iter().cloned());
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            let (group_keys, group_order) = self.apply_mappings(mappings, input);
+            keys.extend(group_keys);
+            order.extend(group_order);
+        }
+
+        // Finally, substitute modifiers based on the keys that
+        // they are being combined with
         for map in &self.mappings {
-            if let Mapping::Remap { input, output } = map {
-                if input.is_subset(&keys_minus_remapped) {
+            if let Mapping::SubstituteModifier {
+                modifier,
+                replacement,
+                except,
+            } = map
+            {
+                if !keys.contains(modifier) {
+                    continue;
+                }
+                let mut others = keys.iter().filter(|k| !is_modifier(k)).peekable();
+                if others.peek().is_some() && others.all(|k| !except.contains(k)) {
+                    keys.remove(modifier);
+                    keys.insert(*replacement);
+                }
+            }
+        }
+
+        // The taps of DualRole keys that were tapped again quickly are
+        // output as they are, like any other tap
+        for input in &self.quick_tapping {
+            if let Some(Mapping::DualRole { tap, .. }) = self.lookup_dual_role_mapping(*input) {
+                keys.extend(tap);
+            }
+        }
+
+        // As are the keys that macros are holding
+        keys.extend(self.macro_held.iter().cloned());
+        // And the output of ScanRemap keys
+        keys.extend(self.scan_held.values().flatten().cloned());
+
+        (keys, order)
+    }
+
+    /// Returns the output of `mappings` for the input `keys`,
+    /// and the `order` hints of the Remap items that applied
+    fn apply_mappings(
+        &self,
+        mappings: &[Mapping],
+        mut keys: HashSet<KeyCode>,
+    ) -> (HashSet<KeyCode>, Vec<KeyCode>) {
+        let mut order = vec![];
+
+        // First phase is to apply any DualRole and MultiFunction mappings
+        // as they are likely to be used to produce modifiers when held.
+        let deferred_hold = self.deferred_hold().map(|(code, _)| code);
+        for map in mappings {
+            match map {
+                Mapping::DualRole {
+                    input,
+                    hold,
+                    hold_timeout,
+                    ..
+                } => {
+                    if keys.contains(input) {
+                        keys.remove(input);
+                        for h in hold {
+                            // Hold keys that turn out to be released
+                            // without anything else happening in the
+                            // meantime are never pressed
+                            let deferred = deferred_hold == Some(*input)
+                                && (hold_timeout.is_some() || is_modifier(h));
+                            if !deferred {
+                                keys.insert(*h);
+                            }
+                        }
+                    }
+                }
+                Mapping::MultiFunction {
+                    input,
+                    hold,
+                    hold_after_tap,
+                    ..
+                } => {
+                    if keys.remove(input) {
+                        for h in self.multi_function_hold_keys(input, hold, hold_after_tap) {
+                            keys.insert(*h);
+                        }
+                    }
+                }
+                Mapping::Turbo { input, output, .. } => {
+                    if keys.remove(input) && self.turbo.get(input).is_some_and(|t| t.pressed) {
+                        keys.extend(output.iter().cloned());
+                    }
+                }
+                Mapping::Remap { .. }
+                | Mapping::Swap { .. }
+                | Mapping::Encoder { .. }
+                | Mapping::Sequence { .. }
+                | Mapping::RawKey { .. }
+                | Mapping::SubstituteModifier { .. }
+                | Mapping::ModLock { .. }
+                | Mapping::Macro { .. }
+                | Mapping::Command { .. }
+                | Mapping::ScanRemap { .. }
+                | Mapping::MouseKey { .. }
+                | Mapping::OneShot { .. }
+                | Mapping::CapsWord { .. }
+                | Mapping::TapDance { .. }
+                | Mapping::Layer { .. }
+                | Mapping::Profile { .. } => {}
+            }
+        }
+
+        let mut keys_minus_remapped = keys.clone();
+        let keys_before_remaps = keys.clone();
+
+        // Second pass to apply Remap and Swap items
+        for map in mappings {
+            if let Mapping::Swap { a, b } = map {
+                // Both at once, so that they don't undo each other
+                // when both are held
+                let held: Vec<(KeyCode, KeyCode)> = [(*a, *b), (*b, *a)]
+                    .into_iter()
+                    .filter(|(input, _)| keys_minus_remapped.contains(input))
+                    .collect();
+                for (input, _) in &held {
+                    keys.remove(input);
+                }
+                for (input, output) in held {
+                    keys.insert(output);
+                    if !is_modifier(&input) {
+                        keys_minus_remapped.remove(&input);
+                        keys_minus_remapped.remove(&output);
+                    }
+                }
+            }
+            if let Mapping::Remap {
+                input,
+                output,
+                order: remap_order,
+                exact,
+            } = map
+            {
+                if input.is_subset(&keys_minus_remapped)
+                    && !(*exact
+                        && (keys_before_remaps.iter().any(|k| !input.contains(k))
+                            || !self.passthrough_held.is_empty()))
+                {
+                    order.extend(remap_order.iter().cloned());
+                    for i in input {
+                        keys.remove(i);
+                        if !is_modifier(i) {
+                            keys_minus_remapped.remove(i);
+                        }
+                    }
+                    for o in output {
+                        keys.insert(*o);
+                        // Outputs that apply are not visible as
+                        // inputs for later remap rules
+                        if !is_modifier(o) {
+                            keys_minus_remapped.remove(o);
+                        }
+                    }
+                }
+            }
+        }
+
+        (keys, order)
+    }
+
+    /// Compute the difference between our desired set of keys
+    /// and the set of keys that are currently pressed in the
+    /// output device.
+    /// Release any keys that should not be pressed, and then
+    /// press any keys that should be pressed.
+    ///
+    /// When releasing, release modifiers last so that mappings
+    /// that produce eg: CTRL-C don't emit a random C character
+    /// when released.
+    ///
+    /// Similarly, when pressing, emit modifiers first so that
+    /// we don't emit C and then CTRL for such a mapping.
+    /// Keys named in the `order` of an applied Remap are pressed
+    /// first in that sequence, and released last in reverse.
+    fn compute_and_apply_keys(&mut self, time: &TimeVal) -> Result<()> {
+        let (mut desired_keys, order) = self.compute_keys();
+        self.expired_holds.retain(|key| desired_keys.contains(key));
+        desired_keys.retain(|key| !self.expired_holds.contains(key));
+        if self.caps_word {
+            let pressed: Vec<KeyCode> = desired_keys
+                .difference(&self.output_keys)
+                .cloned()
+                .collect();
+            self.update_caps_word(&pressed, &desired_keys);
+            if self.caps_word && desired_keys.iter().any(is_shifted_by_caps_word) {
+                desired_keys.insert(KeyCode::KEY_LEFTSHIFT);
+            }
+        }
+        let mut to_release: Vec<KeyCode> = self
+            .output_keys
+            .difference(&desired_keys)
+            .cloned()
+            .collect();
+
+        let mut to_press: Vec<KeyCode> = desired_keys
+            .difference(&self.output_keys)
+            .cloned()
+            .collect();
+
+        if !to_release.is_empty() {
+            let release_order: Vec<KeyCode> =
+                self.output_order.iter().chain(&order).cloned().collect();
+            to_release.sort_by(|a, b| press_order(&release_order, a, b).reverse());
+            self.emit_keys(&to_release, time, KeyEventType::Release)?;
+        }
+        self.output_order = order.clone();
+        if !to_press.is_empty() {
+            to_press.sort_by(|a, b| press_order(&order, a, b));
+            if self.settling.is_some() {
+                // The other keys are pressed once the modifiers settle
+                to_press.retain(is_modifier);
+            } else if let Some(settle) = self.modifier_settle {
+                // Give the consumers of the output device a chance to
+                // see the modifiers before the keys that they modify.
+                // The other keys are pressed by expire_timeouts.
+                let modifiers = to_press.iter().filter(|k| is_modifier(k)).count();
+                if modifiers > 0 && modifiers < to_press.len() {
+                    to_press.retain(is_modifier);
+                    self.settling = Some(PendingSettle {
+                        deadline: timeval_add(time, settle),
+                        held_back: vec![],
+                    });
+                }
+            }
+            self.emit_keys(&to_press, time, KeyEventType::Press)?;
+        }
+        Ok(())
+    }
+
+    fn lookup_dual_role_mapping(&self, code: KeyCode) -> Option<Mapping> {
+        for map in self.mappings_for(code) {
+            if let Mapping::DualRole { input, .. } = map {
+                if *input == code {
+                    // A DualRole mapping has the highest precedence
+                    // so we've found our match
+                    return Some(map.clone());
+                }
+            }
+        }
+        None
+    }
+
+    fn lookup_encoder_mapping(&self, event: &InputEvent) -> Option<Mapping> {
+        let EventCode::EV_REL(axis) = event.event_code else {
+            return None;
+        };
+        self.mappings
+            .iter()
+            .find(|map| matches!(map, Mapping::Encoder { input, .. } if *input == axis))
+            .cloned()
+    }
+
+    /// Produce taps for the movement of an encoder axis
+    fn update_with_encoder_event(&mut self, event: &InputEvent, mapping: Mapping) -> Result<()> {
+        let Mapping::Encoder {
+            input,
+            cw,
+            ccw,
+            acceleration,
+        } = mapping
+        else {
+            return Ok(());
+        };
+        if event.value == 0 {
+            return Ok(());
+        }
+        let direction = event.value.signum();
+
+        let mut taps = 1;
+        if let Some(accel) = acceleration {
+            if let Some((last, last_direction, last_taps)) = self.encoder_state.get(&input) {
+                if *last_direction == direction && timeval_diff(&event.time, last) < accel.interval
+                {
+                    taps = (last_taps + 1).min(accel.max);
+                }
+            }
+        }
+        self.encoder_state
+            .insert(input, (event.time, direction, taps));
+
+        let keys = if direction > 0 { cw } else { ccw };
+        for _ in 0..(taps * event.value.unsigned_abs()) {
+            self.emit_tap(&keys, &event.time)?;
+        }
+        Ok(())
+    }
+
+    fn lookup_multi_function_mapping(&self, code: KeyCode) -> Option<Mapping> {
+        self.mappings_for(code)
+            .iter()
+            .find(|map| matches!(map, Mapping::MultiFunction { input, .. } if *input == code))
+            .cloned()
+    }
+
+    fn lookup_mapping(&self, code: KeyCode) -> Option<Mapping> {
+        let mut candidates = vec![];
+
+        for map in self.mappings_for(code) {
+            match map {
+                Mapping::DualRole { input, .. }
+                | Mapping::MultiFunction { input, .. }
+                | Mapping::Turbo { input, .. } => {
+                    if *input == code {
+                        // A DualRole, MultiFunction or Turbo mapping has
+                        // the highest precedence so we've found our match
+                        return Some(map.clone());
+                    }
+                }
+                Mapping::Swap { a, b } => {
+                    if *a == code || *b == code {
+                        candidates.push(map);
+                    }
+                }
+                Mapping::Remap { input, exact, .. } => {
+                    // Look for a mapping that includes the current key.
+                    // If part of a chord, all of its component keys must
+                    // also be pressed, and if it is exact, nothing else.
+                    let mut code_matched = false;
+                    let mut all_matched = !*exact
+                        || (self.input_state.keys().all(|k| input.contains(k))
+                            && self.passthrough_held.is_empty());
                     for i in input {
-                        keys.remove(i);
-                        if !is_modifier(i) {
-                            keys_minus_remapped.remove(i);
+                        if *i == code {
+                            code_matched = true;
+                        } else if !self.input_state.contains_key(i) {
+                            all_matched = false;
+                            break;
                         }
                     }
-                    for o in output {
-                        keys.insert(o.clone());
-                        // Outputs that apply are not visible as
-                        // inputs for later remap rules
-                        if !is_modifier(o) {
-                            keys_minus_remapped.remove(o);
+                    if code_matched && all_matched {
+                        candidates.push(map);
+                    }
+                }
+                Mapping::Encoder { .. }
+                | Mapping::Sequence { .. }
+                | Mapping::RawKey { .. }
+                | Mapping::SubstituteModifier { .. }
+                | Mapping::ModLock { .. }
+                | Mapping::Macro { .. }
+                | Mapping::Command { .. }
+                | Mapping::ScanRemap { .. }
+                | Mapping::MouseKey { .. }
+                | Mapping::OneShot { .. }
+                | Mapping::CapsWord { .. }
+                | Mapping::TapDance { .. }
+                | Mapping::Layer { .. }
+                | Mapping::Profile { .. } => {}
+            }
+        }
+
+        // Any matches must be Remap or Swap entries.  We want the one
+        // with the most active keys
+        candidates.sort_by_key(|map| match map {
+            Mapping::Remap { input, .. } => std::cmp::Reverse(input.len()),
+            _ => std::cmp::Reverse(1),
+        });
+
+        candidates.first().map(|&m| m.clone())
+    }
+
+    /// Returns true if the event doesn't make sense given the keys that
+    /// we believe to be held: a release without a prior press, or a
+    /// second press without an intervening release.  These are usually
+    /// a sign of failing hardware.
+    fn is_impossible_event(&self, event_type: KeyEventType, code: KeyCode) -> bool {
+        let held = self.input_state.contains_key(&code);
+        match event_type {
+            KeyEventType::Press if held => {
+                log::warn!("{code:?} was pressed while it was already down");
+                true
+            }
+            KeyEventType::Release if !held => {
+                log::warn!("{code:?} was released without having been pressed");
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn update_with_event(&mut self, event: &InputEvent, code: KeyCode) -> Result<()> {
+        if let Some(pending) = &mut self.settling {
+            pending.held_back.push(event.clone());
+            return Ok(());
+        }
+        if self.released_held.contains(&code) {
+            if matches!(KeyEventType::from_value(event.value), KeyEventType::Release) {
+                self.released_held.remove(&code);
+            }
+            log::trace!("RELEASED {:?}", event);
+            return Ok(());
+        }
+        if self.update_dual_role_decision(event, code)? {
+            return Ok(());
+        }
+        if self.update_blocked(event, code) {
+            log::trace!("BLOCKED {:?}", event);
+            return Ok(());
+        }
+        if self.update_autoshift(event, code)? {
+            return Ok(());
+        }
+        if self.update_tap_dance(event, code)? {
+            return Ok(());
+        }
+        if let Some(Mapping::RawKey {
+            press,
+            release,
+            repeat,
+            ..
+        }) = self.lookup_raw_key_mapping(code)
+        {
+            let keys = match KeyEventType::from_value(event.value) {
+                KeyEventType::Press => press,
+                KeyEventType::Release => release,
+                KeyEventType::Repeat => repeat,
+                KeyEventType::Unknown(_) => vec![],
+            };
+            return self.emit_tap(&keys, &event.time);
+        }
+        if self.update_layers(event, code)? {
+            return Ok(());
+        }
+        if self.update_profiles(event, code)? {
+            return Ok(());
+        }
+        if let Some(Mapping::ModLock { input, output, .. }) = self.lookup_mod_lock_mapping(code) {
+            if matches!(KeyEventType::from_value(event.value), KeyEventType::Press) {
+                if self.locked.remove(&input).is_some() {
+                    log::debug!("{input:?}: unlocked {output:?}");
+                } else {
+                    log::debug!("{input:?}: locked {output:?}");
+                    self.locked.insert(input, event.time);
+                }
+                self.compute_and_apply_keys(&event.time)?;
+            }
+            return Ok(());
+        }
+        if let Some(Mapping::CapsWord { input }) = self.lookup_caps_word_mapping(code) {
+            if matches!(KeyEventType::from_value(event.value), KeyEventType::Press) {
+                self.caps_word = !self.caps_word;
+                log::debug!(
+                    "{input:?}: caps word {}",
+                    if self.caps_word { "on" } else { "off" }
+                );
+                self.compute_and_apply_keys(&event.time)?;
+            }
+            return Ok(());
+        }
+        if matches!(KeyEventType::from_value(event.value), KeyEventType::Release)
+            && self.mouse_keys.remove(&code).is_some()
+        {
+            return Ok(());
+        }
+        if self.update_one_shot(event, code)? {
+            return Ok(());
+        }
+        if let Some(Mapping::MouseKey {
+            input,
+            direction,
+            initial_speed,
+            max_speed,
+            ramp,
+        }) = self.lookup_mouse_key_mapping(code)
+        {
+            if matches!(KeyEventType::from_value(event.value), KeyEventType::Press) {
+                self.cancel_pending_tap();
+                if self.mouse_keys.is_empty() {
+                    self.last_mouse_key_tick = event.time;
+                }
+                let (axis, sign) = direction.axis();
+                self.mouse_keys.insert(
+                    input,
+                    MouseKeyState {
+                        axis,
+                        sign,
+                        initial_speed: initial_speed as f64,
+                        max_speed: max_speed as f64,
+                        ramp,
+                        since: event.time,
+                        // Move straight away
+                        remainder: 1.0,
+                    },
+                );
+                self.move_mouse(&event.time)?;
+            }
+            return Ok(());
+        }
+        if let Some(Mapping::Macro { output, delay, .. }) = self.lookup_macro_mapping(code) {
+            if matches!(KeyEventType::from_value(event.value), KeyEventType::Press) {
+                self.cancel_pending_tap();
+                if self.macro_queue.is_empty() {
+                    self.next_macro_key = event.time;
+                }
+                self.macro_queue
+                    .extend(output.into_iter().map(|key| (key, delay)));
+                self.type_macro_keys(&event.time)?;
+            }
+            return Ok(());
+        }
+        if let Some(Mapping::Command {
+            command,
+            min_hold,
+            release,
+            ..
+        }) = self.lookup_command_mapping(code)
+        {
+            match (KeyEventType::from_value(event.value), min_hold) {
+                (KeyEventType::Press, None) => {
+                    self.cancel_pending_tap();
+                    self.run_command(command);
+                }
+                (KeyEventType::Press, Some(min_hold)) => {
+                    self.cancel_pending_tap();
+                    self.held_commands
+                        .insert(code, (command, timeval_add(&event.time, min_hold)));
+                }
+                (KeyEventType::Release, _) if self.held_commands.remove(&code).is_some() => {
+                    log::debug!("{code:?} was released too soon to run its command");
+                }
+                (KeyEventType::Release, _) => {
+                    if let Some(release) = release {
+                        self.run_command(release);
+                    }
+                }
+                _ => {}
+            }
+            return Ok(());
+        }
+        if !self.replaying_sequence && self.update_sequence(event, code)? {
+            return Ok(());
+        }
+        self.update_turbo(event, code);
+        let event_type = KeyEventType::from_value(event.value);
+        if self.is_impossible_event(event_type, code) && self.sanitize {
+            log::debug!("DROP {:?}", event);
+            return Ok(());
+        }
+        match event_type {
+            KeyEventType::Release => {
+                let pressed_at = match self.input_state.remove(&code) {
+                    None => {
+                        self.write_event_and_sync(event)?;
+                        return Ok(());
+                    }
+                    Some(p) => p,
+                };
+
+                self.compute_and_apply_keys(&event.time)?;
+                if self.release_one_shots(code) {
+                    self.compute_and_apply_keys(&event.time)?;
+                }
+                self.release_multi_function(code, &event.time)?;
+                let consumed = self.hold_consumed.remove(&code);
+                self.resolved_holds.remove(&code);
+
+                if let Some(Mapping::DualRole {
+                    tap,
+                    abort_hold_emits_tap,
+                    hold_timeout,
+                    ..
+                }) = self.lookup_dual_role_mapping(code)
+                {
+                    // If released quickly enough, becomes a tap press.
+                    // Normally any other key press in the meantime prevents
+                    // that, but abort_hold_emits_tap only considers keys
+                    // that would have used the hold function.
+                    let tapping = self.tapping.take() == Some(code);
+                    if (tapping || (abort_hold_emits_tap && !consumed))
+                        && timeval_diff(&event.time, &pressed_at)
+                            <= hold_timeout.unwrap_or(DUAL_ROLE_TAP_TIMEOUT)
+                    {
+                        self.emit_keys(&tap, &event.time, KeyEventType::Press)?;
+                        self.emit_keys(&tap, &event.time, KeyEventType::Release)?;
+                        self.last_dual_role_tap = Some((code, event.time));
+                    }
+                }
+                self.pressed_under.remove(&code);
+            }
+            KeyEventType::Press => {
+                if self.input_state.insert(code, event.time).is_some() {
+                    // We missed its release, so any holds that expired
+                    // while it was held are over
+                    self.expired_holds.clear();
+                }
+                if !is_modifier(&code) && self.lookup_dual_role_mapping(code).is_none() {
+                    let others: Vec<KeyCode> = self
+                        .input_state
+                        .keys()
+                        .filter(|k| **k != code)
+                        .copied()
+                        .collect();
+                    // Their holds have been decided, and so are no longer
+                    // held back once this key is released
+                    self.resolved_holds.extend(&others);
+                    self.hold_consumed.extend(others);
+                }
+                if !is_modifier(&code) {
+                    self.use_one_shots(code);
+                }
+                self.interrupt_multi_function(code, &event.time)?;
+
+                match self.lookup_mapping(code) {
+                    Some(Mapping::MultiFunction { .. }) => {
+                        self.cancel_pending_tap();
+                        self.press_multi_function(code, &event.time)?;
+                        self.compute_and_apply_keys(&event.time)?;
+                    }
+                    Some(_) => {
+                        self.compute_and_apply_keys(&event.time)?;
+                        self.tapping.replace(code);
+                    }
+                    None => {
+                        // Just pass it through
+                        self.cancel_pending_tap();
+                        self.compute_and_apply_keys(&event.time)?;
+                    }
+                }
+            }
+            KeyEventType::Repeat => {
+                match self.lookup_mapping(code) {
+                    Some(Mapping::DualRole { hold, .. }) => {
+                        // The hold keys may not have been pressed yet
+                        let held: Vec<KeyCode> = hold
+                            .into_iter()
+                            .filter(|k| self.output_keys.contains(k))
+                            .collect();
+                        if !held.is_empty() {
+                            self.emit_keys(&held, &event.time, KeyEventType::Repeat)?;
+                        }
+                    }
+                    // A remap to nothing swallows the autorepeat too
+                    Some(Mapping::Remap { output, .. }) if output.is_empty() => {}
+                    Some(Mapping::Remap { output, .. }) => {
+                        let output: Vec<KeyCode> = output.iter().cloned().collect();
+                        self.emit_keys(&output, &event.time, KeyEventType::Repeat)?;
+                    }
+                    Some(Mapping::Swap { a, b }) => {
+                        let other = if code == a { b } else { a };
+                        self.emit_keys(&[other], &event.time, KeyEventType::Repeat)?;
+                    }
+                    Some(Mapping::MultiFunction {
+                        hold,
+                        hold_after_tap,
+                        ..
+                    }) => {
+                        let held = self
+                            .multi_function_hold_keys(&code, &hold, &hold_after_tap)
+                            .to_vec();
+                        if !held.is_empty() {
+                            self.emit_keys(&held, &event.time, KeyEventType::Repeat)?;
                         }
                     }
+                    // It is repeated by pressing it over and over
+                    Some(Mapping::Turbo { .. }) => {}
+                    Some(Mapping::Encoder { .. })
+                    | Some(Mapping::Sequence { .. })
+                    | Some(Mapping::RawKey { .. })
+                    | Some(Mapping::SubstituteModifier { .. })
+                    | Some(Mapping::ModLock { .. })
+                    | Some(Mapping::Macro { .. })
+                    | Some(Mapping::Command { .. })
+                    | Some(Mapping::ScanRemap { .. })
+                    | Some(Mapping::MouseKey { .. })
+                    | Some(Mapping::OneShot { .. })
+                    | Some(Mapping::CapsWord { .. })
+                    | Some(Mapping::TapDance { .. })
+                    | Some(Mapping::Layer { .. })
+                    | Some(Mapping::Profile { .. })
+                    | None => {
+                        // Just pass it through
+                        self.cancel_pending_tap();
+                        self.write_event_and_sync(event)?;
+                    }
+                }
+            }
+            KeyEventType::Unknown(_) => {
+                self.write_event_and_sync(event)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Swallows the keys that have no mapping while a blocking layer is
+    /// active.  Returns true if the event was for such a key.
+    fn update_blocked(&mut self, event: &InputEvent, code: KeyCode) -> bool {
+        match KeyEventType::from_value(event.value) {
+            KeyEventType::Press => {
+                let blocked = self
+                    .block_unmapped
+                    .as_ref()
+                    .is_some_and(|mapped| !mapped.contains(&code))
+                    && !self.input_state.contains_key(&code);
+                if blocked {
+                    self.blocked_keys.insert(code);
+                }
+                blocked
+            }
+            KeyEventType::Release => self.blocked_keys.remove(&code),
+            _ => self.blocked_keys.contains(&code),
+        }
+    }
+
+    /// Handles the press and release of Layer keys.  Returns true
+    /// if the event was for a Layer key, and so has been consumed.
+    fn update_layers(&mut self, event: &InputEvent, code: KeyCode) -> Result<bool> {
+        // The key that activated a layer may have a different
+        // meaning within it, so its release is tracked separately
+        if let Some((layer, mode, pressed_at)) = self.layer_keys.get(&code).cloned() {
+            if matches!(KeyEventType::from_value(event.value), KeyEventType::Release) {
+                self.layer_keys.remove(&code);
+                self.last_layer_tap = pressed_at
+                    .filter(|t| timeval_diff(&event.time, t) <= LAYER_DOUBLE_TAP_TIMEOUT)
+                    .map(|_| (code, event.time));
+                if mode == LayerMode::Momentary {
+                    if let Some(idx) = self.active_layers.iter().rposition(|l| *l == (layer, mode))
+                    {
+                        self.active_layers.remove(idx);
+                    }
+                    self.layers_changed(&event.time)?;
+                }
+            }
+            return Ok(true);
+        }
+        let Some(Mapping::Layer {
+            input,
+            layer,
+            mode,
+            lock_on_double_tap,
+            output,
+        }) = self.lookup_layer_mapping(code)
+        else {
+            return Ok(false);
+        };
+        if matches!(KeyEventType::from_value(event.value), KeyEventType::Press) {
+            self.cancel_pending_tap();
+            // Emit, then switch
+            self.emit_tap(&output, &event.time)?;
+            let toggled_on = self.active_layers.contains(&(layer, LayerMode::Toggle));
+            let double_tapped = lock_on_double_tap
+                && self.last_layer_tap.take().is_some_and(|(key, released)| {
+                    key == input && timeval_diff(&event.time, &released) <= LAYER_DOUBLE_TAP_TIMEOUT
+                });
+            let pressed_at = (lock_on_double_tap && !double_tapped).then_some(event.time);
+            self.layer_keys.insert(input, (layer, mode, pressed_at));
+            if double_tapped {
+                // The layer is locked, or unlocked, and is also
+                // active for as long as the key is held this time
+                if toggled_on {
+                    log::debug!("{input:?}: unlocked layer `{}`", self.layers[layer].name);
+                    self.active_layers
+                        .retain(|l| *l != (layer, LayerMode::Toggle));
+                } else {
+                    log::debug!("{input:?}: locked layer `{}`", self.layers[layer].name);
+                    self.active_layers.push((layer, LayerMode::Toggle));
+                }
+            }
+            if mode == LayerMode::Toggle && toggled_on {
+                self.active_layers
+                    .retain(|l| *l != (layer, LayerMode::Toggle));
+            } else {
+                self.active_layers.push((layer, mode));
+            }
+            self.layers_changed(&event.time)?;
+        }
+        Ok(true)
+    }
+
+    /// Handles the press and release of Profile keys.  Returns true
+    /// if the event was for a Profile key, and so has been consumed.
+    fn update_profiles(&mut self, event: &InputEvent, code: KeyCode) -> Result<bool> {
+        // The key may have a different meaning in the new profile,
+        // so its release is tracked separately
+        if self.profile_keys.contains(&code) {
+            if matches!(KeyEventType::from_value(event.value), KeyEventType::Release) {
+                self.profile_keys.remove(&code);
+            }
+            return Ok(true);
+        }
+        let Some(Mapping::Profile { input, profile }) = self.lookup_profile_mapping(code) else {
+            return Ok(false);
+        };
+        if matches!(KeyEventType::from_value(event.value), KeyEventType::Press) {
+            self.cancel_pending_tap();
+            self.switch_profile(profile, &event.time)?;
+            self.profile_keys.insert(input);
+        }
+        Ok(true)
+    }
+
+    fn lookup_profile_mapping(&self, code: KeyCode) -> Option<Mapping> {
+        self.mappings_for(code)
+            .iter()
+            .find(|map| matches!(map, Mapping::Profile { input, .. } if *input == code))
+            .cloned()
+    }
+
+    fn layers_changed(&mut self, time: &TimeVal) -> Result<()> {
+        log::debug!(
+            "active layers: {:?}, focused application: {:?}, locks: {:?}, switches: {:?}",
+            self.active_layers
+                .iter()
+                .map(|(layer, _)| &self.layers[*layer].name)
+                .collect::<Vec<_>>(),
+            self.focused_app,
+            self.locks,
+            self.switches
+        );
+        self.rebuild_mappings();
+        self.compute_and_apply_keys(time)
+    }
+
+    /// Recompute `mappings` from the active layers and profile.  Layers
+    /// that were activated by a key come before those that apply to the
+    /// focused application, and the profile comes after all of them.
+    fn rebuild_mappings(&mut self) {
+        let mut seen = HashSet::new();
+        let mut layers = vec![];
+        for (layer, _) in self.active_layers.iter().rev() {
+            if seen.insert(*layer) {
+                layers.push(&self.layers[*layer]);
+            }
+        }
+        for (idx, layer) in self.layers.iter().enumerate() {
+            if layer.condition_holds(self.focused_app.as_deref(), &self.locks, &self.switches)
+                && seen.insert(idx)
+            {
+                layers.push(layer);
+            }
+        }
+        let mut mappings = vec![];
+        let mut blocking = false;
+        let profile = self.profiles.get(self.active_profile);
+        let below = layers
+            .into_iter()
+            .map(|layer| (&layer.mappings, layer.default))
+            .chain(profile.map(|profile| (&profile.mappings, LayerDefault::Transparent)))
+            .chain([(&self.base_mappings, LayerDefault::Transparent)]);
+        for (layer_mappings, default) in below {
+            // Below a blocking layer, only the keys that change
+            // the layer or profile are left, so that it can be left
+            mappings.extend(
+                layer_mappings
+                    .iter()
+                    .filter(|map| {
+                        !blocking || matches!(map, Mapping::Layer { .. } | Mapping::Profile { .. })
+                    })
+                    .cloned(),
+            );
+            blocking |= default == LayerDefault::Block;
+        }
+        self.block_unmapped = blocking.then(|| mapping_input_keys(&mappings));
+        let previous = Rc::new(std::mem::replace(&mut self.mappings, mappings));
+
+        // Held keys that mean something else now keep their meaning,
+        // so that changing layers doesn't change what they output
+        // while they are held.  Modifiers aren't kept, so that they
+        // can still be combined with the keys that are pressed next.
+        let mentions = |mappings: &[Mapping], key: &KeyCode| -> Vec<Mapping> {
+            mappings
+                .iter()
+                .filter(|map| mapping_input_keys(std::slice::from_ref(map)).contains(key))
+                .cloned()
+                .collect()
+        };
+        for key in self.input_state.keys() {
+            if is_modifier(key) || self.pressed_under.contains_key(key) {
+                continue;
+            }
+            if mentions(&previous, key) != mentions(&self.mappings, key) {
+                self.pressed_under.insert(*key, previous.clone());
+            }
+        }
+    }
+
+    /// Returns the mappings that apply to `code`: those that were in
+    /// effect when it was pressed, if they have changed in the meantime
+    fn mappings_for(&self, code: KeyCode) -> &[Mapping] {
+        self.pressed_under
+            .get(&code)
+            .map_or(&self.mappings, |mappings| mappings)
+    }
+
+    fn lookup_layer_mapping(&self, code: KeyCode) -> Option<Mapping> {
+        self.mappings_for(code)
+            .iter()
+            .find(|map| matches!(map, Mapping::Layer { input, .. } if *input == code))
+            .cloned()
+    }
+
+    /// Handles the events of TapDance keys, and resolves those that
+    /// are interrupted by another key.  Returns true if the event was
+    /// for a TapDance key, and so has been consumed.
+    fn update_tap_dance(&mut self, event: &InputEvent, code: KeyCode) -> Result<bool> {
+        let event_type = KeyEventType::from_value(event.value);
+        if let Some(state) = self.tap_dance.get_mut(&code) {
+            match event_type {
+                KeyEventType::Press => {
+                    state.count += 1;
+                    state.released = None;
+                    if state.count >= state.taps.len() {
+                        // There is no point waiting for another tap
+                        let state = self.tap_dance.remove(&code).unwrap();
+                        self.emit_tap(&state.taps[state.taps.len() - 1], &event.time)?;
+                    }
+                }
+                KeyEventType::Release => state.released = Some(event.time),
+                _ => {}
+            }
+            return Ok(true);
+        }
+        if matches!(event_type, KeyEventType::Press) {
+            self.resolve_tap_dances(&event.time)?;
+        }
+        let Some(Mapping::TapDance { taps, timeout, .. }) = self.lookup_tap_dance_mapping(code)
+        else {
+            return Ok(false);
+        };
+        if matches!(event_type, KeyEventType::Press) && !taps.is_empty() {
+            self.cancel_pending_tap();
+            if taps.len() == 1 {
+                self.emit_tap(&taps[0], &event.time)?;
+            } else {
+                self.tap_dance.insert(
+                    code,
+                    TapDanceState {
+                        taps,
+                        timeout,
+                        count: 1,
+                        released: None,
+                    },
+                );
+            }
+        }
+        Ok(true)
+    }
+
+    /// Emit the taps of the TapDance keys that are in progress,
+    /// according to the number of times that they have been pressed
+    fn resolve_tap_dances(&mut self, time: &TimeVal) -> Result<()> {
+        for (_, state) in std::mem::take(&mut self.tap_dance) {
+            self.emit_tap(&state.taps[state.count - 1], time)?;
+        }
+        Ok(())
+    }
+
+    fn tap_dance_deadline(&self) -> Option<TimeVal> {
+        self.tap_dance
+            .values()
+            .filter_map(|state| state.released.map(|t| timeval_add(&t, state.timeout)))
+            .min()
+    }
+
+    fn handle_tap_dance_timeouts(&mut self, time: &TimeVal) -> Result<()> {
+        let expired: Vec<KeyCode> = self
+            .tap_dance
+            .iter()
+            .filter(|(_, state)| {
+                state
+                    .released
+                    .is_some_and(|t| timeval_add(&t, state.timeout) <= *time)
+            })
+            .map(|(key, _)| *key)
+            .collect();
+        for key in expired {
+            if let Some(state) = self.tap_dance.remove(&key) {
+                self.emit_tap(&state.taps[state.count - 1], time)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Starts pressing the output of a Turbo key over and over when
+    /// it is pressed, and stops when it is released.  The events are
+    /// then processed as usual, which applies the output.
+    fn update_turbo(&mut self, event: &InputEvent, code: KeyCode) {
+        let Some(Mapping::Turbo { interval, .. }) = self.lookup_turbo_mapping(code) else {
+            return;
+        };
+        match KeyEventType::from_value(event.value) {
+            KeyEventType::Press => {
+                let half_interval = interval / 2;
+                self.turbo.insert(
+                    code,
+                    TurboState {
+                        pressed: true,
+                        half_interval,
+                        next: timeval_add(&event.time, half_interval),
+                    },
+                );
+            }
+            KeyEventType::Release => {
+                self.turbo.remove(&code);
+            }
+            KeyEventType::Repeat | KeyEventType::Unknown(_) => {}
+        }
+    }
+
+    /// Presses or releases the output of each Turbo key that is due.
+    /// If we were woken up late, such as when the system is busy, the
+    /// ticks that were missed are dropped rather than sent in a burst:
+    /// only one is made, and the next is timed from `time`.
+    fn handle_turbo_timeouts(&mut self, time: &TimeVal) -> Result<()> {
+        let mut changed = false;
+        for state in self.turbo.values_mut() {
+            if state.next <= *time {
+                state.pressed = !state.pressed;
+                state.next = timeval_add(&state.next, state.half_interval);
+                if state.next <= *time {
+                    state.next = timeval_add(time, state.half_interval);
+                }
+                changed = true;
+            }
+        }
+        if changed {
+            self.compute_and_apply_keys(time)?;
+        }
+        Ok(())
+    }
+
+    fn lookup_turbo_mapping(&self, code: KeyCode) -> Option<Mapping> {
+        self.mappings_for(code)
+            .iter()
+            .find(|map| matches!(map, Mapping::Turbo { input, .. } if *input == code))
+            .cloned()
+    }
+
+    fn lookup_tap_dance_mapping(&self, code: KeyCode) -> Option<Mapping> {
+        self.mappings_for(code)
+            .iter()
+            .find(|map| matches!(map, Mapping::TapDance { input, .. } if *input == code))
+            .cloned()
+    }
+
+    /// Handles the events of OneShot keys.  Returns true if the
+    /// event was for a OneShot key, and so has been consumed.
+    fn update_one_shot(&mut self, event: &InputEvent, code: KeyCode) -> Result<bool> {
+        let event_type = KeyEventType::from_value(event.value);
+        // The key may mean something else by the time it is released
+        if matches!(event_type, KeyEventType::Release) {
+            match self.one_shot.get(&code) {
+                Some(OneShotState::Held { used: false }) => {
+                    self.one_shot.insert(code, OneShotState::Armed);
+                }
+                Some(OneShotState::Held { used: true }) => {
+                    self.one_shot.remove(&code);
+                    self.compute_and_apply_keys(&event.time)?;
+                }
+                Some(_) | None => {
+                    return Ok(self.lookup_one_shot_mapping(code).is_some());
+                }
+            }
+            return Ok(true);
+        }
+        if self.lookup_one_shot_mapping(code).is_none() {
+            return Ok(false);
+        }
+        if matches!(event_type, KeyEventType::Press) {
+            self.cancel_pending_tap();
+            if self.one_shot.remove(&code).is_none() {
+                self.one_shot
+                    .insert(code, OneShotState::Held { used: false });
+            }
+            self.compute_and_apply_keys(&event.time)?;
+        }
+        Ok(true)
+    }
+
+    /// Called when `code` is pressed: OneShot keys that are held are
+    /// now being used as modifiers, and those that are armed apply to it
+    fn use_one_shots(&mut self, code: KeyCode) {
+        for state in self.one_shot.values_mut() {
+            match state {
+                OneShotState::Held { used } => *used = true,
+                OneShotState::Armed => *state = OneShotState::Applied(code),
+                OneShotState::Applied(_) => {}
+            }
+        }
+    }
+
+    /// Called when `code` is released: OneShot keys that applied to
+    /// it are finished.  Returns true if there were any.
+    fn release_one_shots(&mut self, code: KeyCode) -> bool {
+        let before = self.one_shot.len();
+        self.one_shot
+            .retain(|_, state| *state != OneShotState::Applied(code));
+        self.one_shot.len() != before
+    }
+
+    fn lookup_one_shot_mapping(&self, code: KeyCode) -> Option<Mapping> {
+        self.mappings_for(code)
+            .iter()
+            .find(|map| matches!(map, Mapping::OneShot { input, .. } if *input == code))
+            .cloned()
+    }
+
+    fn lookup_mouse_key_mapping(&self, code: KeyCode) -> Option<Mapping> {
+        self.mappings_for(code)
+            .iter()
+            .find(|map| matches!(map, Mapping::MouseKey { input, .. } if *input == code))
+            .cloned()
+    }
+
+    fn mouse_key_deadline(&self) -> Option<TimeVal> {
+        if self.mouse_keys.is_empty() {
+            None
+        } else {
+            Some(timeval_add(&self.last_mouse_key_tick, MOUSE_KEY_TICK))
+        }
+    }
+
+    /// Moves the pointer, or scrolls, by the distance that the held
+    /// MouseKey keys cover since the previous tick
+    fn move_mouse(&mut self, time: &TimeVal) -> Result<()> {
+        // Don't jump if we were held up for some reason
+        let elapsed = timeval_diff(time, &self.last_mouse_key_tick)
+            .min(MOUSE_KEY_TICK * 4)
+            .as_secs_f64();
+        self.last_mouse_key_tick = *time;
+        let mut movement: HashMap<RelCode, i32> = HashMap::new();
+        for state in self.mouse_keys.values_mut() {
+            let progress = if state.ramp.is_zero() {
+                1.0
+            } else {
+                (timeval_diff(time, &state.since).as_secs_f64() / state.ramp.as_secs_f64()).min(1.0)
+            };
+            let speed = state.initial_speed + (state.max_speed - state.initial_speed) * progress;
+            let distance = speed * elapsed + state.remainder;
+            state.remainder = distance.fract();
+            *movement.entry(state.axis).or_default() += state.sign * distance.trunc() as i32;
+        }
+        let mut moved = false;
+        for (axis, value) in movement {
+            if value != 0 {
+                self.write_event(&InputEvent::new(time, &EventCode::EV_REL(axis), value))?;
+                moved = true;
+            }
+        }
+        if moved {
+            self.generate_sync_event(time)?;
+        }
+        Ok(())
+    }
+
+    /// Handles the events of keys whose press had a scancode, from the
+    /// MSC_SCAN earlier in the same frame, that has a ScanRemap.
+    /// Returns true if the event was for one, and so has been consumed.
+    fn update_scan_remap(&mut self, event: &InputEvent, code: KeyCode) -> Result<bool> {
+        let event_type = KeyEventType::from_value(event.value);
+        if let Some(output) = self.scan_held.get(&code) {
+            match event_type {
+                KeyEventType::Release => {
+                    self.scan_held.remove(&code);
+                    self.compute_and_apply_keys(&event.time)?;
+                }
+                KeyEventType::Repeat => {
+                    if let Some(&last) = output.last() {
+                        self.emit_keys(&[last], &event.time, KeyEventType::Repeat)?;
+                    }
                 }
+                _ => {}
             }
+            return Ok(true);
         }
+        if !matches!(event_type, KeyEventType::Press) {
+            return Ok(false);
+        }
+        let Some(scan) = self.frame_scan else {
+            return Ok(false);
+        };
+        let Some(output) = self.mappings.iter().find_map(|map| match map {
+            Mapping::ScanRemap { scan: s, output } if *s == scan => Some(output.clone()),
+            _ => None,
+        }) else {
+            return Ok(false);
+        };
+        self.cancel_pending_tap();
+        self.scan_held.insert(code, output);
+        self.compute_and_apply_keys(&event.time)?;
+        Ok(true)
+    }
 
-        keys
+    /// Runs the commands of the Command keys that have
+    /// been held for their min_hold
+    fn run_held_commands(&mut self, time: &TimeVal) {
+        let mut due: Vec<(CommandSpec, TimeVal)> = vec![];
+        self.held_commands.retain(|_, (command, deadline)| {
+            if *deadline > *time {
+                return true;
+            }
+            due.push((command.clone(), *deadline));
+            false
+        });
+        due.sort_by_key(|(_, deadline)| *deadline);
+        for (command, _) in due {
+            self.run_command(command);
+        }
     }
 
-    /// Compute the difference between our desired set of keys
-    /// and the set of keys that are currently pressed in the
-    /// output device.
-    /// Release any keys that should not be pressed, and then
-    /// press any keys that should be pressed.
-    ///
-    /// When releasing, release modifiers last so that mappings
-    /// that produce eg: CTRL-C don't emit a random C character
-    /// when released.
-    ///
-    /// Similarly, when pressing, emit modifiers first so that
-    /// we don't emit C and then CTRL for such a mapping.
-    fn compute_and_apply_keys(&mut self, time: &TimeVal) -> Result<()> {
-        let desired_keys = self.compute_keys();
-        let mut to_release: Vec<KeyCode> = self
-            .output_keys
-            .difference(&desired_keys)
+    /// Runs `command`.  While a layer is active, it is told the name of
+    /// the topmost one in `EVREMAP_LAYER`, and its mappings in
+    /// `EVREMAP_LAYER_KEYS`, one per line, as the input keys and output
+    /// keys of each joined by `+` with `=` in between, for drawing a
+    /// legend of the layer.
+    fn run_command(&mut self, mut command: CommandSpec) {
+        let layer = self
+            .active_layers
+            .last()
+            .map(|(layer, _)| &self.layers[*layer])
+            .or_else(|| {
+                self.layers.iter().find(|layer| {
+                    layer.condition_holds(self.focused_app.as_deref(), &self.locks, &self.switches)
+                })
+            });
+        if let Some(layer) = layer {
+            let join = |keys: HashSet<KeyCode>| {
+                let mut keys: Vec<String> = keys.iter().map(|k| format!("{k:?}")).collect();
+                keys.sort();
+                keys.join("+")
+            };
+            let keys: Vec<String> = layer
+                .mappings
+                .iter()
+                .filter_map(|map| {
+                    let map = std::slice::from_ref(map);
+                    let input = mapping_input_keys(map);
+                    (!input.is_empty())
+                        .then(|| format!("{}={}", join(input), join(mapping_output_keys(map))))
+                })
+                .collect();
+            command
+                .env
+                .entry("EVREMAP_LAYER".to_string())
+                .or_insert_with(|| layer.name.clone());
+            command
+                .env
+                .entry("EVREMAP_LAYER_KEYS".to_string())
+                .or_insert_with(|| keys.join("\n"));
+        }
+        self.pending_output.push(Output::Run(command));
+    }
+
+    fn lookup_command_mapping(&self, code: KeyCode) -> Option<Mapping> {
+        self.mappings_for(code)
+            .iter()
+            .find(|map| matches!(map, Mapping::Command { input, .. } if *input == code))
             .cloned()
-            .collect();
+    }
 
-        let mut to_press: Vec<KeyCode> = desired_keys
-            .difference(&self.output_keys)
+    fn lookup_macro_mapping(&self, code: KeyCode) -> Option<Mapping> {
+        self.mappings_for(code)
+            .iter()
+            .find(|map| matches!(map, Mapping::Macro { input, .. } if *input == code))
             .cloned()
-            .collect();
+    }
 
-        if !to_release.is_empty() {
-            to_release.sort_by(modifiers_last);
-            self.emit_keys(&to_release, time, KeyEventType::Release)?;
+    fn macro_deadline(&self) -> Option<TimeVal> {
+        if self.macro_queue.is_empty() {
+            None
+        } else {
+            Some(self.next_macro_key)
         }
-        if !to_press.is_empty() {
-            to_press.sort_by(modifiers_first);
-            self.emit_keys(&to_press, time, KeyEventType::Press)?;
+    }
+
+    /// Carries out the queued Macro steps that are due.  Each key is
+    /// pressed and released in its own SYN_REPORT, so that they are seen
+    /// as separate key strokes rather than as a chord.
+    fn type_macro_keys(&mut self, time: &TimeVal) -> Result<()> {
+        while self.next_macro_key <= *time {
+            let Some((step, delay)) = self.macro_queue.pop_front() else {
+                break;
+            };
+            match step {
+                MacroStep::Tap(key) => self.emit_tap(&[key], time)?,
+                MacroStep::Press(key) => {
+                    self.macro_held.insert(key);
+                    self.compute_and_apply_keys(time)?;
+                }
+                MacroStep::Release(key) => {
+                    self.macro_held.remove(&key);
+                    self.compute_and_apply_keys(time)?;
+                }
+                MacroStep::Delay(duration) => {
+                    self.next_macro_key = timeval_add(time, duration);
+                    continue;
+                }
+            }
+            self.next_macro_key = timeval_add(time, delay);
         }
         Ok(())
     }
 
-    fn lookup_dual_role_mapping(&self, code: KeyCode) -> Option<Mapping> {
-        for map in &self.mappings {
-            if let Mapping::DualRole { input, .. } = map {
-                if *input == code {
-                    // A DualRole mapping has the highest precedence
-                    // so we've found our match
-                    return Some(map.clone());
+    /// Handles the events of autoshift keys, holding each one back
+    /// until it is either released or has been held for long enough
+    /// to be typed with Shift.  Returns true if the event has been
+    /// consumed.
+    fn update_autoshift(&mut self, event: &InputEvent, code: KeyCode) -> Result<bool> {
+        let Some(autoshift) = &self.autoshift else {
+            return Ok(false);
+        };
+        let is_autoshift_key = autoshift.keys.contains(&code);
+        let pending = self.autoshift_pending.map(|(key, _)| key);
+        match KeyEventType::from_value(event.value) {
+            KeyEventType::Press => {
+                // Pressing another key means that the pending one
+                // was tapped, as part of typing quickly
+                self.autoshift_typed.remove(&code);
+                self.type_autoshift(false, &event.time)?;
+                let eligible = is_autoshift_key
+                    && !mapping_input_keys(&self.mappings).contains(&code)
+                    && !self.output_keys.iter().any(is_modifier);
+                if eligible {
+                    self.autoshift_pending = Some((code, event.time));
+                }
+                Ok(eligible)
+            }
+            KeyEventType::Repeat => {
+                Ok(pending == Some(code) || self.autoshift_typed.contains(&code))
+            }
+            KeyEventType::Release => {
+                if pending == Some(code) {
+                    self.type_autoshift(false, &event.time)?;
                 }
+                Ok(self.autoshift_typed.remove(&code))
             }
+            KeyEventType::Unknown(_) => Ok(false),
         }
-        None
     }
 
-    fn lookup_mapping(&self, code: KeyCode) -> Option<Mapping> {
-        let mut candidates = vec![];
+    fn autoshift_deadline(&self) -> Option<TimeVal> {
+        let (_, since) = self.autoshift_pending?;
+        Some(timeval_add(&since, self.autoshift.as_ref()?.timeout))
+    }
 
-        for map in &self.mappings {
-            match map {
-                Mapping::DualRole { input, .. } => {
-                    if *input == code {
-                        // A DualRole mapping has the highest precedence
-                        // so we've found our match
-                        return Some(map.clone());
-                    }
+    /// Types the pending autoshift key, if there is one, with Shift
+    /// if `shifted` is true
+    fn type_autoshift(&mut self, shifted: bool, time: &TimeVal) -> Result<()> {
+        let Some((key, _)) = self.autoshift_pending.take() else {
+            return Ok(());
+        };
+        self.autoshift_typed.insert(key);
+        if shifted {
+            log::debug!("{key:?}: held for long enough to be shifted");
+            self.emit_keys(&[KeyCode::KEY_LEFTSHIFT], time, KeyEventType::Press)?;
+            self.emit_tap(&[key], time)?;
+            self.emit_keys(&[KeyCode::KEY_LEFTSHIFT], time, KeyEventType::Release)
+        } else {
+            self.emit_tap(&[key], time)
+        }
+    }
+
+    /// Handles the DualRole keys that are decided differently from the
+    /// default: those that are tapped quickly after a previous tap, and
+    /// those that hold back the keys pressed after them until it is
+    /// decided whether they are tapped or held.  Returns true if the
+    /// event has been consumed.
+    fn update_dual_role_decision(&mut self, event: &InputEvent, code: KeyCode) -> Result<bool> {
+        let event_type = KeyEventType::from_value(event.value);
+        if let Some(pending) = &mut self.dual_role_pending {
+            if code == pending.input {
+                if matches!(event_type, KeyEventType::Release) {
+                    self.decide_dual_role(false, &event.time)?;
                 }
-                Mapping::Remap { input, .. } => {
-                    // Look for a mapping that includes the current key.
-                    // If part of a chord, all of its component keys must
-                    // also be pressed.
-                    let mut code_matched = false;
-                    let mut all_matched = true;
-                    for i in input {
-                        if *i == code {
-                            code_matched = true;
-                        } else if !self.input_state.contains_key(i) {
-                            all_matched = false;
-                            break;
-                        }
-                    }
-                    if code_matched && all_matched {
-                        candidates.push(map);
+                return Ok(true);
+            }
+            pending.held_back.push(event.clone());
+            let input = pending.input;
+            let same_hand = pending.chordal_hold
+                && matches!(
+                    (self.hands.get(&input), self.hands.get(&code)),
+                    (Some(a), Some(b)) if a == b
+                );
+            if matches!(event_type, KeyEventType::Press) && same_hand {
+                // Typed as part of a word, rather than as a shortcut
+                self.dual_role_tapped.insert(input);
+                self.decide_dual_role(false, &event.time)?;
+                return Ok(true);
+            }
+            let Some(pending) = &self.dual_role_pending else {
+                return Ok(true);
+            };
+            let held = match event_type {
+                KeyEventType::Press => pending.hold_on_other_key_press,
+                // The other key was both pressed and released while
+                // the DualRole key was held
+                KeyEventType::Release => {
+                    pending.permissive_hold
+                        && pending.held_back.iter().any(|e| {
+                            e.event_code == event.event_code
+                                && matches!(KeyEventType::from_value(e.value), KeyEventType::Press)
+                        })
+                }
+                _ => false,
+            };
+            if held {
+                self.decide_dual_role(true, &event.time)?;
+            }
+            return Ok(true);
+        }
+
+        if self.dual_role_tapped.contains(&code) {
+            match event_type {
+                KeyEventType::Press => {
+                    self.dual_role_tapped.remove(&code);
+                }
+                KeyEventType::Release => {
+                    self.dual_role_tapped.remove(&code);
+                    return Ok(true);
+                }
+                _ => return Ok(true),
+            }
+        }
+
+        if self.quick_tapping.contains(&code) {
+            match event_type {
+                KeyEventType::Release => {
+                    self.quick_tapping.remove(&code);
+                    self.compute_and_apply_keys(&event.time)?;
+                }
+                KeyEventType::Repeat => {
+                    if let Some(Mapping::DualRole { tap, .. }) = self.lookup_dual_role_mapping(code)
+                    {
+                        self.emit_keys(&tap, &event.time, KeyEventType::Repeat)?;
                     }
                 }
+                _ => {}
             }
+            return Ok(true);
         }
 
-        // Any matches must be Remap entries.  We want the one
-        // with the most active keys
-        candidates.sort_by(|a, b| match (a, b) {
-            (Mapping::Remap { input: input_a, .. }, Mapping::Remap { input: input_b, .. }) => {
-                input_a.len().cmp(&input_b.len()).reverse()
+        if !matches!(event_type, KeyEventType::Press) || self.resolved_holds.contains(&code) {
+            return Ok(false);
+        }
+        let Some(Mapping::DualRole {
+            tap,
+            hold_timeout,
+            hold_on_other_key_press,
+            permissive_hold,
+            quick_tap,
+            chordal_hold,
+            ..
+        }) = self.lookup_dual_role_mapping(code)
+        else {
+            return Ok(false);
+        };
+        let quick = quick_tap.is_some_and(|quick_tap| {
+            self.last_dual_role_tap.is_some_and(|(key, tapped_at)| {
+                key == code && timeval_diff(&event.time, &tapped_at) <= quick_tap
+            })
+        });
+        if quick {
+            log::debug!("{code:?}: tapped again quickly; holding {tap:?}");
+            self.cancel_pending_tap();
+            self.quick_tapping.insert(code);
+            self.compute_and_apply_keys(&event.time)?;
+            return Ok(true);
+        }
+        if hold_on_other_key_press && !permissive_hold && !chordal_hold {
+            return Ok(false);
+        }
+        self.dual_role_pending = Some(PendingDualRole {
+            input: code,
+            tap,
+            hold_on_other_key_press,
+            permissive_hold,
+            chordal_hold,
+            deadline: timeval_add(&event.time, hold_timeout.unwrap_or(DUAL_ROLE_TAP_TIMEOUT)),
+            held_back: vec![],
+        });
+        Ok(true)
+    }
+
+    /// Decides whether the pending DualRole key is `held` or tapped,
+    /// then processes the events that were held back while waiting
+    fn decide_dual_role(&mut self, held: bool, time: &TimeVal) -> Result<()> {
+        let Some(pending) = self.dual_role_pending.take() else {
+            return Ok(());
+        };
+        let input = pending.input;
+        if held {
+            log::debug!("{input:?}: decided to be held");
+            // Processed as an ordinary press, whose hold applies
+            // straight away and which can no longer become a tap
+            self.resolved_holds.insert(input);
+            self.update_with_event(&make_event(input, time, KeyEventType::Press), input)?;
+            self.hold_consumed.insert(input);
+            self.cancel_pending_tap();
+        } else {
+            log::debug!("{input:?}: decided to be tapped");
+            self.emit_tap(&pending.tap, time)?;
+            self.last_dual_role_tap = Some((input, *time));
+        }
+        for event in &pending.held_back {
+            if let EventCode::EV_KEY(key) = event.event_code {
+                self.update_with_event(event, key)?;
             }
-            _ => unreachable!(),
+        }
+        Ok(())
+    }
+
+    fn lookup_caps_word_mapping(&self, code: KeyCode) -> Option<Mapping> {
+        self.mappings_for(code)
+            .iter()
+            .find(|map| matches!(map, Mapping::CapsWord { input } if *input == code))
+            .cloned()
+    }
+
+    /// Turns Caps Word off if any of the `pressed` keys ends the word,
+    /// or is pressed together with a modifier other than Shift.
+    /// `held` are all of the keys held on the output device.
+    fn update_caps_word(&mut self, pressed: &[KeyCode], held: &HashSet<KeyCode>) {
+        let with_modifier = held.iter().any(|key| {
+            is_modifier(key) && !matches!(key, KeyCode::KEY_LEFTSHIFT | KeyCode::KEY_RIGHTSHIFT)
         });
+        let ends_word = pressed
+            .iter()
+            .any(|key| !is_modifier(key) && (with_modifier || !continues_caps_word(key)));
+        if ends_word {
+            log::debug!("caps word off: {pressed:?} ended the word");
+            self.caps_word = false;
+        }
+    }
+
+    fn lookup_mod_lock_mapping(&self, code: KeyCode) -> Option<Mapping> {
+        self.mappings_for(code)
+            .iter()
+            .find(|map| matches!(map, Mapping::ModLock { input, .. } if *input == code))
+            .cloned()
+    }
+
+    fn lookup_raw_key_mapping(&self, code: KeyCode) -> Option<Mapping> {
+        self.mappings_for(code)
+            .iter()
+            .find(|map| matches!(map, Mapping::RawKey { input, .. } if *input == code))
+            .cloned()
+    }
 
-        candidates.get(0).map(|&m| m.clone())
+    /// Returns the Sequence that begins with the `pressed` keys,
+    /// preferring one that is complete
+    fn lookup_sequence_mapping(&self, pressed: &[KeyCode]) -> Option<Mapping> {
+        let candidates: Vec<&Mapping> = self
+            .mappings
+            .iter()
+            .filter(
+                |map| matches!(map, Mapping::Sequence { keys, .. } if keys.starts_with(pressed)),
+            )
+            .collect();
+        candidates
+            .iter()
+            .find(
+                |map| matches!(map, Mapping::Sequence { keys, .. } if keys.len() == pressed.len()),
+            )
+            .or(candidates.first())
+            .map(|map| (*map).clone())
     }
 
-    pub fn update_with_event(&mut self, event: &InputEvent, code: KeyCode) -> Result<()> {
+    /// Hold back the key events that might be part of a Sequence.
+    /// Returns true if the event was consumed.
+    fn update_sequence(&mut self, event: &InputEvent, code: KeyCode) -> Result<bool> {
         let event_type = KeyEventType::from_value(event.value);
-        match event_type {
-            KeyEventType::Release => {
-                let pressed_at = match self.input_state.remove(&code) {
-                    None => {
-                        self.write_event_and_sync(event)?;
-                        return Ok(());
-                    }
-                    Some(p) => p,
-                };
+        if !matches!(event_type, KeyEventType::Press) {
+            if matches!(event_type, KeyEventType::Release) && self.sequence_swallow.remove(&code) {
+                return Ok(true);
+            }
+            if let Some(pending) = &mut self.sequence {
+                if pending.pressed.contains(&code) {
+                    pending.held_back.push(event.clone());
+                    return Ok(true);
+                }
+            }
+            return Ok(false);
+        }
 
-                self.compute_and_apply_keys(&event.time)?;
+        let pending = self.sequence.take();
+        let mut pressed = pending
+            .as_ref()
+            .map(|pending| pending.pressed.clone())
+            .unwrap_or_default();
+        pressed.push(code);
 
-                if let Some(Mapping::DualRole { tap, .. }) =
-                    self.lookup_dual_role_mapping(code.clone())
-                {
-                    // If released quickly enough, becomes a tap press.
-                    if let Some(tapping) = self.tapping.take() {
-                        if tapping == code
-                            && timeval_diff(&event.time, &pressed_at) <= Duration::from_millis(200)
-                        {
-                            self.emit_keys(&tap, &event.time, KeyEventType::Press)?;
-                            self.emit_keys(&tap, &event.time, KeyEventType::Release)?;
-                        }
-                    }
+        let Some(Mapping::Sequence {
+            keys,
+            output,
+            window,
+        }) = self.lookup_sequence_mapping(&pressed)
+        else {
+            return match pending {
+                Some(pending) => {
+                    // The sequence is broken; let its keys through, and
+                    // then see whether this key starts another one
+                    self.replay_sequence(pending)?;
+                    self.update_sequence(event, code)
                 }
-            }
-            KeyEventType::Press => {
-                self.input_state.insert(code.clone(), event.time.clone());
+                None => Ok(false),
+            };
+        };
 
-                match self.lookup_mapping(code.clone()) {
-                    Some(_) => {
-                        self.compute_and_apply_keys(&event.time)?;
-                        self.tapping.replace(code);
-                    }
-                    None => {
-                        // Just pass it through
-                        self.cancel_pending_tap();
-                        self.compute_and_apply_keys(&event.time)?;
+        let mut held_back = pending.map(|pending| pending.held_back).unwrap_or_default();
+        held_back.push(event.clone());
+        if keys.len() == pressed.len() {
+            for event in &held_back {
+                if let EventCode::EV_KEY(key) = event.event_code {
+                    match KeyEventType::from_value(event.value) {
+                        KeyEventType::Press => {
+                            self.sequence_swallow.insert(key);
+                        }
+                        KeyEventType::Release => {
+                            self.sequence_swallow.remove(&key);
+                        }
+                        _ => {}
                     }
                 }
             }
-            KeyEventType::Repeat => {
-                match self.lookup_mapping(code.clone()) {
-                    Some(Mapping::DualRole { hold, .. }) => {
-                        self.emit_keys(&hold, &event.time, KeyEventType::Repeat)?;
-                    }
-                    Some(Mapping::Remap { output, .. }) => {
-                        let output: Vec<KeyCode> = output.iter().cloned().collect();
-                        self.emit_keys(&output, &event.time, KeyEventType::Repeat)?;
-                    }
-                    None => {
-                        // Just pass it through
-                        self.cancel_pending_tap();
-                        self.write_event_and_sync(event)?;
-                    }
+            self.emit_tap(&output, &event.time)?;
+        } else {
+            self.sequence.replace(PendingSequence {
+                pressed,
+                held_back,
+                deadline: timeval_add(&event.time, window),
+            });
+        }
+        Ok(true)
+    }
+
+    /// Process the events of a broken Sequence as though they had
+    /// not been held back
+    fn replay_sequence(&mut self, pending: PendingSequence) -> Result<()> {
+        self.replaying_sequence = true;
+        let mut result = Ok(());
+        for event in &pending.held_back {
+            if let EventCode::EV_KEY(key) = event.event_code {
+                result = self.update_with_event(event, key);
+                if result.is_err() {
+                    break;
                 }
             }
-            KeyEventType::Unknown(_) => {
-                self.write_event_and_sync(event)?;
-            }
         }
-
-        Ok(())
+        self.replaying_sequence = false;
+        result
     }
 
     fn cancel_pending_tap(&mut self) {
@@ -353,7 +4617,7 @@ impl InputMapper {
         event_type: KeyEventType,
     ) -> Result<()> {
         for k in key {
-            let event = make_event(k.clone(), time, event_type);
+            let event = make_event(*k, time, event_type);
             self.write_event(&event)?;
         }
         self.generate_sync_event(time)?;
@@ -367,16 +4631,70 @@ impl InputMapper {
     }
 
     fn write_event(&mut self, event: &InputEvent) -> Result<()> {
-        log::trace!("OUT: {:?}", event);
-        self.output.write_event(&event)?;
+        if let EventCode::EV_KEY(ref key) = event.event_code {
+            if self.expired_holds.contains(key)
+                && matches!(KeyEventType::from_value(event.value), KeyEventType::Repeat)
+            {
+                return Ok(());
+            }
+        }
+        match event.event_code {
+            EventCode::EV_KEY(ref key)
+                if self.modifier_rewrite.contains_key(key)
+                    || self.modifier_rewrite.values().any(|target| target == key) =>
+            {
+                let target = self.modifier_rewrite.get(key).copied().unwrap_or(*key);
+                let holders = self.rewrite_held.entry(target).or_default();
+                let changed = match KeyEventType::from_value(event.value) {
+                    KeyEventType::Press => holders.insert(*key) && holders.len() == 1,
+                    KeyEventType::Release => holders.remove(key) && holders.is_empty(),
+                    KeyEventType::Repeat | KeyEventType::Unknown(_) => true,
+                };
+                if !changed {
+                    log::trace!("OUT: {:?} (still held by {:?})", target, holders);
+                } else if target == *key {
+                    log::trace!("OUT: {:?}", event);
+                    self.write_output(event);
+                } else {
+                    let rewritten =
+                        InputEvent::new(&event.time, &EventCode::EV_KEY(target), event.value);
+                    log::trace!("OUT: {:?} (rewritten from {:?})", rewritten, key);
+                    self.write_output(&rewritten);
+                }
+            }
+            _ => {
+                log::trace!("OUT: {:?}", event);
+                self.write_output(event);
+            }
+        }
         if let EventCode::EV_KEY(ref key) = event.event_code {
             let event_type = KeyEventType::from_value(event.value);
+            // Keys that are tapped rather than held, such as the tap
+            // of a DualRole key, can also end the word
+            if self.caps_word && matches!(event_type, KeyEventType::Press) {
+                let held = self.output_keys.clone();
+                self.update_caps_word(&[*key], &held);
+            }
+            if matches!(event_type, KeyEventType::Press) {
+                let lock = match key {
+                    KeyCode::KEY_NUMLOCK => Some(&mut self.locks.numlock),
+                    KeyCode::KEY_CAPSLOCK => Some(&mut self.locks.capslock),
+                    KeyCode::KEY_SCROLLLOCK => Some(&mut self.locks.scrolllock),
+                    _ => None,
+                };
+                if let Some(lock) = lock {
+                    *lock = !*lock;
+                    self.locks_changed = true;
+                }
+            }
             match event_type {
                 KeyEventType::Press | KeyEventType::Repeat => {
-                    self.output_keys.insert(key.clone());
+                    self.output_keys.insert(*key);
+                    self.held_since.entry(*key).or_insert(event.time);
                 }
                 KeyEventType::Release => {
                     self.output_keys.remove(key);
+                    self.held_since.remove(key);
                 }
                 _ => {}
             }
@@ -384,32 +4702,333 @@ impl InputMapper {
         Ok(())
     }
 
-    fn generate_sync_event(&self, time: &TimeVal) -> Result<()> {
-        self.output.write_event(&InputEvent::new(
+    fn write_output(&mut self, event: &InputEvent) {
+        if self.overlay && !self.overlay_allows(event) {
+            log::trace!("OVERLAY DROP {:?}", event);
+            return;
+        }
+        self.pending_output.push(Output::Event(event.clone()));
+    }
+
+    /// Decides whether an event is output in overlay mode.  Keys that
+    /// are physically held are already seen, so only the presses of
+    /// other keys are output, along with their repeats and releases.
+    /// A SYN_REPORT is only output if something else was before it.
+    fn overlay_allows(&mut self, event: &InputEvent) -> bool {
+        let allowed = match event.event_code {
+            EventCode::EV_SYN(evdev_rs::enums::EV_SYN::SYN_REPORT) => {
+                return std::mem::take(&mut self.overlay_pending_syn);
+            }
+            EventCode::EV_KEY(key) => match KeyEventType::from_value(event.value) {
+                KeyEventType::Press => {
+                    !self.physical_keys.contains(&key) && self.overlay_keys.insert(key)
+                }
+                KeyEventType::Release => self.overlay_keys.remove(&key),
+                _ => self.overlay_keys.contains(&key),
+            },
+            _ => true,
+        };
+        self.overlay_pending_syn |= allowed;
+        allowed
+    }
+
+    /// Returns the entry that handles `code`, given the keys that
+    /// are currently held
+    pub fn matching_rule(&self, code: KeyCode) -> Option<&Mapping> {
+        self.mappings.iter().find(|map| match map {
+            Mapping::Remap { input, .. } => {
+                input.contains(&code)
+                    && input
+                        .iter()
+                        .all(|k| *k == code || self.input_state.contains_key(k))
+            }
+            _ => mapping_input_keys(std::slice::from_ref(map)).contains(&code),
+        })
+    }
+
+    fn generate_sync_event(&mut self, time: &TimeVal) -> Result<()> {
+        self.write_output(&InputEvent::new(
             time,
             &EventCode::EV_SYN(evdev_rs::enums::EV_SYN::SYN_REPORT),
             0,
-        ))?;
+        ));
         Ok(())
     }
 }
 
+impl Drop for InputMapper {
+    fn drop(&mut self) {
+        // This also happens when unwinding from a panic, or returning
+        // an error from the middle of processing an event, so don't
+        // leave anything held down system-wide
+        self.release_output_keys();
+        // Release the physical device before the virtual device is
+        // destroyed, so that there is no window where neither is usable
+        if self.grab {
+            if let Err(err) = self.input.grab(GrabMode::Ungrab) {
+                log::debug!("failed to ungrab input device: {err:#}");
+            }
+        }
+    }
+}
+
+/// Returns the keys that the mappings respond to
+fn mapping_input_keys(mappings: &[Mapping]) -> HashSet<KeyCode> {
+    let mut keys = HashSet::new();
+    for map in mappings {
+        match map {
+            Mapping::DualRole { input, .. }
+            | Mapping::MultiFunction { input, .. }
+            | Mapping::RawKey { input, .. }
+            | Mapping::ModLock { input, .. }
+            | Mapping::Macro { input, .. }
+            | Mapping::MouseKey { input, .. }
+            | Mapping::OneShot { input, .. }
+            | Mapping::CapsWord { input }
+            | Mapping::TapDance { input, .. }
+            | Mapping::Turbo { input, .. }
+            | Mapping::Command { input, .. }
+            | Mapping::Layer { input, .. }
+            | Mapping::Profile { input, .. } => {
+                keys.insert(*input);
+            }
+            Mapping::Remap { input, .. } => keys.extend(input.iter().cloned()),
+            Mapping::Swap { a, b } => keys.extend([*a, *b]),
+            Mapping::Sequence { keys: sequence, .. } => keys.extend(sequence.iter().cloned()),
+            Mapping::Encoder { .. }
+            | Mapping::SubstituteModifier { .. }
+            | Mapping::ScanRemap { .. } => {}
+        }
+    }
+    keys
+}
+
+/// Returns the keys that the mappings can produce
+fn mapping_output_keys(mappings: &[Mapping]) -> HashSet<KeyCode> {
+    let mut keys = HashSet::new();
+    for map in mappings {
+        match map {
+            Mapping::DualRole { tap, hold, .. } => keys.extend(tap.iter().chain(hold).cloned()),
+            Mapping::MultiFunction {
+                tap,
+                hold,
+                double_tap,
+                hold_after_tap,
+                ..
+            } => keys.extend(
+                tap.iter()
+                    .chain(hold)
+                    .chain(double_tap)
+                    .chain(hold_after_tap)
+                    .cloned(),
+            ),
+            Mapping::Encoder { cw, ccw, .. } => keys.extend(cw.iter().chain(ccw).cloned()),
+            Mapping::RawKey {
+                press,
+                release,
+                repeat,
+                ..
+            } => keys.extend(press.iter().chain(release).chain(repeat).cloned()),
+            Mapping::SubstituteModifier { replacement, .. } => {
+                keys.insert(*replacement);
+            }
+            Mapping::Remap { output, .. } => keys.extend(output.iter().cloned()),
+            Mapping::Swap { a, b } => keys.extend([*a, *b]),
+            Mapping::Sequence { output, .. }
+            | Mapping::ScanRemap { output, .. }
+            | Mapping::ModLock { output, .. }
+            | Mapping::OneShot { output, .. }
+            | Mapping::Turbo { output, .. }
+            | Mapping::Layer { output, .. } => keys.extend(output.iter().cloned()),
+            Mapping::Macro { output, .. } => keys.extend(output.iter().filter_map(MacroStep::key)),
+            Mapping::TapDance { taps, .. } => keys.extend(taps.iter().flatten().cloned()),
+            Mapping::CapsWord { .. } => {
+                keys.insert(KeyCode::KEY_LEFTSHIFT);
+            }
+            Mapping::Profile { .. } | Mapping::MouseKey { .. } | Mapping::Command { .. } => {}
+        }
+    }
+    keys
+}
+
+/// Returns the keys that must not be passed through by
+/// low_latency_passthrough: the inputs of the mappings, and the
+/// modifiers that they substitute or rewrite
+fn low_latency_mapped_keys(
+    mappings: &[Mapping],
+    modifier_rewrite: &HashMap<KeyCode, KeyCode>,
+) -> HashSet<KeyCode> {
+    let mut mapped_keys = mapping_input_keys(mappings);
+    mapped_keys.extend(modifier_rewrite.keys().cloned());
+    mapped_keys.extend(mappings.iter().filter_map(|map| match map {
+        Mapping::SubstituteModifier { modifier, .. } => Some(*modifier),
+        _ => None,
+    }));
+    mapped_keys
+}
+
+/// Returns which locks are on, according to the LEDs of the device
+fn device_lock_state(input: &Device) -> LockState {
+    let led = |led| input.event_value(&EventCode::EV_LED(led)) == Some(1);
+    LockState {
+        numlock: led(evdev_rs::enums::EV_LED::LED_NUML),
+        capslock: led(evdev_rs::enums::EV_LED::LED_CAPSL),
+        scrolllock: led(evdev_rs::enums::EV_LED::LED_SCROLLL),
+    }
+}
+
+/// Returns the LEDs supported by the device
+fn device_leds(input: &Device) -> Vec<EventCode> {
+    EventCode::EV_LED(evdev_rs::enums::EV_LED::LED_NUML)
+        .iter()
+        .filter(|code| input.has(*code))
+        .collect()
+}
+
+/// Describes an event for people to read, such as `KEY_A press`
+pub fn describe_event(event: &InputEvent) -> String {
+    match event.event_code {
+        EventCode::EV_KEY(key) => {
+            let action = match KeyEventType::from_value(event.value) {
+                KeyEventType::Release => "release".to_string(),
+                KeyEventType::Press => "press".to_string(),
+                KeyEventType::Repeat => "repeat".to_string(),
+                KeyEventType::Unknown(value) => value.to_string(),
+            };
+            format!("{key:?} {action}")
+        }
+        ref code => format!("{code} {}", event.value),
+    }
+}
+
 fn make_event(key: KeyCode, time: &TimeVal, event_type: KeyEventType) -> InputEvent {
     InputEvent::new(time, &EventCode::EV_KEY(key), event_type.value())
 }
 
-fn is_modifier(key: &KeyCode) -> bool {
-    match key {
+/// Returns true for the keys that Caps Word shifts: the letters, and
+/// the minus key so that words can be joined with underscores
+fn is_shifted_by_caps_word(key: &KeyCode) -> bool {
+    matches!(
+        key,
+        KeyCode::KEY_A
+            | KeyCode::KEY_B
+            | KeyCode::KEY_C
+            | KeyCode::KEY_D
+            | KeyCode::KEY_E
+            | KeyCode::KEY_F
+            | KeyCode::KEY_G
+            | KeyCode::KEY_H
+            | KeyCode::KEY_I
+            | KeyCode::KEY_J
+            | KeyCode::KEY_K
+            | KeyCode::KEY_L
+            | KeyCode::KEY_M
+            | KeyCode::KEY_N
+            | KeyCode::KEY_O
+            | KeyCode::KEY_P
+            | KeyCode::KEY_Q
+            | KeyCode::KEY_R
+            | KeyCode::KEY_S
+            | KeyCode::KEY_T
+            | KeyCode::KEY_U
+            | KeyCode::KEY_V
+            | KeyCode::KEY_W
+            | KeyCode::KEY_X
+            | KeyCode::KEY_Y
+            | KeyCode::KEY_Z
+            | KeyCode::KEY_MINUS
+    )
+}
+
+/// Returns true for the keys that can be typed without ending Caps Word
+fn continues_caps_word(key: &KeyCode) -> bool {
+    is_shifted_by_caps_word(key)
+        || matches!(
+            key,
+            KeyCode::KEY_1
+                | KeyCode::KEY_2
+                | KeyCode::KEY_3
+                | KeyCode::KEY_4
+                | KeyCode::KEY_5
+                | KeyCode::KEY_6
+                | KeyCode::KEY_7
+                | KeyCode::KEY_8
+                | KeyCode::KEY_9
+                | KeyCode::KEY_0
+                | KeyCode::KEY_BACKSPACE
+                | KeyCode::KEY_DELETE
+        )
+}
+
+/// Returns the types of event that `input` supports
+fn event_types(input: &Device) -> Vec<EventType> {
+    (0..EventType::EV_MAX as u32)
+        .filter_map(evdev_rs::enums::int_to_event_type)
+        .filter(|ty| *ty != EventType::EV_SYN && input.has(*ty))
+        .collect()
+}
+
+/// The buttons of touchpads, touchscreens and tablets that report
+/// which tool is in use and whether it is touching
+fn is_touch_key(key: &KeyCode) -> bool {
+    (KeyCode::BTN_TOOL_PEN as u32..=KeyCode::BTN_TOOL_QUADTAP as u32).contains(&(*key as u32))
+}
+
+fn is_mouse_button(key: &KeyCode) -> bool {
+    matches!(
+        key,
+        KeyCode::BTN_LEFT
+            | KeyCode::BTN_RIGHT
+            | KeyCode::BTN_MIDDLE
+            | KeyCode::BTN_SIDE
+            | KeyCode::BTN_EXTRA
+            | KeyCode::BTN_FORWARD
+            | KeyCode::BTN_BACK
+            | KeyCode::BTN_TASK
+    )
+}
+
+pub fn is_modifier(key: &KeyCode) -> bool {
+    matches!(
+        key,
         KeyCode::KEY_FN
-        | KeyCode::KEY_LEFTALT
-        | KeyCode::KEY_RIGHTALT
-        | KeyCode::KEY_LEFTMETA
-        | KeyCode::KEY_RIGHTMETA
-        | KeyCode::KEY_LEFTCTRL
-        | KeyCode::KEY_RIGHTCTRL
-        | KeyCode::KEY_LEFTSHIFT
-        | KeyCode::KEY_RIGHTSHIFT => true,
-        _ => false,
+            | KeyCode::KEY_LEFTALT
+            | KeyCode::KEY_RIGHTALT
+            | KeyCode::KEY_LEFTMETA
+            | KeyCode::KEY_RIGHTMETA
+            | KeyCode::KEY_LEFTCTRL
+            | KeyCode::KEY_RIGHTCTRL
+            | KeyCode::KEY_LEFTSHIFT
+            | KeyCode::KEY_RIGHTSHIFT
+    )
+}
+
+/// The sequence in which modifiers are pressed when nothing else
+/// has been specified
+fn modifier_rank(key: &KeyCode) -> u8 {
+    match key {
+        KeyCode::KEY_LEFTCTRL => 0,
+        KeyCode::KEY_RIGHTCTRL => 1,
+        KeyCode::KEY_LEFTSHIFT => 2,
+        KeyCode::KEY_RIGHTSHIFT => 3,
+        KeyCode::KEY_LEFTALT => 4,
+        KeyCode::KEY_RIGHTALT => 5,
+        KeyCode::KEY_LEFTMETA => 6,
+        KeyCode::KEY_RIGHTMETA => 7,
+        _ => 8,
+    }
+}
+
+/// Orders the keys listed in `order` ahead of all others, in the
+/// sequence in which they are listed.  The remaining keys are
+/// ordered by `modifiers_first`.
+fn press_order(order: &[KeyCode], a: &KeyCode, b: &KeyCode) -> Ordering {
+    let position = |key: &KeyCode| order.iter().position(|k| k == key);
+    match (position(a), position(b)) {
+        (Some(a), Some(b)) => a.cmp(&b),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => modifiers_first(a, b),
     }
 }
 
@@ -419,7 +5038,7 @@ fn is_modifier(key: &KeyCode) -> bool {
 fn modifiers_first(a: &KeyCode, b: &KeyCode) -> Ordering {
     if is_modifier(a) {
         if is_modifier(b) {
-            Ordering::Equal
+            modifier_rank(a).cmp(&modifier_rank(b))
         } else {
             Ordering::Less
         }
@@ -430,7 +5049,3 @@ fn modifiers_first(a: &KeyCode, b: &KeyCode) -> Ordering {
         Ordering::Equal
     }
 }
-
-fn modifiers_last(a: &KeyCode, b: &KeyCode) -> Ordering {
-    modifiers_first(a, b).reverse()
-}
@@ -0,0 +1,333 @@
+use anyhow::{bail, Context, Result};
+use std::ffi::{c_int, c_long, CString};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const AUDIT_ARCH: u32 = 0;
+
+const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1 << 0;
+const LANDLOCK_RULE_PATH_BENEATH: c_int = 1;
+const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+const ACCESS_FS_REFER: u64 = 1 << 13;
+const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+const ACCESS_FS_IOCTL_DEV: u64 = 1 << 15;
+/// The rights that the first version of Landlock knows about
+const ACCESS_FS_V1: u64 = (1 << 13) - 1;
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: c_int,
+}
+
+/// Restricts what the process can do once it is remapping, so that
+/// a bug in evremap, which sees every keystroke, can do less harm.
+///
+/// Landlock limits the files that can be opened to the input devices,
+/// which are opened again when they come back after going away, the
+/// directory of the config file, which is read again to reload it,
+/// and read-only access to /sys and /usr.  It is skipped with a warning
+/// on kernels without Landlock.  It applies to this thread and those
+/// that it starts later, which is where devices and files are opened.
+///
+/// A seccomp filter on every thread then only allows the system calls
+/// that the remap loop needs.  The rest fail with EPERM, rather than
+/// killing evremap with keys held down.  This includes starting other
+/// programs, so `[[command]]` entries and the X11 focus watcher no
+/// longer work, and creating sockets, so a `Notifier` for systemd has
+/// to be created first.
+pub fn enter(config_file: &Path) -> Result<()> {
+    if AUDIT_ARCH == 0 {
+        bail!("the sandbox isn't supported on this architecture");
+    }
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        return Err(std::io::Error::last_os_error()).context("setting no_new_privs");
+    }
+    let config_dir = config_file
+        .canonicalize()
+        .context(format!("resolving {}", config_file.display()))?
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| PathBuf::from("/"));
+    let read = ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR;
+    let rules = [
+        (
+            PathBuf::from("/dev/input"),
+            read | ACCESS_FS_WRITE_FILE | ACCESS_FS_IOCTL_DEV,
+        ),
+        (PathBuf::from("/sys"), read),
+        (PathBuf::from("/usr"), read),
+        (PathBuf::from("/proc/self"), read),
+        (config_dir, read),
+    ];
+    match restrict_paths(&rules) {
+        Ok(true) => log::info!("Restricted file access with Landlock"),
+        Ok(false) => log::warn!("Landlock isn't available, so file access isn't restricted"),
+        Err(err) => return Err(err).context("restricting file access with Landlock"),
+    }
+    install_seccomp_filter().context("installing the seccomp filter")?;
+    log::info!("Restricted system calls with seccomp");
+    Ok(())
+}
+
+/// Applies a Landlock ruleset that only allows the accesses in
+/// `rules` beneath their paths.  Returns false if the kernel doesn't
+/// support Landlock.
+fn restrict_paths(rules: &[(PathBuf, u64)]) -> Result<bool> {
+    let abi = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            std::ptr::null::<RulesetAttr>(),
+            0usize,
+            LANDLOCK_CREATE_RULESET_VERSION,
+        )
+    };
+    if abi < 1 {
+        return Ok(false);
+    }
+    let mut handled = ACCESS_FS_V1;
+    if abi >= 2 {
+        handled |= ACCESS_FS_REFER;
+    }
+    if abi >= 3 {
+        handled |= ACCESS_FS_TRUNCATE;
+    }
+    if abi >= 5 {
+        handled |= ACCESS_FS_IOCTL_DEV;
+    }
+    let attr = RulesetAttr {
+        handled_access_fs: handled,
+    };
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            &attr,
+            std::mem::size_of::<RulesetAttr>(),
+            0u32,
+        )
+    };
+    let ruleset = owned_fd(fd).context("landlock_create_ruleset")?;
+    for (path, access) in rules {
+        // Devices and directories that don't exist here can be ignored
+        let Ok(name) = CString::new(path.as_os_str().as_bytes()) else {
+            continue;
+        };
+        let fd = unsafe { libc::open(name.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+        let Ok(dir) = owned_fd(fd as c_long) else {
+            continue;
+        };
+        let rule = PathBeneathAttr {
+            allowed_access: access & handled,
+            parent_fd: dir.as_raw_fd(),
+        };
+        let status = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_add_rule,
+                ruleset.as_raw_fd(),
+                LANDLOCK_RULE_PATH_BENEATH,
+                &rule,
+                0u32,
+            )
+        };
+        if status != 0 {
+            return Err(std::io::Error::last_os_error())
+                .context(format!("allowing access to {}", path.display()));
+        }
+    }
+    let status =
+        unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0u32) };
+    if status != 0 {
+        return Err(std::io::Error::last_os_error()).context("landlock_restrict_self");
+    }
+    Ok(true)
+}
+
+fn owned_fd(fd: c_long) -> Result<OwnedFd> {
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as c_int) })
+}
+
+/// The system calls that the remap loop, the threads of the D-Bus
+/// service and the Rust runtime make
+fn allowed_syscalls() -> Vec<c_long> {
+    let mut allowed = vec![
+        // Reading and writing devices, sockets and files
+        libc::SYS_read,
+        libc::SYS_write,
+        libc::SYS_readv,
+        libc::SYS_writev,
+        libc::SYS_pread64,
+        libc::SYS_pwrite64,
+        libc::SYS_lseek,
+        libc::SYS_close,
+        libc::SYS_ioctl,
+        libc::SYS_fcntl,
+        libc::SYS_openat,
+        libc::SYS_newfstatat,
+        libc::SYS_fstat,
+        libc::SYS_statx,
+        libc::SYS_readlinkat,
+        libc::SYS_getdents64,
+        libc::SYS_inotify_init1,
+        libc::SYS_inotify_add_watch,
+        libc::SYS_inotify_rm_watch,
+        libc::SYS_pipe2,
+        libc::SYS_eventfd2,
+        // Waiting for them
+        libc::SYS_ppoll,
+        libc::SYS_pselect6,
+        libc::SYS_epoll_ctl,
+        libc::SYS_epoll_pwait,
+        libc::SYS_futex,
+        libc::SYS_sched_yield,
+        // Control requests, D-Bus and systemd notifications
+        libc::SYS_accept4,
+        libc::SYS_recvfrom,
+        libc::SYS_recvmsg,
+        libc::SYS_sendto,
+        libc::SYS_sendmsg,
+        libc::SYS_shutdown,
+        libc::SYS_getsockopt,
+        libc::SYS_setsockopt,
+        libc::SYS_getsockname,
+        libc::SYS_getpeername,
+        // Clocks and timers
+        libc::SYS_clock_gettime,
+        libc::SYS_clock_getres,
+        libc::SYS_clock_nanosleep,
+        libc::SYS_nanosleep,
+        libc::SYS_gettimeofday,
+        // Memory, threads, signals and the like
+        libc::SYS_mmap,
+        libc::SYS_munmap,
+        libc::SYS_mremap,
+        libc::SYS_mprotect,
+        libc::SYS_madvise,
+        libc::SYS_brk,
+        libc::SYS_getrandom,
+        libc::SYS_set_robust_list,
+        libc::SYS_rseq,
+        libc::SYS_prctl,
+        libc::SYS_sched_getaffinity,
+        libc::SYS_rt_sigaction,
+        libc::SYS_rt_sigprocmask,
+        libc::SYS_rt_sigreturn,
+        libc::SYS_sigaltstack,
+        libc::SYS_getpid,
+        libc::SYS_gettid,
+        libc::SYS_getuid,
+        libc::SYS_geteuid,
+        libc::SYS_getgid,
+        libc::SYS_getegid,
+        libc::SYS_tgkill,
+        // Stopping and reaping programs started before the sandbox
+        libc::SYS_kill,
+        libc::SYS_wait4,
+        libc::SYS_waitid,
+        libc::SYS_exit,
+        libc::SYS_exit_group,
+    ];
+    #[cfg(target_arch = "x86_64")]
+    allowed.extend([
+        libc::SYS_poll,
+        libc::SYS_select,
+        libc::SYS_epoll_wait,
+        libc::SYS_open,
+        libc::SYS_stat,
+        libc::SYS_lstat,
+        libc::SYS_readlink,
+    ]);
+    allowed
+}
+
+fn statement(code: u32, k: u32) -> libc::sock_filter {
+    libc::sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+fn jump(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter {
+        code: code as u16,
+        jt,
+        jf,
+        k,
+    }
+}
+
+fn install_seccomp_filter() -> Result<()> {
+    const NR: u32 = std::mem::offset_of!(libc::seccomp_data, nr) as u32;
+    const ARCH: u32 = std::mem::offset_of!(libc::seccomp_data, arch) as u32;
+    // The low half of the first argument, on a little endian machine
+    const ARG0: u32 = std::mem::offset_of!(libc::seccomp_data, args) as u32;
+    let load = libc::BPF_LD | libc::BPF_W | libc::BPF_ABS;
+    let jeq = libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K;
+    let ret = libc::BPF_RET | libc::BPF_K;
+    let errno = |errno: c_int| libc::SECCOMP_RET_ERRNO | (errno as u32 & libc::SECCOMP_RET_DATA);
+
+    let mut program = vec![
+        statement(load, ARCH),
+        jump(jeq, AUDIT_ARCH, 1, 0),
+        statement(ret, libc::SECCOMP_RET_KILL_PROCESS),
+        statement(load, NR),
+    ];
+    for nr in allowed_syscalls() {
+        program.push(jump(jeq, nr as u32, 0, 1));
+        program.push(statement(ret, libc::SECCOMP_RET_ALLOW));
+    }
+    // Threads may be started, such as by the D-Bus service, but not
+    // processes.  The arguments of clone3 are out of reach of the
+    // filter, so it claims not to exist, which makes glibc use clone.
+    program.extend([
+        jump(jeq, libc::SYS_clone3 as u32, 0, 1),
+        statement(ret, errno(libc::ENOSYS)),
+        jump(jeq, libc::SYS_clone as u32, 0, 3),
+        statement(load, ARG0),
+        jump(
+            libc::BPF_JMP | libc::BPF_JSET | libc::BPF_K,
+            libc::CLONE_THREAD as u32,
+            0,
+            1,
+        ),
+        statement(ret, libc::SECCOMP_RET_ALLOW),
+        statement(ret, errno(libc::EPERM)),
+    ]);
+
+    let prog = libc::sock_fprog {
+        len: program.len() as u16,
+        filter: program.as_mut_ptr(),
+    };
+    let status = unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            libc::SECCOMP_SET_MODE_FILTER,
+            libc::SECCOMP_FILTER_FLAG_TSYNC,
+            &prog,
+        )
+    };
+    if status != 0 {
+        if status > 0 {
+            bail!("thread {status} can't be given the filter");
+        }
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
@@ -0,0 +1,100 @@
+use anyhow::Result;
+use std::io::Read;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+
+/// Follows logind on the system bus, and becomes readable when a
+/// login session is locked: either because logind asked its screen
+/// locker to lock it, or because the screen locker says it has
+pub struct SessionLockWatcher {
+    wakeup: UnixStream,
+}
+
+impl SessionLockWatcher {
+    #[cfg(feature = "dbus")]
+    pub fn spawn() -> Result<Self> {
+        use anyhow::Context;
+        use std::io::Write;
+
+        let (wakeup, mut notify) = UnixStream::pair().context("creating a socket pair")?;
+        wakeup.set_nonblocking(true)?;
+        let connection =
+            zbus::blocking::Connection::system().context("connecting to the system bus")?;
+        let messages = zbus::blocking::MessageIterator::for_match_rule(
+            "type='signal',sender='org.freedesktop.login1',\
+             path_namespace='/org/freedesktop/login1/session'",
+            &connection,
+            None,
+        )
+        .context("following logind")?;
+        std::thread::Builder::new()
+            .name("session-lock".to_string())
+            .spawn(move || {
+                for message in messages {
+                    let locked = match message {
+                        Ok(message) => is_lock_signal(&message),
+                        Err(err) => {
+                            log::error!("No longer watching for the session lock: {err:#}");
+                            return;
+                        }
+                    };
+                    if locked {
+                        log::debug!("a session was locked");
+                        if notify.write_all(&[0]).is_err() {
+                            return;
+                        }
+                    }
+                }
+            })
+            .context("starting the session lock watcher thread")?;
+        Ok(Self { wakeup })
+    }
+
+    #[cfg(not(feature = "dbus"))]
+    pub fn spawn() -> Result<Self> {
+        anyhow::bail!("evremap was built without the dbus feature")
+    }
+
+    /// Returns true if a session was locked since this was last called
+    pub fn locked(&self) -> bool {
+        let mut buf = [0u8; 64];
+        let mut locked = false;
+        while let Ok(n) = (&self.wakeup).read(&mut buf) {
+            if n == 0 {
+                break;
+            }
+            locked = true;
+        }
+        locked
+    }
+}
+
+impl AsRawFd for SessionLockWatcher {
+    fn as_raw_fd(&self) -> RawFd {
+        self.wakeup.as_raw_fd()
+    }
+}
+
+/// Returns true for the `Lock` signal of a logind session, and for
+/// a change of its `LockedHint` property to true
+#[cfg(feature = "dbus")]
+fn is_lock_signal(message: &zbus::Message) -> bool {
+    use std::collections::HashMap;
+    use zbus::zvariant::OwnedValue;
+
+    let header = message.header();
+    match header.member().map(|member| member.as_str()) {
+        Some("Lock") => true,
+        Some("PropertiesChanged") => message
+            .body()
+            .deserialize::<(String, HashMap<String, OwnedValue>, Vec<String>)>()
+            .is_ok_and(|(interface, changed, _)| {
+                interface == "org.freedesktop.login1.Session"
+                    && changed
+                        .get("LockedHint")
+                        .and_then(|value| bool::try_from(value).ok())
+                        == Some(true)
+            }),
+        _ => false,
+    }
+}
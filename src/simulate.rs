@@ -0,0 +1,305 @@
+use anyhow::{bail, Context, Result};
+use evdev_rs::{InputEvent, TimeVal};
+use evremap::mapping::{EventCode, EventType, KeyCode, MappingConfig};
+use evremap::remapper::{describe_event, MappingEngine, Output};
+use serde::Deserialize;
+use std::path::Path;
+use std::time::Duration;
+
+/// How long to keep handling timeouts after the last line of the
+/// script, so that taps and holds that are still pending are decided
+const SETTLE_TIME: Duration = Duration::from_secs(10);
+
+/// Feeds the key events described by `script_file` through the mappings
+/// in `config_file`, and prints the events that would be emitted.  If
+/// `expect_file` is given, the output is compared against its contents
+/// instead, and an error is returned if they differ.
+///
+/// Each line of the script is a time in milliseconds since the start,
+/// optionally followed by a key name and `press`, `release` or `repeat`.
+/// A line with only a time lets the time pass.  Blank lines and lines
+/// beginning with `#` are ignored.
+pub fn simulate(config_file: &Path, script_file: &Path, expect_file: Option<&Path>) -> Result<()> {
+    let config = MappingConfig::from_file(config_file).context(format!(
+        "loading MappingConfig from {}",
+        config_file.display()
+    ))?;
+    // Only the mappings of the first device are simulated
+    let config = config.split_devices().remove(0);
+    let script = std::fs::read_to_string(script_file)
+        .context(format!("reading {}", script_file.display()))?;
+
+    let mut events: Vec<(i64, Option<(KeyCode, i32)>)> = vec![];
+    for (idx, line) in script.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let event =
+            parse_line(line).context(format!("line {} of {}", idx + 1, script_file.display()))?;
+        if events
+            .last()
+            .is_some_and(|(last_time, _)| event.0 < *last_time)
+        {
+            bail!(
+                "line {} of {}: time goes backwards",
+                idx + 1,
+                script_file.display()
+            );
+        }
+        events.push(event);
+    }
+    let output =
+        run_events(config, &events).context(format!("running {}", script_file.display()))?;
+
+    let mut result = String::new();
+    for event in output {
+        let time = as_millis(&event.time);
+        result.push_str(&format!("{time} {}\n", describe_event(&event)));
+    }
+
+    let Some(expect_file) = expect_file else {
+        print!("{result}");
+        return Ok(());
+    };
+    let expected = std::fs::read_to_string(expect_file)
+        .context(format!("reading {}", expect_file.display()))?;
+    let expected: Vec<&str> = expected
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .collect();
+    let actual: Vec<&str> = result.lines().collect();
+    if actual != expected {
+        println!("Expected:");
+        for line in &expected {
+            println!("  {line}");
+        }
+        println!("Actual:");
+        for line in &actual {
+            println!("  {line}");
+        }
+        bail!(
+            "the output of {} doesn't match {}",
+            script_file.display(),
+            expect_file.display()
+        );
+    }
+    println!("{}: OK", script_file.display());
+    Ok(())
+}
+
+/// A file of scenarios for `evremap test`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ScenarioFile {
+    #[serde(default)]
+    scenario: Vec<Scenario>,
+}
+
+/// Key events to feed through the mappings, and the
+/// events that they are expected to produce
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Scenario {
+    name: String,
+    events: Vec<ScenarioEvent>,
+    expect: Vec<ExpectedEvent>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ScenarioEvent {
+    at_ms: i64,
+    key: String,
+    value: i32,
+}
+
+/// An event that should be emitted.  Its time is only
+/// checked if `at_ms` is given.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ExpectedEvent {
+    #[serde(default)]
+    at_ms: Option<i64>,
+    key: String,
+    value: i32,
+}
+
+/// Runs each scenario in `scenario_file` through the mappings in
+/// `config_file`, and returns an error if any of them doesn't
+/// produce the events that it expects
+pub fn test_scenarios(config_file: &Path, scenario_file: &Path) -> Result<()> {
+    let config = MappingConfig::from_file(config_file).context(format!(
+        "loading MappingConfig from {}",
+        config_file.display()
+    ))?;
+    // Only the mappings of the first device are tested
+    let config = config.split_devices().remove(0);
+    let scenarios = std::fs::read_to_string(scenario_file)
+        .context(format!("reading {}", scenario_file.display()))?;
+    let scenarios: ScenarioFile =
+        toml::from_str(&scenarios).context(format!("parsing {}", scenario_file.display()))?;
+    if scenarios.scenario.is_empty() {
+        bail!("{} has no scenarios", scenario_file.display());
+    }
+
+    let mut failed = 0;
+    for scenario in &scenarios.scenario {
+        if !run_scenario(&config, scenario).context(format!("scenario `{}`", scenario.name))? {
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        bail!(
+            "{failed} of the {} scenarios in {} failed",
+            scenarios.scenario.len(),
+            scenario_file.display()
+        );
+    }
+    Ok(())
+}
+
+/// Runs a scenario and prints whether it produced the expected events
+fn run_scenario(config: &MappingConfig, scenario: &Scenario) -> Result<bool> {
+    let mut events = vec![];
+    for event in &scenario.events {
+        let key = match EventCode::from_str(&EventType::EV_KEY, &event.key) {
+            Some(EventCode::EV_KEY(key)) => key,
+            _ => bail!("unknown key `{}`", event.key),
+        };
+        events.push((event.at_ms, Some((key, event.value))));
+    }
+    events.sort_by_key(|(time, _)| *time);
+    for expected in &scenario.expect {
+        if ![EventType::EV_KEY, EventType::EV_REL]
+            .iter()
+            .any(|event_type| EventCode::from_str(event_type, &expected.key).is_some())
+        {
+            bail!("unknown key `{}` in expect", expected.key);
+        }
+    }
+
+    let output = run_events(config.clone(), &events)?;
+    let matches = output.len() == scenario.expect.len()
+        && output
+            .iter()
+            .zip(&scenario.expect)
+            .all(|(event, expected)| {
+                event_name(&event.event_code) == expected.key
+                    && event.value == expected.value
+                    && expected
+                        .at_ms
+                        .is_none_or(|time| time == as_millis(&event.time))
+            });
+    if matches {
+        println!("{}: OK", scenario.name);
+        return Ok(true);
+    }
+    println!("{}: FAILED", scenario.name);
+    println!("Expected:");
+    for expected in &scenario.expect {
+        match expected.at_ms {
+            Some(time) => println!("  {time} {} {}", expected.key, expected.value),
+            None => println!("  {} {}", expected.key, expected.value),
+        }
+    }
+    println!("Actual:");
+    for event in &output {
+        println!(
+            "  {} {} {}",
+            as_millis(&event.time),
+            event_name(&event.event_code),
+            event.value
+        );
+    }
+    Ok(false)
+}
+
+/// Feeds `events`, each at its time in milliseconds, through the
+/// mappings in `config`, and returns the events that are emitted,
+/// apart from the `EV_SYN` reports.  `events` must be in order of time.
+fn run_events(
+    config: MappingConfig,
+    events: &[(i64, Option<(KeyCode, i32)>)],
+) -> Result<Vec<InputEvent>> {
+    let mut engine = MappingEngine::new(config, &millis(0));
+    let mut output = vec![];
+    let mut last_time = 0;
+    for &(time, event) in events {
+        last_time = time;
+        output.extend(run_timeouts(&mut engine, &millis(time))?);
+        if let Some((key, value)) = event {
+            output.extend(engine.process_key(key, value, &millis(time))?);
+        }
+    }
+    let settle_time = last_time + SETTLE_TIME.as_millis() as i64;
+    output.extend(run_timeouts(&mut engine, &millis(settle_time))?);
+    Ok(output
+        .into_iter()
+        .filter_map(|item| match item {
+            Output::Event(event) if !matches!(event.event_code, EventCode::EV_SYN(_)) => {
+                Some(event)
+            }
+            _ => None,
+        })
+        .collect())
+}
+
+/// The name of an event code, as it is written in a scenario
+fn event_name(code: &EventCode) -> String {
+    match code {
+        EventCode::EV_KEY(key) => format!("{key:?}"),
+        code => code.to_string(),
+    }
+}
+
+/// Parses a line of the script into its time in milliseconds,
+/// and the key and value of its event, if any
+fn parse_line(line: &str) -> Result<(i64, Option<(KeyCode, i32)>)> {
+    let mut words = line.split_whitespace();
+    let time = words.next().unwrap_or_default();
+    let time: i64 = time
+        .parse()
+        .context(format!("expected a time in milliseconds, not `{time}`"))?;
+    let Some(key) = words.next() else {
+        return Ok((time, None));
+    };
+    let key = match EventCode::from_str(&EventType::EV_KEY, key) {
+        Some(EventCode::EV_KEY(key)) => key,
+        _ => bail!("unknown key `{key}`"),
+    };
+    let value = match words.next() {
+        Some("press") => 1,
+        Some("release") => 0,
+        Some("repeat") => 2,
+        other => bail!(
+            "expected press, release or repeat, not `{}`",
+            other.unwrap_or_default()
+        ),
+    };
+    if let Some(extra) = words.next() {
+        bail!("unexpected `{extra}`");
+    }
+    Ok((time, Some((key, value))))
+}
+
+/// Handles each timeout that falls due up to `time`
+fn run_timeouts(engine: &mut MappingEngine, time: &TimeVal) -> Result<Vec<Output>> {
+    let mut output = vec![];
+    while let Some(deadline) = engine.next_deadline().filter(|deadline| deadline <= time) {
+        output.extend(engine.handle_timeouts(&deadline)?);
+        if engine.next_deadline() == Some(deadline) {
+            bail!("a timeout at {deadline:?} was not resolved");
+        }
+    }
+    Ok(output)
+}
+
+fn as_millis(time: &TimeVal) -> i64 {
+    time.tv_sec * 1000 + time.tv_usec / 1000
+}
+
+fn millis(ms: i64) -> TimeVal {
+    TimeVal::new(ms / 1000, (ms % 1000) * 1000)
+}
@@ -0,0 +1,62 @@
+use crate::mapping::CommandSpec;
+use crate::privileges::{lookup_account, Account};
+use anyhow::{Context, Result};
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+
+/// Starts `command` in the background.  When it has a user, it runs
+/// with their uid, groups and home directory, which needs evremap to
+/// be running as root.  A thread waits for it to exit, so that it
+/// doesn't linger as a zombie.
+pub fn spawn(command: &CommandSpec) -> Result<()> {
+    let mut process = Command::new(&command.argv[0]);
+    process
+        .args(&command.argv[1..])
+        .stdin(Stdio::null())
+        .current_dir("/");
+    match &command.user {
+        Some(user) => {
+            let account = lookup_account(user)?;
+            process
+                .env("HOME", &account.home)
+                .env("USER", &account.name)
+                .env("LOGNAME", &account.name);
+            // Where the session bus of the user is, for the likes of playerctl
+            let runtime_dir = format!("/run/user/{}", account.uid);
+            if std::path::Path::new(&runtime_dir).is_dir() {
+                process.env("XDG_RUNTIME_DIR", &runtime_dir);
+            }
+            let Account {
+                uid, gid, groups, ..
+            } = account;
+            // The groups must be set before the uid, which std would
+            // do the other way around, and setgroups is safe to call
+            // between fork and exec
+            unsafe {
+                process.pre_exec(move || {
+                    if libc::setgroups(groups.len(), groups.as_ptr()) != 0
+                        || libc::setgid(gid) != 0
+                        || libc::setuid(uid) != 0
+                    {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                    Ok(())
+                });
+            }
+        }
+        None => {
+            if unsafe { libc::geteuid() } == 0 {
+                log::warn!(
+                    "Running {:?} as root; set `user` on the command entry \
+                     to run it as someone else",
+                    command.argv
+                );
+            }
+        }
+    }
+    process.envs(&command.env);
+    let mut child = process.spawn().context("starting it")?;
+    log::debug!("Started {:?} as pid {}", command.argv, child.id());
+    std::thread::spawn(move || child.wait());
+    Ok(())
+}
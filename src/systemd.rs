@@ -0,0 +1,72 @@
+use anyhow::{Context, Result};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::time::Duration;
+
+/// Sends notifications to the service manager, when running as a
+/// `Type=notify` systemd service.  This implements the protocol of
+/// sd_notify(3) directly, so that we don't depend on libsystemd.
+pub struct Notifier {
+    socket: UnixDatagram,
+    address: SocketAddr,
+    /// How often the service manager expects `WATCHDOG=1`,
+    /// if it has a watchdog enabled for us
+    watchdog_interval: Option<Duration>,
+}
+
+impl Notifier {
+    /// Returns a notifier if the service manager asked for
+    /// notifications by setting NOTIFY_SOCKET
+    pub fn from_env() -> Option<Self> {
+        let path = std::env::var_os("NOTIFY_SOCKET")?;
+        match Self::connect(&path.to_string_lossy()) {
+            Ok(notifier) => Some(notifier),
+            Err(err) => {
+                log::warn!("Unable to notify systemd: {err:#}");
+                None
+            }
+        }
+    }
+
+    fn connect(path: &str) -> Result<Self> {
+        // A leading @ means an address in the abstract namespace
+        let address = match path.strip_prefix('@') {
+            Some(name) => SocketAddr::from_abstract_name(name),
+            None => SocketAddr::from_pathname(path),
+        }
+        .context(format!("invalid NOTIFY_SOCKET {path}"))?;
+        let socket = UnixDatagram::unbound().context("creating a socket")?;
+        Ok(Self {
+            socket,
+            address,
+            watchdog_interval: watchdog_interval(),
+        })
+    }
+
+    pub fn notify(&self, state: &str) {
+        if let Err(err) = self.socket.send_to_addr(state.as_bytes(), &self.address) {
+            log::warn!("Unable to notify systemd of {state:?}: {err:#}");
+        }
+    }
+
+    /// Returns how often to send `WATCHDOG=1`: twice as often as
+    /// the service manager requires, so that a late wakeup isn't
+    /// mistaken for a hang
+    pub fn watchdog_interval(&self) -> Option<Duration> {
+        self.watchdog_interval.map(|interval| interval / 2)
+    }
+}
+
+/// Returns the watchdog interval that the service manager set
+/// for this process, if any
+fn watchdog_interval() -> Option<Duration> {
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    // WATCHDOG_PID says which process the watchdog is for,
+    // in case the variables were inherited by a child
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse() != Ok(std::process::id()) {
+            return None;
+        }
+    }
+    (usec > 0).then(|| Duration::from_micros(usec))
+}
@@ -0,0 +1,126 @@
+use anyhow::{bail, Context, Result};
+use evdev_rs::{Device, ReadFlag, ReadStatus};
+use evremap::deviceinfo::DeviceInfo;
+use evremap::mapping::{EventCode, KeyCode, MappingConfig};
+use std::io::Write;
+use std::path::Path;
+
+/// Guides the user through choosing a device and pressing pairs of keys,
+/// and writes the resulting remapping config to `config_file`
+pub fn run_wizard(config_file: &Path) -> Result<()> {
+    if config_file.exists() {
+        bail!(
+            "{} already exists; refusing to overwrite it",
+            config_file.display()
+        );
+    }
+
+    let devices = DeviceInfo::obtain_device_list()?;
+    if devices.is_empty() {
+        bail!("No input devices found. You may need to run this as root.");
+    }
+    for (idx, item) in devices.iter().enumerate() {
+        println!("{:3}: {} ({})", idx + 1, item.name, item.path.display());
+    }
+    let device = loop {
+        let answer = prompt("Which device do you want to remap? ")?;
+        match answer.parse::<usize>() {
+            Ok(n) if n >= 1 && n <= devices.len() => break &devices[n - 1],
+            _ => println!("Please enter a number between 1 and {}", devices.len()),
+        }
+    };
+
+    let f =
+        std::fs::File::open(&device.path).context(format!("opening {}", device.path.display()))?;
+    let input = Device::new_from_file(f).with_context(|| {
+        format!(
+            "failed to create new Device from file {}",
+            device.path.display()
+        )
+    })?;
+
+    let mut remaps = vec![];
+    loop {
+        // Discard the keys that were typed to answer the previous prompt
+        while input.has_event_pending() {
+            input.next_event(ReadFlag::NORMAL)?;
+        }
+        println!("Press the key that you want to remap");
+        let from = next_key_press(&input)?;
+        println!("Now press the key that {from:?} should produce");
+        let to = next_key_press(&input)?;
+        println!("{from:?} -> {to:?}");
+        remaps.push((from, to));
+
+        // The key presses were also seen by the terminal; don't
+        // mistake them for an answer to the prompt
+        flush_stdin();
+        let answer = prompt("Remap another key? [y/N] ")?;
+        if !answer.eq_ignore_ascii_case("y") {
+            break;
+        }
+    }
+
+    let mut toml_data = format!(
+        "device_name = {}\n",
+        toml::Value::String(device.name.clone())
+    );
+    if devices.iter().filter(|d| d.name == device.name).count() > 1 {
+        toml_data.push_str(&format!(
+            "phys = {}\n",
+            toml::Value::String(device.phys.clone())
+        ));
+    }
+    for (from, to) in remaps {
+        toml_data.push_str(&format!(
+            "\n[[remap]]\ninput = [\"{}\"]\noutput = [\"{}\"]\n",
+            EventCode::EV_KEY(from),
+            EventCode::EV_KEY(to)
+        ));
+    }
+
+    std::fs::write(config_file, toml_data).context(format!("writing {}", config_file.display()))?;
+    MappingConfig::from_file(config_file).context(format!(
+        "verifying the generated config {}",
+        config_file.display()
+    ))?;
+    println!(
+        "Wrote {}. Run `evremap remap {}` to use it.",
+        config_file.display(),
+        config_file.display()
+    );
+    Ok(())
+}
+
+fn prompt(question: &str) -> Result<String> {
+    print!("{question}");
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    if std::io::stdin().read_line(&mut answer)? == 0 {
+        bail!("end of input");
+    }
+    Ok(answer.trim().to_string())
+}
+
+fn flush_stdin() {
+    unsafe {
+        libc::tcflush(libc::STDIN_FILENO, libc::TCIFLUSH);
+    }
+}
+
+/// Waits for a key to be pressed on the device and returns it
+fn next_key_press(input: &Device) -> Result<KeyCode> {
+    loop {
+        let (status, event) = input.next_event(ReadFlag::NORMAL | ReadFlag::BLOCKING)?;
+        match status {
+            ReadStatus::Success => {
+                if let EventCode::EV_KEY(key) = event.event_code {
+                    if event.value == 1 {
+                        return Ok(key);
+                    }
+                }
+            }
+            ReadStatus::Sync => bail!("ReadStatus::Sync!"),
+        }
+    }
+}
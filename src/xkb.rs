@@ -0,0 +1,280 @@
+use crate::mapping::{KeyCode, MacroStep};
+use anyhow::{bail, Result};
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+
+/// evdev key codes are the XKB key codes less 8, a legacy of X11
+const EVDEV_OFFSET: u32 = 8;
+const XKB_MOD_INVALID: u32 = 0xffff_ffff;
+
+/// The names that select an XKB keymap, as in `setxkbmap`
+#[repr(C)]
+struct RuleNames {
+    rules: *const c_char,
+    model: *const c_char,
+    layout: *const c_char,
+    variant: *const c_char,
+    options: *const c_char,
+}
+
+/// The functions of libxkbcommon that we use.  The library is loaded
+/// when a config needs it, rather than linked, so that evremap doesn't
+/// require it otherwise.
+struct Functions {
+    context_new: unsafe extern "C" fn(c_int) -> *mut c_void,
+    context_unref: unsafe extern "C" fn(*mut c_void),
+    keymap_new_from_names:
+        unsafe extern "C" fn(*mut c_void, *const RuleNames, c_int) -> *mut c_void,
+    keymap_unref: unsafe extern "C" fn(*mut c_void),
+    keymap_min_keycode: unsafe extern "C" fn(*mut c_void) -> u32,
+    keymap_max_keycode: unsafe extern "C" fn(*mut c_void) -> u32,
+    keymap_num_levels_for_key: unsafe extern "C" fn(*mut c_void, u32, u32) -> u32,
+    keymap_key_get_syms_by_level:
+        unsafe extern "C" fn(*mut c_void, u32, u32, u32, *mut *const u32) -> c_int,
+    keymap_key_get_mods_for_level:
+        unsafe extern "C" fn(*mut c_void, u32, u32, u32, *mut u32, usize) -> usize,
+    keymap_mod_get_index: unsafe extern "C" fn(*mut c_void, *const c_char) -> u32,
+    keysym_to_utf32: unsafe extern "C" fn(u32) -> u32,
+}
+
+impl Functions {
+    fn load(lib: *mut c_void) -> Result<Self> {
+        unsafe fn sym<T>(lib: *mut c_void, name: &CStr) -> Result<T> {
+            let ptr = libc::dlsym(lib, name.as_ptr());
+            if ptr.is_null() {
+                bail!("libxkbcommon has no {}", name.to_string_lossy());
+            }
+            Ok(std::mem::transmute_copy(&ptr))
+        }
+        unsafe {
+            Ok(Self {
+                context_new: sym(lib, c"xkb_context_new")?,
+                context_unref: sym(lib, c"xkb_context_unref")?,
+                keymap_new_from_names: sym(lib, c"xkb_keymap_new_from_names")?,
+                keymap_unref: sym(lib, c"xkb_keymap_unref")?,
+                keymap_min_keycode: sym(lib, c"xkb_keymap_min_keycode")?,
+                keymap_max_keycode: sym(lib, c"xkb_keymap_max_keycode")?,
+                keymap_num_levels_for_key: sym(lib, c"xkb_keymap_num_levels_for_key")?,
+                keymap_key_get_syms_by_level: sym(lib, c"xkb_keymap_key_get_syms_by_level")?,
+                keymap_key_get_mods_for_level: sym(lib, c"xkb_keymap_key_get_mods_for_level")?,
+                keymap_mod_get_index: sym(lib, c"xkb_keymap_mod_get_index")?,
+                keysym_to_utf32: sym(lib, c"xkb_keysym_to_utf32")?,
+            })
+        }
+    }
+}
+
+/// A key, and the modifiers to hold while pressing it,
+/// that together type a character
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyStroke {
+    pub key: KeyCode,
+    pub modifiers: Vec<KeyCode>,
+}
+
+/// An XKB keymap, for finding the keys that type
+/// the characters of a layout
+pub struct Keymap {
+    lib: *mut c_void,
+    fns: Functions,
+    context: *mut c_void,
+    keymap: *mut c_void,
+}
+
+impl Keymap {
+    /// Compiles the keymap of `layout` and its `variant`, such as
+    /// `de` and `nodeadkeys`.  XKB chooses those that are None from
+    /// the XKB_DEFAULT_LAYOUT environment variable and friends,
+    /// and otherwise uses the US layout.
+    pub fn new(layout: Option<&str>, variant: Option<&str>) -> Result<Self> {
+        let layout = layout.map(CString::new).transpose()?;
+        let variant = variant.map(CString::new).transpose()?;
+        let lib = unsafe { libc::dlopen(c"libxkbcommon.so.0".as_ptr(), libc::RTLD_NOW) };
+        if lib.is_null() {
+            bail!("unable to load libxkbcommon.so.0; is libxkbcommon installed?");
+        }
+        let fns = match Functions::load(lib) {
+            Ok(fns) => fns,
+            Err(err) => {
+                unsafe { libc::dlclose(lib) };
+                return Err(err);
+            }
+        };
+        let context = unsafe { (fns.context_new)(0) };
+        let mut keymap = Self {
+            lib,
+            fns,
+            context,
+            keymap: std::ptr::null_mut(),
+        };
+        if context.is_null() {
+            bail!("unable to create an XKB context");
+        }
+        let names = RuleNames {
+            rules: std::ptr::null(),
+            model: std::ptr::null(),
+            layout: layout.as_ref().map_or(std::ptr::null(), |s| s.as_ptr()),
+            variant: variant.as_ref().map_or(std::ptr::null(), |s| s.as_ptr()),
+            options: std::ptr::null(),
+        };
+        keymap.keymap = unsafe { (keymap.fns.keymap_new_from_names)(context, &names, 0) };
+        if keymap.keymap.is_null() {
+            bail!(
+                "unable to compile the XKB keymap for layout {:?} variant {:?}",
+                layout.as_deref().unwrap_or(c"(default)"),
+                variant.as_deref().unwrap_or(c"(default)"),
+            );
+        }
+        Ok(keymap)
+    }
+
+    /// Returns the key that types `c` on the first layout of the
+    /// keymap, and the modifiers that it needs, if there is one.
+    /// Only Shift and AltGr are used as modifiers.
+    pub fn find(&self, c: char) -> Option<KeyStroke> {
+        let c = match c {
+            // Return types a carriage return
+            '\n' => '\r',
+            c => c,
+        };
+        let fns = &self.fns;
+        let shift = self.mod_index(c"Shift");
+        let level3 = [self.mod_index(c"Mod5"), self.mod_index(c"LevelThree")];
+        let min = unsafe { (fns.keymap_min_keycode)(self.keymap) };
+        let max = unsafe { (fns.keymap_max_keycode)(self.keymap) };
+        let mut best: Option<KeyStroke> = None;
+        for code in min.max(EVDEV_OFFSET)..=max {
+            let levels = unsafe { (fns.keymap_num_levels_for_key)(self.keymap, code, 0) };
+            for level in 0..levels {
+                let mut syms = std::ptr::null();
+                let count = unsafe {
+                    (fns.keymap_key_get_syms_by_level)(self.keymap, code, 0, level, &mut syms)
+                };
+                if count != 1 || unsafe { (fns.keysym_to_utf32)(*syms) } != c as u32 {
+                    continue;
+                }
+                let Some(key) = evdev_rs::enums::int_to_ev_key(code - EVDEV_OFFSET) else {
+                    continue;
+                };
+                let mut masks = [0u32; 16];
+                let count = unsafe {
+                    (fns.keymap_key_get_mods_for_level)(
+                        self.keymap,
+                        code,
+                        0,
+                        level,
+                        masks.as_mut_ptr(),
+                        masks.len(),
+                    )
+                };
+                for &mask in &masks[..count.min(masks.len())] {
+                    let mut modifiers = vec![];
+                    let mut rest = mask;
+                    if let Some(shift) = shift.filter(|shift| mask & (1 << shift) != 0) {
+                        modifiers.push(KeyCode::KEY_LEFTSHIFT);
+                        rest &= !(1 << shift);
+                    }
+                    let level3_mask = level3.iter().flatten().fold(0, |m, idx| m | (1 << idx));
+                    if mask & level3_mask != 0 {
+                        modifiers.push(KeyCode::KEY_RIGHTALT);
+                        rest &= !level3_mask;
+                    }
+                    if rest != 0 {
+                        // Needs a modifier that we don't know how to press
+                        continue;
+                    }
+                    if best
+                        .as_ref()
+                        .is_none_or(|best| modifiers.len() < best.modifiers.len())
+                    {
+                        best = Some(KeyStroke { key, modifiers });
+                    }
+                }
+            }
+        }
+        best
+    }
+
+    fn mod_index(&self, name: &CStr) -> Option<u32> {
+        let idx = unsafe { (self.fns.keymap_mod_get_index)(self.keymap, name.as_ptr()) };
+        (idx != XKB_MOD_INVALID && idx < 32).then_some(idx)
+    }
+}
+
+impl Drop for Keymap {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.keymap.is_null() {
+                (self.fns.keymap_unref)(self.keymap);
+            }
+            if !self.context.is_null() {
+                (self.fns.context_unref)(self.context);
+            }
+            libc::dlclose(self.lib);
+        }
+    }
+}
+
+/// Returns the macro steps that type `text`.  Characters that aren't
+/// on the layout of `keymap`, or every character if there is no keymap,
+/// are typed as their code point after Ctrl+Shift+U, which GTK and
+/// IBus understand.
+pub fn type_text(text: &str, keymap: Option<&Keymap>) -> Vec<MacroStep> {
+    let find = |c: char| keymap.and_then(|keymap| keymap.find(c));
+    let mut steps = vec![];
+    for c in text.chars() {
+        match find(c) {
+            Some(stroke) => push_stroke(&mut steps, &stroke),
+            None => {
+                let u = find('u').map_or(KeyCode::KEY_U, |stroke| stroke.key);
+                steps.push(MacroStep::Press(KeyCode::KEY_LEFTCTRL));
+                steps.push(MacroStep::Press(KeyCode::KEY_LEFTSHIFT));
+                steps.push(MacroStep::Tap(u));
+                steps.push(MacroStep::Release(KeyCode::KEY_LEFTSHIFT));
+                steps.push(MacroStep::Release(KeyCode::KEY_LEFTCTRL));
+                for digit in format!("{:x}", c as u32).chars() {
+                    let stroke = find(digit).unwrap_or_else(|| KeyStroke {
+                        key: us_hex_digit_key(digit),
+                        modifiers: vec![],
+                    });
+                    push_stroke(&mut steps, &stroke);
+                }
+                steps.push(MacroStep::Tap(KeyCode::KEY_SPACE));
+            }
+        }
+    }
+    steps
+}
+
+fn push_stroke(steps: &mut Vec<MacroStep>, stroke: &KeyStroke) {
+    steps.extend(stroke.modifiers.iter().map(|&m| MacroStep::Press(m)));
+    steps.push(MacroStep::Tap(stroke.key));
+    steps.extend(
+        stroke
+            .modifiers
+            .iter()
+            .rev()
+            .map(|&m| MacroStep::Release(m)),
+    );
+}
+
+/// The key for a hex digit on a US layout
+fn us_hex_digit_key(digit: char) -> KeyCode {
+    match digit {
+        '0' => KeyCode::KEY_0,
+        '1' => KeyCode::KEY_1,
+        '2' => KeyCode::KEY_2,
+        '3' => KeyCode::KEY_3,
+        '4' => KeyCode::KEY_4,
+        '5' => KeyCode::KEY_5,
+        '6' => KeyCode::KEY_6,
+        '7' => KeyCode::KEY_7,
+        '8' => KeyCode::KEY_8,
+        '9' => KeyCode::KEY_9,
+        'a' => KeyCode::KEY_A,
+        'b' => KeyCode::KEY_B,
+        'c' => KeyCode::KEY_C,
+        'd' => KeyCode::KEY_D,
+        'e' => KeyCode::KEY_E,
+        _ => KeyCode::KEY_F,
+    }
+}
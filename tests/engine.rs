@@ -0,0 +1,2662 @@
+use evdev_rs::enums::{EventCode, EV_MSC, EV_SYN};
+use evdev_rs::{InputEvent, TimeVal};
+use evremap::mapping::{
+    default_hands, AutoShift, CommandSpec, KeyCode, LayerCondition, LayerDefault, LayerMode,
+    LedCode, MacroStep, Mapping, ReleaseTrigger, SwitchCode,
+};
+use evremap::remapper::{MappingEngine, Output};
+use evremap::MappingConfig;
+use std::time::Duration;
+
+const PRESS: i32 = 1;
+const RELEASE: i32 = 0;
+
+/// Runs `events`, each of which is a key, value and time in
+/// milliseconds, through a fresh engine.  Returns the key events
+/// that it produces, including those produced by timeouts.
+fn run(config: MappingConfig, events: &[(KeyCode, i32, i64)]) -> Vec<(KeyCode, i32)> {
+    let mut engine = MappingEngine::new(config, &millis(0));
+    let mut output = vec![];
+    for (key, value, time) in events {
+        let time = millis(*time);
+        while let Some(deadline) = engine.next_deadline().filter(|d| *d <= time) {
+            output.extend(engine.handle_timeouts(&deadline).unwrap());
+        }
+        output.extend(engine.process_key(*key, *value, &time).unwrap());
+    }
+    key_events(output)
+}
+
+/// Returns the key events among `output`, as a key and value
+fn key_events(output: Vec<Output>) -> Vec<(KeyCode, i32)> {
+    output
+        .into_iter()
+        .filter_map(|item| match item {
+            Output::Event(event) => match event.event_code {
+                EventCode::EV_KEY(key) => Some((key, event.value)),
+                _ => None,
+            },
+            Output::Run(_) => None,
+        })
+        .collect()
+}
+
+fn millis(ms: i64) -> TimeVal {
+    TimeVal::new(ms / 1000, (ms % 1000) * 1000)
+}
+
+#[test]
+fn unmapped_keys_pass_through() {
+    let config = MappingConfig::builder().build();
+    assert_eq!(
+        run(
+            config,
+            &[(KeyCode::KEY_A, PRESS, 0), (KeyCode::KEY_A, RELEASE, 50)]
+        ),
+        vec![(KeyCode::KEY_A, PRESS), (KeyCode::KEY_A, RELEASE)]
+    );
+}
+
+fn dual_role_config() -> MappingConfig {
+    MappingConfig::builder()
+        .dual_role(
+            KeyCode::KEY_CAPSLOCK,
+            [KeyCode::KEY_LEFTCTRL],
+            [KeyCode::KEY_ESC],
+        )
+        .build()
+}
+
+#[test]
+fn dual_role_tap() {
+    assert_eq!(
+        run(
+            dual_role_config(),
+            &[
+                (KeyCode::KEY_CAPSLOCK, PRESS, 0),
+                (KeyCode::KEY_CAPSLOCK, RELEASE, 50),
+            ]
+        ),
+        vec![
+            (KeyCode::KEY_LEFTCTRL, PRESS),
+            (KeyCode::KEY_LEFTCTRL, RELEASE),
+            (KeyCode::KEY_ESC, PRESS),
+            (KeyCode::KEY_ESC, RELEASE),
+        ]
+    );
+}
+
+#[test]
+fn dual_role_hold() {
+    assert_eq!(
+        run(
+            dual_role_config(),
+            &[
+                (KeyCode::KEY_CAPSLOCK, PRESS, 0),
+                (KeyCode::KEY_C, PRESS, 50),
+                (KeyCode::KEY_C, RELEASE, 100),
+                (KeyCode::KEY_CAPSLOCK, RELEASE, 150),
+            ]
+        ),
+        vec![
+            (KeyCode::KEY_LEFTCTRL, PRESS),
+            (KeyCode::KEY_C, PRESS),
+            (KeyCode::KEY_C, RELEASE),
+            (KeyCode::KEY_LEFTCTRL, RELEASE),
+        ]
+    );
+}
+
+#[test]
+fn dual_role_released_after_timeout() {
+    assert_eq!(
+        run(
+            dual_role_config(),
+            &[
+                (KeyCode::KEY_CAPSLOCK, PRESS, 0),
+                (KeyCode::KEY_CAPSLOCK, RELEASE, 500),
+            ]
+        ),
+        vec![
+            (KeyCode::KEY_LEFTCTRL, PRESS),
+            (KeyCode::KEY_LEFTCTRL, RELEASE),
+        ]
+    );
+}
+
+#[test]
+fn suppressed_modifier_stays_held_after_another_key_uses_it() {
+    let mut config = dual_role_config();
+    config.suppress_modifier_pulses = true;
+    assert_eq!(
+        run(
+            config,
+            &[
+                (KeyCode::KEY_CAPSLOCK, PRESS, 0),
+                (KeyCode::KEY_C, PRESS, 50),
+                (KeyCode::KEY_C, RELEASE, 100),
+                (KeyCode::KEY_CAPSLOCK, RELEASE, 1000),
+            ]
+        ),
+        vec![
+            (KeyCode::KEY_LEFTCTRL, PRESS),
+            (KeyCode::KEY_C, PRESS),
+            (KeyCode::KEY_C, RELEASE),
+            (KeyCode::KEY_LEFTCTRL, RELEASE),
+        ]
+    );
+}
+
+#[test]
+fn suppressed_modifier_pulse_is_not_emitted_for_a_tap() {
+    let mut config = dual_role_config();
+    config.suppress_modifier_pulses = true;
+    assert_eq!(
+        run(
+            config,
+            &[
+                (KeyCode::KEY_CAPSLOCK, PRESS, 0),
+                (KeyCode::KEY_CAPSLOCK, RELEASE, 50),
+            ]
+        ),
+        vec![(KeyCode::KEY_ESC, PRESS), (KeyCode::KEY_ESC, RELEASE)]
+    );
+}
+
+#[test]
+fn keys_wait_for_their_modifiers_to_settle() {
+    let mut config = MappingConfig::builder()
+        .remap([KeyCode::KEY_F1], [KeyCode::KEY_LEFTCTRL, KeyCode::KEY_C])
+        .build();
+    config.modifier_settle = Some(Duration::from_millis(5));
+    let mut engine = MappingEngine::new(config, &millis(0));
+    let mut output = vec![];
+    output.extend(
+        engine
+            .process_key(KeyCode::KEY_F1, PRESS, &millis(0))
+            .unwrap(),
+    );
+    assert_eq!(engine.next_deadline(), Some(millis(5)));
+    // Released before the modifier has settled
+    output.extend(
+        engine
+            .process_key(KeyCode::KEY_F1, RELEASE, &millis(2))
+            .unwrap(),
+    );
+    output.extend(engine.handle_timeouts(&millis(5)).unwrap());
+    assert_eq!(engine.next_deadline(), None);
+    let events: Vec<(KeyCode, i32, i64)> = output
+        .into_iter()
+        .filter_map(|item| match item {
+            Output::Event(event) => match event.event_code {
+                EventCode::EV_KEY(key) => Some((
+                    key,
+                    event.value,
+                    event.time.tv_sec * 1000 + event.time.tv_usec / 1000,
+                )),
+                _ => None,
+            },
+            Output::Run(_) => None,
+        })
+        .collect();
+    assert_eq!(
+        events,
+        vec![
+            (KeyCode::KEY_LEFTCTRL, PRESS, 0),
+            (KeyCode::KEY_C, PRESS, 5),
+            (KeyCode::KEY_C, RELEASE, 5),
+            (KeyCode::KEY_LEFTCTRL, RELEASE, 5),
+        ]
+    );
+}
+
+#[test]
+fn chord() {
+    let config = MappingConfig::builder()
+        .remap([KeyCode::KEY_LEFTALT, KeyCode::KEY_H], [KeyCode::KEY_LEFT])
+        .build();
+    assert_eq!(
+        run(
+            config,
+            &[
+                (KeyCode::KEY_LEFTALT, PRESS, 0),
+                (KeyCode::KEY_H, PRESS, 50),
+                (KeyCode::KEY_H, RELEASE, 100),
+                (KeyCode::KEY_LEFTALT, RELEASE, 150),
+            ]
+        ),
+        vec![
+            (KeyCode::KEY_LEFTALT, PRESS),
+            (KeyCode::KEY_LEFTALT, RELEASE),
+            (KeyCode::KEY_LEFT, PRESS),
+            (KeyCode::KEY_LEFT, RELEASE),
+            (KeyCode::KEY_LEFTALT, PRESS),
+            (KeyCode::KEY_LEFTALT, RELEASE),
+        ]
+    );
+}
+
+#[test]
+fn modifiers_are_pressed_first_and_released_last() {
+    let config = MappingConfig::builder()
+        .remap(
+            [KeyCode::KEY_F1],
+            [
+                KeyCode::KEY_C,
+                KeyCode::KEY_LEFTSHIFT,
+                KeyCode::KEY_LEFTCTRL,
+            ],
+        )
+        .build();
+    assert_eq!(
+        run(
+            config,
+            &[(KeyCode::KEY_F1, PRESS, 0), (KeyCode::KEY_F1, RELEASE, 50)]
+        ),
+        vec![
+            (KeyCode::KEY_LEFTCTRL, PRESS),
+            (KeyCode::KEY_LEFTSHIFT, PRESS),
+            (KeyCode::KEY_C, PRESS),
+            (KeyCode::KEY_C, RELEASE),
+            (KeyCode::KEY_LEFTSHIFT, RELEASE),
+            (KeyCode::KEY_LEFTCTRL, RELEASE),
+        ]
+    );
+}
+
+#[test]
+fn layer_applies_while_its_app_is_focused() {
+    let mut config = MappingConfig::builder()
+        .layer(
+            "terminal",
+            vec![Mapping::Remap {
+                input: [KeyCode::KEY_F1].into(),
+                output: [KeyCode::KEY_HELP].into(),
+                order: vec![],
+                exact: false,
+            }],
+        )
+        .build();
+    config.layers[0].only_when = Some(LayerCondition {
+        apps: vec!["kitty".to_string()],
+        ..Default::default()
+    });
+    let mut engine = MappingEngine::new(config, &millis(0));
+    let tap = |engine: &mut MappingEngine, time| {
+        let mut output = engine
+            .process_key(KeyCode::KEY_F1, PRESS, &millis(time))
+            .unwrap();
+        output.extend(
+            engine
+                .process_key(KeyCode::KEY_F1, RELEASE, &millis(time + 10))
+                .unwrap(),
+        );
+        output
+            .into_iter()
+            .filter_map(|item| match item {
+                Output::Event(event) => match event.event_code {
+                    EventCode::EV_KEY(key) => Some(key),
+                    _ => None,
+                },
+                Output::Run(_) => None,
+            })
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(tap(&mut engine, 0), vec![KeyCode::KEY_F1, KeyCode::KEY_F1]);
+    engine
+        .set_focused_app(Some("Kitty".to_string()), &millis(100))
+        .unwrap();
+    assert_eq!(
+        tap(&mut engine, 200),
+        vec![KeyCode::KEY_HELP, KeyCode::KEY_HELP]
+    );
+    engine
+        .set_focused_app(Some("firefox".to_string()), &millis(300))
+        .unwrap();
+    assert_eq!(
+        tap(&mut engine, 400),
+        vec![KeyCode::KEY_F1, KeyCode::KEY_F1]
+    );
+}
+
+#[test]
+fn layer_applies_while_numlock_is_off() {
+    let mut config = MappingConfig::builder()
+        .layer(
+            "navigation",
+            vec![Mapping::Remap {
+                input: [KeyCode::KEY_KP1].into(),
+                output: [KeyCode::KEY_END].into(),
+                order: vec![],
+                exact: false,
+            }],
+        )
+        .build();
+    config.layers[0].only_when = Some(LayerCondition {
+        numlock: Some(false),
+        ..Default::default()
+    });
+    assert_eq!(
+        run(
+            config,
+            &[
+                (KeyCode::KEY_KP1, PRESS, 0),
+                (KeyCode::KEY_KP1, RELEASE, 10),
+                (KeyCode::KEY_NUMLOCK, PRESS, 20),
+                (KeyCode::KEY_NUMLOCK, RELEASE, 30),
+                (KeyCode::KEY_KP1, PRESS, 40),
+                (KeyCode::KEY_KP1, RELEASE, 50),
+            ]
+        ),
+        vec![
+            (KeyCode::KEY_END, PRESS),
+            (KeyCode::KEY_END, RELEASE),
+            (KeyCode::KEY_NUMLOCK, PRESS),
+            (KeyCode::KEY_NUMLOCK, RELEASE),
+            (KeyCode::KEY_KP1, PRESS),
+            (KeyCode::KEY_KP1, RELEASE),
+        ]
+    );
+}
+
+#[test]
+fn key_keeps_its_layer_when_numlock_is_pressed_while_it_is_held() {
+    let mut config = MappingConfig::builder()
+        .layer(
+            "navigation",
+            vec![Mapping::Remap {
+                input: [KeyCode::KEY_KP1].into(),
+                output: [KeyCode::KEY_END].into(),
+                order: vec![],
+                exact: false,
+            }],
+        )
+        .build();
+    config.layers[0].only_when = Some(LayerCondition {
+        numlock: Some(false),
+        ..Default::default()
+    });
+    assert_eq!(
+        run(
+            config,
+            &[
+                (KeyCode::KEY_KP1, PRESS, 0),
+                (KeyCode::KEY_NUMLOCK, PRESS, 20),
+                (KeyCode::KEY_NUMLOCK, RELEASE, 30),
+                (KeyCode::KEY_KP1, RELEASE, 50),
+            ]
+        ),
+        vec![
+            (KeyCode::KEY_END, PRESS),
+            (KeyCode::KEY_NUMLOCK, PRESS),
+            (KeyCode::KEY_NUMLOCK, RELEASE),
+            (KeyCode::KEY_END, RELEASE),
+        ]
+    );
+}
+
+#[test]
+fn profile_key_switches_profile() {
+    let config = MappingConfig::builder()
+        .mapping(Mapping::Profile {
+            input: KeyCode::KEY_F11,
+            profile: 0,
+        })
+        .mapping(Mapping::Profile {
+            input: KeyCode::KEY_F12,
+            profile: 1,
+        })
+        .profile("typing", vec![])
+        .profile(
+            "gaming",
+            vec![Mapping::Remap {
+                input: [KeyCode::KEY_CAPSLOCK].into(),
+                output: [KeyCode::KEY_LEFTCTRL].into(),
+                order: vec![],
+                exact: false,
+            }],
+        )
+        .build();
+    assert_eq!(
+        run(
+            config,
+            &[
+                (KeyCode::KEY_CAPSLOCK, PRESS, 0),
+                (KeyCode::KEY_CAPSLOCK, RELEASE, 10),
+                (KeyCode::KEY_F12, PRESS, 20),
+                (KeyCode::KEY_F12, RELEASE, 30),
+                (KeyCode::KEY_CAPSLOCK, PRESS, 40),
+                (KeyCode::KEY_CAPSLOCK, RELEASE, 50),
+                (KeyCode::KEY_F11, PRESS, 60),
+                (KeyCode::KEY_F11, RELEASE, 70),
+                (KeyCode::KEY_CAPSLOCK, PRESS, 80),
+                (KeyCode::KEY_CAPSLOCK, RELEASE, 90),
+            ]
+        ),
+        vec![
+            (KeyCode::KEY_CAPSLOCK, PRESS),
+            (KeyCode::KEY_CAPSLOCK, RELEASE),
+            (KeyCode::KEY_LEFTCTRL, PRESS),
+            (KeyCode::KEY_LEFTCTRL, RELEASE),
+            (KeyCode::KEY_CAPSLOCK, PRESS),
+            (KeyCode::KEY_CAPSLOCK, RELEASE),
+        ]
+    );
+}
+
+#[test]
+fn indicator_led_is_lit_while_its_layer_is_active() {
+    let mut config = MappingConfig::builder()
+        .mapping(Mapping::Layer {
+            input: KeyCode::KEY_SCROLLLOCK,
+            layer: 0,
+            mode: LayerMode::Toggle,
+            lock_on_double_tap: false,
+            output: vec![],
+        })
+        .layer("nav", vec![])
+        .build();
+    config.layers[0].led = Some(LedCode::LED_SCROLLL);
+    let mut engine = MappingEngine::new(config, &millis(0));
+    assert_eq!(engine.indicator_leds(), vec![(LedCode::LED_SCROLLL, false)]);
+    for (value, time) in [(PRESS, 0), (RELEASE, 10)] {
+        engine
+            .process_key(KeyCode::KEY_SCROLLLOCK, value, &millis(time))
+            .unwrap();
+    }
+    assert_eq!(engine.indicator_leds(), vec![(LedCode::LED_SCROLLL, true)]);
+    for (value, time) in [(PRESS, 20), (RELEASE, 30)] {
+        engine
+            .process_key(KeyCode::KEY_SCROLLLOCK, value, &millis(time))
+            .unwrap();
+    }
+    assert_eq!(engine.indicator_leds(), vec![(LedCode::LED_SCROLLL, false)]);
+}
+
+#[test]
+fn chatter_is_debounced() {
+    let mut config = MappingConfig::builder().build();
+    config.debounce = Some(Duration::from_millis(20));
+    assert_eq!(
+        run(
+            config,
+            &[
+                // The release bounces
+                (KeyCode::KEY_E, PRESS, 0),
+                (KeyCode::KEY_E, RELEASE, 50),
+                (KeyCode::KEY_E, PRESS, 52),
+                (KeyCode::KEY_E, RELEASE, 55),
+                // A tap that is quicker than the debounce time
+                (KeyCode::KEY_E, PRESS, 100),
+                (KeyCode::KEY_E, RELEASE, 110),
+                (KeyCode::KEY_A, PRESS, 200),
+            ]
+        ),
+        vec![
+            (KeyCode::KEY_E, PRESS),
+            (KeyCode::KEY_E, RELEASE),
+            (KeyCode::KEY_E, PRESS),
+            (KeyCode::KEY_E, RELEASE),
+            (KeyCode::KEY_A, PRESS),
+        ]
+    );
+}
+
+#[test]
+fn macro_steps_hold_keys_and_wait() {
+    let config = MappingConfig::builder()
+        .mapping(Mapping::Macro {
+            input: KeyCode::KEY_F13,
+            output: vec![
+                MacroStep::Press(KeyCode::KEY_LEFTCTRL),
+                MacroStep::Tap(KeyCode::KEY_C),
+                MacroStep::Delay(Duration::from_millis(50)),
+                MacroStep::Release(KeyCode::KEY_LEFTCTRL),
+            ],
+            delay: Duration::ZERO,
+        })
+        .build();
+    assert_eq!(
+        run(
+            config,
+            &[
+                (KeyCode::KEY_F13, PRESS, 0),
+                (KeyCode::KEY_F13, RELEASE, 10),
+                (KeyCode::KEY_A, PRESS, 40),
+                (KeyCode::KEY_A, RELEASE, 45),
+                (KeyCode::KEY_B, PRESS, 100),
+            ]
+        ),
+        vec![
+            (KeyCode::KEY_LEFTCTRL, PRESS),
+            (KeyCode::KEY_C, PRESS),
+            (KeyCode::KEY_C, RELEASE),
+            (KeyCode::KEY_A, PRESS),
+            (KeyCode::KEY_A, RELEASE),
+            (KeyCode::KEY_LEFTCTRL, RELEASE),
+            (KeyCode::KEY_B, PRESS),
+        ]
+    );
+}
+
+#[test]
+fn command_runs_once_per_press() {
+    let command = CommandSpec {
+        argv: vec!["playerctl".to_string(), "play-pause".to_string()],
+        env: Default::default(),
+        user: None,
+    };
+    let config = MappingConfig::builder()
+        .mapping(Mapping::Command {
+            input: KeyCode::KEY_F5,
+            command: command.clone(),
+            min_hold: None,
+            release: None,
+        })
+        .build();
+    let mut engine = MappingEngine::new(config, &millis(0));
+    let mut output = vec![];
+    for (value, time) in [(PRESS, 0), (2, 500), (2, 530), (RELEASE, 600)] {
+        output.extend(
+            engine
+                .process_key(KeyCode::KEY_F5, value, &millis(time))
+                .unwrap(),
+        );
+    }
+    let runs: Vec<_> = output
+        .iter()
+        .filter_map(|item| match item {
+            Output::Run(command) => Some(command),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(runs, vec![&command]);
+    assert!(!output.iter().any(|item| matches!(
+        item,
+        Output::Event(event) if matches!(event.event_code, EventCode::EV_KEY(_))
+    )));
+}
+
+#[test]
+fn overlay_only_outputs_added_keys() {
+    let mut config = MappingConfig::builder()
+        .remap([KeyCode::KEY_F1], [KeyCode::KEY_F1, KeyCode::KEY_SYSRQ])
+        .build();
+    config.overlay = true;
+    assert_eq!(
+        run(
+            config,
+            &[
+                (KeyCode::KEY_C, PRESS, 0),
+                (KeyCode::KEY_C, RELEASE, 10),
+                (KeyCode::KEY_F1, PRESS, 20),
+                (KeyCode::KEY_F1, 2, 520),
+                (KeyCode::KEY_F1, RELEASE, 530),
+            ]
+        ),
+        vec![
+            (KeyCode::KEY_SYSRQ, PRESS),
+            (KeyCode::KEY_SYSRQ, 2),
+            (KeyCode::KEY_SYSRQ, RELEASE),
+        ]
+    );
+}
+
+#[test]
+fn exact_remap_only_applies_without_other_keys() {
+    let config = MappingConfig::builder()
+        .mapping(Mapping::Remap {
+            input: [KeyCode::KEY_LEFTALT, KeyCode::KEY_F4].into(),
+            output: [KeyCode::KEY_ESC].into(),
+            order: vec![],
+            exact: true,
+        })
+        .build();
+    assert_eq!(
+        run(
+            config,
+            &[
+                (KeyCode::KEY_LEFTALT, PRESS, 0),
+                (KeyCode::KEY_F4, PRESS, 10),
+                (KeyCode::KEY_F4, RELEASE, 20),
+                (KeyCode::KEY_LEFTSHIFT, PRESS, 30),
+                (KeyCode::KEY_F4, PRESS, 40),
+                (KeyCode::KEY_F4, RELEASE, 50),
+            ]
+        ),
+        vec![
+            (KeyCode::KEY_LEFTALT, PRESS),
+            (KeyCode::KEY_LEFTALT, RELEASE),
+            (KeyCode::KEY_ESC, PRESS),
+            (KeyCode::KEY_ESC, RELEASE),
+            (KeyCode::KEY_LEFTALT, PRESS),
+            (KeyCode::KEY_LEFTSHIFT, PRESS),
+            (KeyCode::KEY_F4, PRESS),
+            (KeyCode::KEY_F4, RELEASE),
+        ]
+    );
+}
+
+#[test]
+fn exact_remap_sees_keys_that_were_passed_through() {
+    let mut config = MappingConfig::builder()
+        .mapping(Mapping::Remap {
+            input: [KeyCode::KEY_LEFTALT, KeyCode::KEY_F4].into(),
+            output: [KeyCode::KEY_ESC].into(),
+            order: vec![],
+            exact: true,
+        })
+        .build();
+    config.low_latency_passthrough = true;
+    assert_eq!(
+        run(
+            config,
+            &[
+                (KeyCode::KEY_LEFTSHIFT, PRESS, 0),
+                (KeyCode::KEY_LEFTALT, PRESS, 10),
+                (KeyCode::KEY_F4, PRESS, 20),
+                (KeyCode::KEY_F4, RELEASE, 30),
+                (KeyCode::KEY_LEFTALT, RELEASE, 40),
+                (KeyCode::KEY_LEFTSHIFT, RELEASE, 50),
+                (KeyCode::KEY_LEFTALT, PRESS, 60),
+                (KeyCode::KEY_F4, PRESS, 70),
+                (KeyCode::KEY_F4, RELEASE, 80),
+                (KeyCode::KEY_LEFTALT, RELEASE, 90),
+            ]
+        ),
+        vec![
+            (KeyCode::KEY_LEFTSHIFT, PRESS),
+            (KeyCode::KEY_LEFTALT, PRESS),
+            (KeyCode::KEY_F4, PRESS),
+            (KeyCode::KEY_F4, RELEASE),
+            (KeyCode::KEY_LEFTALT, RELEASE),
+            (KeyCode::KEY_LEFTSHIFT, RELEASE),
+            (KeyCode::KEY_LEFTALT, PRESS),
+            (KeyCode::KEY_LEFTALT, RELEASE),
+            (KeyCode::KEY_ESC, PRESS),
+            (KeyCode::KEY_ESC, RELEASE),
+            (KeyCode::KEY_LEFTALT, PRESS),
+            (KeyCode::KEY_LEFTALT, RELEASE),
+        ]
+    );
+}
+
+#[test]
+fn swapped_keys_can_be_held_together() {
+    let config = MappingConfig::builder()
+        .mapping(Mapping::Swap {
+            a: KeyCode::KEY_LEFTALT,
+            b: KeyCode::KEY_LEFTMETA,
+        })
+        .build();
+    assert_eq!(
+        run(
+            config,
+            &[
+                (KeyCode::KEY_LEFTALT, PRESS, 0),
+                (KeyCode::KEY_LEFTMETA, PRESS, 10),
+                (KeyCode::KEY_LEFTALT, RELEASE, 20),
+                (KeyCode::KEY_LEFTMETA, RELEASE, 30),
+            ]
+        ),
+        vec![
+            (KeyCode::KEY_LEFTMETA, PRESS),
+            (KeyCode::KEY_LEFTALT, PRESS),
+            (KeyCode::KEY_LEFTMETA, RELEASE),
+            (KeyCode::KEY_LEFTALT, RELEASE),
+        ]
+    );
+}
+
+#[test]
+fn disabled_key_is_swallowed() {
+    let config = MappingConfig::builder()
+        .mapping(Mapping::Remap {
+            input: [KeyCode::KEY_RFKILL].into(),
+            output: [].into(),
+            order: vec![],
+            exact: false,
+        })
+        .build();
+    assert_eq!(
+        run(
+            config,
+            &[
+                (KeyCode::KEY_RFKILL, PRESS, 0),
+                (KeyCode::KEY_RFKILL, 2, 500),
+                (KeyCode::KEY_RFKILL, RELEASE, 510),
+                (KeyCode::KEY_A, PRESS, 600),
+            ]
+        ),
+        vec![(KeyCode::KEY_A, PRESS)]
+    );
+}
+
+#[test]
+fn toggle_key_holds_its_output_until_pressed_again() {
+    let config = MappingConfig::builder()
+        .mapping(Mapping::ModLock {
+            input: KeyCode::KEY_F9,
+            output: vec![KeyCode::KEY_W],
+            timeout: None,
+        })
+        .build();
+    assert_eq!(
+        run(
+            config,
+            &[
+                (KeyCode::KEY_F9, PRESS, 0),
+                (KeyCode::KEY_F9, RELEASE, 10),
+                (KeyCode::KEY_A, PRESS, 500),
+                (KeyCode::KEY_A, RELEASE, 510),
+                (KeyCode::KEY_F9, PRESS, 1000),
+                (KeyCode::KEY_F9, RELEASE, 1010),
+            ]
+        ),
+        vec![
+            (KeyCode::KEY_W, PRESS),
+            (KeyCode::KEY_A, PRESS),
+            (KeyCode::KEY_A, RELEASE),
+            (KeyCode::KEY_W, RELEASE),
+        ]
+    );
+}
+
+#[test]
+fn turbo_key_presses_its_output_repeatedly() {
+    let config = MappingConfig::builder()
+        .mapping(Mapping::Turbo {
+            input: KeyCode::KEY_F,
+            output: vec![KeyCode::KEY_SPACE],
+            interval: Duration::from_millis(50),
+        })
+        .build();
+    assert_eq!(
+        run(
+            config,
+            &[
+                (KeyCode::KEY_F, PRESS, 0),
+                (KeyCode::KEY_F, 2, 60),
+                (KeyCode::KEY_F, RELEASE, 120),
+            ]
+        ),
+        vec![
+            (KeyCode::KEY_SPACE, PRESS),
+            (KeyCode::KEY_SPACE, RELEASE),
+            (KeyCode::KEY_SPACE, PRESS),
+            (KeyCode::KEY_SPACE, RELEASE),
+            (KeyCode::KEY_SPACE, PRESS),
+            (KeyCode::KEY_SPACE, RELEASE),
+        ]
+    );
+}
+
+#[test]
+fn turbo_key_does_not_catch_up_after_a_late_wakeup() {
+    let config = MappingConfig::builder()
+        .mapping(Mapping::Turbo {
+            input: KeyCode::KEY_F,
+            output: vec![KeyCode::KEY_SPACE],
+            interval: Duration::from_millis(50),
+        })
+        .build();
+    let mut engine = MappingEngine::new(config, &millis(0));
+    let output = engine
+        .process_key(KeyCode::KEY_F, PRESS, &millis(0))
+        .unwrap();
+    assert_eq!(key_events(output), vec![(KeyCode::KEY_SPACE, PRESS)]);
+    assert_eq!(engine.next_deadline(), Some(millis(25)));
+
+    // Woken up a second late, when 39 ticks are overdue
+    let output = engine.handle_timeouts(&millis(1000)).unwrap();
+    assert_eq!(key_events(output), vec![(KeyCode::KEY_SPACE, RELEASE)]);
+    assert_eq!(engine.next_deadline(), Some(millis(1025)));
+
+    let output = engine.handle_timeouts(&millis(1025)).unwrap();
+    assert_eq!(key_events(output), vec![(KeyCode::KEY_SPACE, PRESS)]);
+}
+
+#[test]
+fn keys_released_while_events_were_dropped_are_released() {
+    let mut engine = MappingEngine::new(dual_role_config(), &millis(0));
+    let mut output = vec![];
+    output.extend(
+        engine
+            .process_key(KeyCode::KEY_CAPSLOCK, PRESS, &millis(0))
+            .unwrap(),
+    );
+    output.extend(
+        engine
+            .process_key(KeyCode::KEY_C, PRESS, &millis(50))
+            .unwrap(),
+    );
+    // Only the release of C was lost
+    output.extend(
+        engine
+            .release_keys_not_held(|key| key == KeyCode::KEY_CAPSLOCK, &millis(100))
+            .unwrap(),
+    );
+    let keys: Vec<(KeyCode, i32)> = output
+        .into_iter()
+        .filter_map(|item| match item {
+            Output::Event(event) => match event.event_code {
+                EventCode::EV_KEY(key) => Some((key, event.value)),
+                _ => None,
+            },
+            Output::Run(_) => None,
+        })
+        .collect();
+    assert_eq!(
+        keys,
+        vec![
+            (KeyCode::KEY_LEFTCTRL, PRESS),
+            (KeyCode::KEY_C, PRESS),
+            (KeyCode::KEY_C, RELEASE),
+        ]
+    );
+}
+
+#[test]
+fn keys_held_for_longer_than_max_hold_are_released() {
+    let mut config = MappingConfig::builder().build();
+    config.max_hold = Some(Duration::from_secs(1));
+    assert_eq!(
+        run(
+            config,
+            &[
+                (KeyCode::KEY_A, PRESS, 0),
+                (KeyCode::KEY_A, RELEASE, 2000),
+                (KeyCode::KEY_A, PRESS, 2100),
+            ]
+        ),
+        vec![
+            (KeyCode::KEY_A, PRESS),
+            (KeyCode::KEY_A, RELEASE),
+            (KeyCode::KEY_A, PRESS),
+        ]
+    );
+}
+
+#[test]
+fn caps_word_shifts_letters_until_the_end_of_the_word() {
+    let config = MappingConfig::builder()
+        .mapping(Mapping::CapsWord {
+            input: KeyCode::KEY_CAPSLOCK,
+        })
+        .build();
+    assert_eq!(
+        run(
+            config,
+            &[
+                (KeyCode::KEY_CAPSLOCK, PRESS, 0),
+                (KeyCode::KEY_CAPSLOCK, RELEASE, 10),
+                (KeyCode::KEY_A, PRESS, 20),
+                (KeyCode::KEY_A, RELEASE, 30),
+                (KeyCode::KEY_1, PRESS, 40),
+                (KeyCode::KEY_1, RELEASE, 50),
+                (KeyCode::KEY_SPACE, PRESS, 60),
+                (KeyCode::KEY_SPACE, RELEASE, 70),
+                (KeyCode::KEY_B, PRESS, 80),
+                (KeyCode::KEY_B, RELEASE, 90),
+            ]
+        ),
+        vec![
+            (KeyCode::KEY_LEFTSHIFT, PRESS),
+            (KeyCode::KEY_A, PRESS),
+            (KeyCode::KEY_A, RELEASE),
+            (KeyCode::KEY_LEFTSHIFT, RELEASE),
+            (KeyCode::KEY_1, PRESS),
+            (KeyCode::KEY_1, RELEASE),
+            (KeyCode::KEY_SPACE, PRESS),
+            (KeyCode::KEY_SPACE, RELEASE),
+            (KeyCode::KEY_B, PRESS),
+            (KeyCode::KEY_B, RELEASE),
+        ]
+    );
+}
+
+#[test]
+fn autoshift_shifts_keys_that_are_held() {
+    let mut config = MappingConfig::builder().build();
+    config.autoshift = Some(AutoShift {
+        timeout: Duration::from_millis(200),
+        keys: AutoShift::default_keys(),
+    });
+    assert_eq!(
+        run(
+            config,
+            &[
+                (KeyCode::KEY_A, PRESS, 0),
+                (KeyCode::KEY_A, RELEASE, 50),
+                (KeyCode::KEY_B, PRESS, 100),
+                (KeyCode::KEY_B, 2, 350),
+                (KeyCode::KEY_B, RELEASE, 400),
+            ]
+        ),
+        vec![
+            (KeyCode::KEY_A, PRESS),
+            (KeyCode::KEY_A, RELEASE),
+            (KeyCode::KEY_LEFTSHIFT, PRESS),
+            (KeyCode::KEY_B, PRESS),
+            (KeyCode::KEY_B, RELEASE),
+            (KeyCode::KEY_LEFTSHIFT, RELEASE),
+        ]
+    );
+}
+
+fn home_row_mod_config() -> MappingConfig {
+    MappingConfig::builder()
+        .mapping(Mapping::DualRole {
+            input: KeyCode::KEY_A,
+            hold: vec![KeyCode::KEY_LEFTCTRL],
+            tap: vec![KeyCode::KEY_A],
+            abort_hold_emits_tap: false,
+            hold_timeout: Some(Duration::from_millis(200)),
+            hold_on_other_key_press: false,
+            permissive_hold: true,
+            quick_tap: Some(Duration::from_millis(150)),
+            chordal_hold: false,
+        })
+        .build()
+}
+
+#[test]
+fn home_row_mod_rolled_into_the_next_key_is_a_tap() {
+    assert_eq!(
+        run(
+            home_row_mod_config(),
+            &[
+                (KeyCode::KEY_A, PRESS, 0),
+                (KeyCode::KEY_S, PRESS, 50),
+                (KeyCode::KEY_A, RELEASE, 80),
+                (KeyCode::KEY_S, RELEASE, 120),
+            ]
+        ),
+        vec![
+            (KeyCode::KEY_A, PRESS),
+            (KeyCode::KEY_A, RELEASE),
+            (KeyCode::KEY_S, PRESS),
+            (KeyCode::KEY_S, RELEASE),
+        ]
+    );
+}
+
+#[test]
+fn home_row_mod_with_permissive_hold() {
+    assert_eq!(
+        run(
+            home_row_mod_config(),
+            &[
+                (KeyCode::KEY_A, PRESS, 0),
+                (KeyCode::KEY_S, PRESS, 50),
+                (KeyCode::KEY_S, RELEASE, 80),
+                (KeyCode::KEY_A, RELEASE, 150),
+            ]
+        ),
+        vec![
+            (KeyCode::KEY_LEFTCTRL, PRESS),
+            (KeyCode::KEY_S, PRESS),
+            (KeyCode::KEY_S, RELEASE),
+            (KeyCode::KEY_LEFTCTRL, RELEASE),
+        ]
+    );
+}
+
+#[test]
+fn home_row_mod_quick_tap_holds_the_tap() {
+    assert_eq!(
+        run(
+            home_row_mod_config(),
+            &[
+                (KeyCode::KEY_A, PRESS, 0),
+                (KeyCode::KEY_A, RELEASE, 50),
+                (KeyCode::KEY_A, PRESS, 100),
+                (KeyCode::KEY_A, RELEASE, 500),
+            ]
+        ),
+        vec![
+            (KeyCode::KEY_A, PRESS),
+            (KeyCode::KEY_A, RELEASE),
+            (KeyCode::KEY_A, PRESS),
+            (KeyCode::KEY_A, RELEASE),
+        ]
+    );
+}
+
+#[test]
+fn chordal_hold_taps_when_the_next_key_is_on_the_same_hand() {
+    let mut config = MappingConfig::builder()
+        .mapping(Mapping::DualRole {
+            input: KeyCode::KEY_F,
+            hold: vec![KeyCode::KEY_LEFTCTRL],
+            tap: vec![KeyCode::KEY_F],
+            abort_hold_emits_tap: false,
+            hold_timeout: Some(Duration::from_millis(200)),
+            hold_on_other_key_press: true,
+            permissive_hold: false,
+            quick_tap: None,
+            chordal_hold: true,
+        })
+        .build();
+    config.hands = default_hands();
+    assert_eq!(
+        run(
+            config,
+            &[
+                (KeyCode::KEY_F, PRESS, 0),
+                (KeyCode::KEY_D, PRESS, 50),
+                (KeyCode::KEY_F, RELEASE, 80),
+                (KeyCode::KEY_D, RELEASE, 120),
+                (KeyCode::KEY_F, PRESS, 500),
+                (KeyCode::KEY_J, PRESS, 550),
+                (KeyCode::KEY_J, RELEASE, 580),
+                (KeyCode::KEY_F, RELEASE, 620),
+            ]
+        ),
+        vec![
+            (KeyCode::KEY_F, PRESS),
+            (KeyCode::KEY_F, RELEASE),
+            (KeyCode::KEY_D, PRESS),
+            (KeyCode::KEY_D, RELEASE),
+            (KeyCode::KEY_LEFTCTRL, PRESS),
+            (KeyCode::KEY_J, PRESS),
+            (KeyCode::KEY_J, RELEASE),
+            (KeyCode::KEY_LEFTCTRL, RELEASE),
+        ]
+    );
+}
+
+#[test]
+fn touchpad_buttons_pass_through_while_a_dual_role_key_is_held() {
+    let config = MappingConfig::builder()
+        .mapping(Mapping::DualRole {
+            input: KeyCode::KEY_CAPSLOCK,
+            hold: vec![KeyCode::KEY_LEFTCTRL],
+            tap: vec![KeyCode::KEY_ESC],
+            abort_hold_emits_tap: false,
+            hold_timeout: Some(Duration::from_millis(200)),
+            hold_on_other_key_press: true,
+            permissive_hold: false,
+            quick_tap: None,
+            chordal_hold: false,
+        })
+        .build();
+    assert_eq!(
+        run(
+            config,
+            &[
+                (KeyCode::KEY_CAPSLOCK, PRESS, 0),
+                (KeyCode::BTN_TOUCH, PRESS, 20),
+                (KeyCode::BTN_TOOL_FINGER, PRESS, 20),
+                (KeyCode::BTN_TOUCH, RELEASE, 60),
+                (KeyCode::BTN_TOOL_FINGER, RELEASE, 60),
+                (KeyCode::KEY_CAPSLOCK, RELEASE, 100),
+            ]
+        ),
+        vec![
+            (KeyCode::BTN_TOUCH, PRESS),
+            (KeyCode::BTN_TOOL_FINGER, PRESS),
+            (KeyCode::BTN_TOUCH, RELEASE),
+            (KeyCode::BTN_TOOL_FINGER, RELEASE),
+            (KeyCode::KEY_ESC, PRESS),
+            (KeyCode::KEY_ESC, RELEASE),
+        ]
+    );
+}
+
+#[test]
+fn layer_applies_while_not_in_tablet_mode() {
+    let mut config = MappingConfig::builder()
+        .layer(
+            "keyboard",
+            vec![Mapping::Remap {
+                input: [KeyCode::KEY_CAPSLOCK].into(),
+                output: [KeyCode::KEY_ESC].into(),
+                order: vec![],
+                exact: false,
+            }],
+        )
+        .build();
+    config.layers[0].only_when = Some(LayerCondition {
+        switches: vec![(SwitchCode::SW_TABLET_MODE, false)],
+        ..Default::default()
+    });
+    let mut engine = MappingEngine::new(config, &millis(0));
+    let tap = |engine: &mut MappingEngine, time| {
+        let mut output = engine
+            .process_key(KeyCode::KEY_CAPSLOCK, PRESS, &millis(time))
+            .unwrap();
+        output.extend(
+            engine
+                .process_key(KeyCode::KEY_CAPSLOCK, RELEASE, &millis(time + 10))
+                .unwrap(),
+        );
+        output
+            .into_iter()
+            .filter_map(|item| match item {
+                Output::Event(event) => match event.event_code {
+                    EventCode::EV_KEY(key) => Some(key),
+                    _ => None,
+                },
+                Output::Run(_) => None,
+            })
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(
+        tap(&mut engine, 0),
+        vec![KeyCode::KEY_ESC, KeyCode::KEY_ESC]
+    );
+    let tablet_mode = InputEvent::new(
+        &millis(100),
+        &EventCode::EV_SW(SwitchCode::SW_TABLET_MODE),
+        1,
+    );
+    let output = engine.process_event(&tablet_mode).unwrap();
+    assert!(matches!(&output[..], [Output::Event(event)] if *event == tablet_mode));
+    assert_eq!(
+        tap(&mut engine, 200),
+        vec![KeyCode::KEY_CAPSLOCK, KeyCode::KEY_CAPSLOCK]
+    );
+    engine
+        .set_switch(SwitchCode::SW_TABLET_MODE, false, &millis(300))
+        .unwrap();
+    assert_eq!(
+        tap(&mut engine, 400),
+        vec![KeyCode::KEY_ESC, KeyCode::KEY_ESC]
+    );
+}
+
+#[test]
+fn scan_remap_matches_the_scancode_of_the_frame() {
+    let config = MappingConfig::builder()
+        .mapping(Mapping::ScanRemap {
+            scan: 0x700e2,
+            output: vec![KeyCode::KEY_F13],
+        })
+        .build();
+    let mut engine = MappingEngine::new(config, &millis(0));
+    let mut output = vec![];
+    for (scan, value, time) in [
+        (0x700e2, PRESS, 0),
+        (0x700e2, RELEASE, 50),
+        (0x700e3, PRESS, 100),
+        (0x700e3, RELEASE, 150),
+    ] {
+        for (code, value) in [
+            (EventCode::EV_MSC(EV_MSC::MSC_SCAN), scan),
+            (EventCode::EV_KEY(KeyCode::KEY_UNKNOWN), value),
+            (EventCode::EV_SYN(EV_SYN::SYN_REPORT), 0),
+        ] {
+            let event = InputEvent::new(&millis(time), &code, value);
+            output.extend(engine.process_event(&event).unwrap());
+        }
+    }
+    let keys: Vec<(KeyCode, i32)> = output
+        .into_iter()
+        .filter_map(|item| match item {
+            Output::Event(event) => match event.event_code {
+                EventCode::EV_KEY(key) => Some((key, event.value)),
+                _ => None,
+            },
+            Output::Run(_) => None,
+        })
+        .collect();
+    assert_eq!(
+        keys,
+        vec![
+            (KeyCode::KEY_F13, PRESS),
+            (KeyCode::KEY_F13, RELEASE),
+            (KeyCode::KEY_UNKNOWN, PRESS),
+            (KeyCode::KEY_UNKNOWN, RELEASE),
+        ]
+    );
+}
+
+#[test]
+fn session_lock_releases_everything() {
+    let mut config = MappingConfig::builder()
+        .mapping(Mapping::ModLock {
+            input: KeyCode::KEY_F9,
+            output: vec![KeyCode::KEY_W],
+            timeout: None,
+        })
+        .build();
+    config.release_triggers = vec![ReleaseTrigger::SessionLock];
+    let mut engine = MappingEngine::new(config, &millis(0));
+    let mut output = vec![];
+    output.extend(
+        engine
+            .process_key(KeyCode::KEY_F9, PRESS, &millis(0))
+            .unwrap(),
+    );
+    output.extend(
+        engine
+            .process_key(KeyCode::KEY_F9, RELEASE, &millis(10))
+            .unwrap(),
+    );
+    output.extend(engine.session_locked(&millis(500)).unwrap());
+    assert_eq!(
+        key_events(output),
+        vec![(KeyCode::KEY_W, PRESS), (KeyCode::KEY_W, RELEASE)]
+    );
+}
+
+#[test]
+fn keys_held_across_a_release_are_released_once() {
+    let mut config = MappingConfig::builder()
+        .mapping(Mapping::Remap {
+            input: [KeyCode::KEY_CAPSLOCK].into(),
+            output: [KeyCode::KEY_LEFTCTRL].into(),
+            order: vec![],
+            exact: false,
+        })
+        .build();
+    config.release_triggers = vec![ReleaseTrigger::SessionLock];
+    let mut engine = MappingEngine::new(config, &millis(0));
+    let mut output = vec![];
+    output.extend(
+        engine
+            .process_key(KeyCode::KEY_CAPSLOCK, PRESS, &millis(0))
+            .unwrap(),
+    );
+    output.extend(engine.session_locked(&millis(100)).unwrap());
+    output.extend(
+        engine
+            .process_key(KeyCode::KEY_CAPSLOCK, RELEASE, &millis(200))
+            .unwrap(),
+    );
+    output.extend(
+        engine
+            .process_key(KeyCode::KEY_CAPSLOCK, PRESS, &millis(300))
+            .unwrap(),
+    );
+    output.extend(
+        engine
+            .process_key(KeyCode::KEY_CAPSLOCK, RELEASE, &millis(400))
+            .unwrap(),
+    );
+    assert_eq!(
+        key_events(output),
+        vec![
+            (KeyCode::KEY_LEFTCTRL, PRESS),
+            (KeyCode::KEY_LEFTCTRL, RELEASE),
+            (KeyCode::KEY_LEFTCTRL, PRESS),
+            (KeyCode::KEY_LEFTCTRL, RELEASE),
+        ]
+    );
+}
+
+#[test]
+fn key_released_while_paused_works_after_resuming() {
+    let mut config = MappingConfig::builder()
+        .mapping(Mapping::Remap {
+            input: [KeyCode::KEY_CAPSLOCK].into(),
+            output: [KeyCode::KEY_LEFTCTRL].into(),
+            order: vec![],
+            exact: false,
+        })
+        .build();
+    config.release_triggers = vec![ReleaseTrigger::SessionLock];
+    let mut engine = MappingEngine::new(config, &millis(0));
+    let mut output = vec![];
+    output.extend(
+        engine
+            .process_key(KeyCode::KEY_CAPSLOCK, PRESS, &millis(0))
+            .unwrap(),
+    );
+    // Everything is released, as when pausing, and the release of
+    // CAPSLOCK isn't seen.  Resuming finds that it is no longer held.
+    output.extend(engine.session_locked(&millis(100)).unwrap());
+    output.extend(
+        engine
+            .release_keys_not_held(|_| false, &millis(300))
+            .unwrap(),
+    );
+    output.extend(
+        engine
+            .process_key(KeyCode::KEY_CAPSLOCK, PRESS, &millis(400))
+            .unwrap(),
+    );
+    output.extend(
+        engine
+            .process_key(KeyCode::KEY_CAPSLOCK, RELEASE, &millis(500))
+            .unwrap(),
+    );
+    assert_eq!(
+        key_events(output),
+        vec![
+            (KeyCode::KEY_LEFTCTRL, PRESS),
+            (KeyCode::KEY_LEFTCTRL, RELEASE),
+            (KeyCode::KEY_LEFTCTRL, PRESS),
+            (KeyCode::KEY_LEFTCTRL, RELEASE),
+        ]
+    );
+}
+
+#[test]
+fn session_lock_is_ignored_unless_it_is_a_trigger() {
+    let mut engine = MappingEngine::new(MappingConfig::builder().build(), &millis(0));
+    let mut output = vec![];
+    output.extend(
+        engine
+            .process_key(KeyCode::KEY_A, PRESS, &millis(0))
+            .unwrap(),
+    );
+    output.extend(engine.session_locked(&millis(500)).unwrap());
+    assert_eq!(key_events(output), vec![(KeyCode::KEY_A, PRESS)]);
+}
+
+fn nav_layer_config() -> MappingConfig {
+    nav_layer_config_with(false)
+}
+
+fn nav_layer_config_with(lock_on_double_tap: bool) -> MappingConfig {
+    MappingConfig::builder()
+        .mapping(Mapping::Layer {
+            input: KeyCode::KEY_CAPSLOCK,
+            layer: 0,
+            mode: LayerMode::Momentary,
+            lock_on_double_tap,
+            output: vec![],
+        })
+        .layer(
+            "nav",
+            vec![Mapping::Remap {
+                input: [KeyCode::KEY_J].into(),
+                output: [KeyCode::KEY_DOWN].into(),
+                order: vec![],
+                exact: false,
+            }],
+        )
+        .build()
+}
+
+#[test]
+fn key_keeps_its_layer_after_the_layer_key_is_released() {
+    assert_eq!(
+        run(
+            nav_layer_config(),
+            &[
+                (KeyCode::KEY_CAPSLOCK, PRESS, 0),
+                (KeyCode::KEY_J, PRESS, 100),
+                (KeyCode::KEY_CAPSLOCK, RELEASE, 150),
+                (KeyCode::KEY_J, 2, 200),
+                (KeyCode::KEY_J, RELEASE, 250),
+                (KeyCode::KEY_J, PRESS, 300),
+                (KeyCode::KEY_J, RELEASE, 350),
+            ]
+        ),
+        vec![
+            (KeyCode::KEY_DOWN, PRESS),
+            (KeyCode::KEY_DOWN, 2),
+            (KeyCode::KEY_DOWN, RELEASE),
+            (KeyCode::KEY_J, PRESS),
+            (KeyCode::KEY_J, RELEASE),
+        ]
+    );
+}
+
+#[test]
+fn key_keeps_its_layer_when_the_focus_or_a_switch_changes() {
+    let mut config = MappingConfig::builder()
+        .layer(
+            "terminal",
+            vec![Mapping::Remap {
+                input: [KeyCode::KEY_F1].into(),
+                output: [KeyCode::KEY_HELP].into(),
+                order: vec![],
+                exact: false,
+            }],
+        )
+        .build();
+    config.layers[0].only_when = Some(LayerCondition {
+        apps: vec!["kitty".to_string()],
+        switches: vec![(SwitchCode::SW_TABLET_MODE, false)],
+        ..Default::default()
+    });
+    let mut engine = MappingEngine::new(config, &millis(0));
+    let mut output = vec![];
+    output.extend(
+        engine
+            .set_focused_app(Some("kitty".to_string()), &millis(0))
+            .unwrap(),
+    );
+    output.extend(
+        engine
+            .process_key(KeyCode::KEY_F1, PRESS, &millis(100))
+            .unwrap(),
+    );
+    output.extend(
+        engine
+            .set_focused_app(Some("firefox".to_string()), &millis(150))
+            .unwrap(),
+    );
+    output.extend(
+        engine
+            .process_key(KeyCode::KEY_F1, RELEASE, &millis(200))
+            .unwrap(),
+    );
+    output.extend(
+        engine
+            .set_focused_app(Some("kitty".to_string()), &millis(300))
+            .unwrap(),
+    );
+    output.extend(
+        engine
+            .process_key(KeyCode::KEY_F1, PRESS, &millis(400))
+            .unwrap(),
+    );
+    output.extend(
+        engine
+            .set_switch(SwitchCode::SW_TABLET_MODE, true, &millis(450))
+            .unwrap(),
+    );
+    output.extend(
+        engine
+            .process_key(KeyCode::KEY_F1, RELEASE, &millis(500))
+            .unwrap(),
+    );
+    assert_eq!(
+        key_events(output),
+        vec![
+            (KeyCode::KEY_HELP, PRESS),
+            (KeyCode::KEY_HELP, RELEASE),
+            (KeyCode::KEY_HELP, PRESS),
+            (KeyCode::KEY_HELP, RELEASE),
+        ]
+    );
+}
+
+#[test]
+fn one_shot_applies_to_the_next_key_with_low_latency_passthrough() {
+    let mut config = MappingConfig::builder()
+        .mapping(Mapping::OneShot {
+            input: KeyCode::KEY_RIGHTSHIFT,
+            output: vec![KeyCode::KEY_LEFTSHIFT],
+        })
+        .build();
+    config.low_latency_passthrough = true;
+    assert_eq!(
+        run(
+            config,
+            &[
+                (KeyCode::KEY_RIGHTSHIFT, PRESS, 0),
+                (KeyCode::KEY_RIGHTSHIFT, RELEASE, 10),
+                (KeyCode::KEY_A, PRESS, 100),
+                (KeyCode::KEY_A, RELEASE, 110),
+                (KeyCode::KEY_B, PRESS, 200),
+                (KeyCode::KEY_B, RELEASE, 210),
+            ]
+        ),
+        vec![
+            (KeyCode::KEY_LEFTSHIFT, PRESS),
+            (KeyCode::KEY_A, PRESS),
+            (KeyCode::KEY_A, RELEASE),
+            (KeyCode::KEY_LEFTSHIFT, RELEASE),
+            (KeyCode::KEY_B, PRESS),
+            (KeyCode::KEY_B, RELEASE),
+        ]
+    );
+}
+
+#[test]
+fn tap_dance_is_resolved_before_a_key_with_low_latency_passthrough() {
+    let mut config = MappingConfig::builder()
+        .mapping(Mapping::TapDance {
+            input: KeyCode::KEY_ESC,
+            taps: vec![vec![KeyCode::KEY_ESC], vec![KeyCode::KEY_CAPSLOCK]],
+            timeout: Duration::from_millis(200),
+        })
+        .build();
+    config.low_latency_passthrough = true;
+    assert_eq!(
+        run(
+            config,
+            &[
+                (KeyCode::KEY_ESC, PRESS, 0),
+                (KeyCode::KEY_ESC, RELEASE, 10),
+                (KeyCode::KEY_A, PRESS, 50),
+                (KeyCode::KEY_A, RELEASE, 60),
+            ]
+        ),
+        vec![
+            (KeyCode::KEY_ESC, PRESS),
+            (KeyCode::KEY_ESC, RELEASE),
+            (KeyCode::KEY_A, PRESS),
+            (KeyCode::KEY_A, RELEASE),
+        ]
+    );
+}
+
+#[test]
+fn double_tapping_a_layer_key_locks_its_layer() {
+    let mut engine = MappingEngine::new(nav_layer_config_with(true), &millis(0));
+    let mut output = vec![];
+    let mut events = |engine: &mut MappingEngine, events: &[(KeyCode, i32, i64)]| {
+        for (key, value, time) in events {
+            output.extend(engine.process_key(*key, *value, &millis(*time)).unwrap());
+        }
+    };
+    events(
+        &mut engine,
+        &[
+            (KeyCode::KEY_CAPSLOCK, PRESS, 0),
+            (KeyCode::KEY_CAPSLOCK, RELEASE, 50),
+            (KeyCode::KEY_CAPSLOCK, PRESS, 150),
+            (KeyCode::KEY_CAPSLOCK, RELEASE, 200),
+            (KeyCode::KEY_J, PRESS, 1000),
+            (KeyCode::KEY_J, RELEASE, 1050),
+        ],
+    );
+    assert!(engine
+        .state_as_json()
+        .contains("\"locked_layers\":[\"nav\"]"));
+    events(
+        &mut engine,
+        &[
+            (KeyCode::KEY_CAPSLOCK, PRESS, 2000),
+            (KeyCode::KEY_CAPSLOCK, RELEASE, 2050),
+            (KeyCode::KEY_CAPSLOCK, PRESS, 2150),
+            (KeyCode::KEY_CAPSLOCK, RELEASE, 2200),
+            (KeyCode::KEY_J, PRESS, 3000),
+            (KeyCode::KEY_J, RELEASE, 3050),
+        ],
+    );
+    assert!(engine.state_as_json().contains("\"locked_layers\":[]"));
+    assert_eq!(
+        key_events(output),
+        vec![
+            (KeyCode::KEY_DOWN, PRESS),
+            (KeyCode::KEY_DOWN, RELEASE),
+            (KeyCode::KEY_J, PRESS),
+            (KeyCode::KEY_J, RELEASE),
+        ]
+    );
+}
+
+#[test]
+fn slow_taps_of_a_layer_key_do_not_lock_its_layer() {
+    assert_eq!(
+        run(
+            nav_layer_config_with(true),
+            &[
+                (KeyCode::KEY_CAPSLOCK, PRESS, 0),
+                (KeyCode::KEY_CAPSLOCK, RELEASE, 50),
+                (KeyCode::KEY_CAPSLOCK, PRESS, 600),
+                (KeyCode::KEY_CAPSLOCK, RELEASE, 650),
+                (KeyCode::KEY_J, PRESS, 1000),
+                (KeyCode::KEY_J, RELEASE, 1050),
+            ]
+        ),
+        vec![(KeyCode::KEY_J, PRESS), (KeyCode::KEY_J, RELEASE)]
+    );
+}
+
+#[test]
+fn layer_key_emits_its_output_and_then_switches_layer() {
+    let config = MappingConfig::builder()
+        .mapping(Mapping::Layer {
+            input: KeyCode::KEY_F13,
+            layer: 0,
+            mode: LayerMode::Toggle,
+            lock_on_double_tap: false,
+            output: vec![
+                KeyCode::KEY_LEFTCTRL,
+                KeyCode::KEY_LEFTSHIFT,
+                KeyCode::KEY_P,
+            ],
+        })
+        .layer(
+            "palette",
+            vec![Mapping::Remap {
+                input: [KeyCode::KEY_J].into(),
+                output: [KeyCode::KEY_DOWN].into(),
+                order: vec![],
+                exact: false,
+            }],
+        )
+        .build();
+    let mut engine = MappingEngine::new(config, &millis(0));
+    let mut output = vec![];
+    for (key, value, time) in [
+        (KeyCode::KEY_F13, PRESS, 0),
+        (KeyCode::KEY_F13, RELEASE, 50),
+        (KeyCode::KEY_J, PRESS, 100),
+        (KeyCode::KEY_J, RELEASE, 150),
+    ] {
+        output.extend(engine.process_key(key, value, &millis(time)).unwrap());
+    }
+    assert_eq!(
+        key_events(output),
+        vec![
+            (KeyCode::KEY_LEFTCTRL, PRESS),
+            (KeyCode::KEY_LEFTSHIFT, PRESS),
+            (KeyCode::KEY_P, PRESS),
+            (KeyCode::KEY_LEFTCTRL, RELEASE),
+            (KeyCode::KEY_LEFTSHIFT, RELEASE),
+            (KeyCode::KEY_P, RELEASE),
+            (KeyCode::KEY_DOWN, PRESS),
+            (KeyCode::KEY_DOWN, RELEASE),
+        ]
+    );
+    assert!(engine.state_as_json().contains("\"layers\":[\"palette\"]"));
+}
+
+fn numpad_layer_config(default: LayerDefault) -> MappingConfig {
+    let mut config = MappingConfig::builder()
+        .mapping(Mapping::Layer {
+            input: KeyCode::KEY_NUMLOCK,
+            layer: 0,
+            mode: LayerMode::Toggle,
+            lock_on_double_tap: false,
+            output: vec![],
+        })
+        .remap([KeyCode::KEY_K], [KeyCode::KEY_L])
+        .layer(
+            "numpad",
+            vec![Mapping::Remap {
+                input: [KeyCode::KEY_J].into(),
+                output: [KeyCode::KEY_KP1].into(),
+                order: vec![],
+                exact: false,
+            }],
+        )
+        .build();
+    config.layers[0].default = default;
+    config
+}
+
+const NUMPAD_EVENTS: &[(KeyCode, i32, i64)] = &[
+    (KeyCode::KEY_NUMLOCK, PRESS, 0),
+    (KeyCode::KEY_NUMLOCK, RELEASE, 10),
+    (KeyCode::KEY_J, PRESS, 100),
+    (KeyCode::KEY_J, RELEASE, 110),
+    (KeyCode::KEY_K, PRESS, 200),
+    (KeyCode::KEY_K, RELEASE, 210),
+    (KeyCode::KEY_A, PRESS, 300),
+    (KeyCode::KEY_A, RELEASE, 310),
+    (KeyCode::KEY_NUMLOCK, PRESS, 400),
+    (KeyCode::KEY_NUMLOCK, RELEASE, 410),
+    (KeyCode::KEY_A, PRESS, 500),
+    (KeyCode::KEY_A, RELEASE, 510),
+];
+
+#[test]
+fn transparent_layer_falls_through_for_unmapped_keys() {
+    assert_eq!(
+        run(
+            numpad_layer_config(LayerDefault::Transparent),
+            NUMPAD_EVENTS
+        ),
+        vec![
+            (KeyCode::KEY_KP1, PRESS),
+            (KeyCode::KEY_KP1, RELEASE),
+            (KeyCode::KEY_L, PRESS),
+            (KeyCode::KEY_L, RELEASE),
+            (KeyCode::KEY_A, PRESS),
+            (KeyCode::KEY_A, RELEASE),
+            (KeyCode::KEY_A, PRESS),
+            (KeyCode::KEY_A, RELEASE),
+        ]
+    );
+}
+
+#[test]
+fn blocking_layer_swallows_unmapped_keys() {
+    assert_eq!(
+        run(numpad_layer_config(LayerDefault::Block), NUMPAD_EVENTS),
+        vec![
+            (KeyCode::KEY_KP1, PRESS),
+            (KeyCode::KEY_KP1, RELEASE),
+            (KeyCode::KEY_A, PRESS),
+            (KeyCode::KEY_A, RELEASE),
+        ]
+    );
+}
+
+#[test]
+fn command_with_min_hold_only_runs_once_held_for_long_enough() {
+    let command = CommandSpec {
+        argv: vec!["systemctl".to_string(), "suspend".to_string()],
+        env: Default::default(),
+        user: None,
+    };
+    let config = MappingConfig::builder()
+        .mapping(Mapping::Command {
+            input: KeyCode::KEY_SLEEP,
+            command: command.clone(),
+            min_hold: Some(Duration::from_millis(500)),
+            release: None,
+        })
+        .build();
+    let mut engine = MappingEngine::new(config, &millis(0));
+    let mut output = vec![];
+    // Released too soon, and then held for long enough
+    for (value, time) in [(PRESS, 0), (RELEASE, 300), (PRESS, 1000)] {
+        output.extend(
+            engine
+                .process_key(KeyCode::KEY_SLEEP, value, &millis(time))
+                .unwrap(),
+        );
+    }
+    assert_eq!(engine.next_deadline(), Some(millis(1500)));
+    assert!(!output.iter().any(|item| matches!(item, Output::Run(_))));
+    output.extend(engine.handle_timeouts(&millis(1500)).unwrap());
+    output.extend(
+        engine
+            .process_key(KeyCode::KEY_SLEEP, RELEASE, &millis(2000))
+            .unwrap(),
+    );
+    let runs: Vec<_> = output
+        .iter()
+        .filter_map(|item| match item {
+            Output::Run(command) => Some(command),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(runs, vec![&command]);
+}
+
+#[test]
+fn command_is_told_about_the_active_layer() {
+    let command = |argv: &str| CommandSpec {
+        argv: vec![argv.to_string()],
+        env: Default::default(),
+        user: None,
+    };
+    let config = MappingConfig::builder()
+        .mapping(Mapping::Layer {
+            input: KeyCode::KEY_SCROLLLOCK,
+            layer: 0,
+            mode: LayerMode::Toggle,
+            lock_on_double_tap: false,
+            output: vec![],
+        })
+        .mapping(Mapping::Command {
+            input: KeyCode::KEY_F1,
+            command: command("show-legend"),
+            min_hold: None,
+            release: Some(command("hide-legend")),
+        })
+        .layer(
+            "nav",
+            vec![Mapping::Remap {
+                input: [KeyCode::KEY_J].into(),
+                output: [KeyCode::KEY_DOWN].into(),
+                order: vec![],
+                exact: false,
+            }],
+        )
+        .build();
+    let mut engine = MappingEngine::new(config, &millis(0));
+    let mut output = vec![];
+    for (key, value, time) in [
+        (KeyCode::KEY_SCROLLLOCK, PRESS, 0),
+        (KeyCode::KEY_SCROLLLOCK, RELEASE, 10),
+        (KeyCode::KEY_F1, PRESS, 100),
+        (KeyCode::KEY_F1, RELEASE, 500),
+    ] {
+        output.extend(engine.process_key(key, value, &millis(time)).unwrap());
+    }
+    let runs: Vec<_> = output
+        .into_iter()
+        .filter_map(|item| match item {
+            Output::Run(command) => Some(command),
+            _ => None,
+        })
+        .collect();
+    let mut show = command("show-legend");
+    show.env
+        .insert("EVREMAP_LAYER".to_string(), "nav".to_string());
+    show.env.insert(
+        "EVREMAP_LAYER_KEYS".to_string(),
+        "KEY_J=KEY_DOWN".to_string(),
+    );
+    let mut hide = command("hide-legend");
+    hide.env = show.env.clone();
+    assert_eq!(runs, vec![show, hide]);
+}
+
+/// Writes `toml` to a file of its own and loads it, as evremap does
+fn load_config(name: &str, toml: &str) -> MappingConfig {
+    try_load_config(name, toml).unwrap()
+}
+
+/// Like `load_config`, but returns the error that loading it fails with
+fn try_load_config(name: &str, toml: &str) -> Result<MappingConfig, String> {
+    let path =
+        std::env::temp_dir().join(format!("evremap-test-{}-{name}.toml", std::process::id()));
+    std::fs::write(&path, toml).unwrap();
+    let config = MappingConfig::from_file(&path);
+    std::fs::remove_file(&path).unwrap();
+    config
+        .map(|config| config.split_devices().remove(0))
+        .map_err(|err| format!("{err:#}"))
+}
+
+#[test]
+fn config_file_to_output_frames() {
+    let config = load_config(
+        "frames",
+        r#"
+device_name = "test keyboard"
+
+[[dual_role]]
+input = "KEY_CAPSLOCK"
+hold = ["KEY_LEFTCTRL"]
+tap = ["KEY_ESC"]
+
+[[remap]]
+input = ["KEY_LEFTALT", "KEY_H"]
+output = ["KEY_LEFT"]
+"#,
+    );
+    let mut engine = MappingEngine::new(config, &millis(0));
+    let mut output = vec![];
+    for (key, scan, value, time) in [
+        (KeyCode::KEY_CAPSLOCK, 0x3a, PRESS, 0),
+        (KeyCode::KEY_CAPSLOCK, 0x3a, RELEASE, 50),
+        (KeyCode::KEY_LEFTALT, 0x38, PRESS, 100),
+        (KeyCode::KEY_H, 0x23, PRESS, 150),
+        (KeyCode::KEY_H, 0x23, RELEASE, 200),
+        (KeyCode::KEY_LEFTALT, 0x38, RELEASE, 250),
+    ] {
+        for (code, value) in [
+            (EventCode::EV_MSC(EV_MSC::MSC_SCAN), scan),
+            (EventCode::EV_KEY(key), value),
+            (EventCode::EV_SYN(EV_SYN::SYN_REPORT), 0),
+        ] {
+            let event = InputEvent::new(&millis(time), &code, value);
+            output.extend(engine.process_event(&event).unwrap());
+        }
+    }
+    let events: Vec<(EventCode, i32)> = output
+        .into_iter()
+        .filter_map(|item| match item {
+            Output::Event(event) => Some((event.event_code, event.value)),
+            Output::Run(_) => None,
+        })
+        .collect();
+    let keys: Vec<(KeyCode, i32)> = events
+        .iter()
+        .filter_map(|(code, value)| match code {
+            EventCode::EV_KEY(key) => Some((*key, *value)),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(
+        keys,
+        vec![
+            (KeyCode::KEY_LEFTCTRL, PRESS),
+            (KeyCode::KEY_LEFTCTRL, RELEASE),
+            (KeyCode::KEY_ESC, PRESS),
+            (KeyCode::KEY_ESC, RELEASE),
+            (KeyCode::KEY_LEFTALT, PRESS),
+            (KeyCode::KEY_LEFTALT, RELEASE),
+            (KeyCode::KEY_LEFT, PRESS),
+            (KeyCode::KEY_LEFT, RELEASE),
+            (KeyCode::KEY_LEFTALT, PRESS),
+            (KeyCode::KEY_LEFTALT, RELEASE),
+        ]
+    );
+    // Each key event is reported in a frame of its own
+    let mut in_frame = false;
+    for (code, _) in &events {
+        match code {
+            EventCode::EV_KEY(_) => {
+                assert!(!in_frame, "two key events in one frame: {events:?}");
+                in_frame = true;
+            }
+            EventCode::EV_SYN(EV_SYN::SYN_REPORT) => in_frame = false,
+            _ => {}
+        }
+    }
+    assert!(!in_frame, "the last frame wasn't reported");
+}
+
+#[test]
+fn higher_priority_entries_are_considered_first() {
+    let config = load_config(
+        "priority",
+        r#"
+device_name = "test keyboard"
+
+[[remap]]
+input = ["KEY_A"]
+output = ["KEY_B"]
+
+[[remap]]
+input = ["KEY_A"]
+output = ["KEY_C"]
+priority = 10
+
+[[remap]]
+input = ["KEY_A"]
+output = ["KEY_D"]
+priority = 10
+"#,
+    );
+    // The first of the entries with the highest priority wins
+    assert_eq!(
+        run(
+            config,
+            &[(KeyCode::KEY_A, PRESS, 0), (KeyCode::KEY_A, RELEASE, 50)]
+        ),
+        vec![(KeyCode::KEY_C, PRESS), (KeyCode::KEY_C, RELEASE)]
+    );
+}
+
+#[test]
+fn priority_is_compared_across_kinds_of_entry() {
+    let config = load_config(
+        "priority-kinds",
+        r#"
+device_name = "test keyboard"
+
+[[dual_role]]
+input = "KEY_A"
+hold = ["KEY_LEFTCTRL"]
+tap = ["KEY_ESC"]
+
+[[remap]]
+input = ["KEY_A"]
+output = ["KEY_C"]
+priority = 1
+"#,
+    );
+    let is_remap: Vec<bool> = config
+        .mappings
+        .iter()
+        .map(|map| matches!(map, Mapping::Remap { .. }))
+        .collect();
+    assert_eq!(is_remap, vec![true, false]);
+}
+
+#[test]
+fn keymap_expands_to_single_key_remaps() {
+    let keymap = load_config(
+        "keymap",
+        r#"
+device_name = "test keyboard"
+
+[keymap]
+KEY_CAPSLOCK = "KEY_ESC"
+KEY_ESC = "KEY_CAPSLOCK"
+"#,
+    );
+    let remaps = load_config(
+        "keymap-remaps",
+        r#"
+device_name = "test keyboard"
+
+[[remap]]
+input = ["KEY_CAPSLOCK"]
+output = ["KEY_ESC"]
+
+[[remap]]
+input = ["KEY_ESC"]
+output = ["KEY_CAPSLOCK"]
+"#,
+    );
+    assert_eq!(keymap.mappings.len(), 2);
+    for map in &keymap.mappings {
+        assert!(remaps.mappings.contains(map), "{map:?} isn't in {remaps:?}");
+    }
+    assert_eq!(
+        run(
+            keymap,
+            &[
+                (KeyCode::KEY_CAPSLOCK, PRESS, 0),
+                (KeyCode::KEY_CAPSLOCK, RELEASE, 50),
+                (KeyCode::KEY_ESC, PRESS, 100),
+                (KeyCode::KEY_ESC, RELEASE, 150),
+            ]
+        ),
+        vec![
+            (KeyCode::KEY_ESC, PRESS),
+            (KeyCode::KEY_ESC, RELEASE),
+            (KeyCode::KEY_CAPSLOCK, PRESS),
+            (KeyCode::KEY_CAPSLOCK, RELEASE),
+        ]
+    );
+}
+
+#[test]
+fn keymap_rejects_unknown_and_duplicate_keys() {
+    for (name, toml) in [
+        ("keymap-input", "[keymap]\nKEY_NOPE = \"KEY_ESC\"\n"),
+        ("keymap-output", "[keymap]\nKEY_CAPSLOCK = \"KEY_NOPE\"\n"),
+        (
+            "keymap-duplicate",
+            "[keymap]\nKEY_CAPSLOCK = \"KEY_ESC\"\nKEY_CAPSLOCK = \"KEY_A\"\n",
+        ),
+    ] {
+        let toml = format!("device_name = \"test keyboard\"\n\n{toml}");
+        let err = try_load_config(name, &toml).expect_err(name);
+        assert!(
+            err.contains("KEY_NOPE") || err.contains("KEY_CAPSLOCK"),
+            "{name}: {err}"
+        );
+    }
+}
+
+#[test]
+fn substitute_modifier_keeps_its_exceptions() {
+    let toml = r#"
+device_name = "test keyboard"
+
+[[substitute_modifier]]
+modifier = "KEY_LEFTCTRL"
+replacement = "KEY_LEFTMETA"
+except = ["KEY_C", "KEY_V"]
+"#;
+    let excepted = run(
+        load_config("substitute-except", toml),
+        &[
+            (KeyCode::KEY_LEFTCTRL, PRESS, 0),
+            (KeyCode::KEY_C, PRESS, 50),
+            (KeyCode::KEY_C, RELEASE, 100),
+            (KeyCode::KEY_LEFTCTRL, RELEASE, 150),
+        ],
+    );
+    let substituted = run(
+        load_config("substitute", toml),
+        &[
+            (KeyCode::KEY_LEFTCTRL, PRESS, 0),
+            (KeyCode::KEY_X, PRESS, 50),
+            (KeyCode::KEY_X, RELEASE, 100),
+            (KeyCode::KEY_LEFTCTRL, RELEASE, 150),
+        ],
+    );
+    assert_eq!(
+        excepted,
+        vec![
+            (KeyCode::KEY_LEFTCTRL, PRESS),
+            (KeyCode::KEY_C, PRESS),
+            (KeyCode::KEY_C, RELEASE),
+            (KeyCode::KEY_LEFTCTRL, RELEASE),
+        ]
+    );
+    // CTRL is held on its own again once X is released
+    assert_eq!(
+        substituted,
+        vec![
+            (KeyCode::KEY_LEFTCTRL, PRESS),
+            (KeyCode::KEY_LEFTCTRL, RELEASE),
+            (KeyCode::KEY_LEFTMETA, PRESS),
+            (KeyCode::KEY_X, PRESS),
+            (KeyCode::KEY_X, RELEASE),
+            (KeyCode::KEY_LEFTMETA, RELEASE),
+            (KeyCode::KEY_LEFTCTRL, PRESS),
+            (KeyCode::KEY_LEFTCTRL, RELEASE),
+        ]
+    );
+}
+
+#[test]
+fn dual_role_can_mix_a_button_tap_with_a_key_hold() {
+    let config = || {
+        MappingConfig::builder()
+            .dual_role(
+                KeyCode::KEY_F13,
+                [KeyCode::KEY_LEFTCTRL],
+                [KeyCode::BTN_LEFT],
+            )
+            .build()
+    };
+    // Tapped while another key is held
+    let tapped = run(
+        config(),
+        &[
+            (KeyCode::KEY_A, PRESS, 0),
+            (KeyCode::KEY_F13, PRESS, 50),
+            (KeyCode::KEY_F13, RELEASE, 100),
+            (KeyCode::KEY_A, RELEASE, 150),
+        ],
+    );
+    let held = run(
+        config(),
+        &[
+            (KeyCode::KEY_F13, PRESS, 0),
+            (KeyCode::BTN_LEFT, PRESS, 50),
+            (KeyCode::BTN_LEFT, RELEASE, 100),
+            (KeyCode::KEY_F13, RELEASE, 150),
+        ],
+    );
+    assert_eq!(
+        tapped,
+        vec![
+            (KeyCode::KEY_A, PRESS),
+            (KeyCode::KEY_LEFTCTRL, PRESS),
+            (KeyCode::KEY_LEFTCTRL, RELEASE),
+            (KeyCode::BTN_LEFT, PRESS),
+            (KeyCode::BTN_LEFT, RELEASE),
+            (KeyCode::KEY_A, RELEASE),
+        ]
+    );
+    // Held for a Ctrl+click
+    assert_eq!(
+        held,
+        vec![
+            (KeyCode::KEY_LEFTCTRL, PRESS),
+            (KeyCode::BTN_LEFT, PRESS),
+            (KeyCode::BTN_LEFT, RELEASE),
+            (KeyCode::KEY_LEFTCTRL, RELEASE),
+        ]
+    );
+}
+
+#[test]
+fn remap_presses_its_order_first() {
+    let config = MappingConfig::builder()
+        .mapping(Mapping::Remap {
+            input: [KeyCode::KEY_F1].into(),
+            output: [
+                KeyCode::KEY_LEFTCTRL,
+                KeyCode::KEY_LEFTSHIFT,
+                KeyCode::KEY_T,
+            ]
+            .into(),
+            order: vec![KeyCode::KEY_LEFTSHIFT, KeyCode::KEY_LEFTCTRL],
+            exact: false,
+        })
+        .build();
+    assert_eq!(
+        run(
+            config,
+            &[(KeyCode::KEY_F1, PRESS, 0), (KeyCode::KEY_F1, RELEASE, 50)]
+        ),
+        vec![
+            (KeyCode::KEY_LEFTSHIFT, PRESS),
+            (KeyCode::KEY_LEFTCTRL, PRESS),
+            (KeyCode::KEY_T, PRESS),
+            (KeyCode::KEY_T, RELEASE),
+            (KeyCode::KEY_LEFTCTRL, RELEASE),
+            (KeyCode::KEY_LEFTSHIFT, RELEASE),
+        ]
+    );
+}
+
+/// Rewrites the left CTRL to the left META in everything written
+fn rewrite_ctrl(mut config: MappingConfig) -> MappingConfig {
+    config
+        .modifier_rewrite
+        .insert(KeyCode::KEY_LEFTCTRL, KeyCode::KEY_LEFTMETA);
+    config
+}
+
+#[test]
+fn modifier_rewrite_applies_to_dual_role_holds() {
+    assert_eq!(
+        run(
+            rewrite_ctrl(dual_role_config()),
+            &[
+                (KeyCode::KEY_CAPSLOCK, PRESS, 0),
+                (KeyCode::KEY_C, PRESS, 50),
+                (KeyCode::KEY_C, RELEASE, 100),
+                (KeyCode::KEY_CAPSLOCK, RELEASE, 150),
+            ]
+        ),
+        vec![
+            (KeyCode::KEY_LEFTMETA, PRESS),
+            (KeyCode::KEY_C, PRESS),
+            (KeyCode::KEY_C, RELEASE),
+            (KeyCode::KEY_LEFTMETA, RELEASE),
+        ]
+    );
+}
+
+#[test]
+fn modifier_rewrite_applies_to_macros() {
+    let config = MappingConfig::builder()
+        .mapping(Mapping::Macro {
+            input: KeyCode::KEY_F13,
+            output: vec![
+                MacroStep::Press(KeyCode::KEY_LEFTCTRL),
+                MacroStep::Tap(KeyCode::KEY_C),
+                MacroStep::Release(KeyCode::KEY_LEFTCTRL),
+            ],
+            delay: Duration::ZERO,
+        })
+        .build();
+    assert_eq!(
+        run(
+            rewrite_ctrl(config),
+            &[
+                (KeyCode::KEY_F13, PRESS, 0),
+                (KeyCode::KEY_F13, RELEASE, 10)
+            ]
+        ),
+        vec![
+            (KeyCode::KEY_LEFTMETA, PRESS),
+            (KeyCode::KEY_C, PRESS),
+            (KeyCode::KEY_C, RELEASE),
+            (KeyCode::KEY_LEFTMETA, RELEASE),
+        ]
+    );
+}
+
+#[test]
+fn modifier_rewrite_applies_to_chord_outputs() {
+    let config = MappingConfig::builder()
+        .remap(
+            [KeyCode::KEY_J, KeyCode::KEY_K],
+            [KeyCode::KEY_LEFTCTRL, KeyCode::KEY_W],
+        )
+        .build();
+    assert_eq!(
+        run(
+            rewrite_ctrl(config),
+            &[
+                (KeyCode::KEY_J, PRESS, 0),
+                (KeyCode::KEY_K, PRESS, 10),
+                (KeyCode::KEY_K, RELEASE, 50),
+                (KeyCode::KEY_J, RELEASE, 60),
+            ]
+        ),
+        vec![
+            (KeyCode::KEY_J, PRESS),
+            (KeyCode::KEY_J, RELEASE),
+            (KeyCode::KEY_LEFTMETA, PRESS),
+            (KeyCode::KEY_W, PRESS),
+            (KeyCode::KEY_W, RELEASE),
+            (KeyCode::KEY_LEFTMETA, RELEASE),
+            (KeyCode::KEY_J, PRESS),
+            (KeyCode::KEY_J, RELEASE),
+        ]
+    );
+}
+
+#[test]
+fn modifier_rewrite_target_is_held_while_any_of_its_sources_is() {
+    let mut config = rewrite_ctrl(MappingConfig::builder().build());
+    config
+        .modifier_rewrite
+        .insert(KeyCode::KEY_RIGHTCTRL, KeyCode::KEY_LEFTMETA);
+    assert_eq!(
+        run(
+            config,
+            &[
+                (KeyCode::KEY_LEFTCTRL, PRESS, 0),
+                (KeyCode::KEY_RIGHTCTRL, PRESS, 10),
+                (KeyCode::KEY_LEFTCTRL, RELEASE, 20),
+                (KeyCode::KEY_A, PRESS, 30),
+                (KeyCode::KEY_A, RELEASE, 40),
+                (KeyCode::KEY_RIGHTCTRL, RELEASE, 50),
+            ]
+        ),
+        vec![
+            (KeyCode::KEY_LEFTMETA, PRESS),
+            (KeyCode::KEY_A, PRESS),
+            (KeyCode::KEY_A, RELEASE),
+            (KeyCode::KEY_LEFTMETA, RELEASE),
+        ]
+    );
+}
+
+#[test]
+fn held_output_keys_are_what_the_output_device_sees() {
+    let mut config = rewrite_ctrl(
+        MappingConfig::builder()
+            .remap([KeyCode::KEY_F1], [KeyCode::KEY_LEFTCTRL])
+            .build(),
+    );
+    config.low_latency_passthrough = true;
+    let mut engine = MappingEngine::new(config, &millis(0));
+    engine
+        .process_key(KeyCode::KEY_A, PRESS, &millis(0))
+        .unwrap();
+    engine
+        .process_key(KeyCode::KEY_F1, PRESS, &millis(10))
+        .unwrap();
+    assert_eq!(
+        engine.held_output_keys(),
+        vec![KeyCode::KEY_A, KeyCode::KEY_LEFTMETA]
+    );
+}
+
+#[test]
+fn dragged_mouse_button_is_released_after_max_drag() {
+    let mut config = MappingConfig::builder()
+        .dual_role(KeyCode::KEY_F13, [KeyCode::BTN_LEFT], [KeyCode::KEY_ESC])
+        .build();
+    config.max_drag = Some(Duration::from_millis(500));
+    // The release of F13 is lost, and the next event is long after
+    assert_eq!(
+        run(
+            config,
+            &[(KeyCode::KEY_F13, PRESS, 0), (KeyCode::KEY_A, PRESS, 2000)]
+        ),
+        vec![
+            (KeyCode::BTN_LEFT, PRESS),
+            (KeyCode::BTN_LEFT, RELEASE),
+            (KeyCode::KEY_A, PRESS),
+        ]
+    );
+}
+
+fn abort_hold_config() -> MappingConfig {
+    MappingConfig::builder()
+        .mapping(Mapping::DualRole {
+            input: KeyCode::KEY_CAPSLOCK,
+            hold: vec![KeyCode::KEY_LEFTCTRL],
+            tap: vec![KeyCode::KEY_ESC],
+            abort_hold_emits_tap: true,
+            hold_timeout: None,
+            hold_on_other_key_press: true,
+            permissive_hold: false,
+            quick_tap: None,
+            chordal_hold: false,
+        })
+        .build()
+}
+
+#[test]
+fn aborted_hold_emits_tap() {
+    // SHIFT went down while CAPSLOCK was held, but nothing used CTRL
+    let aborted = run(
+        abort_hold_config(),
+        &[
+            (KeyCode::KEY_CAPSLOCK, PRESS, 0),
+            (KeyCode::KEY_LEFTSHIFT, PRESS, 30),
+            (KeyCode::KEY_CAPSLOCK, RELEASE, 60),
+            (KeyCode::KEY_LEFTSHIFT, RELEASE, 90),
+        ],
+    );
+    // C used CTRL, so releasing CAPSLOCK isn't a tap
+    let used = run(
+        abort_hold_config(),
+        &[
+            (KeyCode::KEY_CAPSLOCK, PRESS, 0),
+            (KeyCode::KEY_C, PRESS, 30),
+            (KeyCode::KEY_CAPSLOCK, RELEASE, 60),
+            (KeyCode::KEY_C, RELEASE, 90),
+        ],
+    );
+    assert_eq!(
+        aborted,
+        vec![
+            (KeyCode::KEY_LEFTCTRL, PRESS),
+            (KeyCode::KEY_LEFTSHIFT, PRESS),
+            (KeyCode::KEY_LEFTCTRL, RELEASE),
+            (KeyCode::KEY_ESC, PRESS),
+            (KeyCode::KEY_ESC, RELEASE),
+            (KeyCode::KEY_LEFTSHIFT, RELEASE),
+        ]
+    );
+    assert_eq!(
+        used,
+        vec![
+            (KeyCode::KEY_LEFTCTRL, PRESS),
+            (KeyCode::KEY_C, PRESS),
+            (KeyCode::KEY_LEFTCTRL, RELEASE),
+            (KeyCode::KEY_C, RELEASE),
+        ]
+    );
+}
+
+fn shift_lock_config(timeout: Option<Duration>) -> MappingConfig {
+    MappingConfig::builder()
+        .mapping(Mapping::ModLock {
+            input: KeyCode::KEY_F9,
+            output: vec![KeyCode::KEY_LEFTSHIFT],
+            timeout,
+        })
+        .build()
+}
+
+#[test]
+fn mod_lock_holds_its_modifier_until_tapped_again() {
+    let mut engine = MappingEngine::new(shift_lock_config(None), &millis(0));
+    let mut output = vec![];
+    for (key, value, time) in [
+        (KeyCode::KEY_F9, PRESS, 0),
+        (KeyCode::KEY_F9, RELEASE, 50),
+        (KeyCode::KEY_A, PRESS, 100),
+        (KeyCode::KEY_A, RELEASE, 150),
+        (KeyCode::KEY_B, PRESS, 200),
+        (KeyCode::KEY_B, RELEASE, 250),
+    ] {
+        output.extend(engine.process_key(key, value, &millis(time)).unwrap());
+    }
+    assert!(
+        engine.state_as_json().contains("\"locked\":[\"KEY_F9\"]"),
+        "{}",
+        engine.state_as_json()
+    );
+    for (key, value, time) in [
+        (KeyCode::KEY_F9, PRESS, 300),
+        (KeyCode::KEY_F9, RELEASE, 350),
+        (KeyCode::KEY_C, PRESS, 400),
+        (KeyCode::KEY_C, RELEASE, 450),
+    ] {
+        output.extend(engine.process_key(key, value, &millis(time)).unwrap());
+    }
+    assert!(
+        engine.state_as_json().contains("\"locked\":[]"),
+        "{}",
+        engine.state_as_json()
+    );
+    assert_eq!(
+        key_events(output),
+        vec![
+            (KeyCode::KEY_LEFTSHIFT, PRESS),
+            (KeyCode::KEY_A, PRESS),
+            (KeyCode::KEY_A, RELEASE),
+            (KeyCode::KEY_B, PRESS),
+            (KeyCode::KEY_B, RELEASE),
+            (KeyCode::KEY_LEFTSHIFT, RELEASE),
+            (KeyCode::KEY_C, PRESS),
+            (KeyCode::KEY_C, RELEASE),
+        ]
+    );
+}
+
+#[test]
+fn mod_lock_times_out() {
+    assert_eq!(
+        run(
+            shift_lock_config(Some(Duration::from_millis(500))),
+            &[
+                (KeyCode::KEY_F9, PRESS, 0),
+                (KeyCode::KEY_F9, RELEASE, 50),
+                (KeyCode::KEY_A, PRESS, 100),
+                (KeyCode::KEY_A, RELEASE, 150),
+                (KeyCode::KEY_B, PRESS, 1000),
+                (KeyCode::KEY_B, RELEASE, 1050),
+            ]
+        ),
+        vec![
+            (KeyCode::KEY_LEFTSHIFT, PRESS),
+            (KeyCode::KEY_A, PRESS),
+            (KeyCode::KEY_A, RELEASE),
+            (KeyCode::KEY_LEFTSHIFT, RELEASE),
+            (KeyCode::KEY_B, PRESS),
+            (KeyCode::KEY_B, RELEASE),
+        ]
+    );
+}
+
+#[test]
+fn repeats_pass_through_or_repeat_the_mapped_output() {
+    let config = MappingConfig::builder()
+        .remap([KeyCode::KEY_CAPSLOCK], [KeyCode::KEY_ESC])
+        .build();
+    assert_eq!(
+        run(
+            config,
+            &[
+                (KeyCode::KEY_A, PRESS, 0),
+                (KeyCode::KEY_A, 2, 500),
+                (KeyCode::KEY_A, 2, 530),
+                (KeyCode::KEY_A, RELEASE, 550),
+                (KeyCode::KEY_CAPSLOCK, PRESS, 600),
+                (KeyCode::KEY_CAPSLOCK, 2, 1100),
+                (KeyCode::KEY_CAPSLOCK, RELEASE, 1150),
+            ]
+        ),
+        vec![
+            (KeyCode::KEY_A, PRESS),
+            (KeyCode::KEY_A, 2),
+            (KeyCode::KEY_A, 2),
+            (KeyCode::KEY_A, RELEASE),
+            (KeyCode::KEY_ESC, PRESS),
+            (KeyCode::KEY_ESC, 2),
+            (KeyCode::KEY_ESC, RELEASE),
+        ]
+    );
+}
+
+/// Typing j then k quickly produces ESC, and j on its own is DOWN
+fn sequence_config() -> MappingConfig {
+    load_config(
+        "sequence",
+        r#"
+device_name = "test keyboard"
+
+[[sequence]]
+keys = ["KEY_J", "KEY_K"]
+output = ["KEY_ESC"]
+window_ms = 150
+
+[[remap]]
+input = ["KEY_J"]
+output = ["KEY_DOWN"]
+"#,
+    )
+}
+
+#[test]
+fn sequence_taps_its_output() {
+    assert_eq!(
+        run(
+            sequence_config(),
+            &[
+                (KeyCode::KEY_J, PRESS, 0),
+                (KeyCode::KEY_K, PRESS, 50),
+                (KeyCode::KEY_J, RELEASE, 80),
+                (KeyCode::KEY_K, RELEASE, 100),
+            ]
+        ),
+        vec![(KeyCode::KEY_ESC, PRESS), (KeyCode::KEY_ESC, RELEASE)]
+    );
+}
+
+#[test]
+fn broken_sequence_replays_its_keys_in_order() {
+    assert_eq!(
+        run(
+            sequence_config(),
+            &[
+                (KeyCode::KEY_J, PRESS, 0),
+                (KeyCode::KEY_J, RELEASE, 30),
+                (KeyCode::KEY_A, PRESS, 60),
+                (KeyCode::KEY_A, RELEASE, 90),
+            ]
+        ),
+        vec![
+            (KeyCode::KEY_DOWN, PRESS),
+            (KeyCode::KEY_DOWN, RELEASE),
+            (KeyCode::KEY_A, PRESS),
+            (KeyCode::KEY_A, RELEASE),
+        ]
+    );
+}
+
+#[test]
+fn sequence_times_out() {
+    assert_eq!(
+        run(
+            sequence_config(),
+            &[
+                (KeyCode::KEY_J, PRESS, 0),
+                (KeyCode::KEY_K, PRESS, 400),
+                (KeyCode::KEY_K, RELEASE, 450),
+                (KeyCode::KEY_J, RELEASE, 500),
+            ]
+        ),
+        vec![
+            (KeyCode::KEY_DOWN, PRESS),
+            (KeyCode::KEY_K, PRESS),
+            (KeyCode::KEY_K, RELEASE),
+            (KeyCode::KEY_DOWN, RELEASE),
+        ]
+    );
+}
+
+#[test]
+fn fn_key_can_be_a_layer_key() {
+    let config = load_config(
+        "fn-layer",
+        r#"
+device_name = "test keyboard"
+low_latency_passthrough = true
+
+[[layer_key]]
+input = "KEY_FN"
+layer = "nav"
+
+[[layer]]
+name = "nav"
+
+[[layer.remap]]
+input = ["KEY_H"]
+output = ["KEY_LEFT"]
+"#,
+    );
+    assert_eq!(
+        run(
+            config,
+            &[
+                (KeyCode::KEY_FN, PRESS, 0),
+                (KeyCode::KEY_H, PRESS, 50),
+                (KeyCode::KEY_H, RELEASE, 100),
+                (KeyCode::KEY_FN, RELEASE, 150),
+                (KeyCode::KEY_H, PRESS, 200),
+                (KeyCode::KEY_H, RELEASE, 250),
+            ]
+        ),
+        vec![
+            (KeyCode::KEY_LEFT, PRESS),
+            (KeyCode::KEY_LEFT, RELEASE),
+            (KeyCode::KEY_H, PRESS),
+            (KeyCode::KEY_H, RELEASE),
+        ]
+    );
+}
+
+#[test]
+fn migrate_config_keeps_comments() {
+    let path =
+        std::env::temp_dir().join(format!("evremap-test-{}-migrate.toml", std::process::id()));
+    std::fs::write(
+        &path,
+        r#"# My laptop's keyboard
+device_name = "test keyboard"
+
+# Caps Lock is Ctrl when held
+[[dual_role]]
+input = "KEY_CAPSLOCK"
+hold = ["KEY_LEFTCTRL"] # and Esc when tapped
+tap = ["KEY_ESC"]
+"#,
+    )
+    .unwrap();
+    let migrated = evremap::mapping::migrate_config_file(&path);
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(
+        migrated.unwrap(),
+        r#"# My laptop's keyboard
+device_name = "test keyboard"
+version = 1
+
+# Caps Lock is Ctrl when held
+[[dual_role]]
+input = "KEY_CAPSLOCK"
+hold = ["KEY_LEFTCTRL"] # and Esc when tapped
+tap = ["KEY_ESC"]
+"#
+    );
+}
@@ -0,0 +1,34 @@
+//! Tests that what evremap sets up before it enters the sandbox keeps
+//! working inside it.  Entering the sandbox can't be undone, so these
+//! are kept apart from the other tests, in a process of their own.
+
+use evremap::systemd::Notifier;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::path::Path;
+use std::time::Duration;
+
+#[test]
+fn notifier_works_in_the_sandbox() {
+    let name = format!("evremap-test-{}", std::process::id());
+    let systemd = UnixDatagram::bind_addr(&SocketAddr::from_abstract_name(&name).unwrap()).unwrap();
+    systemd
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    std::env::set_var("NOTIFY_SOCKET", format!("@{name}"));
+
+    let notifier = Notifier::from_env().expect("no notifier was created");
+    evremap::sandbox::enter(Path::new(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/Cargo.toml"
+    )))
+    .unwrap();
+    // A notifier can no longer be created, which is why main
+    // creates it before entering the sandbox
+    assert!(Notifier::from_env().is_none());
+
+    notifier.notify("READY=1");
+    let mut buf = [0; 64];
+    let len = systemd.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..len], b"READY=1");
+}
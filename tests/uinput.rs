@@ -0,0 +1,162 @@
+//! End-to-end tests that remap a virtual keyboard, made with uinput,
+//! through the whole of `InputMapper`, and read back what comes out of
+//! the virtual device that it creates.  They need read and write access
+//! to /dev/uinput and /dev/input/event*, so they are only built with
+//! `cargo test --features uinput-tests`.
+#![cfg(feature = "uinput-tests")]
+
+use evdev_rs::enums::{EventCode, EV_SYN};
+use evdev_rs::{Device, DeviceWrapper, InputEvent, ReadFlag, TimeVal, UInputDevice, UninitDevice};
+use evremap::mapping::{KeyCode, RelCode};
+use evremap::remapper::InputMapper;
+use evremap::MappingConfig;
+use std::fs::OpenOptions;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// How long to wait for a device to appear, or an event to come out
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Creates a virtual keyboard that reports `keys`, and returns
+/// it along with the path of its device node
+fn source_device(keys: &[KeyCode]) -> (UInputDevice, PathBuf) {
+    let device = UninitDevice::new().unwrap();
+    device.set_name("evremap test keyboard");
+    for key in keys {
+        device.enable(EventCode::EV_KEY(*key)).unwrap();
+    }
+    let device = UInputDevice::create_from_device(&device).unwrap();
+    let path = PathBuf::from(device.devnode().expect("the device has no device node"));
+    (device, path)
+}
+
+/// Starts remapping the device at `path` with `config`
+/// on a thread of its own
+fn start_mapper(path: &Path, config: MappingConfig) {
+    let path = path.to_path_buf();
+    let (created, wait) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let mapper = InputMapper::create_mapper(&path, config, false);
+        let mut mappers = match mapper {
+            Ok(mapper) => vec![mapper],
+            Err(err) => {
+                created.send(Err(err)).unwrap();
+                return;
+            }
+        };
+        created.send(Ok(())).unwrap();
+        if let Err(err) = InputMapper::run_mappers(&mut mappers, &[], None) {
+            panic!("{err:#}");
+        }
+    });
+    wait.recv().unwrap().unwrap();
+}
+
+/// Opens the virtual device that remaps the device at `path`,
+/// waiting for its device node to appear
+fn output_device(path: &Path) -> Device {
+    let name = format!("evremap Virtual input for {}", path.display());
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        for entry in std::fs::read_dir("/dev/input").unwrap() {
+            let entry = entry.unwrap();
+            if !entry.file_name().to_string_lossy().starts_with("event") {
+                continue;
+            }
+            let Ok(file) = OpenOptions::new()
+                .read(true)
+                .custom_flags(libc::O_NONBLOCK)
+                .open(entry.path())
+            else {
+                continue;
+            };
+            if let Ok(device) = Device::new_from_file(file) {
+                if device.name() == Some(name.as_str()) {
+                    return device;
+                }
+            }
+        }
+        assert!(Instant::now() < deadline, "{name} didn't appear");
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+/// Writes a press or release of `key` to `device`, in a frame of its own
+fn write_key(device: &UInputDevice, key: KeyCode, value: i32) {
+    let time = TimeVal::new(0, 0);
+    device
+        .write_event(&InputEvent::new(&time, &EventCode::EV_KEY(key), value))
+        .unwrap();
+    device
+        .write_event(&InputEvent::new(
+            &time,
+            &EventCode::EV_SYN(EV_SYN::SYN_REPORT),
+            0,
+        ))
+        .unwrap();
+}
+
+/// Reads key events from `device` until `count` of them have come out
+fn read_keys(device: &Device, count: usize) -> Vec<(KeyCode, i32)> {
+    let deadline = Instant::now() + TIMEOUT;
+    let mut keys = vec![];
+    while keys.len() < count {
+        match device.next_event(ReadFlag::NORMAL) {
+            Ok((_, event)) => {
+                if let EventCode::EV_KEY(key) = event.event_code {
+                    keys.push((key, event.value));
+                }
+            }
+            Err(err) if err.raw_os_error() == Some(libc::EAGAIN) => {
+                assert!(Instant::now() < deadline, "only received {keys:?}");
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            Err(err) => panic!("reading the virtual device: {err}"),
+        }
+    }
+    keys
+}
+
+#[test]
+fn remaps_a_virtual_keyboard() {
+    let (source, path) = source_device(&[KeyCode::KEY_CAPSLOCK, KeyCode::KEY_A]);
+    let config = MappingConfig::builder()
+        .remap([KeyCode::KEY_CAPSLOCK], [KeyCode::KEY_ESC])
+        .build();
+    start_mapper(&path, config);
+    let output = output_device(&path);
+
+    write_key(&source, KeyCode::KEY_CAPSLOCK, 1);
+    write_key(&source, KeyCode::KEY_CAPSLOCK, 0);
+    write_key(&source, KeyCode::KEY_A, 1);
+    write_key(&source, KeyCode::KEY_A, 0);
+    assert_eq!(
+        read_keys(&output, 4),
+        vec![
+            (KeyCode::KEY_ESC, 1),
+            (KeyCode::KEY_ESC, 0),
+            (KeyCode::KEY_A, 1),
+            (KeyCode::KEY_A, 0),
+        ]
+    );
+}
+
+#[test]
+fn advertises_extra_output_keys() {
+    let (_source, path) = source_device(&[KeyCode::KEY_A]);
+    let mut config = MappingConfig::builder().build();
+    config.extra_output_keys = vec![KeyCode::KEY_F13, KeyCode::BTN_LEFT];
+    start_mapper(&path, config);
+    let output = output_device(&path);
+
+    for key in [KeyCode::KEY_A, KeyCode::KEY_F13, KeyCode::BTN_LEFT] {
+        assert!(
+            output.has(EventCode::EV_KEY(key)),
+            "{key:?} isn't supported"
+        );
+    }
+    // A mouse button makes it look like a mouse, so that libinput
+    // doesn't ignore the button
+    assert!(output.has(EventCode::EV_REL(RelCode::REL_X)));
+}